@@ -0,0 +1,933 @@
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::ast::*;
+use crate::parser::Span;
+
+/**
+ * Predicates whose argument domains have more combinations than this are not
+ * checked for coverage.
+ */
+const MAX_COVERAGE_COMBINATIONS: usize = 10_000;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/**
+ * A problem found by static analysis of a program.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    // Stable identifier of the kind of problem, e.g. `left-recursion`, for
+    // tools that filter or look up diagnostics
+    pub code: &'static str,
+    pub severity: Severity,
+    // Index of the offending clause in Program.clauses
+    pub clause: usize,
+    pub message: String,
+    // How the problem might be fixed, if there's an obvious way
+    pub suggestion: Option<String>,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => f.write_str("warning"),
+            Severity::Error => f.write_str("error"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: clause {}: {}", self.severity, self.clause + 1, self.message)
+    }
+}
+
+/**
+ * Writes diagnostics as a JSON array for editors and CI pipelines, one
+ * object per line with the diagnostic's code, severity, the span of its
+ * clause, message and suggestion (null if there is none). Spans are of the
+ * program's clauses as returned by parse_with_spans.
+ */
+pub fn diagnostics_to_json(diagnostics: &[Diagnostic], spans: &[Span]) -> String {
+    let objects: Vec<String> = diagnostics
+        .iter()
+        .map(|diagnostic| {
+            json_object(
+                diagnostic.code,
+                diagnostic.severity,
+                spans.get(diagnostic.clause).copied(),
+                &diagnostic.message,
+                diagnostic.suggestion.as_deref(),
+            )
+        })
+        .collect();
+    json_array(&objects)
+}
+
+/**
+ * Writes a single error that isn't about a clause, such as a syntax error,
+ * in the format of diagnostics_to_json.
+ */
+pub fn error_to_json(code: &str, span: Option<Span>, message: &str) -> String {
+    json_array(&[json_object(code, Severity::Error, span, message, None)])
+}
+
+fn json_array(objects: &[String]) -> String {
+    if objects.is_empty() {
+        return String::from("[]\n");
+    }
+    format!("[\n  {}\n]\n", objects.join(",\n  "))
+}
+
+fn json_object(code: &str, severity: Severity, span: Option<Span>, message: &str, suggestion: Option<&str>) -> String {
+    let span = match span {
+        Some(span) => format!(
+            "{{\"line\": {}, \"column\": {}, \"end_line\": {}, \"end_column\": {}}}",
+            span.line, span.column, span.end_line, span.end_column
+        ),
+        None => String::from("null"),
+    };
+    format!(
+        "{{\"code\": {}, \"severity\": {}, \"span\": {}, \"message\": {}, \"suggestion\": {}}}",
+        json_string(code),
+        json_string(&severity.to_string()),
+        span,
+        json_string(message),
+        suggestion.map_or(String::from("null"), json_string)
+    )
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/**
+ * Runs all static checks over a program.
+ */
+pub fn check_program(program: &Program) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for (i, clause) in program.clauses.iter().enumerate() {
+        check_infinite_recursion(i, clause, &mut diagnostics);
+        check_goal_order(i, clause, &mut diagnostics);
+    }
+    diagnostics.extend(check_types(program));
+    diagnostics
+}
+
+/**
+ * Returns the name and arity of the predicate a head or goal refers to, or
+ * None if it is a variable.
+ */
+pub fn predicate_indicator(term: &Term) -> Option<(&str, usize)> {
+    match term {
+        Term::Simple(SimpleTerm::Atom(name)) => Some((name, 0)),
+        Term::Compound(CompoundTerm { name: SimpleTerm::Atom(name), parameters }) => {
+            Some((name, parameters.len()))
+        },
+        _ => None
+    }
+}
+
+/**
+ * Flags clauses that recurse without any chance of terminating:
+ * - a body goal identical to the head (`p X if p X`) repeats the same call
+ * - a recursive first goal (left recursion) where no argument is a proper
+ *   subterm of the corresponding head argument, so nothing gets smaller
+ */
+fn check_infinite_recursion(index: usize, clause: &Clause, diagnostics: &mut Vec<Diagnostic>) {
+    let indicator = match predicate_indicator(&clause.head) {
+        Some(indicator) => indicator,
+        None => return,
+    };
+
+    for (i, goal) in clause.body.iter().enumerate() {
+        if predicate_indicator(goal) != Some(indicator) {
+            continue;
+        }
+
+        if *goal == clause.head {
+            diagnostics.push(Diagnostic {
+                code: "infinite-recursion",
+                severity: Severity::Warning,
+                clause: index,
+                message: format!(
+                    "{}/{} calls itself with the same arguments and will never terminate",
+                    indicator.0, indicator.1
+                ),
+                suggestion: Some(String::from("call it with a smaller argument, or remove the call")),
+            });
+            return;
+        }
+
+        if i == 0 && !has_decreasing_argument(&clause.head, goal) {
+            diagnostics.push(Diagnostic {
+                code: "left-recursion",
+                severity: Severity::Warning,
+                clause: index,
+                message: format!(
+                    "{}/{} is left-recursive without a decreasing argument and may never terminate",
+                    indicator.0, indicator.1
+                ),
+                suggestion: Some(String::from("move the recursive goal after a goal that binds its arguments")),
+            });
+            return;
+        }
+    }
+}
+
+/**
+ * Flags goals that use variables which can't be bound yet when they run,
+ * given that goals run from left to right and that the caller may bind the
+ * variables of the head:
+ * - `X is E` where E has such a variable, which is an instantiation error
+ * - `not G` where G has one (floundering): the negation fails if G has a
+ *   solution for any value of the variable, rather than for the value it
+ *   gets later
+ *
+ * Variables starting with `_` are never flagged, since they are meant to be
+ * left unbound.
+ */
+fn check_goal_order(index: usize, clause: &Clause, diagnostics: &mut Vec<Diagnostic>) {
+    let mut bound = BTreeSet::new();
+    add_variables(&clause.head, &mut bound);
+    check_goals_order(index, &clause.body, &mut bound, diagnostics);
+}
+
+fn check_goals_order(index: usize, goals: &[Term], bound: &mut BTreeSet<String>, diagnostics: &mut Vec<Diagnostic>) {
+    for goal in goals {
+        let (name, parameters) = match goal {
+            Term::Compound(CompoundTerm { name: SimpleTerm::Atom(name), parameters }) => (name.as_str(), parameters),
+            _ => {
+                add_variables(goal, bound);
+                continue;
+            },
+        };
+        match (name, parameters.as_slice()) {
+            (IS, [_, expression]) => {
+                let unbound = unbound_variables(expression, bound);
+                if !unbound.is_empty() {
+                    diagnostics.push(Diagnostic {
+                        code: "unbound-arithmetic",
+                        severity: Severity::Warning,
+                        clause: index,
+                        message: format!(
+                            "`{}` evaluates {} before {} bound, which is an instantiation error",
+                            goal,
+                            unbound.join(", "),
+                            if unbound.len() == 1 { "it is" } else { "they are" }
+                        ),
+                        suggestion: Some(String::from("move the goal after the goals that bind its variables")),
+                    });
+                }
+            },
+            (NOT, [negated]) => {
+                let unbound = unbound_variables(negated, bound);
+                if !unbound.is_empty() {
+                    diagnostics.push(Diagnostic {
+                        code: "floundering-negation",
+                        severity: Severity::Warning,
+                        clause: index,
+                        message: format!(
+                            "`{}` runs with {} unbound, so it fails if the goal has a solution for any value of {}",
+                            goal,
+                            unbound.join(", "),
+                            if unbound.len() == 1 { "it" } else { "them" }
+                        ),
+                        suggestion: Some(String::from(
+                            "bind the variables before the negation, or start their names with _ if any value will do"
+                        )),
+                    });
+                }
+                // Negation binds nothing
+                continue;
+            },
+            (IF_THEN_ELSE, [condition, branches @ ..]) => {
+                // The else branch runs when the condition has failed
+                let before = bound.clone();
+                let mut goals = conjunction_goals(condition).to_vec();
+                goals.extend_from_slice(branches.first().map_or(&[], conjunction_goals));
+                check_goals_order(index, &goals, bound, diagnostics);
+                if let Some(otherwise) = branches.get(1) {
+                    let mut otherwise_bound = before;
+                    check_goals_order(index, conjunction_goals(otherwise), &mut otherwise_bound, diagnostics);
+                    bound.extend(otherwise_bound);
+                }
+                continue;
+            },
+            _ => (),
+        }
+        add_variables(goal, bound);
+    }
+}
+
+/**
+ * Returns the goals of a branch of an if-then-else, `and G...`.
+ */
+fn conjunction_goals(term: &Term) -> &[Term] {
+    match term {
+        Term::Compound(CompoundTerm { name: SimpleTerm::Atom(name), parameters }) if name == CONJUNCTION => parameters,
+        _ => std::slice::from_ref(term),
+    }
+}
+
+fn add_variables(term: &Term, variables: &mut BTreeSet<String>) {
+    term.walk(Order::PreOrder, &mut |term| {
+        if let Term::Simple(SimpleTerm::Variable(name)) = term {
+            variables.insert(name.clone());
+        }
+    });
+}
+
+/**
+ * Returns the variables of a term that aren't bound, in the order they
+ * first appear, leaving out those starting with `_`.
+ */
+fn unbound_variables(term: &Term, bound: &BTreeSet<String>) -> Vec<String> {
+    let mut unbound = Vec::new();
+    term.walk(Order::PreOrder, &mut |term| {
+        if let Term::Simple(SimpleTerm::Variable(name)) = term {
+            if !name.starts_with('_') && !bound.contains(name) && !unbound.contains(name) {
+                unbound.push(name.clone());
+            }
+        }
+    });
+    unbound
+}
+
+/**
+ * Analysis for decision-table style predicates, where the last argument is
+ * the result and the others are the conditions (a predicate with a single
+ * argument only has conditions). Reports pairs of clauses whose conditions
+ * overlap (some call matches both) and, for predicates whose conditions are
+ * all atoms or variables, combinations of those atoms that no clause covers.
+ */
+pub fn check_coverage(program: &Program) -> Vec<Diagnostic> {
+    let mut predicates: Vec<((&str, usize), Vec<usize>)> = Vec::new();
+    for (i, clause) in program.clauses.iter().enumerate() {
+        if let Some(indicator) = predicate_indicator(&clause.head) {
+            match predicates.iter_mut().find(|(p, _)| *p == indicator) {
+                Some((_, clauses)) => clauses.push(i),
+                None => predicates.push((indicator, vec![i])),
+            }
+        }
+    }
+
+    let mut diagnostics = Vec::new();
+    for ((name, arity), clauses) in predicates {
+        let heads: Vec<Term> = clauses
+            .iter()
+            .map(|i| conditions(&program.clauses[*i].head))
+            .collect();
+
+        for (a, first) in heads.iter().enumerate() {
+            for (b, second) in heads.iter().enumerate().skip(a + 1) {
+                if heads_overlap(first, second) {
+                    diagnostics.push(Diagnostic {
+                        code: "overlapping-heads",
+                        severity: Severity::Warning,
+                        clause: clauses[b],
+                        message: format!(
+                            "head of {}/{} overlaps with clause {}",
+                            name, arity, clauses[a] + 1
+                        ),
+                        suggestion: None,
+                    });
+                }
+            }
+        }
+
+        for mut pattern in uncovered_patterns(&heads) {
+            if arity > 1 {
+                if let Term::Compound(compound_term) = &mut pattern {
+                    compound_term.parameters.push(Term::variable("_"));
+                }
+            }
+            diagnostics.push(Diagnostic {
+                code: "uncovered-case",
+                severity: Severity::Warning,
+                clause: clauses[0],
+                message: format!("no clause of {}/{} covers {}", name, arity, pattern),
+                suggestion: Some(format!("add a clause for {}", pattern)),
+            });
+        }
+    }
+    diagnostics
+}
+
+/**
+ * Returns the head without its last (result) argument.
+ */
+fn conditions(head: &Term) -> Term {
+    let mut head = head.clone();
+    if let Term::Compound(compound_term) = &mut head {
+        if compound_term.parameters.len() > 1 {
+            compound_term.parameters.pop();
+        }
+    }
+    head
+}
+
+/**
+ * Returns true if some call would unify with both heads.
+ */
+fn heads_overlap(first: &Term, second: &Term) -> bool {
+    // Rename the variables of the second head apart from the first one
+    let mut second = second.clone();
+    second.walk_mut(Order::PreOrder, &mut |term| {
+        if let Term::Simple(SimpleTerm::Variable(name)) = term {
+            name.push('\'');
+        }
+    });
+    unify(first, &second, &mut HashMap::new())
+}
+
+/**
+ * Enumerates every combination of the atoms used in each argument position
+ * and returns the ones that no head matches, written as goals (with `_` for
+ * positions that only ever hold variables).
+ */
+fn uncovered_patterns(heads: &[Term]) -> Vec<Term> {
+    let arity = match &heads[0] {
+        Term::Compound(compound_term) => compound_term.parameters.len(),
+        _ => return Vec::new(),
+    };
+    let mut domains: Vec<BTreeSet<&str>> = vec![BTreeSet::new(); arity];
+    for head in heads {
+        if let Term::Compound(compound_term) = head {
+            for (i, param) in compound_term.parameters.iter().enumerate() {
+                match param {
+                    Term::Simple(SimpleTerm::Atom(atom)) => { domains[i].insert(atom); },
+                    Term::Simple(SimpleTerm::Variable(_)) => (),
+                    Term::Simple(SimpleTerm::Number(_)) | Term::Compound(_) => return Vec::new(),
+                }
+            }
+        }
+    }
+
+    if domains.iter().all(|domain| domain.is_empty()) {
+        return Vec::new();
+    }
+    let combinations = domains
+        .iter()
+        .try_fold(1usize, |n, domain| n.checked_mul(domain.len().max(1)));
+    match combinations {
+        Some(n) if n <= MAX_COVERAGE_COMBINATIONS => (),
+        _ => return Vec::new(),
+    }
+
+    let name = match &heads[0] {
+        Term::Compound(compound_term) => compound_term.name.clone(),
+        _ => unreachable!(),
+    };
+    let mut patterns = vec![Vec::new()];
+    for domain in &domains {
+        let values: Vec<Term> = if domain.is_empty() {
+            vec![Term::variable("_")]
+        } else {
+            domain.iter().map(|atom| Term::atom(atom)).collect()
+        };
+        patterns = patterns
+            .into_iter()
+            .flat_map(|pattern: Vec<Term>| values.iter().map(move |value| {
+                let mut pattern = pattern.clone();
+                pattern.push(value.clone());
+                pattern
+            }))
+            .collect();
+    }
+
+    patterns
+        .into_iter()
+        .map(|parameters| Term::Compound(CompoundTerm { name: name.clone(), parameters }))
+        .filter(|pattern| !heads.iter().any(|head| heads_overlap(head, pattern)))
+        .collect()
+}
+
+/**
+ * Unifies two terms under the given variable bindings, adding to them.
+ */
+fn unify(a: &Term, b: &Term, bindings: &mut HashMap<String, Term>) -> bool {
+    let a = resolve(a, bindings);
+    let b = resolve(b, bindings);
+    match (&a, &b) {
+        (Term::Simple(SimpleTerm::Variable(x)), Term::Simple(SimpleTerm::Variable(y))) if x == y => true,
+        (Term::Simple(SimpleTerm::Variable(x)), _) => {
+            bindings.insert(x.clone(), b.clone());
+            true
+        },
+        (_, Term::Simple(SimpleTerm::Variable(y))) => {
+            bindings.insert(y.clone(), a.clone());
+            true
+        },
+        (Term::Simple(x), Term::Simple(y)) => x == y,
+        (Term::Compound(x), Term::Compound(y)) => {
+            x.parameters.len() == y.parameters.len()
+                && unify(&Term::Simple(x.name.clone()), &Term::Simple(y.name.clone()), bindings)
+                && x.parameters.iter().zip(&y.parameters).all(|(x, y)| unify(x, y, bindings))
+        },
+        _ => false
+    }
+}
+
+/**
+ * Follows variable bindings until reaching an unbound variable or a
+ * non-variable term.
+ */
+fn resolve(term: &Term, bindings: &HashMap<String, Term>) -> Term {
+    let mut term = term;
+    while let Term::Simple(SimpleTerm::Variable(name)) = term {
+        match bindings.get(name) {
+            Some(bound) => term = bound,
+            None => break,
+        }
+    }
+    term.clone()
+}
+
+/**
+ * Type of a predicate argument, as named in a type declaration. Any other
+ * name than `atom`, `number` and `any` is a user type, which can hold atoms
+ * and compound terms.
+ */
+#[derive(Clone, Debug, PartialEq)]
+enum Type {
+    Atom,
+    Number,
+    Named(String),
+}
+
+impl Type {
+    // `any` puts no constraint on an argument
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "any" => None,
+            "atom" => Some(Type::Atom),
+            "number" => Some(Type::Number),
+            _ => Some(Type::Named(name.to_string())),
+        }
+    }
+
+    /**
+     * Returns the type of a value that has both types, if there is one.
+     * Atoms can be members of any user type.
+     */
+    fn meet(&self, other: &Type) -> Option<Type> {
+        match (self, other) {
+            _ if self == other => Some(self.clone()),
+            (Type::Atom, Type::Named(_)) => Some(other.clone()),
+            (Type::Named(_), Type::Atom) => Some(self.clone()),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Atom => f.write_str("atom"),
+            Type::Number => f.write_str("number"),
+            Type::Named(name) => f.write_str(name),
+        }
+    }
+}
+
+type Signatures = HashMap<(String, usize), Vec<Option<Type>>>;
+
+/**
+ * Checks clauses against the program's type declarations. The argument types
+ * of undeclared predicates are inferred from how their clauses use variables
+ * of known types, so mismatches are also found in calls to them. Programs
+ * without type declarations are never flagged.
+ */
+pub fn check_types(program: &Program) -> Vec<Diagnostic> {
+    let mut signatures = Signatures::new();
+    let mut declared_arities: HashMap<&str, Vec<usize>> = HashMap::new();
+    for declaration in &program.types {
+        let arity = declaration.arguments.len();
+        let types = declaration.arguments.iter().map(|name| Type::from_name(name)).collect();
+        signatures.insert((declaration.name.clone(), arity), types);
+        declared_arities.entry(&declaration.name).or_default().push(arity);
+    }
+
+    // Infer signatures until nothing changes. Positions only ever go from
+    // unknown to known, so this terminates.
+    loop {
+        let mut changed = false;
+        for clause in &program.clauses {
+            let variables = check_clause_types(clause, &signatures, &mut None);
+            let (name, arity) = match predicate_indicator(&clause.head) {
+                Some(indicator) if !declared_arities.contains_key(indicator.0) => indicator,
+                _ => continue,
+            };
+            let parameters = match &clause.head {
+                Term::Compound(compound_term) => &compound_term.parameters,
+                _ => continue,
+            };
+
+            let signature = signatures
+                .entry((name.to_string(), arity))
+                .or_insert_with(|| vec![None; arity]);
+            for (param, slot) in parameters.iter().zip(signature.iter_mut()) {
+                if let (Term::Simple(SimpleTerm::Variable(variable)), None) = (param, &slot) {
+                    if let Some(t) = variables.get(variable) {
+                        *slot = Some(t.clone());
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let mut diagnostics = Vec::new();
+    for (i, clause) in program.clauses.iter().enumerate() {
+        for term in std::iter::once(&clause.head).chain(&clause.body) {
+            if let Some((name, arity)) = predicate_indicator(term) {
+                match declared_arities.get(name) {
+                    Some(arities) if !arities.contains(&arity) => {
+                        let declared: Vec<String> = arities
+                            .iter()
+                            .map(|arity| format!("{}/{}", name, arity))
+                            .collect();
+                        diagnostics.push(Diagnostic {
+                            code: "arity-mismatch",
+                            severity: Severity::Error,
+                            clause: i,
+                            message: format!(
+                                "{}/{} does not match the declared {}",
+                                name, arity, declared.join(", ")
+                            ),
+                            suggestion: match declared.as_slice() {
+                                [declared] => Some(format!("did you mean {}?", declared)),
+                                _ => None,
+                            },
+                        });
+                    },
+                    _ => (),
+                }
+            }
+        }
+
+        let mut messages = Some(Vec::new());
+        check_clause_types(clause, &signatures, &mut messages);
+        for message in messages.unwrap() {
+            diagnostics.push(Diagnostic {
+                code: "type-error",
+                severity: Severity::Error,
+                clause: i,
+                message,
+                suggestion: None,
+            });
+        }
+    }
+    diagnostics
+}
+
+/**
+ * Checks the arguments of the head and body goals of a clause against the
+ * known signatures and returns the types this gives its variables. Mismatches
+ * are added to `messages` if it is Some.
+ */
+fn check_clause_types(
+    clause: &Clause,
+    signatures: &Signatures,
+    messages: &mut Option<Vec<String>>
+) -> HashMap<String, Type> {
+    let mut variables = HashMap::new();
+    for term in std::iter::once(&clause.head).chain(&clause.body) {
+        let (name, parameters) = match term {
+            Term::Compound(CompoundTerm { name: SimpleTerm::Atom(name), parameters }) => {
+                (name, parameters)
+            },
+            _ => continue,
+        };
+        let signature = match signatures.get(&(name.clone(), parameters.len())) {
+            Some(signature) => signature,
+            None => continue,
+        };
+
+        for (i, (param, expected)) in parameters.iter().zip(signature).enumerate() {
+            let expected = match expected {
+                Some(expected) => expected,
+                None => continue,
+            };
+            let problem = match param {
+                Term::Simple(SimpleTerm::Variable(variable)) => {
+                    match variables.get(variable).map(|t: &Type| (t.clone(), t.meet(expected))) {
+                        None => {
+                            variables.insert(variable.clone(), expected.clone());
+                            None
+                        },
+                        Some((_, Some(t))) => {
+                            variables.insert(variable.clone(), t);
+                            None
+                        },
+                        Some((t, None)) => Some(format!(
+                            "variable {} is used as both {} and {}",
+                            variable, t, expected
+                        )),
+                    }
+                },
+                Term::Simple(SimpleTerm::Atom(atom)) if *expected == Type::Number => {
+                    Some(format!("expected number, found atom {}", atom))
+                },
+                Term::Simple(SimpleTerm::Number(value)) if *expected != Type::Number => {
+                    Some(format!("expected {}, found number {}", expected, value))
+                },
+                Term::Compound(_) if *expected == Type::Atom || *expected == Type::Number => {
+                    Some(format!("expected {}, found compound term {}", expected, param))
+                },
+                _ => None
+            };
+
+            if let (Some(problem), Some(messages)) = (problem, messages.as_mut()) {
+                messages.push(format!(
+                    "argument {} of {}/{}: {}",
+                    i + 1, name, parameters.len(), problem
+                ));
+            }
+        }
+    }
+    variables
+}
+
+fn has_decreasing_argument(head: &Term, goal: &Term) -> bool {
+    match (head, goal) {
+        (Term::Compound(head), Term::Compound(goal)) => head.parameters
+            .iter()
+            .zip(&goal.parameters)
+            .any(|(head_arg, goal_arg)| head_arg.iter().skip(1).any(|t| t == goal_arg)),
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analysis::*;
+    use crate::parser::{parse, parse_with_spans};
+
+    fn messages(code: &str) -> Vec<String> {
+        check_program(&parse(code).unwrap())
+            .into_iter()
+            .map(|d| d.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_diagnostics_to_json() {
+        let (program, spans) = parse_with_spans("@type p atom\n@note x\np X if p X\np 3").unwrap();
+        let diagnostics = check_program(&program);
+        assert_eq!(
+            "[\n  \
+             {\"code\": \"infinite-recursion\", \"severity\": \"warning\", \
+             \"span\": {\"line\": 3, \"column\": 1, \"end_line\": 3, \"end_column\": 11}, \
+             \"message\": \"p/1 calls itself with the same arguments and will never terminate\", \
+             \"suggestion\": \"call it with a smaller argument, or remove the call\"},\n  \
+             {\"code\": \"type-error\", \"severity\": \"error\", \
+             \"span\": {\"line\": 4, \"column\": 1, \"end_line\": 4, \"end_column\": 4}, \
+             \"message\": \"argument 1 of p/1: expected atom, found number 3\", \"suggestion\": null}\n\
+             ]\n",
+            diagnostics_to_json(&diagnostics, &spans)
+        );
+        assert_eq!("[]\n", diagnostics_to_json(&[], &spans));
+        assert_eq!(
+            "[\n  {\"code\": \"x\", \"severity\": \"error\", \"span\": null, \
+             \"message\": \"`a \\\\ \\\"b\\\"`\\n\", \"suggestion\": null}\n]\n",
+            error_to_json("x", None, "`a \\ \"b\"`\n")
+        );
+    }
+
+    #[test]
+    fn test_goal_order() {
+        assert_eq!(
+            vec![
+                "warning: clause 1: `is Y (plus Z 1)` evaluates Z before it is bound, which is an instantiation error",
+                "warning: clause 2: `not (q X Y)` runs with Y unbound, so it fails if the goal has a solution for any \
+                 value of it",
+            ],
+            messages("p X Y if Y is plus Z 1 and q Z \n r X if not q X Y")
+        );
+        let diagnostics = check_program(&parse("p Y if Y is times A B").unwrap());
+        assert_eq!("unbound-arithmetic", diagnostics[0].code);
+        assert!(diagnostics[0].message.contains("evaluates A, B before they are bound"));
+
+        // Variables of the head and of earlier goals may be bound
+        assert!(messages("p X Y if q Z and Y is plus Z X").is_empty());
+        assert!(messages("p X if q X Y and not r Y").is_empty());
+        assert!(messages("p X if not q X _Y").is_empty());
+        // Only the condition binds variables for the then branch
+        assert!(messages("p X Y if (if q Z then Y is plus Z 1 else Y = X)").is_empty());
+        assert_eq!(1, messages("p X Y if (if q X then Y = 1 else Y is plus Z 1)").len());
+        assert!(messages("p X Y if (if q X then Z = 1 else Z = 2) and Y is plus Z 1").is_empty());
+    }
+
+    #[test]
+    fn test_identical_recursive_call() {
+        assert_eq!(
+            vec!["warning: clause 1: p/1 calls itself with the same arguments and will never terminate"],
+            messages("p X if p X")
+        );
+        assert_eq!(
+            vec!["warning: clause 2: p/1 calls itself with the same arguments and will never terminate"],
+            messages("p a \n p X if q X and p X")
+        );
+    }
+
+    #[test]
+    fn test_left_recursion() {
+        assert_eq!(
+            vec!["warning: clause 1: path/2 is left-recursive without a decreasing argument and may never terminate"],
+            messages("path X Y if path X Z and edge Z Y")
+        );
+    }
+
+    #[test]
+    fn test_terminating_recursion() {
+        assert!(messages("len (cons H T) (s N) if len T N").is_empty());
+        assert!(messages("path X Y if edge X Z and path Z Y").is_empty());
+        assert!(messages("p X if q X").is_empty());
+        // Different arity is a different predicate
+        assert!(messages("p X if p X Y").is_empty());
+    }
+
+    fn coverage(code: &str) -> Vec<String> {
+        check_coverage(&parse(code).unwrap())
+            .into_iter()
+            .map(|d| d.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_coverage_complete() {
+        let code = "discount gold winter high \n\
+                    discount gold summer low \n\
+                    discount silver Season low";
+        assert!(coverage(code).is_empty());
+    }
+
+    #[test]
+    fn test_coverage_missing_combination() {
+        let code = "discount gold winter high \n\
+                    discount silver summer low";
+        assert_eq!(
+            vec![
+                "warning: clause 1: no clause of discount/3 covers discount gold summer _",
+                "warning: clause 1: no clause of discount/3 covers discount silver winter _",
+            ],
+            coverage(code)
+        );
+    }
+
+    #[test]
+    fn test_coverage_overlap() {
+        let code = "discount gold Season high \n\
+                    discount Level winter low \n\
+                    discount silver summer low";
+        assert_eq!(
+            vec!["warning: clause 2: head of discount/3 overlaps with clause 1"],
+            coverage(code)
+        );
+    }
+
+    #[test]
+    fn test_coverage_repeated_variable() {
+        // same X X only covers the diagonal
+        let code = "same X X yes \n same a b no \n same b c no";
+        assert_eq!(
+            vec!["warning: clause 1: no clause of same/3 covers same a c _"],
+            coverage(code)
+        );
+    }
+
+    #[test]
+    fn test_coverage_single_argument() {
+        assert!(coverage("color red \n color blue").is_empty());
+    }
+
+    #[test]
+    fn test_types_well_typed() {
+        let code = "@type parent person person \n\
+                    parent tom bob \n\
+                    grandparent X Z if parent X Y and parent Y Z";
+        assert!(messages(code).is_empty());
+        // Without declarations nothing is checked
+        assert!(messages("p (f a) \n q X if p X").is_empty());
+    }
+
+    #[test]
+    fn test_types_mismatch() {
+        let code = "@type age person number \n\
+                    age tom old \n\
+                    age (f tom) X";
+        assert_eq!(
+            vec!["error: clause 1: argument 2 of age/2: expected number, found atom old"],
+            messages(code)
+        );
+
+        let code = "@type name atom \n name (first tom)";
+        assert_eq!(
+            vec!["error: clause 1: argument 1 of name/1: expected atom, found compound term first tom"],
+            messages(code)
+        );
+
+        let code = "@type age person number \n age tom 42 \n age 7 8";
+        assert_eq!(
+            vec!["error: clause 2: argument 1 of age/2: expected person, found number 7"],
+            messages(code)
+        );
+    }
+
+    #[test]
+    fn test_types_variable_used_inconsistently() {
+        let code = "@type parent person person \n\
+                    @type lives person city \n\
+                    p X if parent X Y and lives Y X";
+        assert_eq!(
+            vec!["error: clause 1: argument 2 of lives/2: variable X is used as both person and city"],
+            messages(code)
+        );
+    }
+
+    #[test]
+    fn test_types_wrong_arity() {
+        let code = "@type parent person person \n parent tom";
+        assert_eq!(
+            vec!["error: clause 1: parent/1 does not match the declared parent/2"],
+            messages(code)
+        );
+    }
+
+    #[test]
+    fn test_types_inferred_for_undeclared_predicates() {
+        // ancestor is inferred to take two persons, so calling it with a
+        // city is a mismatch even though it has no declaration
+        let code = "@type parent person person \n\
+                    @type lives person city \n\
+                    ancestor X Y if parent X Y \n\
+                    ancestor X Y if parent X Z and ancestor Z Y \n\
+                    p C if lives P C and ancestor P C";
+        assert_eq!(
+            vec!["error: clause 3: argument 2 of ancestor/2: variable C is used as both city and person"],
+            messages(code)
+        );
+    }
+}
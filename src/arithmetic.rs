@@ -0,0 +1,105 @@
+use crate::compiler::Compiler;
+use crate::heap::*;
+use crate::machine::{EngineError, EvaluationError};
+
+/**
+ * Evaluates an arithmetic expression on the heap: a number, or one of the
+ * functions below applied to expressions, e.g. `plus 2 (times 3 X)` with X
+ * bound to a number.
+ *
+ * `div` rounds towards negative infinity and `mod` takes the sign of the
+ * divisor, while `quot` rounds towards zero and `rem` takes the sign of the
+ * dividend. Results that don't fit in 64 bits are an error rather than
+ * wrapping around.
+ *
+ * `shiftLeft` and `shiftRight` shift the bits of a two's complement integer,
+ * the other way for a negative shift, and `shiftRight` keeps the sign.
+ * `bitAnd`, `bitOr` and `xor` combine the bits of two integers, `msb` is the
+ * position of the most significant bit of a positive integer and `gcd` is
+ * the non-negative greatest common divisor.
+ */
+pub(crate) fn evaluate(compiler: &Compiler, expression: HeapEntry) -> Result<i64, EngineError> {
+    let expression = compiler.deref(expression);
+    let (name, arguments) = match expression.tag {
+        HeapTag::Number => return Ok(expression.number_value()),
+        HeapTag::Variable | HeapTag::Unify => return Err(EngineError::Instantiation),
+        HeapTag::Constant => return Err(not_evaluable(&compiler.symbol_table.get(expression.data), 0)),
+        HeapTag::Reference => {
+            let heap = &compiler.heap;
+            let length = heap.read(expression.data).data;
+            let name = compiler.deref(heap.read(expression.data + 1));
+            let arguments: Vec<HeapEntry> = (2..=length).map(|i| heap.read(expression.data + i)).collect();
+            match name.tag {
+                HeapTag::Constant => (compiler.symbol_table.get(name.data), arguments),
+                HeapTag::Variable | HeapTag::Unify => return Err(EngineError::Instantiation),
+                _ => unreachable!("{:?} entry can't be a functor", name),
+            }
+        },
+        HeapTag::Arity | HeapTag::Uninitialized => {
+            unreachable!("{:?} entry can't be the value of a term", expression)
+        },
+    };
+
+    let values = arguments
+        .iter()
+        .map(|argument| evaluate(compiler, *argument))
+        .collect::<Result<Vec<i64>, EngineError>>()?;
+    let result = match (name.as_ref(), values.as_slice()) {
+        ("plus", [a, b]) => a.checked_add(*b),
+        ("minus", [a, b]) => a.checked_sub(*b),
+        ("times", [a, b]) => a.checked_mul(*b),
+        ("neg", [a]) => a.checked_neg(),
+        ("abs", [a]) => a.checked_abs(),
+        ("min", [a, b]) => Some(*a.min(b)),
+        ("max", [a, b]) => Some(*a.max(b)),
+        ("div" | "mod" | "quot" | "rem", [_, 0]) => {
+            return Err(EngineError::Evaluation(EvaluationError::ZeroDivisor));
+        },
+        ("div", [a, b]) => {
+            let rounded_up = a.wrapping_rem(*b) != 0 && (*a < 0) != (*b < 0);
+            a.checked_div(*b).map(|quotient| if rounded_up { quotient - 1 } else { quotient })
+        },
+        ("mod", [a, b]) => {
+            let remainder = a.wrapping_rem(*b);
+            Some(if remainder != 0 && (remainder < 0) != (*b < 0) { remainder + b } else { remainder })
+        },
+        ("quot", [a, b]) => a.checked_div(*b),
+        // The remainder always fits, even of i64::MIN by -1
+        ("rem", [a, b]) => Some(a.wrapping_rem(*b)),
+        ("shiftLeft", [a, b]) => shift_left(*a, *b),
+        ("shiftRight", [a, b]) => b.checked_neg().and_then(|b| shift_left(*a, b)),
+        ("bitAnd", [a, b]) => Some(a & b),
+        ("bitOr", [a, b]) => Some(a | b),
+        ("xor", [a, b]) => Some(a ^ b),
+        ("msb", [a]) if *a <= 0 => return Err(EngineError::Evaluation(EvaluationError::Undefined)),
+        ("msb", [a]) => Some(63 - a.leading_zeros() as i64),
+        ("gcd", [a, b]) => {
+            let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+            while b != 0 {
+                (a, b) = (b, a % b);
+            }
+            i64::try_from(a).ok()
+        },
+        _ => return Err(not_evaluable(&name, values.len())),
+    };
+    result.ok_or(EngineError::Evaluation(EvaluationError::Overflow))
+}
+
+fn not_evaluable(name: &str, arity: usize) -> EngineError {
+    EngineError::NotEvaluable(format!("{}/{}", name, arity))
+}
+
+/**
+ * Shifts a left by b bits, or right by -b bits if b is negative. None if
+ * bits other than copies of the sign bit would be shifted out on the left.
+ */
+fn shift_left(a: i64, b: i64) -> Option<i64> {
+    if b < 0 {
+        return Some(a >> b.unsigned_abs().min(63));
+    }
+    if b >= 64 {
+        return (a == 0).then_some(0);
+    }
+    let shifted = a << b;
+    (shifted >> b == a).then_some(shifted)
+}
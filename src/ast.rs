@@ -1,12 +1,85 @@
-#[derive(Debug, PartialEq)]
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::writer;
+
+/**
+ * Name of the cut goal. When it is reached, the clause it appears in is
+ * committed to: the remaining clauses of the predicate and the remaining
+ * solutions of the goals before the cut are discarded.
+ */
+pub const CUT: &str = "!";
+
+/**
+ * Name of negation as failure: `not G` succeeds, without binding anything,
+ * if the goal G has no solutions.
+ */
+pub const NOT: &str = "not";
+
+/**
+ * Names of unification, `X = Y`, which unifies X and Y, and of its negation,
+ * `X \= Y`, which succeeds without binding anything if they don't unify.
+ */
+pub const UNIFY: &str = "=";
+pub const NOT_UNIFY: &str = "\\=";
+
+/**
+ * Names of structural equality, `X == Y`, which succeeds if X and Y are
+ * identical without binding anything, i.e. unbound variables are only equal
+ * to themselves, and of its negation, `X \== Y`.
+ */
+pub const EQUAL: &str = "==";
+pub const NOT_EQUAL: &str = "\\==";
+
+/**
+ * Name of `compare Order X Y`, which unifies Order with `less`, `equal` or
+ * `greater` according to the standard order of terms: unbound variables
+ * come first, then numbers, atoms and compound terms.
+ */
+pub const COMPARE: &str = "compare";
+
+/**
+ * Name of univ, `T =.. L`, which relates a term to the list of its functor
+ * and arguments, e.g. `f a b =.. cons f (cons a (cons b nil))`.
+ */
+pub const UNIV: &str = "=..";
+
+/**
+ * Name of `X ?= Y`, which succeeds without binding anything if whether X and
+ * Y are identical is already decided: they are identical, or they don't
+ * unify.
+ */
+pub const DECIDED: &str = "?=";
+
+/**
+ * Name of arithmetic evaluation: `is X E` evaluates the arithmetic expression
+ * E and unifies X with the result. Goals can also be written `X is E`.
+ */
+pub const IS: &str = "is";
+
+/**
+ * Names used to represent `(if C then T else E)` goals as terms:
+ * `if (and C...) (and T...) (and E...)`, where the else branch is optional.
+ * Both are keywords, so they can't clash with the name of a user's term.
+ */
+pub const IF_THEN_ELSE: &str = "if";
+pub const CONJUNCTION: &str = "and";
+
+/**
+ * Goals of the condition, then and else branches of an if-then-else goal.
+ */
+pub type IfThenElse<'a> = (&'a [Term], &'a [Term], Option<&'a [Term]>);
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
 pub struct Program {
     pub clauses: Vec<Clause>,
     pub queries: Vec<Query>,
+    pub types: Vec<TypeDeclaration>,
 }
 
 impl Program {
     pub fn new() -> Self {
-        Program { clauses: Vec::new(), queries: Vec::new() }
+        Program { clauses: Vec::new(), queries: Vec::new(), types: Vec::new() }
     }
 
     pub fn push_clause(&mut self, clause: Clause) {
@@ -16,33 +89,505 @@ impl Program {
     pub fn push_query(&mut self, query: Query) {
         self.queries.push(query);
     }
+
+    pub fn push_type(&mut self, declaration: TypeDeclaration) {
+        self.types.push(declaration);
+    }
+}
+
+/**
+ * Signature of a predicate, e.g. `@type parent person person`. Declarations
+ * are only used by static analysis and never affect how a program runs.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TypeDeclaration {
+    pub name: String,
+    // One type name per argument
+    pub arguments: Vec<String>,
+}
+
+/**
+ * An event-condition-action rule: when an event matching `event` happens and
+ * the goals of `condition` can be solved, each goal of `actions` is passed to
+ * a handler.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EventRule {
+    pub event: Term,
+    pub condition: Vec<Term>,
+    pub actions: Vec<Term>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Clause {
     pub head: Term,
     pub body: Vec<Term>,
+    // Written on the lines before the clause, e.g. `@priority 5`
+    pub annotations: Vec<Annotation>,
+}
+
+/**
+ * Metadata attached to a clause, e.g. `@deprecated` or `@source rules.csv`.
+ * Annotations are kept with the compiled clause but don't change how it runs
+ * unless the engine is configured to use them.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Annotation {
+    pub name: String,
+    pub arguments: Vec<String>,
 }
 
-#[derive(Debug, PartialEq)]
+impl Clause {
+    pub fn fact(head: Term) -> Self {
+        Clause { head, body: Vec::new(), annotations: Vec::new() }
+    }
+
+    pub fn rule(head: Term, body: Vec<Term>) -> Self {
+        Clause { head, body, annotations: Vec::new() }
+    }
+
+    /**
+     * Returns the first annotation with the given name.
+     */
+    pub fn annotation(&self, name: &str) -> Option<&Annotation> {
+        self.annotations.iter().find(|annotation| annotation.name == name)
+    }
+
+    /**
+     * Visits every term in the head and then the body of the clause.
+     */
+    pub fn walk<F: FnMut(&Term)>(&self, order: Order, f: &mut F) {
+        self.head.walk(order, f);
+        for goal in &self.body {
+            goal.walk(order, f);
+        }
+    }
+
+    pub fn walk_mut<F: FnMut(&mut Term)>(&mut self, order: Order, f: &mut F) {
+        self.head.walk_mut(order, f);
+        for goal in &mut self.body {
+            goal.walk_mut(order, f);
+        }
+    }
+}
+
+/**
+ * Terms are ordered by the standard order of terms: variables, then atoms,
+ * then compound terms by arity, name and parameters from left to right.
+ * Variables are compared by name.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Term {
     Compound(CompoundTerm),
     Simple(SimpleTerm),
 }
 
-#[derive(Debug, PartialEq)]
+impl Term {
+    pub fn atom(name: &str) -> Self {
+        Term::Simple(SimpleTerm::Atom(name.to_string()))
+    }
+
+    pub fn variable(name: &str) -> Self {
+        Term::Simple(SimpleTerm::Variable(name.to_string()))
+    }
+
+    pub fn number(value: i64) -> Self {
+        Term::Simple(SimpleTerm::Number(value))
+    }
+
+    /**
+     * Creates an atom or variable following the same naming rule as the
+     * parser: names starting with an uppercase letter or `_` are variables.
+     */
+    pub fn from_name(name: &str) -> Self {
+        Term::Simple(SimpleTerm::from_name(name))
+    }
+
+    pub fn cut() -> Self {
+        Term::atom(CUT)
+    }
+
+    pub fn is_cut(&self) -> bool {
+        matches!(self, Term::Simple(SimpleTerm::Atom(name)) if name == CUT)
+    }
+
+    pub fn negation(goal: Term) -> Self {
+        Term::compound(NOT, vec![goal])
+    }
+
+    pub fn evaluation(result: Term, expression: Term) -> Self {
+        Term::compound(IS, vec![result, expression])
+    }
+
+    /**
+     * Creates an if-then-else goal. Once the first solution of the condition
+     * is found, its other solutions and the else branch are discarded.
+     */
+    pub fn if_then_else(condition: Vec<Term>, then: Vec<Term>, otherwise: Option<Vec<Term>>) -> Self {
+        let mut parameters = vec![
+            Term::compound(CONJUNCTION, condition),
+            Term::compound(CONJUNCTION, then),
+        ];
+        if let Some(otherwise) = otherwise {
+            parameters.push(Term::compound(CONJUNCTION, otherwise));
+        }
+        Term::compound(IF_THEN_ELSE, parameters)
+    }
+
+    /**
+     * Returns the goals of the condition, then and else branches of an
+     * if-then-else goal.
+     */
+    pub fn as_if_then_else(&self) -> Option<IfThenElse<'_>> {
+        fn conjunction(term: &Term) -> Option<&[Term]> {
+            match term {
+                Term::Compound(CompoundTerm { name: SimpleTerm::Atom(name), parameters })
+                    if name == CONJUNCTION => Some(parameters.as_slice()),
+                _ => None,
+            }
+        }
+        match self {
+            Term::Compound(CompoundTerm { name: SimpleTerm::Atom(name), parameters })
+                if name == IF_THEN_ELSE && (2..=3).contains(&parameters.len()) =>
+            {
+                let otherwise = match parameters.get(2) {
+                    Some(otherwise) => Some(conjunction(otherwise)?),
+                    None => None,
+                };
+                Some((conjunction(&parameters[0])?, conjunction(&parameters[1])?, otherwise))
+            },
+            _ => None,
+        }
+    }
+
+    pub fn compound(name: &str, parameters: Vec<Term>) -> Self {
+        Term::Compound(CompoundTerm { name: SimpleTerm::from_name(name), parameters })
+    }
+
+    /**
+     * Calls f on this term and every term nested inside it. Pre-order visits a
+     * compound term before its parameters, post-order after them.
+     */
+    pub fn walk<F: FnMut(&Term)>(&self, order: Order, f: &mut F) {
+        if order == Order::PreOrder {
+            f(self);
+        }
+        if let Term::Compound(compound_term) = self {
+            for param in &compound_term.parameters {
+                param.walk(order, f);
+            }
+        }
+        if order == Order::PostOrder {
+            f(self);
+        }
+    }
+
+    /**
+     * Like walk, but f may modify or replace terms. In pre-order, the
+     * parameters of whatever f leaves in place are visited next, so replacing a
+     * term with a compound term visits the new parameters.
+     */
+    pub fn walk_mut<F: FnMut(&mut Term)>(&mut self, order: Order, f: &mut F) {
+        if order == Order::PreOrder {
+            f(self);
+        }
+        if let Term::Compound(compound_term) = self {
+            for param in &mut compound_term.parameters {
+                param.walk_mut(order, f);
+            }
+        }
+        if order == Order::PostOrder {
+            f(self);
+        }
+    }
+
+    /**
+     * Iterates over this term and all nested terms in pre-order.
+     */
+    pub fn iter(&self) -> Subterms<'_> {
+        Subterms { stack: vec![self] }
+    }
+}
+
+/**
+ * Traversal order for Term::walk.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Order {
+    PreOrder,
+    PostOrder,
+}
+
+/**
+ * Pre-order iterator over a term and its subterms, see Term::iter.
+ */
+pub struct Subterms<'a> {
+    stack: Vec<&'a Term>,
+}
+
+impl<'a> Iterator for Subterms<'a> {
+    type Item = &'a Term;
+
+    fn next(&mut self) -> Option<&'a Term> {
+        let term = self.stack.pop()?;
+        if let Term::Compound(compound_term) = term {
+            self.stack.extend(compound_term.parameters.iter().rev());
+        }
+        Some(term)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct CompoundTerm {
     pub name: SimpleTerm,
     pub parameters: Vec<Term>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum SimpleTerm {
     Atom(String),
     Variable(String),
+    Number(i64),
 }
 
-#[derive(Debug, PartialEq)]
+impl SimpleTerm {
+    pub fn from_name(name: &str) -> Self {
+        match name.chars().next() {
+            Some(c) if c.is_uppercase() || c == '_' => SimpleTerm::Variable(name.to_string()),
+            _ => SimpleTerm::Atom(name.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Query {
     pub sub_queries: Vec<Term>,
-}
\ No newline at end of file
+}
+
+impl Query {
+    pub fn new(sub_queries: Vec<Term>) -> Self {
+        Query { sub_queries }
+    }
+
+    pub fn walk<F: FnMut(&Term)>(&self, order: Order, f: &mut F) {
+        for goal in &self.sub_queries {
+            goal.walk(order, f);
+        }
+    }
+
+    pub fn walk_mut<F: FnMut(&mut Term)>(&mut self, order: Order, f: &mut F) {
+        for goal in &mut self.sub_queries {
+            goal.walk_mut(order, f);
+        }
+    }
+}
+
+impl Ord for Term {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Term::Simple(a), Term::Simple(b)) => a.cmp(b),
+            (Term::Simple(_), Term::Compound(_)) => Ordering::Less,
+            (Term::Compound(_), Term::Simple(_)) => Ordering::Greater,
+            (Term::Compound(a), Term::Compound(b)) => a.cmp(b),
+        }
+    }
+}
+
+impl PartialOrd for Term {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CompoundTerm {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.parameters.len().cmp(&other.parameters.len())
+            .then_with(|| self.name.cmp(&other.name))
+            .then_with(|| self.parameters.cmp(&other.parameters))
+    }
+}
+
+impl PartialOrd for CompoundTerm {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SimpleTerm {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Variables come before numbers, which come before atoms
+        let rank = |term: &SimpleTerm| match term {
+            SimpleTerm::Variable(_) => 0,
+            SimpleTerm::Number(_) => 1,
+            SimpleTerm::Atom(_) => 2,
+        };
+        match (self, other) {
+            (SimpleTerm::Variable(a), SimpleTerm::Variable(b)) => a.cmp(b),
+            (SimpleTerm::Number(a), SimpleTerm::Number(b)) => a.cmp(b),
+            (SimpleTerm::Atom(a), SimpleTerm::Atom(b)) => a.cmp(b),
+            _ => rank(self).cmp(&rank(other)),
+        }
+    }
+}
+
+impl PartialOrd for SimpleTerm {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&writer::write_program(self))
+    }
+}
+
+impl fmt::Display for Clause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut out = String::new();
+        writer::write_clause(&mut out, self);
+        f.write_str(&out)
+    }
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut out = String::new();
+        writer::write_term(&mut out, self);
+        f.write_str(&out)
+    }
+}
+
+impl fmt::Display for SimpleTerm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SimpleTerm::Atom(name) | SimpleTerm::Variable(name) => f.write_str(name),
+            SimpleTerm::Number(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut out = String::new();
+        writer::write_query(&mut out, self);
+        f.write_str(&out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::ast::*;
+
+    fn names(terms: Vec<&Term>) -> Vec<String> {
+        terms.into_iter().map(|term| term.to_string()).collect()
+    }
+
+    #[test]
+    fn test_walk_pre_order() {
+        let term = term!(a(b(c, d), e));
+        let mut visited = Vec::new();
+        term.walk(Order::PreOrder, &mut |t| visited.push(t.clone()));
+
+        assert_eq!(
+            vec!["a (b c d) e", "b c d", "c", "d", "e"],
+            names(visited.iter().collect())
+        );
+    }
+
+    #[test]
+    fn test_walk_post_order() {
+        let term = term!(a(b(c, d), e));
+        let mut visited = Vec::new();
+        term.walk(Order::PostOrder, &mut |t| visited.push(t.clone()));
+
+        assert_eq!(
+            vec!["c", "d", "b c d", "e", "a (b c d) e"],
+            names(visited.iter().collect())
+        );
+    }
+
+    #[test]
+    fn test_iter() {
+        let term = term!(a(b(c, d), e));
+        assert_eq!(vec!["a (b c d) e", "b c d", "c", "d", "e"], names(term.iter().collect()));
+    }
+
+    #[test]
+    fn test_walk_mut() {
+        // Rename all variables in a clause
+        let mut clause = clause!(p(X, f(Y)) :- q(X), r(Y));
+        clause.walk_mut(Order::PreOrder, &mut |term| {
+            if let Term::Simple(SimpleTerm::Variable(name)) = term {
+                name.push('1');
+            }
+        });
+        assert_eq!(clause!(p(X1, f(Y1)) :- q(X1), r(Y1)), clause);
+    }
+
+    #[test]
+    fn test_walk_mut_pre_order_visits_replacement() {
+        // Expand `double X` into `pair X X`, then replace every `x` with `y`
+        let mut term = term!(f(double(x)));
+        term.walk_mut(Order::PreOrder, &mut |term| {
+            if let Term::Compound(compound_term) = term {
+                if compound_term.name == SimpleTerm::Atom(String::from("double")) {
+                    let arg = compound_term.parameters[0].clone();
+                    *term = Term::compound("pair", vec![arg.clone(), arg]);
+                }
+            } else if *term == Term::atom("x") {
+                *term = Term::atom("y");
+            }
+        });
+        assert_eq!(term!(f(pair(y, y))), term);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!("a (b c) X", term!(a(b(c), X)).to_string());
+        assert_eq!("p X if q X and r", clause!(p(X) :- q(X), r).to_string());
+        assert_eq!("? (a X) (b)", query!(a(X), b).to_string());
+        assert_eq!("X", SimpleTerm::Variable(String::from("X")).to_string());
+    }
+
+    #[test]
+    fn test_standard_order() {
+        let mut terms = vec![
+            term!(f(b)),
+            term!(g(a)),
+            term!(b),
+            term!(f(a, a)),
+            term!(Y),
+            term!(a),
+            term!(f(a)),
+            term!(X),
+        ];
+        terms.sort();
+
+        assert_eq!(
+            vec![
+                term!(X),
+                term!(Y),
+                term!(a),
+                term!(b),
+                term!(f(a)),
+                term!(f(b)),
+                term!(g(a)),
+                term!(f(a, a)),
+            ],
+            terms
+        );
+    }
+
+    #[test]
+    fn test_hash() {
+        let mut set = HashSet::new();
+        set.insert(term!(f(a, X)));
+        set.insert(term!(f(a, X)));
+        set.insert(term!(f(a, Y)));
+        assert_eq!(2, set.len());
+        assert!(set.contains(&term!(f(a, Y))));
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::analysis::predicate_indicator;
+use crate::ast::*;
+use crate::suggest::closest;
+
+/**
+ * Translates a program into Rust source code with one function per
+ * predicate, so that a fixed rule set can be used without the interpreter.
+ *
+ * Only a datalog-like subset is supported: arguments must be atoms or
+ * variables, predicates may not be recursive, every variable in a clause
+ * head must also appear in its body, and every called predicate must be
+ * defined. For a predicate `parent/2` this generates
+ *
+ * ```text
+ * pub fn parent_2<'a>(a0: Option<&'a str>, a1: Option<&'a str>) -> Vec<[&'a str; 2]>
+ * ```
+ *
+ * which returns every solution matching the arguments that are Some, in
+ * the order the interpreter would find them. Queries are ignored.
+ */
+pub fn generate_rust(program: &Program) -> Result<String, String> {
+    let mut predicates: Vec<((&str, usize), Vec<&Clause>)> = Vec::new();
+    for clause in &program.clauses {
+        check_clause(clause)?;
+        let indicator = predicate_indicator(&clause.head).unwrap();
+        match predicates.iter_mut().find(|(p, _)| *p == indicator) {
+            Some((_, clauses)) => clauses.push(clause),
+            None => predicates.push((indicator, vec![clause])),
+        }
+    }
+
+    let defined: HashMap<(&str, usize), &Vec<&Clause>> = predicates
+        .iter()
+        .map(|(indicator, clauses)| (*indicator, clauses))
+        .collect();
+    for (indicator, _) in &predicates {
+        check_not_recursive(*indicator, &defined, &mut Vec::new(), &mut HashSet::new())?;
+    }
+
+    let mut out = String::from("// Generated by butterfly-prolog codegen. Do not edit.\n");
+    for ((name, arity), clauses) in &predicates {
+        out.push('\n');
+        write_predicate(&mut out, name, *arity, clauses);
+    }
+    Ok(out)
+}
+
+/**
+ * Returns the name of the Rust function for a predicate, e.g. `parent_2`.
+ */
+pub fn function_name(name: &str, arity: usize) -> String {
+    format!("{}_{}", name.replace(':', "_"), arity)
+}
+
+fn check_clause(clause: &Clause) -> Result<(), String> {
+    for goal in std::iter::once(&clause.head).chain(&clause.body) {
+        if predicate_indicator(goal).is_none() {
+            return Err(format!("`{}`: goals that are variables are not supported", clause));
+        }
+        if arguments(goal).iter().any(|param| matches!(param, Term::Compound(_))) {
+            return Err(format!("`{}`: compound arguments are not supported", clause));
+        }
+        if arguments(goal).iter().any(|param| matches!(param, Term::Simple(SimpleTerm::Number(_)))) {
+            return Err(format!("`{}`: number arguments are not supported", clause));
+        }
+    }
+
+    let body_variables: HashSet<&String> = clause.body
+        .iter()
+        .flat_map(arguments)
+        .filter_map(|param| match param {
+            Term::Simple(SimpleTerm::Variable(variable)) => Some(variable),
+            _ => None,
+        })
+        .collect();
+    for param in arguments(&clause.head) {
+        if let Term::Simple(SimpleTerm::Variable(variable)) = param {
+            if !body_variables.contains(variable) {
+                return Err(format!(
+                    "`{}`: head variable {} must appear in the body",
+                    clause, variable
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Depth-first search of the call graph, failing on a cycle or a call to an
+ * undefined predicate.
+ */
+fn check_not_recursive<'a>(
+    indicator: (&'a str, usize),
+    defined: &HashMap<(&'a str, usize), &Vec<&'a Clause>>,
+    path: &mut Vec<(&'a str, usize)>,
+    done: &mut HashSet<(&'a str, usize)>
+) -> Result<(), String> {
+    if done.contains(&indicator) {
+        return Ok(());
+    }
+    if path.contains(&indicator) {
+        return Err(format!("{}/{} is recursive", indicator.0, indicator.1));
+    }
+    let clauses = match defined.get(&indicator) {
+        Some(clauses) => clauses,
+        None => {
+            let name = format!("{}/{}", indicator.0, indicator.1);
+            let candidates: Vec<String> = defined.keys().map(|(name, arity)| format!("{}/{}", name, arity)).collect();
+            return Err(match closest(&name, candidates.iter().map(String::as_str)) {
+                Some(suggestion) => format!("{} is not defined, did you mean {}?", name, suggestion),
+                None => format!("{} is not defined", name),
+            });
+        },
+    };
+
+    path.push(indicator);
+    for clause in clauses.iter() {
+        for goal in &clause.body {
+            check_not_recursive(predicate_indicator(goal).unwrap(), defined, path, done)?;
+        }
+    }
+    path.pop();
+    done.insert(indicator);
+    Ok(())
+}
+
+fn arguments(term: &Term) -> &[Term] {
+    match term {
+        Term::Compound(compound_term) => &compound_term.parameters,
+        Term::Simple(_) => &[],
+    }
+}
+
+fn write_predicate(out: &mut String, name: &str, arity: usize, clauses: &[&Clause]) {
+    let parameters: Vec<String> = (0..arity)
+        .map(|i| format!("a{}: Option<&'a str>", i))
+        .collect();
+    let _ = writeln!(out, "#[allow(unused_variables, unused_labels, non_snake_case)]");
+    let _ = writeln!(
+        out,
+        "pub fn {}<'a>({}) -> Vec<[&'a str; {}]> {{",
+        function_name(name, arity), parameters.join(", "), arity
+    );
+    out.push_str("    let mut solutions = Vec::new();\n");
+    for (i, clause) in clauses.iter().enumerate() {
+        write_clause(out, i, clause);
+    }
+    out.push_str("    solutions\n}\n");
+}
+
+/**
+ * Writes a block that adds the solutions of one clause. Variables are held in
+ * `v_<name>` bindings of type Option<&str> that are shadowed as goals bind
+ * them.
+ */
+fn write_clause(out: &mut String, index: usize, clause: &Clause) {
+    let _ = writeln!(out, "    // {}", clause);
+    let _ = writeln!(out, "    'clause{}: {{", index);
+
+    let mut declared = HashSet::new();
+    for (i, param) in arguments(&clause.head).iter().enumerate() {
+        match param {
+            Term::Simple(SimpleTerm::Atom(atom)) => {
+                let _ = writeln!(
+                    out,
+                    "        if a{}.is_some_and(|v| v != {:?}) {{ break 'clause{}; }}",
+                    i, atom, index
+                );
+            },
+            Term::Simple(SimpleTerm::Variable(variable)) => {
+                if declared.insert(variable) {
+                    let _ = writeln!(out, "        let v_{} = a{};", variable, i);
+                } else {
+                    let _ = writeln!(
+                        out,
+                        "        if let (Some(x), Some(y)) = (v_{0}, a{1}) {{ if x != y {{ break 'clause{2}; }} }}\n        \
+                         let v_{0} = v_{0}.or(a{1});",
+                        variable, i, index
+                    );
+                }
+            },
+            Term::Simple(SimpleTerm::Number(_)) | Term::Compound(_) => unreachable!(),
+        }
+    }
+
+    let mut indent = String::from("        ");
+    for goal in &clause.body {
+        let (name, arity) = predicate_indicator(goal).unwrap();
+        let inputs: Vec<String> = arguments(goal)
+            .iter()
+            .map(|param| match param {
+                Term::Simple(SimpleTerm::Atom(atom)) => format!("Some({:?})", atom),
+                Term::Simple(SimpleTerm::Variable(variable)) if declared.contains(variable) => {
+                    format!("v_{}", variable)
+                },
+                _ => String::from("None"),
+            })
+            .collect();
+        let _ = writeln!(out, "{}for r in {}({}) {{", indent, function_name(name, arity), inputs.join(", "));
+        indent.push_str("    ");
+
+        for (i, param) in arguments(goal).iter().enumerate() {
+            if let Term::Simple(SimpleTerm::Variable(variable)) = param {
+                if !declared.insert(variable) {
+                    let _ = writeln!(
+                        out,
+                        "{}if v_{}.is_some_and(|v| v != r[{}]) {{ continue; }}",
+                        indent, variable, i
+                    );
+                }
+                let _ = writeln!(out, "{}let v_{} = Some(r[{}]);", indent, variable, i);
+            }
+        }
+    }
+
+    let values: Vec<String> = arguments(&clause.head)
+        .iter()
+        .map(|param| match param {
+            Term::Simple(SimpleTerm::Atom(atom)) => format!("{:?}", atom),
+            Term::Simple(SimpleTerm::Variable(variable)) => format!("v_{}.unwrap()", variable),
+            Term::Simple(SimpleTerm::Number(_)) | Term::Compound(_) => unreachable!(),
+        })
+        .collect();
+    let _ = writeln!(out, "{}solutions.push([{}]);", indent, values.join(", "));
+
+    while indent.len() > 8 {
+        indent.truncate(indent.len() - 4);
+        let _ = writeln!(out, "{}}}", indent);
+    }
+    out.push_str("    }\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen::*;
+    use crate::parser::parse;
+
+    fn generate(code: &str) -> Result<String, String> {
+        generate_rust(&parse(code).unwrap())
+    }
+
+    #[test]
+    fn test_generate_facts() {
+        let expected = "\
+// Generated by butterfly-prolog codegen. Do not edit.
+
+#[allow(unused_variables, unused_labels, non_snake_case)]
+pub fn parent_2<'a>(a0: Option<&'a str>, a1: Option<&'a str>) -> Vec<[&'a str; 2]> {
+    let mut solutions = Vec::new();
+    // parent tom bob
+    'clause0: {
+        if a0.is_some_and(|v| v != \"tom\") { break 'clause0; }
+        if a1.is_some_and(|v| v != \"bob\") { break 'clause0; }
+        solutions.push([\"tom\", \"bob\"]);
+    }
+    solutions
+}
+";
+        assert_eq!(expected, generate("parent tom bob").unwrap());
+    }
+
+    #[test]
+    fn test_generate_rule() {
+        let generated = generate(
+            "parent tom bob \n grandparent X Z if parent X Y and parent Y Z"
+        ).unwrap();
+        let expected = "\
+    // grandparent X Z if parent X Y and parent Y Z
+    'clause0: {
+        let v_X = a0;
+        let v_Z = a1;
+        for r in parent_2(v_X, None) {
+            if v_X.is_some_and(|v| v != r[0]) { continue; }
+            let v_X = Some(r[0]);
+            let v_Y = Some(r[1]);
+            for r in parent_2(v_Y, v_Z) {
+                if v_Y.is_some_and(|v| v != r[0]) { continue; }
+                let v_Y = Some(r[0]);
+                if v_Z.is_some_and(|v| v != r[1]) { continue; }
+                let v_Z = Some(r[1]);
+                solutions.push([v_X.unwrap(), v_Z.unwrap()]);
+            }
+        }
+    }
+";
+        assert!(generated.contains(expected), "{}", generated);
+    }
+
+    #[test]
+    fn test_generate_unsupported() {
+        assert_eq!(
+            Err(String::from("`p (f a)`: compound arguments are not supported")),
+            generate("p (f a)")
+        );
+        assert_eq!(
+            Err(String::from("`p X`: head variable X must appear in the body")),
+            generate("p X")
+        );
+        assert_eq!(Err(String::from("q/1 is not defined")), generate("p X if q X"));
+        assert_eq!(
+            Err(String::from("parnet/2 is not defined, did you mean parent/2?")),
+            generate("parent a b \n grandparent X Z if parnet X Y and parent Y Z")
+        );
+        assert_eq!(
+            Err(String::from("p/1 is recursive")),
+            generate("p X if q X \n q X if r X \n q X if p X \n r a")
+        );
+        assert!(generate("p X if X").is_err());
+    }
+}
@@ -1,22 +1,48 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::cell::RefCell;
-use std::cell::RefMut;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
 
 use crate::ast::*;
+use crate::extract::{LIST_CONS, LIST_NIL};
 use crate::heap::*;
+use crate::parser::{parse_statements, parse_statements_recovering, LoadError, SourceStatement, Statement};
 
-struct Compiler {
-    heap: Heap,
-    clauses: Vec<ClauseDescriptor>,
-    symbol_table: SymbolTable,
+pub struct Compiler {
+    pub(crate) heap: Heap,
+    pub(crate) clauses: Vec<ClauseDescriptor>,
+    // Indeces of clauses grouped by the predicate they define
+    pub(crate) predicates: PredicateTable,
+    pub(crate) symbol_table: SymbolTable,
 
     // Keep track of indeces of variables during compilation
     // This is cleared before compiling each clause.
     current_clause_variables: HashMap<String, HeapIndex>,
 
-    queries: Vec<QueryDescriptor>,
-    spines: Vec<Spine>,
-    trail: Vec<HeapIndex>,
+    pub(crate) queries: Vec<QueryDescriptor>,
+    // Initial spines of queries that have not been run yet; the engine also
+    // uses this as its stack of spines while solving
+    pub(crate) spines: Vec<Spine>,
+    // Indeces of variable cells bound since the oldest spine was created
+    pub(crate) trail: Vec<HeapIndex>,
+
+    // When enabled, identical ground subterms are only stored once on the
+    // heap (hash-consing).
+    structure_sharing: bool,
+    // Heap slices of shared ground subterms and the index they are stored at
+    ground_subterms: HashMap<Vec<HeapEntry>, HeapIndex>,
+    shared_subterm_indeces: HashSet<HeapIndex>,
+
+    // Whether consult_str keeps the source text of each clause
+    retain_source: bool,
+    // Indeces of the clauses loaded from each file (or other named source),
+    // so that loading it again replaces them
+    origins: HashMap<String, Vec<usize>>,
+    // Heap cells taken up by clauses removed since the clause area was last
+    // compacted, and how many there may be before it is compacted again
+    dead_cells: usize,
+    compaction_threshold: Option<usize>,
 }
 
 /**
@@ -24,90 +50,323 @@ struct Compiler {
  * Based on the "Clause" class in https://github.com/ptarau/iProlog
  */
 #[derive(Clone, Debug, PartialEq)]
-struct ClauseDescriptor {
+pub(crate) struct ClauseDescriptor {
     // Index to start of clause
-    base: HeapIndex,
+    pub(crate) base: HeapIndex,
     // Length of clause array slice in heap
-    length: HeapIndex,
+    pub(crate) length: HeapIndex,
     // Length of head of clause
-    neck: HeapIndex,
+    pub(crate) neck: HeapIndex,
 
     // Toplevel skeleton of clause (Reference entries that point to each
     // top-level term)
-    terms: Vec<HeapEntry>,
+    pub(crate) terms: Vec<HeapEntry>,
     // Dereferenced subterms of head
-    head_subterms: Vec<HeapEntry>,
+    pub(crate) head_subterms: Vec<HeapEntry>,
+    pub(crate) annotations: Vec<Annotation>,
+    // Value of the clause's `@priority` annotation, 0 if it has none
+    pub(crate) priority: i64,
+    // Text the clause was compiled from, if the compiler retains it
+    pub(crate) source: Option<String>,
+    // Whether the body contains a cut, whose barrier is the spine that
+    // resolved a goal with this clause
+    pub(crate) has_cut: bool,
+    // Whether the clause was replaced by reconsulting the file it was loaded
+    // from, or retracted. Removed clauses are no longer in the predicate
+    // table, but stay on the heap until the clause area is compacted, which
+    // renumbers the clauses.
+    pub(crate) removed: bool,
+    // Set if the clause was asserted while solving
+    pub(crate) asserted: Option<AssertedClause>,
+}
+
+/**
+ * A clause added by `assertz` or `asserta`. The heap above the spines is
+ * discarded on backtracking, so the clause's cells are kept here instead;
+ * ClauseDescriptor.base is where they were compiled and they are copied to
+ * the heap from here.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct AssertedClause {
+    pub(crate) cells: Vec<HeapEntry>,
+    // The clause as it was asserted, for listings
+    pub(crate) clause: Clause,
+}
+
+/**
+ * Clauses grouped by the name and arity of their head, so that looking up the
+ * clauses for a goal doesn't scan the whole program. Names are the Constant
+ * (or Number) cells of the heads and arities are the data of their Arity
+ * cells. Each list of clause indeces is in source order.
+ */
+#[derive(Debug, Default)]
+pub(crate) struct PredicateTable {
+    predicates: HashMap<(HeapEntry, usize), Vec<usize>>,
+    // Clauses whose head has a variable functor, e.g. `X a`, which can define
+    // any predicate of their arity
+    variable_functors: HashMap<usize, Vec<usize>>,
+    // Every clause, for goals whose functor is an unbound variable
+    by_arity: HashMap<usize, Vec<usize>>,
+    // Position of the clauses added with asserta, which come before all the
+    // others, most recent first. Other clauses are ordered by their index.
+    front_positions: HashMap<usize, isize>,
+    // Position of the clause most recently added with asserta, or 0
+    first_position: isize,
 }
 
 /**
  * A descriptor of a query on the heap.
  */
 #[derive(Clone, Debug, PartialEq)]
-struct QueryDescriptor {
+pub(crate) struct QueryDescriptor {
     // Index to start of clause
-    base: HeapIndex,
+    pub(crate) base: HeapIndex,
     // Length of query array slice in heap
-    length: HeapIndex,
+    pub(crate) length: HeapIndex,
 
     // Reference entries that point to each top-level term (sub-goal)
-    terms: Vec<HeapEntry>,
+    pub(crate) terms: Vec<HeapEntry>,
+    // Named variables of the query and the index of their first occurence,
+    // in the order they appear
+    pub(crate) variables: Vec<(String, HeapIndex)>,
 }
 
 /**
  * Stores the current state of the search.
  * Based on the "Spine" class in https://github.com/ptarau/iProlog
  */
-#[derive(Debug, PartialEq)]
-struct Spine {
-    // Top of heap when this spine created
-    base: HeapIndex,
-    // Top of trail when this spine created
-    trail_top: HeapIndex,
-
-    goals: Vec<HeapEntry>,
-    dereferenced_elements: Vec<HeapEntry>,
-    // TODO clarify meaning of this field
-    // Clauses (potentially?) unifiable with first goal in goals
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Spine {
+    // Top of heap before the clause that created this spine was copied
+    pub(crate) base: HeapIndex,
+    // Top of trail before the clause that created this spine was unified
+    pub(crate) trail_top: HeapIndex,
+
+    // Goals left to solve, in the order they are solved (goals[0] is next).
+    // Entries are References to the Arity entry of each goal.
+    pub(crate) goals: Vec<HeapEntry>,
+    // Cut barrier of each goal: the number of spines to keep when the goal
+    // is a cut. The last spine kept is the one whose goal was resolved with
+    // the clause the cut appears in, so that its other clauses are
+    // discarded too. Goals of a query have a barrier of 0.
+    pub(crate) cut_barriers: Vec<usize>,
+    // Registers holding the dereferenced subterms of goals[0], used to
+    // quickly rule out clauses whose head can't match
+    pub(crate) dereferenced_elements: Vec<HeapEntry>,
+    // Clauses that may unify with goals[0], in the order they are tried.
     // Elements are indeces in Compiler.clauses
-    unifiable_clauses: Vec<usize>,
-
-    // TODO clarify meaning of this field
-    // Number clauses that have already been unified
-    num_unified_clauses: usize,
+    pub(crate) unifiable_clauses: Vec<usize>,
+
+    // Number of entries of unifiable_clauses that have already been tried
+    pub(crate) num_unified_clauses: usize,
+    // Number of candidate solutions a nondeterministic builtin has tried
+    // for goals[0]
+    pub(crate) builtin_candidates: usize,
+    // Number of resolution steps from the query to this spine
+    pub(crate) depth: usize,
 }
 
 #[derive(Debug)]
-struct SymbolTable {
+pub(crate) struct SymbolTable {
     // TODO allow storing other types of data like floats
-    symbols: Vec<String>,
-    symbols_to_indeces: HashMap<String, usize>,
+    symbols: Vec<Symbol>,
+    symbols_to_indeces: HashMap<Symbol, usize>,
+
+    // Interned pieces of symbol names. A namespaced atom like `rdf:type` is
+    // stored as two segments so that a prefix shared by many atoms is only
+    // stored once.
+    segments: Vec<String>,
+    segments_to_indeces: HashMap<String, usize>,
+
+    // Namespace that each prefix segment expands to
+    prefixes: HashMap<usize, String>,
+}
+
+/**
+ * A symbol name made of indeces into SymbolTable.segments.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum Symbol {
+    Plain(usize),
+    Namespaced { prefix: usize, local: usize },
 }
 
 impl Compiler {
     pub fn new() -> Self {
+        Self::with_heap_config(HeapConfig::default())
+    }
+
+    /**
+     * Creates a compiler whose heap is preallocated and grown according to
+     * the given configuration.
+     */
+    pub fn with_heap_config(config: HeapConfig) -> Self {
         Compiler {
-            heap: Heap::new(),
+            heap: Heap::with_config(config),
             clauses: Vec::new(),
+            predicates: PredicateTable::default(),
             symbol_table: SymbolTable::new(),
             current_clause_variables: HashMap::new(),
             queries: Vec::new(),
             spines: Vec::new(),
             trail: Vec::new(),
+            structure_sharing: false,
+            ground_subterms: HashMap::new(),
+            shared_subterm_indeces: HashSet::new(),
+            retain_source: false,
+            origins: HashMap::new(),
+            dead_cells: 0,
+            compaction_threshold: None,
         }
     }
 
+    /**
+     * Number of heap entries that can be used before the heap has to grow.
+     */
+    pub fn heap_capacity(&self) -> HeapIndex {
+        self.heap.capacity()
+    }
+
+    /**
+     * Enables or disables sharing of identical ground subterms between
+     * clauses. This can greatly reduce the size of the heap for large,
+     * repetitive fact bases.
+     */
+    pub fn set_structure_sharing(&mut self, enabled: bool) {
+        self.structure_sharing = enabled;
+    }
+
+    /**
+     * Enables or disables keeping the source text of clauses compiled with
+     * consult_str, so that listings can show the code exactly as it was
+     * written, comments and formatting included.
+     */
+    pub fn set_retain_source(&mut self, enabled: bool) {
+        self.retain_source = enabled;
+    }
+
+    /**
+     * Registers the namespace that atoms with the given prefix expand to,
+     * e.g. `rdf` for `http://www.w3.org/1999/02/22-rdf-syntax-ns#`.
+     */
+    pub fn register_prefix(&mut self, prefix: &str, namespace: &str) {
+        self.symbol_table.register_prefix(prefix, namespace);
+    }
+
+    /**
+     * Returns the full name of a compiled atom, expanding its namespace
+     * prefix if one has been registered.
+     */
+    pub fn expand_atom(&self, atom: &str) -> Option<String> {
+        let index = self.symbol_table.get_index(atom)?;
+        Some(self.symbol_table.expand(index).into_owned())
+    }
+
     pub fn compile(&mut self, program: Program) {
+        let first_query = self.queries.len();
         for clause in program.clauses {
-            self.compile_clause(clause);
+            self.compile_clause(clause, None);
         }
 
         for query in program.queries {
             self.compile_query(query);
         }
-        self.create_initial_spine(self.queries.clone());
+        self.create_initial_spine(self.queries[first_query..].to_vec());
+    }
+
+    /**
+     * Parses and compiles source code. Each clause is compiled as soon as it
+     * has been parsed, so the AST of the whole program is never held in
+     * memory at once.
+     */
+    pub fn consult_str(&mut self, code: &str) -> Result<(), String> {
+        let first_query = self.queries.len();
+        let mut statements = parse_statements(code)?;
+        while let Some((statement, source)) = statements.next_with_source() {
+            self.compile_statement(statement, source);
+        }
+        self.create_initial_spine(self.queries[first_query..].to_vec());
+        Ok(())
+    }
+
+    /**
+     * Consults a file. If the file has been consulted before, the clauses
+     * loaded from it then are replaced by the ones it contains now rather
+     * than kept alongside them, so that a file being edited can be loaded
+     * again and again.
+     */
+    pub fn consult_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let code = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        self.reconsult_str(&path.to_string_lossy(), &code)
+    }
+
+    /**
+     * Like consult_str, but the clauses are recorded as loaded from origin,
+     * e.g. a file name, and replace the clauses previously loaded from it.
+     * Clauses loaded from other origins are kept, even if they are for the
+     * same predicates. Nothing is replaced if the code can't be parsed.
+     */
+    pub fn reconsult_str(&mut self, origin: &str, code: &str) -> Result<(), String> {
+        let mut statements = parse_statements(code)?;
+        if let Some(previous) = self.origins.remove(origin) {
+            for clause in &previous {
+                let clause = &mut self.clauses[*clause];
+                if !clause.removed {
+                    clause.removed = true;
+                    self.dead_cells += clause.length;
+                }
+            }
+            self.predicates.remove(&previous.into_iter().collect());
+        }
+
+        let first_clause = self.clauses.len();
+        let first_query = self.queries.len();
+        while let Some((statement, source)) = statements.next_with_source() {
+            self.compile_statement(statement, source);
+        }
+        self.origins.insert(origin.to_string(), (first_clause..self.clauses.len()).collect());
+        self.create_initial_spine(self.queries[first_query..].to_vec());
+        self.compact_if_due();
+        Ok(())
+    }
+
+    /**
+     * Like consult_str, but statements that can't be loaded are skipped
+     * instead of failing the whole load, so that e.g. a machine generated
+     * rule file with a few bad lines can still be queried. Besides
+     * statements with syntax errors, clauses whose head is a number are
+     * skipped. Returns why each statement was skipped, in source order.
+     */
+    pub fn consult_str_lenient(&mut self, code: &str) -> Vec<LoadError> {
+        let first_query = self.queries.len();
+        let (statements, mut errors) = parse_statements_recovering(code);
+        for SourceStatement { statement, source, line } in statements {
+            if let Statement::Clause(Clause { head: head @ Term::Simple(SimpleTerm::Number(_)), .. }) = &statement {
+                let message = format!("`{}`: a number can't be the head of a clause", head);
+                errors.push(LoadError { line, message });
+                continue;
+            }
+            self.compile_statement(statement, source);
+        }
+        errors.sort_by_key(|error| error.line);
+        self.create_initial_spine(self.queries[first_query..].to_vec());
+        errors
     }
 
-    fn compile_clause(&mut self, clause: Clause) {
+    fn compile_statement(&mut self, statement: Statement, source: &str) {
+        match statement {
+            Statement::Clause(clause) => {
+                let source = self.retain_source.then(|| source.to_string());
+                self.compile_clause(clause, source);
+            },
+            Statement::Query(query) => self.compile_query(query),
+            // Type declarations are only used by static analysis
+            Statement::TypeDeclaration(_) => (),
+        }
+    }
+
+    fn compile_clause(&mut self, clause: Clause, source: Option<String>) {
         self.current_clause_variables.clear();
 
         let base = self.heap.len();
@@ -121,6 +380,7 @@ impl Compiler {
         let mut terms = Vec::new();
         terms.push(base);
 
+        let has_cut = clause.body.iter().any(|goal| goal.iter().any(Term::is_cut));
         for term in clause.body {
             let term_index = self.heap.len();
             if let Term::Simple(_) = term {
@@ -135,9 +395,11 @@ impl Compiler {
         // Convert indeces into heap entries
         let terms: Vec<HeapEntry> = terms
             .into_iter()
-            .map(|index| HeapEntry { tag: HeapTag::Reference, data: index } ).collect();
+            .map(|index| HeapEntry::new(HeapTag::Reference, index)).collect();
 
         let head_subterms = self.get_subterms(terms[0]);
+        let arity = self.heap.read(base).data;
+        self.predicates.insert(head_subterms[0], arity, self.clauses.len());
 
         self.clauses.push(ClauseDescriptor {
             base,
@@ -145,9 +407,173 @@ impl Compiler {
             neck,
             terms,
             head_subterms,
+            priority: priority(&clause.annotations),
+            annotations: clause.annotations,
+            source,
+            has_cut,
+            removed: false,
+            asserted: None,
         });
     }
 
+    /**
+     * Compiles a fact asserted while solving and adds it after the other
+     * clauses of its predicate, or before them if at_front is set. The
+     * fact's cells are moved off the heap, which is left as it was. Ground
+     * subterms are never shared with other clauses, since those may be
+     * discarded on backtracking.
+     */
+    pub(crate) fn assert_clause(&mut self, clause: Clause, at_front: bool) {
+        let index = self.clauses.len();
+        let structure_sharing = std::mem::replace(&mut self.structure_sharing, false);
+        self.compile_clause(clause.clone(), None);
+        self.structure_sharing = structure_sharing;
+        if at_front {
+            self.predicates.move_to_front(index);
+        }
+
+        let descriptor = &mut self.clauses[index];
+        let (base, length) = (descriptor.base, descriptor.length);
+        let cells = (base..base + length).map(|i| self.heap.read(i)).collect();
+        descriptor.asserted = Some(AssertedClause { cells, clause });
+        self.heap.truncate(base);
+    }
+
+    /**
+     * Removes a clause from the predicate table. Goals that were already
+     * being resolved with it can still use it.
+     */
+    pub(crate) fn retract_clause(&mut self, index: usize) {
+        let clause = &mut self.clauses[index];
+        clause.removed = true;
+        if clause.asserted.is_none() {
+            self.dead_cells += clause.length;
+        }
+        self.predicates.remove(&HashSet::from([index]));
+    }
+
+    /**
+     * Compacts the clause area automatically once the clauses removed by
+     * retract or by reconsulting take up more than the given number of heap
+     * cells. Off by default. The check is made after each reconsult and,
+     * when an Engine runs the program, before each query starts.
+     */
+    pub fn set_compaction_threshold(&mut self, threshold: Option<usize>) {
+        self.compaction_threshold = threshold;
+    }
+
+    /**
+     * Number of heap cells taken up by clauses that were removed since the
+     * clause area was last compacted.
+     */
+    pub fn dead_clause_cells(&self) -> usize {
+        self.dead_cells
+    }
+
+    pub(crate) fn is_compaction_due(&self) -> bool {
+        self.compaction_threshold.is_some_and(|threshold| self.dead_cells > threshold)
+    }
+
+    fn compact_if_due(&mut self) {
+        if self.is_compaction_due() {
+            self.compact_clauses();
+        }
+    }
+
+    /**
+     * Removes the clauses that were retracted or replaced by reconsulting
+     * from the heap and from the clause list, and returns the number of heap
+     * cells reclaimed. The remaining cells slide down in order, and every
+     * heap index held by the compiler is relocated: clause and query
+     * descriptors, initial spines and shared ground subterms. Clause indeces
+     * in the predicate table and in the origins of reconsulted files are
+     * renumbered. A shared ground subterm that was compiled as part of a
+     * removed clause is kept as long as another clause refers to it.
+     *
+     * Can't be called while a query is running, since its spines and
+     * bindings point into the heap.
+     */
+    pub fn compact_clauses(&mut self) -> usize {
+        assert!(self.trail.is_empty(), "the clause area can't be compacted while a query is running");
+        let len = self.heap.len();
+        let mut marked = vec![true; len];
+        for clause in self.clauses.iter().filter(|clause| clause.removed && clause.asserted.is_none()) {
+            marked[clause.base..clause.base + clause.length].fill(false);
+        }
+        // Ground subterms are shared by References from other clauses, and
+        // may contain References to other shared subterms
+        let mut pending: Vec<HeapIndex> = (0..len).filter(|index| marked[*index]).collect();
+        while let Some(index) = pending.pop() {
+            let entry = self.heap.read(index);
+            if entry.tag == HeapTag::Reference && !marked[entry.data] {
+                let block = entry.data..=entry.data + self.heap.read(entry.data).data;
+                marked[block.clone()].fill(true);
+                pending.extend(block);
+            }
+        }
+
+        // New index of each cell, and of the top of the heap
+        let mut forward = Vec::with_capacity(len + 1);
+        let mut top = 0;
+        for is_marked in &marked {
+            forward.push(top);
+            top += *is_marked as usize;
+        }
+        forward.push(top);
+        let relocate = |entry: HeapEntry| match entry.tag {
+            HeapTag::Variable | HeapTag::Unify | HeapTag::Reference => HeapEntry::new(entry.tag, forward[entry.data]),
+            _ => entry,
+        };
+        for index in (0..len).filter(|index| marked[*index]) {
+            let entry = relocate(self.heap.read(index));
+            self.heap.write(forward[index], entry);
+        }
+        self.heap.truncate(top);
+
+        // New index of each clause, None for the removed ones
+        let mut renumbered = Vec::with_capacity(self.clauses.len());
+        let mut kept = 0;
+        for clause in &self.clauses {
+            renumbered.push((!clause.removed).then_some(kept));
+            kept += !clause.removed as usize;
+        }
+        self.clauses.retain(|clause| !clause.removed);
+        // Asserted clauses keep their cells off the heap
+        for clause in self.clauses.iter_mut().filter(|clause| clause.asserted.is_none()) {
+            clause.base = forward[clause.base];
+            clause.neck = forward[clause.neck];
+            clause.terms.iter_mut().for_each(|entry| *entry = relocate(*entry));
+            clause.head_subterms.iter_mut().for_each(|entry| *entry = relocate(*entry));
+        }
+        self.predicates.renumber(&renumbered);
+        for clauses in self.origins.values_mut() {
+            *clauses = clauses.iter().filter_map(|clause| renumbered[*clause]).collect();
+        }
+
+        for query in &mut self.queries {
+            query.base = forward[query.base];
+            query.terms.iter_mut().for_each(|entry| *entry = relocate(*entry));
+            query.variables.iter_mut().for_each(|(_, index)| *index = forward[*index]);
+        }
+        for spine in &mut self.spines {
+            spine.base = forward[spine.base.min(len)];
+            spine.goals.iter_mut().for_each(|entry| *entry = relocate(*entry));
+            spine.dereferenced_elements.iter_mut().for_each(|entry| *entry = relocate(*entry));
+            spine.unifiable_clauses = spine.unifiable_clauses.iter().filter_map(|clause| renumbered[*clause]).collect();
+        }
+
+        self.ground_subterms = std::mem::take(&mut self.ground_subterms).into_iter()
+            .filter(|(_, index)| marked[*index])
+            .map(|(cells, index)| (cells.into_iter().map(relocate).collect(), forward[index]))
+            .collect();
+        self.shared_subterm_indeces = self.shared_subterm_indeces.iter()
+            .filter(|index| marked[**index])
+            .map(|index| forward[*index])
+            .collect();
+        self.dead_cells = 0;
+        len - top
+    }
+
     /**
      * Top-level simple terms need an Arity entry. This is because every entry
      * in the terms array of a ClauseDescriptor is expected to point to an Arity
@@ -191,6 +617,7 @@ impl Compiler {
                 let heap_entry = HeapEntry::new(HeapTag::Constant, symbol_index);
                 self.heap.write(index, heap_entry);
             },
+            SimpleTerm::Number(value) => self.heap.write(index, HeapEntry::number(value)),
             SimpleTerm::Variable(variable) => {
                 match self.current_clause_variables.get(&variable) {
                     Some(variable_index) => {
@@ -231,7 +658,7 @@ impl Compiler {
                 },
                 Term::Compound(compound_term) => {
                     // Compile the subterm somewhere else in the heap.
-                    let subterm_index = self.compile_compound_term(compound_term);
+                    let subterm_index = self.compile_subterm(compound_term);
                     // Place a reference to the compiled subterm in the current
                     // term's array slice.
                     let reference = HeapEntry::new(HeapTag::Reference, subterm_index);
@@ -243,7 +670,128 @@ impl Compiler {
         start_index
     }
 
-    fn compile_query(&mut self, query: Query) {
+    /**
+     * Builds a compound term on top of the heap while solving, like
+     * compile_compound_term does for the terms of the program. The name and
+     * arguments are cells already on the heap, or values such as Number
+     * cells. Arguments that are unbound variables are shared, not copied.
+     */
+    pub(crate) fn construct_compound(&mut self, name: HeapEntry, arguments: &[HeapEntry]) -> HeapEntry {
+        let start_index = self.heap.alloc(2 + arguments.len());
+        self.heap.write(start_index, HeapEntry::new(HeapTag::Arity, 1 + arguments.len()));
+        for (i, cell) in std::iter::once(&name).chain(arguments).enumerate() {
+            let cell = match self.deref(*cell) {
+                variable if variable.is_var_or_unify() => HeapEntry::new(HeapTag::Unify, variable.data),
+                value => value,
+            };
+            self.heap.write(start_index + 1 + i, cell);
+        }
+        HeapEntry::new(HeapTag::Reference, start_index)
+    }
+
+    /**
+     * Builds a compound term with the given name and a fresh variable for
+     * each argument on top of the heap.
+     */
+    pub(crate) fn construct_compound_with_variables(&mut self, name: HeapEntry, arity: usize) -> HeapEntry {
+        let start_index = self.heap.alloc(2 + arity);
+        self.heap.write(start_index, HeapEntry::new(HeapTag::Arity, 1 + arity));
+        self.heap.write(start_index + 1, name);
+        for index in start_index + 2..start_index + 2 + arity {
+            self.heap.write(index, HeapEntry::new(HeapTag::Variable, index));
+        }
+        HeapEntry::new(HeapTag::Reference, start_index)
+    }
+
+    /**
+     * Copies a term to the top of the heap, with a fresh variable for each
+     * distinct unbound variable in it. Atoms and numbers are their own copy.
+     */
+    pub(crate) fn copy_term(&mut self, term: HeapEntry) -> HeapEntry {
+        // Cell of the copy of each variable, by the cell of the original
+        let mut variables: HashMap<HeapIndex, HeapIndex> = HashMap::new();
+        let root = self.heap.alloc(1);
+        // Cells to copy and where to put their copy
+        let mut pending = vec![(term, root)];
+        while let Some((cell, destination)) = pending.pop() {
+            let cell = self.deref(cell);
+            let copy = match cell.tag {
+                HeapTag::Variable | HeapTag::Unify => match variables.get(&cell.data) {
+                    Some(variable) => HeapEntry::new(HeapTag::Unify, *variable),
+                    None => {
+                        variables.insert(cell.data, destination);
+                        HeapEntry::new(HeapTag::Variable, destination)
+                    },
+                },
+                HeapTag::Reference => {
+                    let length = self.heap.read(cell.data).data;
+                    let start_index = self.heap.alloc(1 + length);
+                    self.heap.write(start_index, HeapEntry::new(HeapTag::Arity, length));
+                    pending.extend((1..=length).map(|i| (self.heap.read(cell.data + i), start_index + i)));
+                    HeapEntry::new(HeapTag::Reference, start_index)
+                },
+                _ => cell,
+            };
+            self.heap.write(destination, copy);
+        }
+        self.heap.read(root)
+    }
+
+    /**
+     * Builds a `cons`/`nil` list of the given elements on top of the heap.
+     */
+    pub(crate) fn construct_list(&mut self, elements: &[HeapEntry]) -> HeapEntry {
+        let cons = HeapEntry::new(HeapTag::Constant, self.symbol_table.intern(LIST_CONS));
+        let mut list = HeapEntry::new(HeapTag::Constant, self.symbol_table.intern(LIST_NIL));
+        for element in elements.iter().rev() {
+            list = self.construct_compound(cons, &[*element, list]);
+        }
+        list
+    }
+
+    /**
+     * Compiles a compound term nested inside another term. With structure
+     * sharing enabled, a ground subterm that is identical to one compiled
+     * earlier is not kept on the heap and the earlier copy is used instead.
+     */
+    fn compile_subterm(&mut self, term: CompoundTerm) -> HeapIndex {
+        let index = self.compile_compound_term(term);
+        if !self.structure_sharing {
+            return index;
+        }
+
+        let arity = self.heap.read(index).data;
+        let cells: Vec<HeapEntry> = (index..index + 1 + arity)
+            .map(|i| self.heap.read(i))
+            .collect();
+        let is_ground = cells.iter().all(|cell| match cell.tag {
+            HeapTag::Variable | HeapTag::Unify => false,
+            HeapTag::Reference => self.shared_subterm_indeces.contains(&cell.data),
+            _ => true,
+        });
+        if !is_ground {
+            return index;
+        }
+
+        match self.ground_subterms.get(&cells) {
+            Some(shared_index) => {
+                // Children of a duplicate subterm are duplicates too, so they
+                // have already been removed and the slice is at the top of
+                // the heap.
+                self.heap.truncate(index);
+                *shared_index
+            },
+            None => {
+                self.ground_subterms.insert(cells, index);
+                self.shared_subterm_indeces.insert(index);
+                index
+            }
+        }
+    }
+
+    pub(crate) fn compile_query(&mut self, query: Query) {
+        self.current_clause_variables.clear();
+
         let base = self.heap.len();
         let mut terms = Vec::new();
         for term in query.sub_queries {
@@ -255,17 +803,35 @@ impl Compiler {
             terms.push(HeapEntry::new(HeapTag::Reference, term_index));
         }
         let length = self.heap.len() - base;
+
+        let mut variables: Vec<(String, HeapIndex)> = self.current_clause_variables
+            .drain()
+            .collect();
+        variables.sort_by_key(|(_, index)| *index);
+
         self.queries.push( QueryDescriptor {
             base,
             length,
             terms,
+            variables,
         });
     }
 
+    /**
+     * Compiles a query that is run once and then discarded by truncating the
+     * heap. Its ground subterms are never shared, since the shared copies
+     * would be discarded with it.
+     */
+    pub(crate) fn compile_temporary_query(&mut self, query: Query) {
+        let structure_sharing = std::mem::replace(&mut self.structure_sharing, false);
+        self.compile_query(query);
+        self.structure_sharing = structure_sharing;
+    }
+
     /**
      * Given the index of a term, returns dereferenced heap entries of subterms.
      */
-    fn get_subterms(&self, reference: HeapEntry) -> Vec<HeapEntry> {
+    pub(crate) fn get_subterms(&self, reference: HeapEntry) -> Vec<HeapEntry> {
         let start_index = reference.data + 1;
         let arity = self.deref_once(reference).data;
 
@@ -283,7 +849,7 @@ impl Compiler {
      * Convert a heap entry to a value that can be stored in a register.
      * (registers are entries in Clause.head_subterms or Spine.dereferenced_elements)
      */
-    fn entry_to_register(&self, entry: HeapEntry) -> HeapEntry {
+    pub(crate) fn entry_to_register(&self, entry: HeapEntry) -> HeapEntry {
         if let HeapTag::Reference = entry.tag {
             return self.deref_once(entry);
         }
@@ -295,7 +861,7 @@ impl Compiler {
     /**
      * Returns the HeapEntry that the pointer HeapEntry points to.
      */
-    fn deref_once(&self, pointer: HeapEntry) -> HeapEntry {
+    pub(crate) fn deref_once(&self, pointer: HeapEntry) -> HeapEntry {
         self.heap.read(pointer.data)
     }
 
@@ -303,7 +869,7 @@ impl Compiler {
      * Follows chain of references until reaching first occurance of variable or
      * a non-variable entry.
      */
-    fn deref(&self, pointer: HeapEntry) -> HeapEntry {
+    pub(crate) fn deref(&self, pointer: HeapEntry) -> HeapEntry {
         let mut result = pointer;
         while result.is_var_or_unify() {
             let dereferenced = self.deref_once(result);
@@ -332,98 +898,240 @@ impl Compiler {
             self.spines.push(spine);
         }
     }
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PredicateTable {
+    fn insert(&mut self, name: HeapEntry, arity: usize, clause: usize) {
+        if name.is_var_or_unify() {
+            self.variable_functors.entry(arity).or_default().push(clause);
+        } else {
+            self.predicates.entry((name, arity)).or_default().push(clause);
+        }
+        self.by_arity.entry(arity).or_default().push(clause);
+    }
+
+    fn remove(&mut self, clauses: &HashSet<usize>) {
+        let lists = self.predicates.values_mut()
+            .chain(self.variable_functors.values_mut())
+            .chain(self.by_arity.values_mut());
+        for list in lists {
+            list.retain(|clause| !clauses.contains(clause));
+        }
+    }
 
     /**
-     * Produce the next spine representing an answer to a query.
-     * This algorithm is taken from iProlog: https://github.com/ptarau/iProlog
+     * Drops the clauses that map to None and renumbers the others, keeping
+     * their order.
      */
-    fn yield_answer_spine(&mut self) -> Option<Spine> {
-        while !self.spines.is_empty() {
-            let mut next_goal = self.spines.pop().unwrap();
-            if !next_goal.has_clauses() {
-                continue;
-            }
-            let unfolded = self.unfold(&mut next_goal);
-            if let None = unfolded {
-                continue;
-            }
-            // Put spine back if still has goals
-            self.spines.push(next_goal);
-            let unfolded = unfolded.unwrap();
-            if unfolded.has_goals() {
-                self.spines.push(unfolded);
-                continue;
-            }
-            return Some(unfolded);
+    fn renumber(&mut self, renumbered: &[Option<usize>]) {
+        let lists = self.predicates.values_mut()
+            .chain(self.variable_functors.values_mut())
+            .chain(self.by_arity.values_mut());
+        for list in lists {
+            *list = list.iter().filter_map(|clause| renumbered[*clause]).collect();
         }
-        None
+        self.front_positions = self.front_positions.drain()
+            .filter_map(|(clause, position)| Some((renumbered[clause]?, position)))
+            .collect();
     }
 
     /**
-     * Unifies the first goal in spine's list of goals with a matching clause
-     * and then returns a new spine.
+     * Moves the most recently inserted clause before the others of its lists.
      */
-    fn unfold(&self, spine: &mut Spine) -> Option<Spine> {
-        let trail_top = self.trail.len();
-        let heap_top = self.heap.len();
-        let base = heap_top;
+    fn move_to_front(&mut self, clause: usize) {
+        self.first_position -= 1;
+        self.front_positions.insert(clause, self.first_position);
+        let lists = self.predicates.values_mut()
+            .chain(self.variable_functors.values_mut())
+            .chain(self.by_arity.values_mut());
+        for list in lists {
+            if list.last() == Some(&clause) {
+                list.pop();
+                list.insert(0, clause);
+            }
+        }
+    }
 
-        let goal = spine.goals.last().unwrap().clone();
+    fn position(&self, clause: usize) -> isize {
+        self.front_positions.get(&clause).copied().unwrap_or(clause as isize)
+    }
 
-        self.populate_spine_dereferenced_elements(spine, goal);
+    /**
+     * Whether clauses were ever defined with the given name and arity, even
+     * if they have all been removed since.
+     */
+    pub(crate) fn is_defined(&self, name: HeapEntry, arity: usize) -> bool {
+        self.predicates.contains_key(&(name, arity))
+    }
 
-        todo!();
+    /**
+     * Returns the names and arities of the predicates that were defined.
+     */
+    pub(crate) fn names(&self) -> impl Iterator<Item = (HeapEntry, usize)> + '_ {
+        self.predicates.keys().copied()
+    }
 
-        None
+    /**
+     * Returns the clauses defined with the given name and arity, leaving out
+     * clauses with a variable functor.
+     */
+    pub(crate) fn predicate(&self, name: HeapEntry, arity: usize) -> &[usize] {
+        self.predicates.get(&(name, arity)).map_or(&[], Vec::as_slice)
     }
 
     /**
-     * Populates dereferenced_elements array of spine based on the the given goal.
+     * Returns the clauses whose head can unify with a goal with the given
+     * dereferenced name cell and arity, in source order.
      */
-    fn populate_spine_dereferenced_elements(&self, spine: &mut Spine, goal: HeapEntry) {
-        if spine.dereferenced_elements.len() > 0 {
-            // Already populated
-            return;
+    pub(crate) fn clauses_for(&self, name: HeapEntry, arity: usize) -> Vec<usize> {
+        let variable_functors = self.variable_functors.get(&arity).map_or(&[][..], Vec::as_slice);
+        if name.is_var_or_unify() {
+            return self.by_arity.get(&arity).cloned().unwrap_or_default();
+        }
+        if !matches!(name.tag, HeapTag::Constant | HeapTag::Number) {
+            // Only a variable functor matches e.g. a compound term
+            return variable_functors.to_vec();
         }
 
-        let goal_base = goal.data + 1;
-        let goal_len = self.deref_once(goal).data;
-
-        for i in 0..goal_len {
-            let entry = self.deref(self.heap.read(goal_base + i));
-            let entry = self.entry_to_register(entry);
-            spine.dereferenced_elements.push(entry);
+        // Merge the two lists, which are both sorted
+        let named = self.predicate(name, arity);
+        let mut clauses = Vec::with_capacity(named.len() + variable_functors.len());
+        let (mut i, mut j) = (0, 0);
+        while i < named.len() || j < variable_functors.len() {
+            if j == variable_functors.len()
+                || (i < named.len() && self.position(named[i]) < self.position(variable_functors[j]))
+            {
+                clauses.push(named[i]);
+                i += 1;
+            } else {
+                clauses.push(variable_functors[j]);
+                j += 1;
+            }
         }
+        clauses
     }
 }
 
 impl SymbolTable {
     fn new() -> Self {
-        SymbolTable { symbols: Vec::new(), symbols_to_indeces: HashMap::new() }
+        SymbolTable {
+            symbols: Vec::new(),
+            symbols_to_indeces: HashMap::new(),
+            segments: Vec::new(),
+            segments_to_indeces: HashMap::new(),
+            prefixes: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.symbols.len()
     }
 
     fn push(&mut self, symbol: &str) -> usize {
-        self.symbols.push(symbol.to_string());
+        let symbol = match split_namespace(symbol) {
+            Some((prefix, local)) => Symbol::Namespaced {
+                prefix: self.push_segment(prefix),
+                local: self.push_segment(local),
+            },
+            None => Symbol::Plain(self.push_segment(symbol)),
+        };
+        self.symbols.push(symbol);
         let index = self.symbols.len() - 1;
-        self.symbols_to_indeces.insert(symbol.to_string(), index);
+        self.symbols_to_indeces.insert(symbol, index);
+        index
+    }
+
+    fn push_segment(&mut self, segment: &str) -> usize {
+        if let Some(index) = self.segments_to_indeces.get(segment) {
+            return *index;
+        }
+        self.segments.push(segment.to_string());
+        let index = self.segments.len() - 1;
+        self.segments_to_indeces.insert(segment.to_string(), index);
         index
     }
 
-    fn get(&self, index: usize) -> &str {
-        &self.symbols[index]
+    pub(crate) fn get(&self, index: usize) -> Cow<'_, str> {
+        match self.symbols[index] {
+            Symbol::Plain(segment) => Cow::Borrowed(&self.segments[segment]),
+            Symbol::Namespaced { prefix, local } => Cow::Owned(
+                format!("{}:{}", self.segments[prefix], self.segments[local])
+            ),
+        }
     }
 
-    fn get_index(&self, symbol: &str) -> Option<usize> {
-        let found = self.symbols_to_indeces.get(symbol);
-        match found {
-            Some(index) => Some(*index),
-            None => None
+    /**
+     * Returns the index of a symbol, adding it if it is new.
+     */
+    pub(crate) fn intern(&mut self, symbol: &str) -> usize {
+        match self.get_index(symbol) {
+            Some(index) => index,
+            None => self.push(symbol),
+        }
+    }
+
+    pub(crate) fn get_index(&self, symbol: &str) -> Option<usize> {
+        let symbol = match split_namespace(symbol) {
+            Some((prefix, local)) => Symbol::Namespaced {
+                prefix: *self.segments_to_indeces.get(prefix)?,
+                local: *self.segments_to_indeces.get(local)?,
+            },
+            None => Symbol::Plain(*self.segments_to_indeces.get(symbol)?),
+        };
+        self.symbols_to_indeces.get(&symbol).copied()
+    }
+
+    fn register_prefix(&mut self, prefix: &str, namespace: &str) {
+        let segment = self.push_segment(prefix);
+        self.prefixes.insert(segment, namespace.to_string());
+    }
+
+    /**
+     * Returns the name of the symbol with its prefix replaced by the
+     * registered namespace, if there is one.
+     */
+    fn expand(&self, index: usize) -> Cow<'_, str> {
+        if let Symbol::Namespaced { prefix, local } = self.symbols[index] {
+            if let Some(namespace) = self.prefixes.get(&prefix) {
+                return Cow::Owned(format!("{}{}", namespace, self.segments[local]));
+            }
         }
+        self.get(index)
+    }
+}
+
+/**
+ * Splits a namespaced symbol like `rdf:type` into its prefix and local name.
+ */
+fn split_namespace(symbol: &str) -> Option<(&str, &str)> {
+    let (prefix, local) = symbol.split_once(':')?;
+    if prefix.is_empty() || local.is_empty() {
+        return None;
     }
+    Some((prefix, local))
+}
+
+/**
+ * Reads the number given by a `@priority` annotation. Clauses without one, or
+ * whose priority isn't a whole number, have priority 0.
+ */
+fn priority(annotations: &[Annotation]) -> i64 {
+    annotations
+        .iter()
+        .find(|annotation| annotation.name == "priority")
+        .and_then(|annotation| annotation.arguments.first())
+        .and_then(|argument| argument.parse().ok())
+        .unwrap_or(0)
 }
 
 impl Spine {
-    fn new(
+    pub(crate) fn new(
         base: usize,
         trail_top: usize,
         goals: Vec<HeapEntry>,
@@ -433,30 +1141,34 @@ impl Spine {
         Spine {
             base,
             trail_top,
+            cut_barriers: vec![0; goals.len()],
             goals,
             dereferenced_elements: Vec::new(),
             unifiable_clauses,
             num_unified_clauses,
+            builtin_candidates: 0,
+            depth: 0,
         }
     }
 
     /**
      * Returns true if spine has clauses that are yet to be unified.
      */
-    fn has_clauses(&self) -> bool {
+    pub(crate) fn has_clauses(&self) -> bool {
         self.num_unified_clauses < self.unifiable_clauses.len()
     }
 
     /**
      * Returns true if spine has goals yet to be solved.
      */
-    fn has_goals(&self) -> bool {
+    pub(crate) fn has_goals(&self) -> bool {
         !self.goals.is_empty()
     }
 }
 
 
 #[cfg(test)]
+#[allow(clippy::vec_init_then_push, clippy::needless_range_loop)]
 mod tests {
     use crate::compiler::*;
 
@@ -502,7 +1214,8 @@ mod tests {
         program.push_clause(
             Clause {
                 head,
-                body
+                body,
+                annotations: Vec::new()
             }
         );
 
@@ -511,7 +1224,8 @@ mod tests {
         program.push_clause(
             Clause {
                 head,
-                body
+                body,
+                annotations: Vec::new()
             }
         );
 
@@ -520,7 +1234,8 @@ mod tests {
         program.push_clause(
             Clause {
                 head,
-                body
+                body,
+                annotations: Vec::new()
             }
         );
 
@@ -561,7 +1276,8 @@ mod tests {
         program.push_clause(
             Clause {
                 head,
-                body
+                body,
+                annotations: Vec::new()
             }
         );
 
@@ -573,7 +1289,8 @@ mod tests {
         program.push_clause(
             Clause {
                 head,
-                body
+                body,
+                annotations: Vec::new()
             }
         );
 
@@ -606,7 +1323,8 @@ mod tests {
         let head = make_compound_term();
         program.push_clause(Clause {
             head,
-            body: Vec::new()
+            body: Vec::new(),
+            annotations: Vec::new()
         });
 
         //println!("ast: {:?}", program);
@@ -648,7 +1366,7 @@ mod tests {
         let mut body = Vec::new();
         body.push(Term::Simple(SimpleTerm::Atom(String::from("x"))));
         body.push(Term::Simple(SimpleTerm::Variable(String::from("Y"))));
-        program.push_clause(Clause { head, body });
+        program.push_clause(Clause { head, body, annotations: Vec::new() });
 
         let mut sub_queries = Vec::new();
         sub_queries.push(Term::Simple(SimpleTerm::Atom(String::from("a"))));
@@ -710,11 +1428,17 @@ mod tests {
             length: 15,
             neck: 11,
             terms: vec![
-                HeapEntry { tag: HeapTag::Reference, data: 0 },
-                HeapEntry { tag: HeapTag::Reference, data: 11 },
-                HeapEntry { tag: HeapTag::Reference, data: 13 },
+                HeapEntry::new(HeapTag::Reference, 0),
+                HeapEntry::new(HeapTag::Reference, 11),
+                HeapEntry::new(HeapTag::Reference, 13),
             ],
             head_subterms: vec![expected_heap[1], expected_heap[4], expected_heap[3]],
+            annotations: Vec::new(),
+            priority: 0,
+            source: None,
+            has_cut: false,
+            removed: false,
+            asserted: None,
         };
 
         assert_eq!(expected_clause, compiler.clauses[0]);
@@ -723,9 +1447,10 @@ mod tests {
             base: 15,
             length: 4,
             terms: vec![
-                HeapEntry { tag: HeapTag::Reference, data: 15 },
-                HeapEntry { tag: HeapTag::Reference, data: 17 },
+                HeapEntry::new(HeapTag::Reference, 15),
+                HeapEntry::new(HeapTag::Reference, 17),
             ],
+            variables: Vec::new(),
         };
 
         assert_eq!(expected_query, compiler.queries[0]);
@@ -740,4 +1465,234 @@ mod tests {
 
         assert_eq!(expected_spine, compiler.spines[0]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_compile_namespaced_atoms() {
+        let mut program = Program::new();
+        let mut parameters = Vec::new();
+        parameters.push(Term::Simple(SimpleTerm::Atom(String::from("ex:tom"))));
+        parameters.push(Term::Simple(SimpleTerm::Atom(String::from("rdf:type"))));
+        parameters.push(Term::Simple(SimpleTerm::Atom(String::from("ex:person"))));
+        program.push_clause(Clause {
+            head: Term::Compound(CompoundTerm {
+                name: SimpleTerm::Atom(String::from("triple")),
+                parameters
+            }),
+            body: Vec::new(),
+            annotations: Vec::new()
+        });
+
+        let mut compiler = Compiler::new();
+        compiler.register_prefix("ex", "http://example.org/");
+        compiler.compile(program);
+
+        assert_eq!(compiler.symbol_table.get(1), "ex:tom");
+        assert_eq!(compiler.symbol_table.get(2), "rdf:type");
+        assert_eq!(compiler.symbol_table.get(3), "ex:person");
+        // triple, ex, tom, rdf, type, person
+        assert_eq!(6, compiler.symbol_table.segments.len());
+
+        assert_eq!(Some(String::from("http://example.org/tom")), compiler.expand_atom("ex:tom"));
+        assert_eq!(Some(String::from("rdf:type")), compiler.expand_atom("rdf:type"));
+        assert_eq!(Some(String::from("triple")), compiler.expand_atom("triple"));
+        assert_eq!(None, compiler.expand_atom("ex:bob"));
+    }
+
+    #[test]
+    fn test_compile_with_structure_sharing() {
+        let code = "p (f a b) \n q (g (f a b)) (f a b) (f X)";
+
+        let mut compiler = Compiler::new();
+        compiler.set_structure_sharing(true);
+        compiler.compile(crate::parser::parse(code).unwrap());
+
+        let expected_heap = vec![
+            // 0: p _3
+            HeapEntry::new(HeapTag::Arity, 2),
+            HeapEntry::new(HeapTag::Constant, 0),
+            HeapEntry::new(HeapTag::Reference, 3),
+
+            // 3: f a b
+            HeapEntry::new(HeapTag::Arity, 3),
+            HeapEntry::new(HeapTag::Constant, 1),
+            HeapEntry::new(HeapTag::Constant, 2),
+            HeapEntry::new(HeapTag::Constant, 3),
+
+            // 7: q _12 _3 _15
+            HeapEntry::new(HeapTag::Arity, 4),
+            HeapEntry::new(HeapTag::Constant, 4),
+            HeapEntry::new(HeapTag::Reference, 12),
+            HeapEntry::new(HeapTag::Reference, 3),
+            HeapEntry::new(HeapTag::Reference, 15),
+
+            // 12: g _3
+            HeapEntry::new(HeapTag::Arity, 2),
+            HeapEntry::new(HeapTag::Constant, 5),
+            HeapEntry::new(HeapTag::Reference, 3),
+
+            // 15: f X (not ground, so not shared)
+            HeapEntry::new(HeapTag::Arity, 2),
+            HeapEntry::new(HeapTag::Constant, 1),
+            HeapEntry::new(HeapTag::Variable, 17),
+        ];
+
+        assert_eq!(expected_heap.len(), compiler.heap.len());
+        for i in 0..expected_heap.len() {
+            assert_eq!(expected_heap[i], compiler.heap.read(i));
+        }
+
+        let mut unshared = Compiler::new();
+        unshared.compile(crate::parser::parse(code).unwrap());
+        assert_eq!(26, unshared.heap.len());
+    }
+
+    #[test]
+    fn test_consult_str() {
+        let code = "a (a (b e f)) c if x and Y \n b \n ? a";
+
+        let mut compiler = Compiler::new();
+        compiler.consult_str(code).unwrap();
+
+        let mut expected = Compiler::new();
+        expected.compile(crate::parser::parse(code).unwrap());
+
+        assert_eq!(expected.heap, compiler.heap);
+        assert_eq!(expected.clauses, compiler.clauses);
+        assert_eq!(expected.spines, compiler.spines);
+    }
+
+    #[test]
+    fn test_compile_query_variables() {
+        // The query's X is not the clause's X
+        let code = "p X if q X \n ? p X (f Y) X";
+
+        let mut compiler = Compiler::new();
+        compiler.compile(crate::parser::parse(code).unwrap());
+
+        // 6: p X _11 X    11: f Y
+        let query = &compiler.queries[0];
+        assert_eq!(6, query.base);
+        assert_eq!(
+            vec![(String::from("X"), 8), (String::from("Y"), 13)],
+            query.variables
+        );
+        assert_eq!(HeapEntry::new(HeapTag::Variable, 8), compiler.heap.read(8));
+        assert_eq!(HeapEntry::new(HeapTag::Unify, 8), compiler.heap.read(10));
+    }
+
+    #[test]
+    fn test_consult_str_error() {
+        let mut compiler = Compiler::new();
+        assert!(compiler.consult_str("a if").is_err());
+        let error = compiler.consult_str("p 99999999999999999999").unwrap_err();
+        assert!(error.contains("number 99999999999999999999 doesn't fit in 64 bits"), "{}", error);
+    }
+
+    #[test]
+    fn test_consult_str_lenient() {
+        let mut compiler = Compiler::new();
+        let errors = compiler.consult_str_lenient("p a \n p if \n 7 if p a \n p b \n ? p X");
+        let errors: Vec<String> = errors.iter().map(LoadError::to_string).collect();
+        assert_eq!(
+            vec![
+                String::from("line 2: syntax error: expected conjunction"),
+                String::from("line 3: `7`: a number can't be the head of a clause"),
+            ],
+            errors
+        );
+
+        let mut expected = Compiler::new();
+        expected.consult_str("p a \n p b \n ? p X").unwrap();
+        assert_eq!(expected.heap, compiler.heap);
+        assert_eq!(expected.clauses, compiler.clauses);
+        assert_eq!(expected.spines, compiler.spines);
+    }
+
+    #[test]
+    fn test_reconsult_str() {
+        let mut compiler = Compiler::new();
+        compiler.reconsult_str("a.pl", "p a \n p b \n q a").unwrap();
+        compiler.reconsult_str("b.pl", "p c").unwrap();
+        compiler.reconsult_str("a.pl", "p d \n r a").unwrap();
+        let name = |compiler: &Compiler, name| {
+            HeapEntry::new(HeapTag::Constant, compiler.symbol_table.get_index(name).unwrap())
+        };
+        let predicates = &compiler.predicates;
+
+        assert_eq!(&[3, 4], predicates.predicate(name(&compiler, "p"), 2));
+        assert!(predicates.predicate(name(&compiler, "q"), 2).is_empty());
+        assert_eq!(&[5], predicates.predicate(name(&compiler, "r"), 2));
+        assert_eq!(vec![3, 4, 5], predicates.clauses_for(HeapEntry::new(HeapTag::Variable, 0), 2));
+        assert!(compiler.clauses[..3].iter().all(|clause| clause.removed));
+
+        // Code that can't be parsed leaves the loaded clauses in place
+        assert!(compiler.reconsult_str("a.pl", "p if").is_err());
+        assert_eq!(&[3, 4], compiler.predicates.predicate(name(&compiler, "p"), 2));
+    }
+
+    #[test]
+    fn test_compact_clauses() {
+        let mut compiler = Compiler::new();
+        compiler.set_structure_sharing(true);
+        compiler.reconsult_str("a.pl", "p (f a b) \n q a").unwrap();
+        compiler.reconsult_str("b.pl", "r (f a b) \n ? r X").unwrap();
+        compiler.reconsult_str("a.pl", "p c \n q c").unwrap();
+        let name = |compiler: &Compiler, name| {
+            HeapEntry::new(HeapTag::Constant, compiler.symbol_table.get_index(name).unwrap())
+        };
+        let dead_cells = compiler.clauses[0].length + compiler.clauses[1].length;
+        assert_eq!(dead_cells, compiler.dead_clause_cells());
+
+        // f a b was compiled as part of the first clause, but r still uses it
+        let heap_cells = compiler.heap.len();
+        assert_eq!(dead_cells - 4, compiler.compact_clauses());
+        assert_eq!(heap_cells - dead_cells + 4, compiler.heap.len());
+        assert_eq!(0, compiler.dead_clause_cells());
+        assert_eq!(3, compiler.clauses.len());
+        assert_eq!(&[1], compiler.predicates.predicate(name(&compiler, "p"), 2));
+        assert_eq!(&[2], compiler.predicates.predicate(name(&compiler, "q"), 2));
+        assert_eq!(&[0], compiler.predicates.predicate(name(&compiler, "r"), 2));
+        assert_eq!(Some(&vec![1, 2]), compiler.origins.get("a.pl"));
+        assert_eq!(vec![0], compiler.spines[0].unifiable_clauses);
+
+        let r = &compiler.clauses[0];
+        let argument = compiler.heap.read(r.base + 2);
+        assert_eq!(HeapTag::Reference, argument.tag);
+        let cells: Vec<HeapEntry> = (argument.data..argument.data + 4).map(|i| compiler.heap.read(i)).collect();
+        let expected = vec![
+            HeapEntry::new(HeapTag::Arity, 3),
+            name(&compiler, "f"),
+            name(&compiler, "a"),
+            name(&compiler, "b"),
+        ];
+        assert_eq!(expected, cells);
+
+        // The relocated subterm is still shared with new clauses
+        compiler.reconsult_str("c.pl", "s (f a b)").unwrap();
+        let s = &compiler.clauses[3];
+        assert_eq!(argument, compiler.heap.read(s.base + 2));
+
+        // Compacted when reconsulting once enough cells are removed
+        compiler.set_compaction_threshold(Some(5));
+        compiler.reconsult_str("c.pl", "").unwrap();
+        assert_eq!(4, compiler.clauses.len());
+        compiler.reconsult_str("a.pl", "").unwrap();
+        assert_eq!(1, compiler.clauses.len());
+        assert_eq!(&[0], compiler.predicates.predicate(name(&compiler, "r"), 2));
+    }
+
+    #[test]
+    fn test_predicate_table() {
+        let mut compiler = Compiler::new();
+        compiler.consult_str("p a \n q a \n X a \n p b \n p a b").unwrap();
+        let p = HeapEntry::new(HeapTag::Constant, compiler.symbol_table.get_index("p").unwrap());
+        let predicates = &compiler.predicates;
+
+        // Arities count the functor
+        assert_eq!(&[0, 3], predicates.predicate(p, 2));
+        assert_eq!(vec![0, 2, 3], predicates.clauses_for(p, 2));
+        assert_eq!(vec![4], predicates.clauses_for(p, 3));
+        assert_eq!(vec![0, 1, 2, 3], predicates.clauses_for(HeapEntry::new(HeapTag::Variable, 0), 2));
+        assert!(predicates.clauses_for(p, 4).is_empty());
+    }
+}
@@ -0,0 +1,191 @@
+use crate::compiler::Spine;
+use crate::heap::*;
+
+/**
+ * First bytes of a saved continuation, followed by the format version.
+ */
+pub(crate) const MAGIC: &[u8; 4] = b"BPLC";
+pub(crate) const VERSION: u8 = 2;
+
+/**
+ * Writes the parts of a saved continuation. Integers are written as 8 bytes,
+ * little-endian, so that a continuation saved on one machine can be restored
+ * on another.
+ */
+#[derive(Default)]
+pub(crate) struct Encoder {
+    pub(crate) bytes: Vec<u8>,
+}
+
+/**
+ * Reads back what an Encoder wrote. Every method fails on truncated or
+ * malformed input instead of panicking.
+ */
+pub(crate) struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl Encoder {
+    pub(crate) fn header(&mut self) {
+        self.bytes.extend_from_slice(MAGIC);
+        self.bytes.push(VERSION);
+    }
+
+    pub(crate) fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    pub(crate) fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn bool(&mut self, value: bool) {
+        self.bytes.push(value as u8);
+    }
+
+    pub(crate) fn entry(&mut self, entry: HeapEntry) {
+        self.bytes.push(match entry.tag {
+            HeapTag::Variable => 0,
+            HeapTag::Unify => 1,
+            HeapTag::Reference => 2,
+            HeapTag::Constant => 3,
+            HeapTag::Number => 4,
+            HeapTag::Arity => 5,
+            HeapTag::Uninitialized => 6,
+        });
+        match entry.tag {
+            HeapTag::Number => self.u64(entry.number_value() as u64),
+            _ => self.usize(entry.data),
+        }
+    }
+
+    pub(crate) fn entries(&mut self, entries: &[HeapEntry]) {
+        self.usize(entries.len());
+        for entry in entries {
+            self.entry(*entry);
+        }
+    }
+
+    pub(crate) fn usizes(&mut self, values: &[usize]) {
+        self.usize(values.len());
+        for value in values {
+            self.usize(*value);
+        }
+    }
+
+    pub(crate) fn spine(&mut self, spine: &Spine) {
+        self.usize(spine.base);
+        self.usize(spine.trail_top);
+        self.entries(&spine.goals);
+        self.usizes(&spine.cut_barriers);
+        self.entries(&spine.dereferenced_elements);
+        self.usizes(&spine.unifiable_clauses);
+        self.usize(spine.num_unified_clauses);
+        self.usize(spine.builtin_candidates);
+        self.usize(spine.depth);
+    }
+}
+
+impl<'a> Decoder<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Decoder { bytes }
+    }
+
+    pub(crate) fn header(&mut self) -> Result<(), String> {
+        if self.take(MAGIC.len())? != MAGIC {
+            return Err(String::from("not a saved continuation"));
+        }
+        match self.take(1)?[0] {
+            VERSION => Ok(()),
+            version => Err(format!("unsupported continuation version {}", version)),
+        }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < length {
+            return Err(String::from("saved continuation is truncated"));
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn usize(&mut self) -> Result<usize, String> {
+        usize::try_from(self.u64()?).map_err(|e| e.to_string())
+    }
+
+    pub(crate) fn bool(&mut self) -> Result<bool, String> {
+        match self.take(1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            byte => Err(format!("invalid flag {} in saved continuation", byte)),
+        }
+    }
+
+    pub(crate) fn entry(&mut self) -> Result<HeapEntry, String> {
+        let tag = match self.take(1)?[0] {
+            0 => HeapTag::Variable,
+            1 => HeapTag::Unify,
+            2 => HeapTag::Reference,
+            3 => HeapTag::Constant,
+            4 => HeapTag::Number,
+            5 => HeapTag::Arity,
+            6 => HeapTag::Uninitialized,
+            byte => return Err(format!("invalid heap tag {} in saved continuation", byte)),
+        };
+        match tag {
+            HeapTag::Number => Ok(HeapEntry::number(self.u64()? as i64)),
+            _ => Ok(HeapEntry::new(tag, self.usize()?)),
+        }
+    }
+
+    /**
+     * Reads a length, checking that there are enough bytes left for that
+     * many items of at least item_size bytes so that corrupt lengths don't
+     * cause huge allocations.
+     */
+    fn length(&mut self, item_size: usize) -> Result<usize, String> {
+        let length = self.usize()?;
+        if length.saturating_mul(item_size) > self.bytes.len() {
+            return Err(String::from("saved continuation is truncated"));
+        }
+        Ok(length)
+    }
+
+    pub(crate) fn entries(&mut self) -> Result<Vec<HeapEntry>, String> {
+        let length = self.length(9)?;
+        (0..length).map(|_| self.entry()).collect()
+    }
+
+    pub(crate) fn usizes(&mut self) -> Result<Vec<usize>, String> {
+        let length = self.length(8)?;
+        (0..length).map(|_| self.usize()).collect()
+    }
+
+    pub(crate) fn spine(&mut self) -> Result<Spine, String> {
+        let base = self.usize()?;
+        let trail_top = self.usize()?;
+        let goals = self.entries()?;
+        let cut_barriers = self.usizes()?;
+        let dereferenced_elements = self.entries()?;
+        let unifiable_clauses = self.usizes()?;
+        let num_unified_clauses = self.usize()?;
+        let mut spine = Spine::new(base, trail_top, goals, unifiable_clauses, num_unified_clauses);
+        if spine.cut_barriers.len() != cut_barriers.len() {
+            return Err(String::from("malformed spine in saved continuation"));
+        }
+        spine.cut_barriers = cut_barriers;
+        spine.dereferenced_elements = dereferenced_elements;
+        spine.builtin_candidates = self.usize()?;
+        spine.depth = self.usize()?;
+        Ok(spine)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
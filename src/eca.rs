@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+use crate::analysis::predicate_indicator;
+use crate::ast::*;
+use crate::compiler::Compiler;
+use crate::machine::Engine;
+use crate::parser::parse_event_rules;
+use crate::rewrite::{matches, substitute};
+
+type ActionHandler = Box<dyn Fn(&[Term]) + Send + Sync>;
+
+/**
+ * Runs event-condition-action rules against a knowledge base. The host
+ * pushes events, which are ground terms such as `temperature kitchen high`,
+ * and for every rule whose event pattern matches, each solution of the
+ * rule's condition runs the rule's actions by calling the handler
+ * registered for them in Rust.
+ *
+ * Conditions are solved against the knowledge base as it is when the event
+ * arrives. Events themselves are not remembered.
+ */
+pub struct EventEngine {
+    knowledge: Program,
+    rules: Vec<EventRule>,
+    handlers: HashMap<(String, usize), ActionHandler>,
+}
+
+impl EventEngine {
+    pub fn new(knowledge: Program) -> Self {
+        EventEngine { knowledge, rules: Vec::new(), handlers: HashMap::new() }
+    }
+
+    pub fn add_rule(&mut self, rule: EventRule) {
+        self.rules.push(rule);
+    }
+
+    /**
+     * Parses rules such as `on temperature R L if critical L do alert R`,
+     * one per line, and adds them.
+     */
+    pub fn add_rules(&mut self, code: &str) -> Result<(), String> {
+        self.rules.extend(parse_event_rules(code)?);
+        Ok(())
+    }
+
+    /**
+     * Registers the function that runs actions with the given name and
+     * arity. It is called with the action's arguments.
+     */
+    pub fn on_action<F>(&mut self, name: &str, arity: usize, handler: F)
+    where
+        F: Fn(&[Term]) + Send + Sync + 'static
+    {
+        self.handlers.insert((name.to_string(), arity), Box::new(handler));
+    }
+
+    /**
+     * Fires the rules matching an event and returns the number of actions
+     * that ran. No action runs if any of them has no handler or a condition
+     * stops with an error.
+     */
+    pub fn push_event(&self, event: &Term) -> Result<usize, String> {
+        if event.iter().any(|term| matches!(term, Term::Simple(SimpleTerm::Variable(_)))) {
+            return Err(format!("event `{}` is not ground", event));
+        }
+
+        // Each matching rule becomes a query for its condition, with the
+        // variables of the event already substituted
+        let mut program = Program::new();
+        program.clauses = self.knowledge.clauses.clone();
+        let mut fired = Vec::new();
+        for rule in &self.rules {
+            let mut bindings = HashMap::new();
+            if !matches(&rule.event, event, &mut bindings) {
+                continue;
+            }
+            let sub_queries = rule.condition
+                .iter()
+                .map(|goal| substitute(goal, &bindings))
+                .collect();
+            program.push_query(Query { sub_queries });
+            fired.push((rule, bindings));
+        }
+        if fired.is_empty() {
+            return Ok(0);
+        }
+
+        let mut compiler = Compiler::new();
+        compiler.compile(program);
+        let mut engine = Engine::new(compiler);
+
+        let mut actions = Vec::new();
+        for (rule, event_bindings) in fired {
+            let mut solutions = engine.next_query().unwrap();
+            for answer in solutions.by_ref() {
+                let mut bindings: HashMap<String, Term> = answer.into();
+                bindings.extend(event_bindings.clone());
+                actions.extend(rule.actions.iter().map(|action| substitute(action, &bindings)));
+            }
+            if let Some(error) = solutions.error() {
+                return Err(format!("condition of rule for `{}` failed with {}", rule.event, error));
+            }
+        }
+
+        let mut calls = Vec::new();
+        for action in &actions {
+            let (name, arity) = predicate_indicator(action)
+                .ok_or(format!("action `{}` is not callable", action))?;
+            let handler = self.handlers
+                .get(&(name.to_string(), arity))
+                .ok_or(format!("no handler for action {}/{}", name, arity))?;
+            let arguments = match action {
+                Term::Compound(compound_term) => compound_term.parameters.as_slice(),
+                Term::Simple(_) => &[],
+            };
+            calls.push((handler, arguments));
+        }
+        for (handler, arguments) in &calls {
+            handler(arguments);
+        }
+        Ok(calls.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::eca::*;
+    use crate::parser::parse;
+
+    /**
+     * Returns an engine whose alert and log actions are recorded in the
+     * returned log.
+     */
+    fn event_engine(knowledge: &str, rules: &str) -> (EventEngine, Arc<Mutex<Vec<String>>>) {
+        let mut engine = EventEngine::new(parse(knowledge).unwrap());
+        engine.add_rules(rules).unwrap();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        for name in ["alert", "log"] {
+            let log = Arc::clone(&log);
+            engine.on_action(name, 1, move |arguments| {
+                log.lock().unwrap().push(format!("{} {}", name, arguments[0]));
+            });
+        }
+        (engine, log)
+    }
+
+    #[test]
+    fn test_push_event() {
+        let (engine, log) = event_engine(
+            "critical high \n critical extreme \n owner kitchen tom \n owner kitchen ann",
+            "on temperature R L if critical L and owner R P do alert P and log R \n\
+             on temperature R low do log R"
+        );
+
+        assert_eq!(Ok(0), engine.push_event(&Term::compound("humidity", vec![Term::atom("hall")])));
+        assert_eq!(Ok(0), engine.push_event(&Term::compound("temperature", vec![
+            Term::atom("kitchen"), Term::atom("normal")
+        ])));
+        assert_eq!(Ok(4), engine.push_event(&Term::compound("temperature", vec![
+            Term::atom("kitchen"), Term::atom("high")
+        ])));
+        assert_eq!(Ok(1), engine.push_event(&Term::compound("temperature", vec![
+            Term::atom("hall"), Term::atom("low")
+        ])));
+        assert_eq!(
+            vec!["alert tom", "log kitchen", "alert ann", "log kitchen", "log hall"],
+            *log.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_push_event_errors() {
+        let (engine, log) = event_engine("", "on door R do alert R and page R");
+        assert_eq!(
+            Err(String::from("no handler for action page/1")),
+            engine.push_event(&Term::compound("door", vec![Term::atom("front")]))
+        );
+        // No action ran
+        assert!(log.lock().unwrap().is_empty());
+
+        assert!(engine.push_event(&Term::compound("door", vec![Term::variable("X")])).is_err());
+
+        // The condition calls an unbound variable
+        let (engine, log) = event_engine("", "on door R if G do alert R");
+        let result = engine.push_event(&Term::compound("door", vec![Term::atom("front")]));
+        assert!(
+            result.as_ref().is_err_and(|error| error.starts_with("condition of rule for `door R` failed with instantiation error")),
+            "{:?}", result
+        );
+        assert!(log.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+use std::fmt;
+
+use crate::ast::*;
+use crate::compiler::Compiler;
+use crate::machine::{Engine, EngineOptions};
+use crate::parser::parse;
+use crate::writer::write_annotation;
+
+/**
+ * Annotations that turn examples written next to a clause into tests:
+ * `@expect G` checks that the goal G has a solution, and
+ * `@expect_count G is N` that it has exactly N. Goals are written like the
+ * goals of a query, but annotation arguments can't contain parentheses.
+ */
+pub const EXPECT: &str = "expect";
+pub const EXPECT_COUNT: &str = "expect_count";
+
+/**
+ * Outcome of checking one expectation.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpectationResult {
+    // Index of the annotated clause in Program.clauses
+    pub clause: usize,
+    pub annotation: Annotation,
+    // Why the expectation doesn't hold, or None if it does
+    pub failure: Option<String>,
+}
+
+impl ExpectationResult {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+impl fmt::Display for ExpectationResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut annotation = String::new();
+        write_annotation(&mut annotation, &self.annotation);
+        match &self.failure {
+            None => write!(f, "clause {}: {}: ok", self.clause + 1, annotation),
+            Some(failure) => write!(f, "clause {}: {}: FAILED: {}", self.clause + 1, annotation, failure),
+        }
+    }
+}
+
+/**
+ * Checks the expectations annotated on a program's clauses against the
+ * program, in source order. The program's own queries are not run.
+ */
+pub fn check_expectations(program: &Program, options: EngineOptions) -> Vec<ExpectationResult> {
+    let mut results = Vec::new();
+    // Goal and expected number of solutions of each expectation that could
+    // be read, by index in results
+    let mut checks = Vec::new();
+    for (i, clause) in program.clauses.iter().enumerate() {
+        for annotation in &clause.annotations {
+            let expectation = match annotation.name.as_str() {
+                EXPECT => read_goal(&annotation.arguments).map(|goal| (goal, None)),
+                EXPECT_COUNT => read_counted_goal(&annotation.arguments),
+                _ => continue,
+            };
+            let failure = match expectation {
+                Ok(expectation) => {
+                    checks.push((results.len(), expectation));
+                    None
+                },
+                Err(message) => Some(message),
+            };
+            results.push(ExpectationResult { clause: i, annotation: annotation.clone(), failure });
+        }
+    }
+
+    let mut tests = Program::new();
+    tests.clauses = program.clauses.clone();
+    tests.queries = checks.iter().map(|(_, (goal, _))| goal.clone()).collect();
+    let mut compiler = Compiler::new();
+    compiler.compile(tests);
+    let mut engine = Engine::with_options(compiler, options);
+
+    for (result, (_, expected)) in checks {
+        let mut solutions = engine.next_query().unwrap();
+        let found = match expected {
+            Some(_) => solutions.by_ref().count(),
+            None => solutions.next().into_iter().count(),
+        };
+        results[result].failure = if let Some(error) = solutions.error() {
+            Some(error.to_string())
+        } else {
+            match expected {
+                Some(expected) if found != expected => {
+                    Some(format!("expected {} solutions, found {}", expected, found))
+                },
+                None if found == 0 => Some(String::from("no solutions")),
+                _ => None,
+            }
+        };
+    }
+    results
+}
+
+fn read_goal(arguments: &[String]) -> Result<Query, String> {
+    let text = arguments.join(" ");
+    let mut program = parse(&format!("? {}", text)).map_err(|_| format!("`{}` is not a goal", text))?;
+    match program.queries.pop() {
+        Some(query) if !query.sub_queries.is_empty() => Ok(query),
+        _ => Err(String::from("missing goal")),
+    }
+}
+
+/**
+ * Reads the arguments of `@expect_count G is N`.
+ */
+fn read_counted_goal(arguments: &[String]) -> Result<(Query, Option<usize>), String> {
+    match arguments {
+        [goal @ .., is, count] if is == IS => {
+            let count = count.parse().map_err(|_| format!("`{}` is not a number of solutions", count))?;
+            Ok((read_goal(goal)?, Some(count)))
+        },
+        _ => Err(format!("expected `@{} <goal> is <count>`", EXPECT_COUNT)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expect::*;
+
+    fn check(code: &str) -> Vec<String> {
+        let options = EngineOptions { max_inferences: Some(1000), ..Default::default() };
+        check_expectations(&parse(code).unwrap(), options)
+            .iter()
+            .map(ExpectationResult::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_check_expectations() {
+        let code = "edge a b \n\
+                    edge b c \n\
+                    edge c d \n\
+                    @expect path a d \n\
+                    @expect_count path X Y is 6 \n\
+                    path X Y if edge X Y \n\
+                    @expect path d a \n\
+                    @expect_count path a Y is 2 \n\
+                    path X Y if edge X Z and path Z Y \n\
+                    ? path a X";
+        assert_eq!(
+            vec![
+                "clause 4: @expect path a d: ok",
+                "clause 4: @expect_count path X Y is 6: ok",
+                "clause 5: @expect path d a: FAILED: no solutions",
+                "clause 5: @expect_count path a Y is 2: FAILED: expected 2 solutions, found 3",
+            ],
+            check(code)
+        );
+    }
+
+    #[test]
+    fn test_check_expectations_errors() {
+        let code = "@expect_count loop is many \n\
+                    @expect_count loop \n\
+                    @expect loop \n\
+                    @expect loop. \n\
+                    loop if loop";
+        assert_eq!(
+            vec![
+                "clause 1: @expect_count loop is many: FAILED: `many` is not a number of solutions",
+                "clause 1: @expect_count loop: FAILED: expected `@expect_count <goal> is <count>`",
+                "clause 1: @expect loop: FAILED: resource error: inference limit exceeded",
+                "clause 1: @expect loop.: FAILED: `loop.` is not a goal",
+            ],
+            check(code)
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use std::fmt;
+
+/**
+ * How the engine would solve a goal of a query, as reported by
+ * Engine::explain without running the query.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GoalPlan {
+    // The goal as written in the query
+    pub goal: String,
+    pub kind: GoalKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GoalKind {
+    // Cut, negation or if-then-else
+    Control,
+    // Name and arity of the builtin, e.g. `atomLength/2`
+    Builtin(String),
+    // The goal is a variable, which calls the term it is bound to
+    Variable,
+    Clauses(ClausePlan),
+}
+
+/**
+ * The clauses a goal is resolved with. Only what is known before running is
+ * used: an argument bound by an earlier goal of the query doesn't narrow
+ * the candidates.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClausePlan {
+    // Name and arity, e.g. `parent/2`, or `_/2` if the name is a variable
+    pub predicate: String,
+    // Clauses looked up in the predicate table, which groups them by name
+    // and arity
+    pub clauses: usize,
+    // Clauses left to try after the argument registers rule out the heads
+    // that don't match the goal's atoms, numbers and compound terms
+    pub candidates: usize,
+    // Positions, from 1, of the arguments the registers compare: those that
+    // are not variables in the goal
+    pub indexed_arguments: Vec<usize>,
+    // Whether clauses of the predicate were added or removed after loading,
+    // by assertz, asserta, retract or reconsulting
+    pub dynamic: bool,
+    // Whether clauses were ever defined with this name and arity. Calling
+    // an undefined predicate fails, or is an error with Unknown::Error.
+    pub defined: bool,
+    // Other predicates that the clauses may call, directly or through each
+    // other, in the order they are found. Builtins are left out.
+    pub calls: Vec<String>,
+}
+
+impl fmt::Display for GoalPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.goal)?;
+        let plan = match &self.kind {
+            GoalKind::Control => return write!(f, "control construct"),
+            GoalKind::Builtin(predicate) => return write!(f, "builtin {}", predicate),
+            GoalKind::Variable => return write!(f, "calls the term its variable is bound to"),
+            GoalKind::Clauses(plan) if !plan.defined => return write!(f, "{} is not defined", plan.predicate),
+            GoalKind::Clauses(plan) => plan,
+        };
+
+        write!(f, "{}, {} of {} clauses to try", plan.predicate, plan.candidates, plan.clauses)?;
+        match plan.indexed_arguments.as_slice() {
+            [] => write!(f, ", no argument indexed")?,
+            [argument] => write!(f, ", argument {} indexed", argument)?,
+            arguments => {
+                let arguments: Vec<String> = arguments.iter().map(usize::to_string).collect();
+                write!(f, ", arguments {} indexed", arguments.join(", "))?
+            },
+        }
+        if plan.dynamic {
+            write!(f, ", dynamic")?;
+        }
+        if !plan.calls.is_empty() {
+            write!(f, ", calls {}", plan.calls.join(" "))?;
+        }
+        Ok(())
+    }
+}
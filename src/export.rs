@@ -0,0 +1,324 @@
+use std::fmt::Write;
+
+use crate::ast::*;
+
+/**
+ * Prolog dialects that a butterfly program can be exported to or imported
+ * from.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Dialect {
+    // SWI-Prolog (also accepted by most ISO-ish systems)
+    Swi,
+}
+
+impl Dialect {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "swi" => Some(Dialect::Swi),
+            _ => None
+        }
+    }
+}
+
+/**
+ * Atoms that are operators in standard Prolog. These need to be wrapped in
+ * parentheses when they appear on their own so that the standard reader does
+ * not try to parse them as an operator application.
+ */
+const STANDARD_OPERATORS: &[&str] = &[
+    ":-", "-->", "?-", ";", "|", "->", "*->", ",", "\\+", "=", "\\=", "==",
+    "\\==", "@<", "@>", "@=<", "@>=", "=..", "?=", "is", "=:=", "=\\=", "<",
+    ">", "=<", ">=", ":", "+", "-", "/\\", "\\/", "xor", "*", "/", "//", "rem",
+    "mod", "div", "<<", ">>", "**", "^", "\\", "dynamic", "discontiguous",
+    "initialization", "meta_predicate", "module_transparent", "multifile",
+    "public", "thread_local", "table",
+];
+
+/**
+ * Butterfly arithmetic functions, their arity and the standard operator with
+ * the same meaning. Expressions evaluated by `is` are written with the
+ * operators, which is what other dialects evaluate.
+ */
+pub(crate) const ARITHMETIC_OPERATORS: &[(&str, usize, &str)] = &[
+    ("plus", 2, "+"),
+    ("minus", 2, "-"),
+    ("times", 2, "*"),
+    ("quot", 2, "//"),
+    ("shiftLeft", 2, "<<"),
+    ("shiftRight", 2, ">>"),
+    ("bitAnd", 2, "/\\"),
+    ("bitOr", 2, "\\/"),
+    ("xor", 2, "xor"),
+    ("neg", 1, "-"),
+];
+
+/**
+ * Translates a butterfly program into the source syntax of another Prolog
+ * dialect.
+ */
+pub fn export(program: &Program, dialect: Dialect) -> String {
+    let mut out = String::new();
+    match dialect {
+        Dialect::Swi => {
+            for clause in &program.clauses {
+                write_clause(&mut out, clause);
+            }
+            for query in &program.queries {
+                write_query(&mut out, query);
+            }
+        }
+    }
+    out
+}
+
+fn write_clause(out: &mut String, clause: &Clause) {
+    write_term(out, &clause.head);
+    if !clause.body.is_empty() {
+        out.push_str(" :-");
+        write_body(out, &clause.body);
+    }
+    out.push_str(".\n");
+}
+
+fn write_query(out: &mut String, query: &Query) {
+    out.push_str("?-");
+    write_body(out, &query.sub_queries);
+    out.push_str(".\n");
+}
+
+fn write_body(out: &mut String, goals: &[Term]) {
+    for (i, goal) in goals.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str("\n    ");
+        write_term(out, goal);
+    }
+}
+
+fn write_term(out: &mut String, term: &Term) {
+    if let Some((condition, then, otherwise)) = term.as_if_then_else() {
+        out.push_str("( ");
+        write_conjunction(out, condition);
+        out.push_str(" -> ");
+        write_conjunction(out, then);
+        if let Some(otherwise) = otherwise {
+            out.push_str(" ; ");
+            write_conjunction(out, otherwise);
+        }
+        out.push_str(" )");
+        return;
+    }
+    if let Term::Compound(CompoundTerm { name: SimpleTerm::Atom(name), parameters }) = term {
+        if name == IS && parameters.len() == 2 {
+            write_term(out, &parameters[0]);
+            out.push_str(" is ");
+            write_expression(out, &parameters[1]);
+            return;
+        }
+    }
+
+    match term {
+        Term::Simple(simple_term) => write_simple_term(out, simple_term),
+        Term::Compound(compound_term) => {
+            let mut parameters = compound_term.parameters.iter();
+            match &compound_term.name {
+                SimpleTerm::Atom(name) => {
+                    write_atom(out, name, false);
+                    out.push('(');
+                    if let Some(first) = parameters.next() {
+                        write_term(out, first);
+                    }
+                },
+                SimpleTerm::Variable(name) => {
+                    // Standard Prolog does not allow `X(a, b)`, so use call/N
+                    out.push_str("call(");
+                    out.push_str(name);
+                },
+                // The parser never produces these
+                SimpleTerm::Number(value) => {
+                    write_atom(out, &value.to_string(), false);
+                    out.push('(');
+                    if let Some(first) = parameters.next() {
+                        write_term(out, first);
+                    }
+                },
+            }
+            for param in parameters {
+                out.push_str(", ");
+                write_term(out, param);
+            }
+            out.push(')');
+        },
+    }
+}
+
+/**
+ * Writes an arithmetic expression, with the butterfly functions that have an
+ * operator in ARITHMETIC_OPERATORS written as that operator. Operands that
+ * are themselves binary operations are bracketed, so precedence never
+ * matters.
+ */
+fn write_expression(out: &mut String, term: &Term) {
+    let Term::Compound(CompoundTerm { name: SimpleTerm::Atom(name), parameters }) = term else {
+        write_term(out, term);
+        return;
+    };
+    let operator = ARITHMETIC_OPERATORS
+        .iter()
+        .find(|(function, arity, _)| function == name && *arity == parameters.len())
+        .map(|(_, _, operator)| *operator);
+    match (operator, parameters.as_slice()) {
+        (Some(operator), [operand]) => {
+            out.push_str(operator);
+            out.push('(');
+            write_expression(out, operand);
+            out.push(')');
+        },
+        (Some(operator), [left, right]) => {
+            write_operand(out, left);
+            let _ = write!(out, " {} ", operator);
+            write_operand(out, right);
+        },
+        _ => {
+            write_atom(out, name, false);
+            out.push('(');
+            for (i, parameter) in parameters.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_expression(out, parameter);
+            }
+            out.push(')');
+        },
+    }
+}
+
+fn write_operand(out: &mut String, term: &Term) {
+    let is_binary_operation = matches!(term, Term::Compound(CompoundTerm { name: SimpleTerm::Atom(name), parameters })
+        if ARITHMETIC_OPERATORS.iter().any(|(function, arity, _)| function == name && *arity == 2 && parameters.len() == 2));
+    if is_binary_operation {
+        out.push('(');
+        write_expression(out, term);
+        out.push(')');
+    } else {
+        write_expression(out, term);
+    }
+}
+
+fn write_conjunction(out: &mut String, goals: &[Term]) {
+    for (i, goal) in goals.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        write_term(out, goal);
+    }
+}
+
+fn write_simple_term(out: &mut String, term: &SimpleTerm) {
+    match term {
+        SimpleTerm::Atom(atom) => write_atom(out, atom, true),
+        SimpleTerm::Variable(variable) => out.push_str(variable),
+        SimpleTerm::Number(value) => { let _ = write!(out, "{}", value); },
+    }
+}
+
+/**
+ * Writes an atom, quoting it if the standard reader would not read it back as
+ * the same atom. `standalone` is false when the atom is the functor of a
+ * compound term, where operators don't need to be bracketed.
+ */
+fn write_atom(out: &mut String, atom: &str, standalone: bool) {
+    if standalone && STANDARD_OPERATORS.contains(&atom) {
+        let _ = write!(out, "({})", atom);
+    } else if is_unquoted_atom(atom) || STANDARD_OPERATORS.contains(&atom) {
+        out.push_str(atom);
+    } else {
+        out.push('\'');
+        for c in atom.chars() {
+            match c {
+                '\'' => out.push_str("\\'"),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\t' => out.push_str("\\t"),
+                _ => out.push(c),
+            }
+        }
+        out.push('\'');
+    }
+}
+
+fn is_unquoted_atom(atom: &str) -> bool {
+    let mut chars = atom.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_lowercase() => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        },
+        _ => atom == "[]" || atom == "!" || atom == ";" || atom == "{}"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::export::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_export_facts_and_rules() {
+        let program = parse("a \n a (a (b e f)) c if a and X \n b").unwrap();
+        let expected = "a.\n\
+                        a(a(b(e, f)), c) :-\n    a,\n    X.\n\
+                        b.\n";
+        assert_eq!(expected, export(&program, Dialect::Swi));
+    }
+
+    #[test]
+    fn test_export_query() {
+        let program = parse("? parent tom X").unwrap();
+        assert_eq!("?-\n    parent(tom, X).\n", export(&program, Dialect::Swi));
+    }
+
+    #[test]
+    fn test_export_if_then_else() {
+        let program = parse("max X Y Z if (if lt X Y and b then eq Z Y else eq Z X) \n ? (if a then b)").unwrap();
+        let expected = "max(X, Y, Z) :-\n    ( lt(X, Y), b -> eq(Z, Y) ; eq(Z, X) ).\n\
+                        ?-\n    ( a -> b ).\n";
+        assert_eq!(expected, export(&program, Dialect::Swi));
+    }
+
+    #[test]
+    fn test_export_variable_functor() {
+        let program = parse("p X if X a b").unwrap();
+        assert_eq!("p(X) :-\n    call(X, a, b).\n", export(&program, Dialect::Swi));
+    }
+
+    #[test]
+    fn test_export_arithmetic() {
+        let program = parse("r X if X is plus 1 2 \n\
+                             s X Y if Y is times (minus X 1) (neg (quot X 2)) \n\
+                             t X if X is max (plus 1 2) (abs -3) \n\
+                             u X Y if Y is xor (bitAnd (shiftLeft X 2) 3) (bitOr (shiftRight X 1) (gcd X (msb X)))").unwrap();
+        let expected = "r(X) :-\n    X is 1 + 2.\n\
+                        s(X, Y) :-\n    Y is (X - 1) * -(X // 2).\n\
+                        t(X) :-\n    X is max(1 + 2, abs(-3)).\n\
+                        u(X, Y) :-\n    Y is ((X << 2) /\\ 3) xor ((X >> 1) \\/ gcd(X, msb(X))).\n";
+        assert_eq!(expected, export(&program, Dialect::Swi));
+    }
+
+    #[test]
+    fn test_export_quotes_atoms() {
+        let mut out = String::new();
+        write_atom(&mut out, "Hello world", true);
+        write_atom(&mut out, "it's", true);
+        write_atom(&mut out, "is", true);
+        write_atom(&mut out, "is", false);
+        assert_eq!("'Hello world''it\\'s'(is)is", out);
+    }
+
+    #[test]
+    fn test_dialect_from_name() {
+        assert_eq!(Some(Dialect::Swi), Dialect::from_name("swi"));
+        assert_eq!(None, Dialect::from_name("gnu"));
+    }
+}
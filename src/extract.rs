@@ -0,0 +1,276 @@
+use std::fmt;
+
+use crate::ast::*;
+
+/**
+ * Names that lists are built from: `cons H T` is a list with head H and
+ * tail T, and `nil` is the empty list.
+ */
+pub const LIST_CONS: &str = "cons";
+pub const LIST_NIL: &str = "nil";
+
+/**
+ * Error converting the binding of a query variable to a Rust value.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExtractError {
+    // The query has no variable with this name
+    UnknownVariable(String),
+    // The term is not of the expected kind, e.g. an atom where a number was
+    // expected
+    Mismatch { expected: &'static str, found: Term },
+    // The number doesn't fit in the requested integer type
+    OutOfRange { value: i64, target: &'static str },
+    // The element at this 0-based position of a list couldn't be converted
+    Element { position: usize, error: Box<ExtractError> },
+}
+
+/**
+ * Types that can be converted from a term, for reading the bindings of an
+ * answer with Answer::get_as.
+ */
+pub trait FromTerm: Sized {
+    fn from_term(term: &Term) -> Result<Self, ExtractError>;
+}
+
+impl FromTerm for Term {
+    fn from_term(term: &Term) -> Result<Self, ExtractError> {
+        Ok(term.clone())
+    }
+}
+
+impl FromTerm for i64 {
+    fn from_term(term: &Term) -> Result<Self, ExtractError> {
+        match term {
+            Term::Simple(SimpleTerm::Number(value)) => Ok(*value),
+            _ => Err(mismatch("number", term)),
+        }
+    }
+}
+
+macro_rules! from_term_for_integers {
+    ($($target:ty),*) => {
+        $(
+            impl FromTerm for $target {
+                fn from_term(term: &Term) -> Result<Self, ExtractError> {
+                    let value = i64::from_term(term)?;
+                    <$target>::try_from(value)
+                        .map_err(|_| ExtractError::OutOfRange { value, target: stringify!($target) })
+                }
+            }
+        )*
+    };
+}
+
+from_term_for_integers!(i8, i16, i32, u8, u16, u32, u64, usize);
+
+/**
+ * Atoms convert to their name.
+ */
+impl FromTerm for String {
+    fn from_term(term: &Term) -> Result<Self, ExtractError> {
+        match term {
+            Term::Simple(SimpleTerm::Atom(name)) => Ok(name.clone()),
+            _ => Err(mismatch("atom", term)),
+        }
+    }
+}
+
+/**
+ * Lists of `cons` and `nil` convert element by element.
+ */
+impl<T: FromTerm> FromTerm for Vec<T> {
+    fn from_term(term: &Term) -> Result<Self, ExtractError> {
+        let mut elements = Vec::new();
+        let mut rest = term;
+        loop {
+            match rest {
+                Term::Simple(SimpleTerm::Atom(name)) if name == LIST_NIL => return Ok(elements),
+                Term::Compound(CompoundTerm { name: SimpleTerm::Atom(name), parameters })
+                    if name == LIST_CONS && parameters.len() == 2 =>
+                {
+                    let element = T::from_term(&parameters[0]).map_err(|error| ExtractError::Element {
+                        position: elements.len(),
+                        error: Box::new(error),
+                    })?;
+                    elements.push(element);
+                    rest = &parameters[1];
+                },
+                _ if elements.is_empty() => return Err(mismatch("list", term)),
+                _ => return Err(mismatch("list", rest)),
+            }
+        }
+    }
+}
+
+/**
+ * Types that can be converted to a term, for returning the results of
+ * foreign functions.
+ */
+pub trait IntoTerm {
+    fn into_term(self) -> Term;
+}
+
+impl IntoTerm for Term {
+    fn into_term(self) -> Term {
+        self
+    }
+}
+
+impl IntoTerm for i64 {
+    fn into_term(self) -> Term {
+        Term::number(self)
+    }
+}
+
+macro_rules! into_term_for_integers {
+    ($($source:ty),*) => {
+        $(
+            impl IntoTerm for $source {
+                fn into_term(self) -> Term {
+                    Term::number(i64::from(self))
+                }
+            }
+        )*
+    };
+}
+
+into_term_for_integers!(i8, i16, i32, u8, u16, u32);
+
+/**
+ * Strings convert to the atom they name.
+ */
+impl IntoTerm for String {
+    fn into_term(self) -> Term {
+        Term::Simple(SimpleTerm::Atom(self))
+    }
+}
+
+impl IntoTerm for &str {
+    fn into_term(self) -> Term {
+        Term::atom(self)
+    }
+}
+
+/**
+ * Vecs convert to `cons`/`nil` lists.
+ */
+impl<T: IntoTerm> IntoTerm for Vec<T> {
+    fn into_term(self) -> Term {
+        self.into_iter().rev().fold(Term::atom(LIST_NIL), |tail, head| {
+            Term::compound(LIST_CONS, vec![head.into_term(), tail])
+        })
+    }
+}
+
+/**
+ * Types that the arguments of a foreign function registered with the
+ * `foreign!` macro can have: anything that converts from a term, and `&str`
+ * for the name of an atom.
+ */
+pub trait ForeignArgument<'a>: Sized {
+    fn from_argument(term: &'a Term) -> Result<Self, ExtractError>;
+}
+
+impl<T: FromTerm> ForeignArgument<'_> for T {
+    fn from_argument(term: &Term) -> Result<Self, ExtractError> {
+        T::from_term(term)
+    }
+}
+
+impl<'a> ForeignArgument<'a> for &'a str {
+    fn from_argument(term: &'a Term) -> Result<Self, ExtractError> {
+        match term {
+            Term::Simple(SimpleTerm::Atom(name)) => Ok(name),
+            _ => Err(mismatch("atom", term)),
+        }
+    }
+}
+
+fn mismatch(expected: &'static str, found: &Term) -> ExtractError {
+    ExtractError::Mismatch { expected, found: found.clone() }
+}
+
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExtractError::UnknownVariable(name) => write!(f, "the query has no variable {}", name),
+            ExtractError::Mismatch { expected, found: Term::Simple(SimpleTerm::Variable(name)) } => {
+                write!(f, "expected {}, found unbound variable {}", expected, name)
+            },
+            ExtractError::Mismatch { expected, found } => write!(f, "expected {}, found {}", expected, found),
+            ExtractError::OutOfRange { value, target } => write!(f, "{} is out of range for {}", value, target),
+            ExtractError::Element { position, error } => write!(f, "list element {}: {}", position, error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::extract::*;
+    use crate::compiler::Compiler;
+    use crate::machine::Engine;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_from_term() {
+        let list = |elements: Vec<Term>| {
+            elements.into_iter().rev().fold(Term::atom(LIST_NIL), |tail, head| {
+                Term::compound(LIST_CONS, vec![head, tail])
+            })
+        };
+        assert_eq!(Ok(-3), i64::from_term(&Term::number(-3)));
+        assert_eq!(Ok(200u8), u8::from_term(&Term::number(200)));
+        assert_eq!(Err(ExtractError::OutOfRange { value: -1, target: "usize" }), usize::from_term(&Term::number(-1)));
+        assert_eq!(Ok(String::from("tom")), String::from_term(&Term::atom("tom")));
+        assert_eq!(
+            Err(ExtractError::Mismatch { expected: "atom", found: Term::number(1) }),
+            String::from_term(&Term::number(1))
+        );
+
+        let numbers = list(vec![Term::number(1), Term::number(2)]);
+        assert_eq!(Ok(vec![1, 2]), Vec::<i64>::from_term(&numbers));
+        assert_eq!(Ok(Vec::<Vec<i64>>::new()), Vec::from_term(&Term::atom(LIST_NIL)));
+        assert_eq!(Ok(vec![vec![1, 2]]), Vec::<Vec<i32>>::from_term(&list(vec![numbers])));
+
+        let error = Vec::<i64>::from_term(&list(vec![Term::number(1), Term::atom("b")])).unwrap_err();
+        assert_eq!("list element 1: expected number, found b", error.to_string());
+        // Lists that don't end in nil are reported at the tail that isn't one
+        let partial = Term::compound(LIST_CONS, vec![Term::atom("a"), Term::variable("T")]);
+        let error = Vec::<String>::from_term(&partial).unwrap_err();
+        assert_eq!("expected list, found unbound variable T", error.to_string());
+    }
+
+    #[test]
+    fn test_into_term() {
+        assert_eq!(Term::number(-3), (-3i64).into_term());
+        assert_eq!(Term::number(200), 200u8.into_term());
+        assert_eq!(Term::atom("tom"), "tom".into_term());
+        let list = Term::compound(LIST_CONS, vec![Term::number(1), Term::compound(LIST_CONS, vec![
+            Term::number(2),
+            Term::atom(LIST_NIL),
+        ])]);
+        assert_eq!(list, vec![1, 2].into_term());
+        assert_eq!(Ok(vec![1, 2]), Vec::<i64>::from_term(&vec![1i64, 2].into_term()));
+
+        assert_eq!(Ok("tom"), <&str>::from_argument(&Term::atom("tom")));
+        assert_eq!(Ok(7u16), u16::from_argument(&Term::number(7)));
+    }
+
+    #[test]
+    fn test_answer_getters() {
+        let code = "p 42 tom (cons 1 (cons 2 nil)) \n ? p N A L";
+        let program = parse(code).unwrap();
+        let mut compiler = Compiler::new();
+        compiler.compile(program.clone());
+        let mut engine = Engine::new(compiler);
+        let answer = &engine.solve_all(&program.queries[0])[0];
+
+        assert_eq!(Ok(42), answer.get_i64("N"));
+        assert_eq!(Ok("tom"), answer.get_atom("A"));
+        assert_eq!(Ok(vec![1u32, 2]), answer.get_list_of::<u32>("L"));
+        assert_eq!(Ok(42u16), answer.get_as("N"));
+        assert_eq!(Err(ExtractError::UnknownVariable(String::from("X"))), answer.get_i64("X"));
+        assert_eq!("expected number, found tom", answer.get_i64("A").unwrap_err().to_string());
+    }
+}
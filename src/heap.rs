@@ -1,21 +1,48 @@
+use crate::compiler::{ClauseDescriptor, Spine};
+
 /**
  * "Heap" memory area to use for storing the compiled representation of a
  * program.
  *
  * Based on heap representation used in https://github.com/ptarau/iProlog
  */
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Default, PartialEq)]
 pub struct Heap {
     buffer: Vec<HeapEntry>,
+    growth: GrowthPolicy,
+}
+
+/**
+ * How much space the heap reserves up front and how it grows when full.
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct HeapConfig {
+    // Number of entries to reserve when the heap is created
+    pub initial_cells: HeapIndex,
+    pub growth: GrowthPolicy,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum GrowthPolicy {
+    // Double the capacity whenever the heap is full
+    #[default]
+    Doubling,
+
+    // Grow the capacity in multiples of the given number of entries
+    Chunked(HeapIndex),
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct HeapEntry {
     pub tag: HeapTag,
     pub data: HeapIndex,
+    // Upper 32 bits of the value of a Number entry, whose data holds the
+    // lower 32 bits, so that numbers are 64-bit even where HeapIndex is
+    // narrower. 0 for other entries.
+    high: i32,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum HeapTag {
     // First occurence of variable in a clause
     Variable,
@@ -43,7 +70,14 @@ pub type HeapIndex = usize;
 
 impl Heap {
     pub fn new() -> Self {
-        Heap { buffer: Vec::new() }
+        Heap { buffer: Vec::new(), growth: GrowthPolicy::Doubling }
+    }
+
+    pub fn with_config(config: HeapConfig) -> Self {
+        Heap {
+            buffer: Vec::with_capacity(config.initial_cells),
+            growth: config.growth,
+        }
     }
 
     /**
@@ -52,14 +86,46 @@ impl Heap {
      */
     pub fn alloc(&mut self, size: HeapIndex) -> HeapIndex{
         let index = self.buffer.len();
+        if index + size > self.buffer.capacity() {
+            self.grow(index + size);
+        }
         self.buffer.resize(index + size, HeapEntry::empty());
         index
     }
 
+    /**
+     * Reserves space for at least `needed` entries according to the growth
+     * policy.
+     */
+    fn grow(&mut self, needed: HeapIndex) {
+        let capacity = match self.growth {
+            GrowthPolicy::Doubling => needed.max(self.buffer.capacity() * 2),
+            GrowthPolicy::Chunked(chunk) => {
+                let chunk = chunk.max(1);
+                needed.div_ceil(chunk) * chunk
+            },
+        };
+        self.buffer.reserve_exact(capacity - self.buffer.len());
+    }
+
+    /**
+     * Number of entries the heap can hold before it has to grow.
+     */
+    pub fn capacity(&self) -> HeapIndex {
+        self.buffer.capacity()
+    }
+
     pub fn write(&mut self, index: HeapIndex, entry: HeapEntry) {
         self.buffer[index] = entry;
     }
 
+    /**
+     * Discards all entries from index onwards.
+     */
+    pub fn truncate(&mut self, index: HeapIndex) {
+        self.buffer.truncate(index);
+    }
+
     pub fn read(&self, index: HeapIndex) -> HeapEntry {
         self.buffer[index]
     }
@@ -67,15 +133,115 @@ impl Heap {
     pub fn len(&self) -> HeapIndex {
         self.buffer.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /**
+     * Checks that the heap is consistent with the clauses, spines and trail
+     * that use it: References point to Arity cells, each Arity cell's slice
+     * fits in the heap without containing another Arity cell, variables
+     * point inside the heap, no Uninitialized cell can be reached from a
+     * clause or goal, and trailed cells can hold a variable's value. Returns
+     * the first inconsistency found. This walks the whole heap, so it is only
+     * meant for catching engine bugs.
+     */
+    pub(crate) fn validate<'a>(
+        &self,
+        clauses: &[ClauseDescriptor],
+        spines: impl IntoIterator<Item = &'a Spine>,
+        trail: &[HeapIndex],
+    ) -> Result<(), String> {
+        let len = self.len();
+        // End of the last slice seen
+        let mut slice_end = 0;
+        for (index, cell) in self.buffer.iter().enumerate() {
+            match cell.tag {
+                HeapTag::Arity if index < slice_end => {
+                    return Err(format!("Arity cell {} is inside the slice ending at {}", index, slice_end));
+                },
+                HeapTag::Arity if cell.data == 0 || index + cell.data >= len => {
+                    return Err(format!("slice of Arity cell {} doesn't fit in the heap", index));
+                },
+                HeapTag::Arity => slice_end = index + cell.data + 1,
+                HeapTag::Reference if cell.data >= len || self.buffer[cell.data].tag != HeapTag::Arity => {
+                    return Err(format!("Reference cell {} doesn't point to an Arity cell", index));
+                },
+                HeapTag::Variable | HeapTag::Unify if cell.data >= len => {
+                    return Err(format!("variable cell {} points outside the heap", index));
+                },
+                _ => (),
+            }
+        }
+
+        let mut reached = vec![false; len];
+        // Asserted clauses are kept off the heap
+        let mut pending: Vec<HeapEntry> = clauses
+            .iter()
+            .filter(|clause| clause.asserted.is_none())
+            .flat_map(|clause| clause.terms.iter().copied())
+            .collect();
+        for spine in spines {
+            if let Some(clause) = spine.unifiable_clauses.iter().find(|clause| **clause >= clauses.len()) {
+                return Err(format!("a spine refers to clause {}, which doesn't exist", clause));
+            }
+            pending.extend(&spine.goals);
+        }
+        while let Some(entry) = pending.pop() {
+            let cells = match entry.tag {
+                HeapTag::Reference => entry.data..=entry.data + self.buffer[entry.data].data,
+                HeapTag::Variable | HeapTag::Unify => entry.data..=entry.data,
+                _ => continue,
+            };
+            for index in cells {
+                if reached[index] {
+                    continue;
+                }
+                reached[index] = true;
+                match self.buffer[index] {
+                    HeapEntry { tag: HeapTag::Uninitialized, .. } => {
+                        return Err(format!("Uninitialized cell {} can be reached", index));
+                    },
+                    cell if cell.tag != HeapTag::Arity => pending.push(cell),
+                    _ => (),
+                }
+            }
+        }
+
+        for index in trail {
+            match self.buffer.get(*index) {
+                Some(cell) if !matches!(cell.tag, HeapTag::Arity | HeapTag::Uninitialized) => (),
+                _ => return Err(format!("trail entry {} is not a variable cell", index)),
+            }
+        }
+        Ok(())
+    }
 }
 
 impl HeapEntry {
     fn empty() -> Self {
-        HeapEntry { tag: HeapTag::Uninitialized, data: 0 }
+        HeapEntry::new(HeapTag::Uninitialized, 0)
     }
 
     pub fn new(tag: HeapTag, data: HeapIndex) -> Self {
-        HeapEntry { tag, data }
+        HeapEntry { tag, data, high: 0 }
+    }
+
+    /**
+     * Number cell holding the value. It is split between the data and the
+     * upper half of the cell so that every value survives the round trip
+     * through number_value, even on 32-bit targets.
+     */
+    pub fn number(value: i64) -> Self {
+        HeapEntry { tag: HeapTag::Number, data: value as u32 as HeapIndex, high: (value >> 32) as i32 }
+    }
+
+    /**
+     * Value of a Number cell.
+     */
+    pub fn number_value(&self) -> i64 {
+        ((self.high as i64) << 32) | self.data as u32 as i64
     }
 
     pub fn is_var_or_unify(&self) -> bool {
@@ -87,6 +253,13 @@ impl HeapEntry {
 mod tests {
     use crate::heap::*;
 
+    #[test]
+    fn test_number_round_trip() {
+        for value in [0, 1, -1, i32::MAX as i64 + 1, i32::MIN as i64 - 1, i64::MAX, i64::MIN] {
+            assert_eq!(value, HeapEntry::number(value).number_value());
+        }
+    }
+
     #[test]
     fn test_alloc() {
         let mut heap = Heap::new();
@@ -133,4 +306,60 @@ mod tests {
             heap.read(index)
         );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_validate() {
+        let mut compiler = crate::compiler::Compiler::new();
+        compiler.consult_str("p X (f X) a \n ? p a Y Z").unwrap();
+        let heap = &compiler.heap;
+        assert_eq!(Ok(()), heap.validate(&compiler.clauses, &compiler.spines, &[]));
+        assert!(heap.validate(&compiler.clauses, &compiler.spines, &[0]).is_err());
+
+        let mut broken = Heap { buffer: heap.buffer.clone(), growth: GrowthPolicy::Doubling };
+        let reference = broken.buffer.iter().position(|cell| cell.tag == HeapTag::Reference).unwrap();
+        broken.write(reference, HeapEntry::new(HeapTag::Reference, reference));
+        assert!(broken.validate(&compiler.clauses, &compiler.spines, &[]).is_err());
+
+        let mut broken = Heap { buffer: heap.buffer.clone(), growth: GrowthPolicy::Doubling };
+        broken.write(1, HeapEntry::empty());
+        assert_eq!(
+            Err(String::from("Uninitialized cell 1 can be reached")),
+            broken.validate(&compiler.clauses, &compiler.spines, &[])
+        );
+    }
+
+    #[test]
+    fn test_initial_capacity() {
+        let heap = Heap::with_config(HeapConfig {
+            initial_cells: 64,
+            growth: GrowthPolicy::Doubling,
+        });
+        assert!(heap.capacity() >= 64);
+        assert_eq!(0, heap.len());
+    }
+
+    #[test]
+    fn test_doubling_growth() {
+        let mut heap = Heap::with_config(HeapConfig {
+            initial_cells: 16,
+            growth: GrowthPolicy::Doubling,
+        });
+        let capacity = heap.capacity();
+        heap.alloc(capacity + 1);
+        assert!(heap.capacity() >= 2 * capacity);
+    }
+
+    #[test]
+    fn test_chunked_growth() {
+        let mut heap = Heap::with_config(HeapConfig {
+            initial_cells: 0,
+            growth: GrowthPolicy::Chunked(100),
+        });
+        heap.alloc(150);
+        assert!(heap.capacity() >= 200 && heap.capacity() < 300);
+        heap.alloc(50);
+        assert!(heap.capacity() >= 200 && heap.capacity() < 300);
+        heap.alloc(1);
+        assert!(heap.capacity() >= 300 && heap.capacity() < 400);
+    }
+}
@@ -0,0 +1,767 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::*;
+use crate::export::{Dialect, ARITHMETIC_OPERATORS};
+use crate::extract::{LIST_CONS, LIST_NIL};
+use crate::parser::{is_atom, letters};
+
+/**
+ * Characters that standard Prolog builds symbolic atoms such as `:-` or `=..`
+ * from.
+ */
+const SYMBOL_CHARS: &str = "+-*/\\^<>=~:.?@#&$";
+
+/**
+ * How the standard reader spells the empty list. List cells are read straight
+ * into LIST_CONS terms, e.g. `[a]` is read as `cons(a, [])`.
+ */
+const NIL: &str = "[]";
+
+/**
+ * Translates a program in the source syntax of another Prolog dialect into a
+ * butterfly program, the reverse of export::export.
+ *
+ * Only the part of the language that butterfly has an equivalent for is
+ * accepted: facts, rules and `?-` queries made of conjunctions, cut, negation,
+ * if-then-else, unification, comparison, univ, `is` and call/N. Lists become
+ * `cons`/`nil` terms and the standard arithmetic operators become their
+ * butterfly functions, e.g. `X is Y + 1` becomes `X is plus Y 1`. Anything
+ * else, e.g. a plain disjunction, a directive or an atom like `foo_bar` that
+ * butterfly can't spell, is reported as an error with its line number.
+ * Variables that butterfly can't spell, e.g. `X1`, and anonymous variables are
+ * renamed.
+ */
+pub fn import(code: &str, dialect: Dialect) -> Result<Program, String> {
+    match dialect {
+        Dialect::Swi => {
+            let tokens = tokenize(code)?;
+            let mut reader = Reader { tokens, position: 0 };
+            let mut program = Program::new();
+            while reader.peek().is_some() {
+                let line = reader.line();
+                let term = reader.read_clause()?;
+                Translator::new(&term)
+                    .translate(term, &mut program)
+                    .map_err(|message| format!("line {}: {}", line, message))?;
+            }
+            Ok(program)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Name(String),
+    Variable(String),
+    Number(i128),
+    Open,
+    Close,
+    OpenList,
+    CloseList,
+    Comma,
+    Bar,
+    End,
+}
+
+#[derive(Clone, Debug)]
+struct Spanned {
+    token: Token,
+    line: usize,
+    // Whether whitespace or a comment comes right before the token, which
+    // tells `f(a)` (a compound term) from `f (a)` (an operator application)
+    layout_before: bool,
+}
+
+fn tokenize(code: &str) -> Result<Vec<Spanned>, String> {
+    let chars: Vec<char> = code.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    let mut line = 1;
+    let mut layout_before = true;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '\n' {
+            line += 1;
+            i += 1;
+            layout_before = true;
+            continue;
+        }
+        if c.is_whitespace() {
+            i += 1;
+            layout_before = true;
+            continue;
+        }
+        if c == '%' {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            layout_before = true;
+            continue;
+        }
+        if c == '/' && chars.get(i + 1) == Some(&'*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                if chars[i] == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+            if i >= chars.len() {
+                return Err(format!("line {}: unterminated block comment", line));
+            }
+            i += 2;
+            layout_before = true;
+            continue;
+        }
+
+        let start = i;
+        let token = match c {
+            '(' => { i += 1; Token::Open },
+            ')' => { i += 1; Token::Close },
+            '[' => { i += 1; Token::OpenList },
+            ']' => { i += 1; Token::CloseList },
+            ',' => { i += 1; Token::Comma },
+            '|' => { i += 1; Token::Bar },
+            '!' | ';' => { i += 1; Token::Name(c.to_string()) },
+            '\'' => {
+                let (name, end) = read_quoted(&chars, i + 1)
+                    .ok_or(format!("line {}: unterminated quoted atom", line))?;
+                i = end;
+                Token::Name(name)
+            },
+            '"' | '`' => return Err(format!("line {}: strings are not supported", line)),
+            '.' if chars.get(i + 1).is_none_or(|next| next.is_whitespace() || *next == '%') => {
+                i += 1;
+                Token::End
+            },
+            _ if c.is_ascii_digit() => {
+                let (value, end) = read_number(&chars, i)
+                    .ok_or(format!("line {}: unsupported number literal", line))?;
+                i = end;
+                Token::Number(value)
+            },
+            _ if c.is_alphabetic() || c == '_' => {
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let name: String = chars[start..i].iter().collect();
+                if c.is_lowercase() {
+                    Token::Name(name)
+                } else {
+                    Token::Variable(name)
+                }
+            },
+            _ if SYMBOL_CHARS.contains(c) => {
+                while i < chars.len() && SYMBOL_CHARS.contains(chars[i]) {
+                    i += 1;
+                }
+                Token::Name(chars[start..i].iter().collect())
+            },
+            _ => return Err(format!("line {}: unexpected character `{}`", line, c)),
+        };
+        tokens.push(Spanned { token, line, layout_before });
+        layout_before = false;
+    }
+    Ok(tokens)
+}
+
+/**
+ * Reads the rest of a quoted atom whose opening quote is right before start,
+ * returning the atom and the position after its closing quote.
+ */
+fn read_quoted(chars: &[char], start: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut i = start;
+    loop {
+        match *chars.get(i)? {
+            '\'' if chars.get(i + 1) == Some(&'\'') => {
+                name.push('\'');
+                i += 2;
+            },
+            '\'' => return Some((name, i + 1)),
+            '\\' => {
+                name.push(escape(*chars.get(i + 1)?)?);
+                i += 2;
+            },
+            c => {
+                name.push(c);
+                i += 1;
+            },
+        }
+    }
+}
+
+fn escape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        '\\' | '\'' | '"' | '`' => Some(c),
+        _ => None,
+    }
+}
+
+/**
+ * Reads an integer starting at start, returning it and the position after
+ * it. Floats and digit groups aren't supported.
+ */
+fn read_number(chars: &[char], start: usize) -> Option<(i128, usize)> {
+    let radix = match (chars[start], chars.get(start + 1)) {
+        ('0', Some('\'')) => {
+            return match (chars.get(start + 2)?, chars.get(start + 3)) {
+                ('\\', Some(c)) => Some((escape(*c)? as i128, start + 4)),
+                ('\'', Some('\'')) => Some(('\'' as i128, start + 4)),
+                (c, _) => Some((*c as i128, start + 3)),
+            };
+        },
+        ('0', Some('x')) => 16,
+        ('0', Some('o')) => 8,
+        ('0', Some('b')) => 2,
+        _ => 10,
+    };
+    let digits_start = if radix == 10 { start } else { start + 2 };
+    let mut i = digits_start;
+    while i < chars.len() && chars[i].is_digit(radix) {
+        i += 1;
+    }
+    if i == digits_start || chars.get(i).is_some_and(|c| c.is_alphanumeric() || *c == '_') {
+        return None;
+    }
+    // A float, e.g. 1.5
+    if chars.get(i) == Some(&'.') && chars.get(i + 1).is_some_and(char::is_ascii_digit) {
+        return None;
+    }
+    let digits: String = chars[digits_start..i].iter().collect();
+    let value = i128::from_str_radix(&digits, radix).ok()?;
+    Some((value, i))
+}
+
+/**
+ * A term as the standard reader sees it, before it is translated into the
+ * butterfly AST.
+ */
+#[derive(Clone, Debug, PartialEq)]
+enum Read {
+    Atom(String),
+    Variable(String),
+    Number(i128),
+    Compound(String, Vec<Read>),
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Associativity {
+    Xfx,
+    Xfy,
+    Yfx,
+}
+
+fn infix_operator(name: &str) -> Option<(u32, Associativity)> {
+    use Associativity::*;
+    match name {
+        ":-" | "-->" => Some((1200, Xfx)),
+        ";" | "|" => Some((1100, Xfy)),
+        "->" | "*->" => Some((1050, Xfy)),
+        "," => Some((1000, Xfy)),
+        "=" | "\\=" | "==" | "\\==" | "@<" | "@>" | "@=<" | "@>=" | "=.." | "?=" | "is"
+            | "=:=" | "=\\=" | "<" | ">" | "=<" | ">=" => Some((700, Xfx)),
+        "+" | "-" | "/\\" | "\\/" | "xor" => Some((500, Yfx)),
+        "*" | "/" | "//" | "rem" | "mod" | "div" | "<<" | ">>" => Some((400, Yfx)),
+        "**" => Some((200, Xfx)),
+        "^" => Some((200, Xfy)),
+        ":" => Some((200, Xfy)),
+        _ => None,
+    }
+}
+
+fn prefix_operator(name: &str) -> Option<(u32, bool)> {
+    // The flag is whether the operator is fy, i.e. its argument may have the
+    // same priority as the operator itself
+    match name {
+        ":-" | "?-" => Some((1200, false)),
+        "dynamic" | "discontiguous" | "initialization" | "multifile" | "table" => Some((1150, false)),
+        "\\+" => Some((900, true)),
+        "-" | "+" | "\\" => Some((200, true)),
+        _ => None,
+    }
+}
+
+struct Reader {
+    tokens: Vec<Spanned>,
+    position: usize,
+}
+
+impl Reader {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|spanned| &spanned.token)
+    }
+
+    fn peek_spanned(&self) -> Option<&Spanned> {
+        self.tokens.get(self.position)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .or(self.tokens.last())
+            .map_or(1, |spanned| spanned.line)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self.peek().cloned().ok_or(format!("line {}: unexpected end of input", self.line()))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: Token, description: &str) -> Result<(), String> {
+        let line = self.line();
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(format!("line {}: expected {}, found {}", line, description, describe(&token))),
+        }
+    }
+
+    fn read_clause(&mut self) -> Result<Read, String> {
+        let term = self.read(1200)?;
+        self.expect(Token::End, "`.`")?;
+        Ok(term)
+    }
+
+    /**
+     * Reads a term whose priority is at most max_priority, by precedence
+     * climbing over the standard operator table.
+     */
+    fn read(&mut self, max_priority: u32) -> Result<Read, String> {
+        let (mut left, mut left_priority) = self.read_primary(max_priority)?;
+        loop {
+            let name = match self.peek() {
+                Some(Token::Name(name)) => name.clone(),
+                Some(Token::Comma) => ",".to_string(),
+                Some(Token::Bar) => "|".to_string(),
+                _ => break,
+            };
+            let Some((priority, associativity)) = infix_operator(&name) else { break };
+            let (left_max, right_max) = match associativity {
+                Associativity::Xfx => (priority - 1, priority - 1),
+                Associativity::Xfy => (priority - 1, priority),
+                Associativity::Yfx => (priority, priority - 1),
+            };
+            if priority > max_priority || left_priority > left_max {
+                break;
+            }
+            self.position += 1;
+            let right = self.read(right_max)?;
+            // `a | b` in a clause body is an old spelling of `a ; b`
+            let name = if name == "|" { ";".to_string() } else { name };
+            left = Read::Compound(name, vec![left, right]);
+            left_priority = priority;
+        }
+        Ok(left)
+    }
+
+    fn read_primary(&mut self, max_priority: u32) -> Result<(Read, u32), String> {
+        let line = self.line();
+        match self.next()? {
+            Token::Number(value) => Ok((Read::Number(value), 0)),
+            Token::Variable(name) => Ok((Read::Variable(name), 0)),
+            Token::Open => {
+                let term = self.read(1200)?;
+                self.expect(Token::Close, "`)`")?;
+                Ok((term, 0))
+            },
+            Token::OpenList => {
+                if self.peek() == Some(&Token::CloseList) {
+                    self.position += 1;
+                    return Ok((Read::Atom(NIL.to_string()), 0));
+                }
+                let mut elements = vec![self.read(999)?];
+                while self.peek() == Some(&Token::Comma) {
+                    self.position += 1;
+                    elements.push(self.read(999)?);
+                }
+                let mut list = Read::Atom(NIL.to_string());
+                if self.peek() == Some(&Token::Bar) {
+                    self.position += 1;
+                    list = self.read(999)?;
+                }
+                self.expect(Token::CloseList, "`]`")?;
+                for element in elements.into_iter().rev() {
+                    list = Read::Compound(LIST_CONS.to_string(), vec![element, list]);
+                }
+                Ok((list, 0))
+            },
+            Token::Name(name) => self.read_name(name, max_priority),
+            token => Err(format!("line {}: unexpected {}", line, describe(&token))),
+        }
+    }
+
+    fn read_name(&mut self, name: String, max_priority: u32) -> Result<(Read, u32), String> {
+        let next = self.peek_spanned().cloned();
+        match next {
+            // A compound term in functional notation, e.g. f(a, b)
+            Some(Spanned { token: Token::Open, layout_before: false, .. }) => {
+                self.position += 1;
+                let mut arguments = vec![self.read(999)?];
+                while self.peek() == Some(&Token::Comma) {
+                    self.position += 1;
+                    arguments.push(self.read(999)?);
+                }
+                self.expect(Token::Close, "`)`")?;
+                Ok((Read::Compound(name, arguments), 0))
+            },
+            // A negative number, e.g. -1
+            Some(Spanned { token: Token::Number(value), layout_before: false, .. }) if name == "-" => {
+                self.position += 1;
+                Ok((Read::Number(-value), 0))
+            },
+            Some(Spanned { token, .. }) if starts_term(&token) => {
+                match prefix_operator(&name) {
+                    Some((priority, fy)) if priority <= max_priority => {
+                        let argument = self.read(if fy { priority } else { priority - 1 })?;
+                        Ok((Read::Compound(name, vec![argument]), priority))
+                    },
+                    _ => Ok((Read::Atom(name), 0)),
+                }
+            },
+            _ => Ok((Read::Atom(name), 0)),
+        }
+    }
+}
+
+/**
+ * Whether a term can start with the token, so that a prefix operator right
+ * before it is applied to it instead of being read as an atom.
+ */
+fn starts_term(token: &Token) -> bool {
+    match token {
+        Token::Name(name) => infix_operator(name).is_none() || prefix_operator(name).is_some(),
+        Token::Variable(_) | Token::Number(_) | Token::Open | Token::OpenList => true,
+        Token::Close | Token::CloseList | Token::Comma | Token::Bar | Token::End => false,
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Name(name) => format!("`{}`", name),
+        Token::Variable(name) => format!("`{}`", name),
+        Token::Number(value) => format!("`{}`", value),
+        Token::Open => "`(`".to_string(),
+        Token::Close => "`)`".to_string(),
+        Token::OpenList => "`[`".to_string(),
+        Token::CloseList => "`]`".to_string(),
+        Token::Comma => "`,`".to_string(),
+        Token::Bar => "`|`".to_string(),
+        Token::End => "`.`".to_string(),
+    }
+}
+
+/**
+ * Translates one clause or query from its read form into the butterfly AST,
+ * renaming its variables where butterfly can't spell them.
+ */
+struct Translator {
+    // Original variable name to butterfly variable name
+    renamed: HashMap<String, String>,
+    // Variable names taken in the clause, so that renaming doesn't merge two
+    // variables
+    taken: HashSet<String>,
+}
+
+impl Translator {
+    fn new(term: &Read) -> Self {
+        fn collect(term: &Read, taken: &mut HashSet<String>) {
+            match term {
+                Read::Variable(name) if is_variable(name) && name != "_" => {
+                    taken.insert(name.clone());
+                },
+                Read::Compound(_, arguments) => {
+                    for argument in arguments {
+                        collect(argument, taken);
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        let mut taken = HashSet::new();
+        collect(term, &mut taken);
+        Translator { renamed: HashMap::new(), taken }
+    }
+
+    fn translate(&mut self, term: Read, program: &mut Program) -> Result<(), String> {
+        match term {
+            Read::Compound(name, mut arguments) if name == "?-" && arguments.len() == 1 => {
+                let sub_queries = self.goals(arguments.remove(0))?;
+                program.push_query(Query { sub_queries });
+            },
+            Read::Compound(name, arguments) if name == ":-" && arguments.len() == 1 => {
+                return Err(format!("directive `:- {}` has no butterfly equivalent", display(&arguments[0])));
+            },
+            Read::Compound(name, mut arguments) if name == ":-" && arguments.len() == 2 => {
+                let body = arguments.pop().unwrap();
+                let head = self.head(arguments.pop().unwrap())?;
+                let body = self.goals(body)?;
+                program.push_clause(Clause::rule(head, body));
+            },
+            Read::Compound(name, _) if name == "-->" => {
+                return Err("DCG rules are not supported".to_string());
+            },
+            head => {
+                let head = self.head(head)?;
+                program.push_clause(Clause::fact(head));
+            },
+        }
+        Ok(())
+    }
+
+    fn head(&mut self, term: Read) -> Result<Term, String> {
+        match term {
+            Read::Atom(_) | Read::Compound(_, _) => self.term(term),
+            _ => Err(format!("`{}` can't be the head of a clause", display(&term))),
+        }
+    }
+
+    /**
+     * Translates a conjunction into its goals.
+     */
+    fn goals(&mut self, term: Read) -> Result<Vec<Term>, String> {
+        let mut goals = Vec::new();
+        let mut rest = term;
+        loop {
+            match rest {
+                Read::Compound(name, mut arguments) if name == "," && arguments.len() == 2 => {
+                    rest = arguments.pop().unwrap();
+                    goals.extend(self.goals(arguments.pop().unwrap())?);
+                },
+                goal => {
+                    goals.push(self.goal(goal)?);
+                    return Ok(goals);
+                },
+            }
+        }
+    }
+
+    fn goal(&mut self, term: Read) -> Result<Term, String> {
+        let Read::Compound(name, mut arguments) = term else {
+            return self.term(term);
+        };
+        match (name.as_str(), arguments.len()) {
+            (";", 2) => {
+                let otherwise = arguments.pop().unwrap();
+                match arguments.pop().unwrap() {
+                    Read::Compound(name, mut branches) if name == "->" && branches.len() == 2 => {
+                        let then = self.goals(branches.pop().unwrap())?;
+                        let condition = self.goals(branches.pop().unwrap())?;
+                        let otherwise = self.goals(otherwise)?;
+                        Ok(Term::if_then_else(condition, then, Some(otherwise)))
+                    },
+                    _ => Err("disjunction `;` without `->` has no butterfly equivalent".to_string()),
+                }
+            },
+            ("->", 2) => {
+                let then = self.goals(arguments.pop().unwrap())?;
+                let condition = self.goals(arguments.pop().unwrap())?;
+                Ok(Term::if_then_else(condition, then, None))
+            },
+            ("\\+" | NOT, 1) => {
+                let goal = arguments.pop().unwrap();
+                if matches!(&goal, Read::Compound(name, _) if name == ",") {
+                    return Err("negation of a conjunction has no butterfly equivalent".to_string());
+                }
+                Ok(Term::negation(self.goal(goal)?))
+            },
+            (IS, 2) => {
+                let expression = self.expression(arguments.pop().unwrap())?;
+                let result = self.term(arguments.pop().unwrap())?;
+                Ok(Term::evaluation(result, expression))
+            },
+            ("call", 1) => self.goal(arguments.pop().unwrap()),
+            ("call", _) => {
+                let extra = arguments.split_off(1);
+                match arguments.pop().unwrap() {
+                    Read::Variable(variable) => {
+                        let name = SimpleTerm::Variable(self.variable(&variable));
+                        let parameters = extra
+                            .into_iter()
+                            .map(|argument| self.term(argument))
+                            .collect::<Result<_, _>>()?;
+                        Ok(Term::Compound(CompoundTerm { name, parameters }))
+                    },
+                    Read::Atom(name) => self.goal(Read::Compound(name, extra)),
+                    Read::Compound(name, mut parameters) => {
+                        parameters.extend(extra);
+                        self.goal(Read::Compound(name, parameters))
+                    },
+                    goal => Err(format!("`{}` is not callable", display(&goal))),
+                }
+            },
+            _ => self.term(Read::Compound(name, arguments)),
+        }
+    }
+
+    /**
+     * Translates an arithmetic expression, replacing the standard operators
+     * with the butterfly functions of the same meaning.
+     */
+    fn expression(&mut self, term: Read) -> Result<Term, String> {
+        let Read::Compound(name, arguments) = term else {
+            return self.term(term);
+        };
+        if name == "+" && arguments.len() == 1 {
+            return self.expression(arguments.into_iter().next().unwrap());
+        }
+        let function = ARITHMETIC_OPERATORS
+            .iter()
+            .find(|(_, arity, operator)| *operator == name && *arity == arguments.len())
+            .map_or(name.as_str(), |(function, _, _)| *function);
+        if !is_atom(function) {
+            return Err(format!("arithmetic function `{}/{}` has no butterfly equivalent", name, arguments.len()));
+        }
+        let parameters = arguments
+            .into_iter()
+            .map(|argument| self.expression(argument))
+            .collect::<Result<_, _>>()?;
+        Ok(Term::compound(function, parameters))
+    }
+
+    fn term(&mut self, term: Read) -> Result<Term, String> {
+        match term {
+            Read::Atom(name) if name == NIL => Ok(Term::atom(LIST_NIL)),
+            Read::Atom(name) if is_atom(&name) || name == CUT => Ok(Term::Simple(SimpleTerm::Atom(name))),
+            Read::Variable(name) => Ok(Term::Simple(SimpleTerm::Variable(self.variable(&name)))),
+            Read::Number(value) => i64::try_from(value)
+                .map(Term::number)
+                .map_err(|_| format!("number `{}` doesn't fit in 64 bits", value)),
+            Read::Compound(name, arguments) if is_atom(&name) || is_operator(&name) => {
+                let parameters = arguments
+                    .into_iter()
+                    .map(|argument| self.term(argument))
+                    .collect::<Result<_, _>>()?;
+                Ok(Term::Compound(CompoundTerm { name: SimpleTerm::Atom(name), parameters }))
+            },
+            Read::Atom(name) | Read::Compound(name, _) => {
+                Err(format!("atom `{}` can't be written in butterfly syntax", name))
+            },
+        }
+    }
+
+    fn variable(&mut self, name: &str) -> String {
+        if is_variable(name) && name != "_" {
+            return name.to_string();
+        }
+        if name != "_" {
+            if let Some(renamed) = self.renamed.get(name) {
+                return renamed.clone();
+            }
+        }
+        // Keep the letters, e.g. X1 becomes X, and add a suffix if that name
+        // is taken
+        let base: String = name.chars().filter(|c| c.is_ascii_alphabetic() || *c == '_').collect();
+        let base = if base.starts_with(|c: char| c == '_' || c.is_ascii_uppercase()) { base } else { format!("V{}", base) };
+        let renamed = (0..)
+            .map(|n| if n == 0 { base.clone() } else { format!("{}{}", base, letters(n)) })
+            .find(|candidate| candidate != "_" && !self.taken.contains(candidate))
+            .unwrap();
+        self.taken.insert(renamed.clone());
+        if name != "_" {
+            self.renamed.insert(name.to_string(), renamed.clone());
+        }
+        renamed
+    }
+}
+
+fn is_variable(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c == '_' || c.is_ascii_uppercase())
+        && chars.all(|c| c.is_ascii_alphabetic())
+}
+
+/**
+ * Operators that butterfly has its own syntax for, e.g. `X = a`.
+ */
+fn is_operator(name: &str) -> bool {
+    [UNIFY, NOT_UNIFY, EQUAL, NOT_EQUAL, UNIV, DECIDED].contains(&name)
+}
+
+/**
+ * Writes a read term back out in standard syntax, for error messages.
+ */
+fn display(term: &Read) -> String {
+    match term {
+        Read::Atom(name) | Read::Variable(name) => name.clone(),
+        Read::Number(value) => value.to_string(),
+        Read::Compound(name, arguments) => {
+            let arguments: Vec<String> = arguments.iter().map(display).collect();
+            format!("{}({})", name, arguments.join(", "))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::import::*;
+    use crate::export::export;
+    use crate::parser::parse;
+
+    fn import_swi(code: &str) -> Result<Program, String> {
+        import(code, Dialect::Swi)
+    }
+
+    #[test]
+    fn test_import_facts_and_rules() {
+        let program = import_swi("% family\nparent(tom, bob).\nancestor(X, Y) :-\n    parent(X, Z), /* step */ ancestor(Z, Y).\n").unwrap();
+        assert_eq!(parse("parent tom bob \n ancestor X Y if parent X Z and ancestor Z Y").unwrap(), program);
+    }
+
+    #[test]
+    fn test_import_query() {
+        let program = import_swi("?- parent(tom, X), call(ancestor, X, Y), X \\= Y, !.\np :- q, \\+ r(a), ( s == t -> t ; u ), !.\n").unwrap();
+        assert_eq!(parse("p if q and not r a and (if s == t then t else u) and ! \n ? (parent tom X) (ancestor X Y) (X \\= Y) !").unwrap(), program);
+    }
+
+    #[test]
+    fn test_import_lists_and_arithmetic() {
+        let program = import_swi("len([], 0).\nlen([_|T], N) :- len(T, M), N is M + 1 * -2.\np([a, b|T], T).\n").unwrap();
+        let expected = parse("len nil 0 \n len (cons _A T) N if len T M and N is plus M (times 1 -2) \n p (cons a (cons b T)) T").unwrap();
+        assert_eq!(expected, program);
+
+        let program = import_swi("u(X, Y) :- Y is ((X << 2) /\\ 3) xor ((X >> 1) \\/ gcd(X, msb(X))).\n").unwrap();
+        let expected = parse("u X Y if Y is xor (bitAnd (shiftLeft X 2) 3) (bitOr (shiftRight X 1) (gcd X (msb X)))").unwrap();
+        assert_eq!(expected, program);
+    }
+
+    #[test]
+    fn test_import_renames_variables() {
+        let program = import_swi("p(X1, X2, X, _, _) :- call(X1, X2, a).\n").unwrap();
+        let expected = parse("p XA XB X _A _B if XA XB a").unwrap();
+        assert_eq!(expected, program);
+    }
+
+    #[test]
+    fn test_import_errors() {
+        assert_eq!(Err("line 2: disjunction `;` without `->` has no butterfly equivalent".to_string()),
+                   import_swi("a.\np :- a ; b.\n"));
+        assert_eq!(Err("line 1: atom `foo_bar` can't be written in butterfly syntax".to_string()),
+                   import_swi("foo_bar.\n"));
+        assert_eq!(Err("line 1: directive `:- dynamic(p)` has no butterfly equivalent".to_string()),
+                   import_swi(":- dynamic p.\n"));
+        assert_eq!(Err("line 1: number `99999999999999999999` doesn't fit in 64 bits".to_string()),
+                   import_swi("p(99999999999999999999).\n"));
+        assert_eq!(Err("line 1: negation of a conjunction has no butterfly equivalent".to_string()),
+                   import_swi("p :- \\+ (q, r).\n"));
+        assert_eq!(Err("line 1: arithmetic function `**/2` has no butterfly equivalent".to_string()),
+                   import_swi("p(X) :- X is 2 ** 3.\n"));
+        assert_eq!(Err("line 1: expected `.`, found `b`".to_string()), import_swi("a b.\n"));
+        assert_eq!(Err("line 1: unexpected end of input".to_string()), import_swi("p(a"));
+        assert_eq!(Err("line 1: unsupported number literal".to_string()), import_swi("p(1.5).\n"));
+    }
+
+    #[test]
+    fn test_import_round_trips_export() {
+        let code = "a \n \
+                    rdf:type a b \n \
+                    max X Y Z if (if lt X Y and b then Z = Y else (Z = X)) and ! \n \
+                    p X if X a b and (not q X) and Y is minus X 1 and f X =.. L and X ?= Y \n \
+                    ? (if a then b) (p -3)";
+        let program = parse(code).unwrap();
+        assert_eq!(program, import(&export(&program, Dialect::Swi), Dialect::Swi).unwrap());
+    }
+}
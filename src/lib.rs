@@ -0,0 +1,46 @@
+extern crate pest;
+#[macro_use]
+extern crate pest_derive;
+
+#[macro_use]
+mod macros;
+
+pub mod ast;
+pub mod parser;
+pub mod heap;
+pub mod compiler;
+pub mod machine;
+pub mod export;
+pub mod import;
+pub mod triples;
+pub mod tables;
+pub mod writer;
+pub mod minimize;
+pub mod codegen;
+pub mod analysis;
+pub mod rewrite;
+pub mod eca;
+pub mod extract;
+pub mod expect;
+pub mod profile;
+pub mod explain;
+mod arithmetic;
+mod continuation;
+mod suggest;
+
+// Parsed programs and compiled databases must stay usable from other threads
+// (e.g. shared between the workers of a web server), so make adding a
+// non-thread-safe field to them a compile error.
+const _: () = {
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[allow(dead_code)]
+    fn assert_thread_safe() {
+        assert_send_sync::<ast::Program>();
+        assert_send_sync::<heap::Heap>();
+        assert_send_sync::<compiler::Compiler>();
+        assert_send_sync::<machine::Engine>();
+        assert_send_sync::<rewrite::RuleSet>();
+        assert_send_sync::<eca::EventEngine>();
+    }
+};
@@ -0,0 +1,5782 @@
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::analysis::predicate_indicator;
+use crate::arithmetic::evaluate;
+use crate::ast::*;
+use crate::compiler::*;
+use crate::continuation::{Decoder, Encoder};
+use crate::explain::{ClausePlan, GoalKind, GoalPlan};
+use crate::extract::{ExtractError, FromTerm, LIST_CONS, LIST_NIL};
+use crate::heap::*;
+use crate::parser::parse;
+use crate::profile::{IndexStatistics, PredicateProfile, Profiler, PROFILE_EXIT};
+use crate::rewrite::RuleSet;
+use crate::suggest::closest;
+use crate::writer::{write_annotation, write_clause, write_term_with_options, NumberFormat, WriteOptions};
+
+/**
+ * Number of resolution steps between checks of the deadline and the
+ * cancellation token, which are too slow to check on every step.
+ */
+const POLL_INTERVAL: u32 = 1024;
+
+/**
+ * Most arguments that functor and univ build a compound term with. Each
+ * argument takes a heap cell, so without a limit `functor T f N` with a huge
+ * N would try to allocate the whole address space.
+ */
+pub const MAX_ARITY: usize = 65_535;
+
+/**
+ * First line of the dumps written by Engine::export_facts. The dump is plain
+ * butterfly source, one fact per line, so it can be read back by any version
+ * of the engine that can parse the facts.
+ */
+pub const FACT_DUMP_HEADER: &str = "# butterfly-prolog facts v1";
+
+/**
+ * Runs the queries of a compiled program.
+ *
+ * The search works like the one in iProlog
+ * (https://github.com/ptarau/iProlog): every resolution step creates a new
+ * Spine holding the goals that are left to solve, and the stack of spines
+ * doubles as the stack of choice points. A clause is used by copying it to
+ * the top of the heap and unifying its head with the first goal. Variable
+ * bindings are recorded on the trail so that backtracking into a spine can
+ * undo them and discard the heap cells allocated since.
+ */
+pub struct Engine {
+    compiler: Compiler,
+    // Initial spines of the queries that have not been run yet, in reverse
+    // order
+    pending: Vec<Spine>,
+    // Resources used by each query that has been run, in the order they ran
+    usage: Vec<Usage>,
+    // Error that stopped the current query
+    error: Option<EngineError>,
+    options: EngineOptions,
+    // Depth beyond which spines are not unfolded, if any
+    depth_limit: Option<usize>,
+    // Whether a spine was not unfolded because of depth_limit since the
+    // current query started
+    depth_limit_reached: bool,
+    // Time by which the current query must be done, if any
+    deadline: Option<Instant>,
+    cancellation: CancellationToken,
+    // Steps left until the deadline and cancellation are checked again
+    steps_until_poll: u32,
+    // Query being run with step, between calls
+    stepping: Option<QueryState>,
+    // Inferences left before the search pauses, while stepping
+    step_budget: Option<u64>,
+    // Whether the search paused because step_budget ran out
+    yielded: bool,
+    // Queries taking turns in step_interleaved, the next one first
+    turns: VecDeque<Turn>,
+    // Heap cells the current query had left after the last collection
+    live_cells: usize,
+    profiler: Profiler,
+    // Counts of EngineOptions::index_statistics, by the name cell and the
+    // Arity cell data of the goals
+    index_statistics: HashMap<(HeapEntry, usize), IndexStatistics>,
+    // Rule sets that `rewrite` uses instead of the facts of the predicate
+    // with the same name
+    rule_sets: HashMap<String, RuleSet>,
+    // Updates held back until the open transaction is committed, if one is
+    // open
+    transaction: Option<Vec<Update>>,
+    // Standing queries by the index returned by Engine::subscribe, None once
+    // unsubscribed
+    subscriptions: Vec<Option<Subscription>>,
+    // Predicates whose clauses were asserted or retracted since the
+    // subscriptions were last refreshed, by the name cell and the length of
+    // their goals
+    changed: HashSet<(HeapEntry, usize)>,
+    // Predicates called so far by the standing query being evaluated
+    called: Option<HashSet<(HeapEntry, usize)>>,
+    // Rust functions registered with Engine::register_foreign, by the name
+    // cell and the length of their goals
+    foreign: HashMap<(HeapEntry, usize), ForeignFunction>,
+}
+
+/**
+ * Handle for stopping an engine's running query from another thread. The
+ * query stops with EngineError::Cancelled soon after cancel is called, or
+ * the next query does if none is running.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+/**
+ * Settings that change how an Engine runs queries.
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct EngineOptions {
+    // Whether unification fails instead of binding a variable to a term
+    // that contains it, e.g. X = f X. Off by default since it makes every
+    // binding walk the bound term.
+    pub occurs_check: bool,
+    pub clause_order: ClauseOrder,
+    pub search: SearchStrategy,
+    // Resolution steps a query may take before it stops with
+    // ResourceError::InferenceLimit, if limited
+    pub max_inferences: Option<u64>,
+    // How TermRefs display numbers
+    pub number_format: NumberFormat,
+    // Limits beyond which TermRefs display subterms and the rest of lists as
+    // `...`, so that a huge answer doesn't flood the terminal. None by
+    // default; TermRef::write_with can still write a term in full.
+    pub max_depth: Option<usize>,
+    pub max_list_length: Option<usize>,
+    // Heap cells a query may allocate before the unreachable ones are
+    // collected between solutions, if collection is enabled
+    pub gc_threshold: Option<usize>,
+    // Whether to check the heap for inconsistencies before every resolution
+    // step and panic if there are any. Only done in debug builds, since it
+    // makes each step walk the whole heap.
+    pub validate_heap: bool,
+    pub unknown: Unknown,
+    // Whether to count how many of the clauses the index returns for each
+    // predicate's goals unify, read with Engine::index_statistics. Off by
+    // default since it adds a lookup to every resolution step.
+    pub index_statistics: bool,
+}
+
+/**
+ * How the engine explores the search tree. The depth of a solution is the
+ * number of resolution steps used to find it.
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SearchStrategy {
+    // Standard Prolog search, which never terminates on e.g. left recursion
+    #[default]
+    DepthFirst,
+    // Depth-first search that gives up on branches deeper than the limit,
+    // so solutions beyond it are missed
+    DepthLimited(usize),
+    // Repeated depth-limited searches with limits 0, 1, 2, ..., reporting
+    // each solution once. Finds every solution eventually, shallowest first,
+    // and stops once a search doesn't reach its limit.
+    //
+    // Like any depth limit, it makes `not` succeed when the negated goal's
+    // solutions are too deep.
+    IterativeDeepening,
+}
+
+impl SearchStrategy {
+    /**
+     * Parses `depth-first`, `iterative-deepening` or `depth-limited:<n>`.
+     */
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "depth-first" => Some(SearchStrategy::DepthFirst),
+            "iterative-deepening" => Some(SearchStrategy::IterativeDeepening),
+            _ => {
+                let limit = name.strip_prefix("depth-limited:")?;
+                limit.parse().ok().map(SearchStrategy::DepthLimited)
+            },
+        }
+    }
+}
+
+/**
+ * Order in which the clauses of a predicate are tried.
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ClauseOrder {
+    // The order they appear in the source
+    #[default]
+    Source,
+    // Highest `@priority` annotation first, clauses with the same priority in
+    // source order. Useful for rules with exceptions: the exceptions get a
+    // higher priority and can cut away the general rule.
+    Priority,
+}
+
+impl ClauseOrder {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "source" => Some(ClauseOrder::Source),
+            "priority" => Some(ClauseOrder::Priority),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * What happens when a goal calls a predicate that has no clauses and isn't a
+ * builtin, like the `unknown` flag of other Prologs.
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Unknown {
+    // The goal fails, as if the predicate were defined with no clauses
+    #[default]
+    Fail,
+    // The query stops with EngineError::UnknownPredicate, which suggests the
+    // closest defined predicate in case the name was mistyped. Asking
+    // currentPrologFlag for a flag that doesn't exist is an error too.
+    Error,
+}
+
+impl Unknown {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "fail" => Some(Unknown::Fail),
+            "error" => Some(Unknown::Error),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Resources used while running a query, for embedders that account for
+ * usage.
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    // Resolution steps, i.e. goals successfully unified with a clause head
+    pub inferences: u64,
+    // Heap entries allocated for clause copies, including ones discarded
+    // on backtracking
+    pub heap_cells: u64,
+}
+
+/**
+ * Error that stops a query.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum EngineError {
+    // A goal, or an arithmetic expression, was an unbound variable
+    Instantiation,
+    // The query ran out of a resource limited by EngineOptions
+    Resource(ResourceError),
+    // The query was stopped through a CancellationToken
+    Cancelled,
+    // An arithmetic expression used a term that is not a number or an
+    // arithmetic function, given as name/arity, e.g. `foo/0`
+    NotEvaluable(String),
+    // A term that had to be a fact or goal was a number, e.g. in `assertz 1`
+    NotCallable(String),
+    Evaluation(EvaluationError),
+    // A goal called a predicate, given as name/arity, that has no clauses
+    // and isn't a builtin. Only an error with Unknown::Error. The suggestion
+    // is the closest predicate or builtin that exists, if one is close.
+    UnknownPredicate { predicate: String, suggestion: Option<String> },
+    // currentPrologFlag was asked for a flag that doesn't exist, with
+    // Unknown::Error
+    UnknownFlag { flag: String, suggestion: Option<String> },
+    Representation(RepresentationError),
+    // A foreign function, given as name/arity, couldn't convert one of its
+    // arguments or returned an error
+    Foreign { predicate: String, error: ForeignError },
+}
+
+/**
+ * Error of a foreign function registered with Engine::register_foreign.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum ForeignError {
+    // The argument at this 0-based position isn't of the type the function
+    // takes
+    Argument { position: usize, error: ExtractError },
+    // The function returned an error, with this message
+    Failed(String),
+}
+
+/**
+ * A term that the engine can't build.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum RepresentationError {
+    // The term would have more than MAX_ARITY arguments, e.g. the term built
+    // by `functor T f 0x7FFFFFFFFFFFFFFF`
+    MaxArity,
+}
+
+/**
+ * Arithmetic that has no integer result.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum EvaluationError {
+    ZeroDivisor,
+    // The result doesn't fit in 64 bits
+    Overflow,
+    // The function has no value for its arguments, e.g. `msb 0`
+    Undefined,
+}
+
+/**
+ * Goal solved by Rust code instead of clauses. It gets the goal's arguments
+ * and returns whether the goal succeeded. Bindings it makes are undone on
+ * backtracking like those of a clause, and it has no other solutions.
+ */
+type SemidetBuiltin = fn(&mut Engine, &[HeapEntry]) -> Result<bool, EngineError>;
+
+/**
+ * Builtin that succeeds exactly once unless it raises an error.
+ */
+type DetBuiltin = fn(&mut Engine, &[HeapEntry]) -> Result<(), EngineError>;
+
+/**
+ * Builtin that can have several solutions. It gets the goal's arguments and
+ * the number of its candidate solutions tried before, and makes the
+ * bindings of the next candidate that succeeds. It returns the number of
+ * candidates tried so far, including that one, and whether any are left to
+ * try, or None if no candidate left succeeds. The bindings are undone before
+ * it is called again for the next solution.
+ */
+type NondetBuiltin = fn(&mut Engine, &[HeapEntry], usize) -> Result<Option<(usize, bool)>, EngineError>;
+
+/**
+ * How a builtin is called. Only a nondet builtin can leave a choice point,
+ * and the engine only keeps one while the builtin has candidates left, so
+ * det and semidet goals are solved without one. A det builtin's goal can
+ * only fail by raising an error.
+ */
+#[derive(Clone, Copy)]
+enum BuiltinFlavor {
+    Det(DetBuiltin),
+    Semidet(SemidetBuiltin),
+    Nondet(NondetBuiltin),
+}
+
+/**
+ * Builtins by name and arity. They take precedence over clauses of the
+ * same predicate.
+ */
+const BUILTINS: &[(&str, usize, BuiltinFlavor)] = &[
+    (UNIFY, 2, BuiltinFlavor::Semidet(Engine::solve_unify)),
+    (NOT_UNIFY, 2, BuiltinFlavor::Semidet(Engine::solve_not_unify)),
+    (EQUAL, 2, BuiltinFlavor::Semidet(Engine::solve_equal)),
+    (NOT_EQUAL, 2, BuiltinFlavor::Semidet(Engine::solve_not_equal)),
+    (COMPARE, 3, BuiltinFlavor::Semidet(Engine::solve_compare)),
+    ("functor", 3, BuiltinFlavor::Semidet(Engine::solve_functor)),
+    ("arg", 3, BuiltinFlavor::Semidet(Engine::solve_arg)),
+    (UNIV, 2, BuiltinFlavor::Semidet(Engine::solve_univ)),
+    ("unifiable", 3, BuiltinFlavor::Semidet(Engine::solve_unifiable)),
+    (DECIDED, 2, BuiltinFlavor::Semidet(Engine::solve_decided)),
+    ("copyTerm", 2, BuiltinFlavor::Semidet(Engine::solve_copy_term)),
+    ("assertz", 1, BuiltinFlavor::Det(Engine::solve_assertz)),
+    ("asserta", 1, BuiltinFlavor::Det(Engine::solve_asserta)),
+    ("retract", 1, BuiltinFlavor::Semidet(Engine::solve_retract)),
+    ("beginTransaction", 0, BuiltinFlavor::Semidet(Engine::solve_begin_transaction)),
+    ("commitTransaction", 0, BuiltinFlavor::Semidet(Engine::solve_commit_transaction)),
+    ("rollbackTransaction", 0, BuiltinFlavor::Semidet(Engine::solve_rollback_transaction)),
+    (IS, 2, BuiltinFlavor::Semidet(Engine::solve_is)),
+    ("currentPrologFlag", 2, BuiltinFlavor::Semidet(Engine::solve_current_prolog_flag)),
+    ("var", 1, BuiltinFlavor::Semidet(Engine::solve_var)),
+    ("nonvar", 1, BuiltinFlavor::Semidet(Engine::solve_nonvar)),
+    ("atom", 1, BuiltinFlavor::Semidet(Engine::solve_atom)),
+    ("number", 1, BuiltinFlavor::Semidet(Engine::solve_number)),
+    ("compound", 1, BuiltinFlavor::Semidet(Engine::solve_compound)),
+    ("ground", 1, BuiltinFlavor::Semidet(Engine::solve_ground)),
+    ("profilePred", 2, BuiltinFlavor::Semidet(Engine::solve_profile_pred)),
+    ("splitString", 4, BuiltinFlavor::Semidet(Engine::solve_split_string)),
+    ("subAtom", 5, BuiltinFlavor::Nondet(Engine::solve_sub_atom)),
+    ("predicateProperty", 2, BuiltinFlavor::Nondet(Engine::solve_predicate_property)),
+    ("currentPredicate", 2, BuiltinFlavor::Nondet(Engine::solve_current_predicate)),
+    ("currentFlag", 2, BuiltinFlavor::Nondet(Engine::solve_current_flag)),
+    ("currentOp", 3, BuiltinFlavor::Nondet(Engine::solve_current_op)),
+    ("rewrite", 3, BuiltinFlavor::Semidet(Engine::solve_rewrite)),
+];
+
+
+/**
+ * Flags that can be read with currentPrologFlag, in the order currentFlag
+ * enumerates them.
+ */
+const FLAGS: &[&str] = &[
+    "version",
+    "features",
+    "bounded",
+    "minInteger",
+    "maxInteger",
+    "maxArity",
+    "maxInferences",
+    "occursCheck",
+    "unknown",
+];
+
+/**
+ * Operators of the grammar, as priority, type and name. Programs can't
+ * define operators; the priorities are those the operators have in
+ * standard Prolog.
+ */
+const OPERATORS: &[(usize, &str, &str)] = &[
+    (700, "xfx", UNIFY),
+    (700, "xfx", NOT_UNIFY),
+    (700, "xfx", EQUAL),
+    (700, "xfx", NOT_EQUAL),
+    (700, "xfx", UNIV),
+    (700, "xfx", DECIDED),
+    (700, "xfx", IS),
+];
+
+/**
+ * Optional Cargo features the crate was built with. There are none yet; a
+ * feature adds its name here behind its cfg attribute.
+ */
+const FEATURES: &[&str] = &[];
+
+/**
+ * What the running engine supports and how it is limited, so that embedders
+ * can adapt to it. Programs read the same information with
+ * `currentPrologFlag Flag Value`.
+ */
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct Capabilities {
+    // Version of this crate, e.g. "0.1.0"
+    pub version: &'static str,
+    pub features: Vec<&'static str>,
+    // Builtin predicates as name/arity
+    pub builtins: Vec<String>,
+    // Range of integers, beyond which arithmetic is an error
+    pub min_integer: i64,
+    pub max_integer: i64,
+    // Most arguments that functor and univ build a compound term with
+    pub max_arity: usize,
+    pub max_inferences: Option<u64>,
+    pub gc_threshold: Option<usize>,
+}
+
+/**
+ * A property of a predicate, as read by Engine::predicate_properties and
+ * by programs with `predicateProperty Head Property`. The engine has no
+ * tabling or modules, so there are no properties for those.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PredicateProperty {
+    // Clauses were ever defined with the predicate's name and arity, or it
+    // is a builtin. Written `defined`.
+    Defined,
+    // Clauses were added or removed after loading, by assertz, asserta,
+    // retract or reconsulting. Written `dynamic`.
+    Dynamic,
+    // Written `builtin`
+    Builtin,
+    // Clauses the predicate has now. Written `numberOfClauses N`.
+    NumberOfClauses(usize),
+}
+
+/**
+ * Limited resource that a query ran out of.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResourceError {
+    // The query took EngineOptions::max_inferences resolution steps
+    InferenceLimit,
+    // The query ran past the deadline given to Engine::solve_with_deadline
+    TimeLimit,
+    // `rewrite` applied this many rules without reaching a normal form
+    RewriteSteps(usize),
+}
+
+/**
+ * Result of running a query.
+ */
+#[non_exhaustive]
+pub enum Outcome<'a> {
+    // The query has at least one solution
+    Solutions(Solutions<'a>),
+    Failure,
+    Error(EngineError),
+}
+
+/**
+ * A solution to a query.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Answer {
+    // Named variables of the query and the terms they are bound to, in the
+    // order they appear in the query
+    bindings: Vec<(String, Term)>,
+}
+
+/**
+ * Iterator over the solutions of one query, which are only searched for as
+ * they are requested. Dropping it discards the remaining solutions.
+ */
+pub struct Solutions<'a> {
+    engine: &'a mut Engine,
+    state: QueryState,
+    // Whether the bindings on the heap are a solution that hasn't been
+    // returned yet
+    fresh: bool,
+}
+
+/**
+ * Progress of the search for the solutions of one query.
+ */
+struct QueryState {
+    // Index of the query in Compiler.queries
+    query: usize,
+    // Heap and trail tops to restore once the query is done
+    base: HeapIndex,
+    trail_top: HeapIndex,
+    // Spine to start the search from, until the first solution is requested
+    initial: Option<Box<Spine>>,
+    // Copy of the initial spine to start the next iteration of iterative
+    // deepening from
+    restart: Option<Box<Spine>>,
+    // Heap and trail tops to backtrack to before searching for the next
+    // solution, while the bindings of the last one are kept for views
+    undo: Option<(HeapIndex, HeapIndex)>,
+    // Number of solutions found so far
+    solutions: usize,
+}
+
+/**
+ * Change to the clauses made in a transaction, applied when it is committed.
+ */
+enum Update {
+    Assert { clause: Clause, at_front: bool },
+    Retract {
+        clause: usize,
+        // Name cell and length of the goals of the clause's predicate
+        predicate: (HeapEntry, usize),
+    },
+}
+
+type Subscriber = Box<dyn FnMut(&AnswerDelta) + Send + Sync>;
+
+type ForeignFunction = Box<dyn Fn(&[Term]) -> Result<Term, ForeignError> + Send + Sync>;
+
+/**
+ * A query registered with Engine::subscribe.
+ */
+struct Subscription {
+    query: Query,
+    // Answers found when the query was last evaluated
+    answers: Vec<Answer>,
+    // Predicates called when the query was last evaluated. The query can only
+    // find other answers once one of them has changed.
+    called: HashSet<(HeapEntry, usize)>,
+    subscriber: Subscriber,
+}
+
+/**
+ * Answers of a standing query that were added or removed since its
+ * subscriber was last notified.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnswerDelta {
+    // Index returned by Engine::subscribe
+    pub subscription: usize,
+    pub added: Vec<Answer>,
+    pub removed: Vec<Answer>,
+}
+
+/**
+ * A query waiting for its turn in Engine::step_interleaved.
+ */
+enum Turn {
+    // Not started yet: the index of the query and its initial spine
+    Waiting(usize, Spine),
+    Parked(Box<ParkedQuery>),
+}
+
+/**
+ * A query that was swapped out of the heap so that others could run, with
+ * what is needed to put it back where it was.
+ */
+struct ParkedQuery {
+    state: QueryState,
+    // Heap cells from the query's base up
+    cells: Vec<HeapEntry>,
+    // The query's part of the trail, and the values of the cells it binds
+    // below its base, i.e. of the query's own variables
+    trail: Vec<HeapIndex>,
+    bound: Vec<HeapEntry>,
+    spines: Vec<Spine>,
+    usage: Usage,
+    depth_limit: Option<usize>,
+    depth_limit_reached: bool,
+    live_cells: usize,
+}
+
+/**
+ * Result of running a query for a bounded number of inferences with
+ * Engine::step.
+ */
+#[derive(Debug, PartialEq)]
+#[non_exhaustive]
+pub enum StepResult {
+    // The inference budget ran out before the search got anywhere; stepping
+    // again continues from there
+    Yielded,
+    // The next solution of the query. Stepping again searches for another.
+    Solution(Answer),
+    // The query has no more solutions, after finding at least one
+    Done,
+    // The query has no solutions at all
+    Failed,
+    Error(EngineError),
+}
+
+/**
+ * A solution to a query that reads its bindings directly from the heap
+ * instead of copying them into an Answer. It borrows the Solutions it came
+ * from, so it can't outlive the search moving on to the next solution.
+ */
+pub struct SolutionView<'a> {
+    engine: &'a Engine,
+    query: usize,
+}
+
+/**
+ * A term on the heap, already dereferenced. Reading it doesn't allocate,
+ * except for the names of namespaced atoms.
+ */
+#[derive(Copy, Clone)]
+pub struct TermRef<'a> {
+    engine: &'a Engine,
+    entry: HeapEntry,
+}
+
+impl Engine {
+    pub fn new(compiler: Compiler) -> Self {
+        Engine::with_options(compiler, EngineOptions::default())
+    }
+
+    pub fn with_options(mut compiler: Compiler, options: EngineOptions) -> Self {
+        let pending = std::mem::take(&mut compiler.spines);
+        Engine {
+            compiler,
+            pending,
+            usage: Vec::new(),
+            error: None,
+            options,
+            depth_limit: None,
+            depth_limit_reached: false,
+            deadline: None,
+            cancellation: CancellationToken::default(),
+            steps_until_poll: POLL_INTERVAL,
+            stepping: None,
+            step_budget: None,
+            yielded: false,
+            turns: VecDeque::new(),
+            live_cells: 0,
+            profiler: Profiler::new(),
+            index_statistics: HashMap::new(),
+            rule_sets: HashMap::new(),
+            transaction: None,
+            subscriptions: Vec::new(),
+            changed: HashSet::new(),
+            called: None,
+            foreign: HashMap::new(),
+        }
+    }
+
+    /**
+     * Returns a handle that stops the running query when cancelled, e.g.
+     * from another thread. The engine stays usable for the next queries.
+     */
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            version: env!("CARGO_PKG_VERSION"),
+            features: FEATURES.to_vec(),
+            builtins: BUILTINS.iter().map(|(name, arity, _)| format!("{}/{}", name, arity)).collect(),
+            min_integer: i64::MIN,
+            max_integer: i64::MAX,
+            max_arity: MAX_ARITY,
+            max_inferences: self.options.max_inferences,
+            gc_threshold: self.options.gc_threshold,
+        }
+    }
+
+    /**
+     * Returns the properties of the predicate with the given name and
+     * arity, or none if it is neither defined nor a builtin.
+     */
+    pub fn predicate_properties(&self, name: &str, arity: usize) -> Vec<PredicateProperty> {
+        if self.is_builtin(name, arity) {
+            return vec![PredicateProperty::Defined, PredicateProperty::Builtin];
+        }
+        match self.compiler.symbol_table.get_index(name) {
+            Some(index) => self.clause_properties(HeapEntry::new(HeapTag::Constant, index), arity + 1),
+            None => Vec::new(),
+        }
+    }
+
+    /**
+     * Returns the name and arity of each predicate that has clauses, sorted
+     * by name and then arity. Builtins are left out.
+     */
+    pub fn current_predicates(&self) -> Vec<(String, usize)> {
+        let predicates = &self.compiler.predicates;
+        let mut names: Vec<(String, usize)> = predicates
+            .names()
+            .filter(|(name, arity)| name.tag == HeapTag::Constant && !predicates.predicate(*name, *arity).is_empty())
+            .map(|(name, arity)| (self.compiler.symbol_table.get(name.data).into_owned(), arity - 1))
+            .collect();
+        names.sort();
+        names
+    }
+
+    /**
+     * Returns each flag with its value, as read by currentPrologFlag.
+     */
+    pub fn current_flags(&mut self) -> Vec<(&'static str, Term)> {
+        let heap_top = self.compiler.heap.len();
+        let flags = FLAGS
+            .iter()
+            .map(|flag| {
+                let value = self.flag_value(flag).unwrap();
+                (*flag, self.decompile_entry(value))
+            })
+            .collect();
+        self.compiler.heap.truncate(heap_top);
+        flags
+    }
+
+    /**
+     * Returns the operators of the grammar as priority, type and name, e.g.
+     * `(700, "xfx", "=")`.
+     */
+    pub fn current_operators(&self) -> &'static [(usize, &'static str, &'static str)] {
+        OPERATORS
+    }
+
+    /**
+     * Returns the properties of the predicate defined by clauses with the
+     * given name and Arity cell data.
+     */
+    fn clause_properties(&self, name: HeapEntry, arity: usize) -> Vec<PredicateProperty> {
+        let predicates = &self.compiler.predicates;
+        if !predicates.is_defined(name, arity) {
+            return Vec::new();
+        }
+        let mut properties = vec![PredicateProperty::Defined];
+        if self.is_dynamic(name, arity) {
+            properties.push(PredicateProperty::Dynamic);
+        }
+        properties.push(PredicateProperty::NumberOfClauses(predicates.predicate(name, arity).len()));
+        properties
+    }
+
+    /**
+     * Whether clauses with the given name and Arity cell data were added or
+     * removed after loading. A variable name matches any name.
+     */
+    fn is_dynamic(&self, name: HeapEntry, arity: usize) -> bool {
+        let heap = &self.compiler.heap;
+        self.compiler.clauses
+            .iter()
+            .filter(|clause| clause.removed || clause.asserted.is_some())
+            .any(|clause| {
+                let (arity_cell, name_cell) = match &clause.asserted {
+                    Some(asserted) => (asserted.cells[0], asserted.cells[1]),
+                    None => (heap.read(clause.base), heap.read(clause.base + 1)),
+                };
+                arity_cell.data == arity && (name.is_var_or_unify() || name_cell == name)
+            })
+    }
+
+    /**
+     * Opens a transaction: until it is committed, the facts asserted and
+     * retracted by queries or with assert_fact and retract_fact are held
+     * back, so that queries see either none of them or all of them. Not
+     * even the query that made them sees them before the commit. The
+     * transaction stays open across queries. Returns false if one is
+     * already open, as transactions don't nest.
+     */
+    pub fn begin_transaction(&mut self) -> bool {
+        if self.transaction.is_some() {
+            return false;
+        }
+        self.transaction = Some(Vec::new());
+        true
+    }
+
+    /**
+     * Applies the updates of the open transaction in the order they were
+     * made. Returns false if no transaction is open.
+     */
+    pub fn commit_transaction(&mut self) -> bool {
+        let committed = self.commit();
+        self.refresh_subscriptions();
+        committed
+    }
+
+    /**
+     * Discards the updates of the open transaction. Returns false if no
+     * transaction is open.
+     */
+    pub fn rollback_transaction(&mut self) -> bool {
+        self.transaction.take().is_some()
+    }
+
+    /**
+     * Adds a fact like `assertz`, or like `asserta` if at_front is true. A
+     * query being stepped through keeps the clauses its goals started with.
+     */
+    pub fn assert_fact(&mut self, fact: Term, at_front: bool) -> Result<(), EngineError> {
+        self.add_fact(fact, at_front)?;
+        self.refresh_subscriptions();
+        Ok(())
+    }
+
+    /**
+     * Removes the first fact that unifies with the given term like
+     * `retract`, and returns whether there was one.
+     */
+    pub fn retract_fact(&mut self, fact: &Term) -> Result<bool, EngineError> {
+        let base = self.compiler.heap.len();
+        let trail_top = self.compiler.trail.len();
+        let fact = self.construct_new_term(fact);
+        let retracted = self.solve_retract(&[fact]);
+        self.backtrack(base, trail_top);
+        self.refresh_subscriptions();
+        retracted
+    }
+
+    /**
+     * Registers a standing query and returns its index. The subscriber is
+     * called with the answers the query has now, if it has any, and then
+     * with the answers it gains and loses whenever facts it depends on are
+     * asserted or retracted, once the query or method that changed them is
+     * done. Only the standing queries that called a changed predicate are
+     * run again. Like solve_all, this abandons a query being stepped
+     * through.
+     *
+     * Changes made by standing queries themselves are not reported, and
+     * neither are those seen only through predicateProperty,
+     * currentPredicate or rewrite. A standing query that runs into an error
+     * keeps the answers found before it.
+     */
+    pub fn subscribe<F>(&mut self, query: &Query, subscriber: F) -> usize
+    where
+        F: FnMut(&AnswerDelta) + Send + Sync + 'static
+    {
+        let index = self.subscriptions.len();
+        let (answers, called) = self.evaluate_standing_query(query);
+        let mut subscriber: Subscriber = Box::new(subscriber);
+        if !answers.is_empty() {
+            subscriber(&AnswerDelta { subscription: index, added: answers.clone(), removed: Vec::new() });
+        }
+        self.subscriptions.push(Some(Subscription { query: query.clone(), answers, called, subscriber }));
+        index
+    }
+
+    /**
+     * Stops notifying the subscriber of a standing query. Returns false if
+     * it was already unsubscribed.
+     */
+    pub fn unsubscribe(&mut self, subscription: usize) -> bool {
+        self.subscriptions.get_mut(subscription).and_then(Option::take).is_some()
+    }
+
+    /**
+     * Runs the standing queries that depend on the facts changed since they
+     * were last run, and notifies their subscribers of the answers that
+     * were added or removed. Returns the number of subscribers notified.
+     * Done automatically when a query or method that may have changed facts
+     * is done, and does nothing while queries are being stepped through.
+     */
+    pub fn refresh_subscriptions(&mut self) -> usize {
+        if self.changed.is_empty() || self.stepping.is_some() || !self.turns.is_empty() {
+            return 0;
+        }
+        let changed = std::mem::take(&mut self.changed);
+        // Taken out so that the changes made by the standing queries aren't
+        // recorded and their solutions don't refresh them again
+        let mut subscriptions = std::mem::take(&mut self.subscriptions);
+        let mut notified = 0;
+        for (index, subscription) in subscriptions.iter_mut().enumerate() {
+            let Some(subscription) = subscription else { continue };
+            if subscription.called.is_disjoint(&changed) {
+                continue;
+            }
+            let (answers, called) = self.evaluate_standing_query(&subscription.query);
+            let (added, removed) = answer_delta(&subscription.answers, &answers);
+            subscription.answers = answers;
+            subscription.called = called;
+            if !added.is_empty() || !removed.is_empty() {
+                (subscription.subscriber)(&AnswerDelta { subscription: index, added, removed });
+                notified += 1;
+            }
+        }
+        self.subscriptions = subscriptions;
+        notified
+    }
+
+    fn evaluate_standing_query(&mut self, query: &Query) -> (Vec<Answer>, HashSet<(HeapEntry, usize)>) {
+        self.called = Some(HashSet::new());
+        let answers = self.solve_all(query);
+        (answers, self.called.take().unwrap_or_default())
+    }
+
+    /**
+     * Makes `rewrite Name Term Out` use a rule set built in Rust, which can
+     * have rules implemented by functions, instead of the facts of the
+     * predicate Name.
+     */
+    pub fn add_rule_set(&mut self, name: &str, rules: RuleSet) {
+        self.rule_sets.insert(name.to_string(), rules);
+    }
+
+    /**
+     * Makes goals `Name A1 .. An R`, where n is the given number of
+     * inputs, call a Rust function with the terms A1 to An and unify R with
+     * the term it returns. The inputs must be ground, or the goal is an
+     * instantiation error. Builtins with the same name and arity take
+     * precedence. The `foreign!` macro registers a function with typed
+     * arguments and converts them.
+     */
+    pub fn register_foreign<F>(&mut self, name: &str, inputs: usize, function: F)
+    where
+        F: Fn(&[Term]) -> Result<Term, ForeignError> + Send + Sync + 'static
+    {
+        let name = self.atom_entry(name);
+        self.foreign.insert((name, inputs + 2), Box::new(function));
+    }
+
+    /**
+     * Starts timing every call of a predicate from the call to each of its
+     * solutions. Timings are kept across queries and read with
+     * profile_statistics. Predicates that aren't profiled run as fast as
+     * before.
+     */
+    pub fn profile_predicate(&mut self, name: &str, arity: usize) {
+        let name_entry = self.atom_entry(name);
+        if self.profiler.exit_symbol.is_none() {
+            self.profiler.exit_symbol = Some(self.compiler.symbol_table.intern(PROFILE_EXIT));
+        }
+        self.profiler.add(name_entry, name, arity);
+    }
+
+    /**
+     * Returns the timings of the profiled predicates, in the order they
+     * were profiled.
+     */
+    pub fn profile_statistics(&self) -> &[PredicateProfile] {
+        &self.profiler.profiles
+    }
+
+    /**
+     * Returns what was counted for each predicate while
+     * EngineOptions::index_statistics was on, sorted by name and then arity.
+     * Counts are kept across queries until reset_index_statistics.
+     */
+    pub fn index_statistics(&self) -> Vec<IndexStatistics> {
+        let mut statistics: Vec<IndexStatistics> = self.index_statistics.values().cloned().collect();
+        statistics.sort_by(|a, b| (&a.name, a.arity).cmp(&(&b.name, b.arity)));
+        statistics
+    }
+
+    pub fn reset_index_statistics(&mut self) {
+        self.index_statistics.clear();
+    }
+
+    /**
+     * Returns the resources used by each query run so far, in the order the
+     * queries ran.
+     */
+    pub fn usage(&self) -> &[Usage] {
+        &self.usage
+    }
+
+    /**
+     * Starts running the next query that has not been run yet, in source
+     * order, and returns an iterator over its solutions.
+     */
+    pub fn next_query(&mut self) -> Option<Solutions<'_>> {
+        self.compact_if_due();
+        let spine = self.pending.pop()?;
+        let query = self.compiler.queries.len() - self.pending.len() - 1;
+        Some(self.start_query(query, spine))
+    }
+
+    /**
+     * Runs a query that is not part of the compiled program and returns all
+     * of its solutions. Answers hold copies of their bindings, so they stay
+     * valid after the heap cells the query used are discarded. The search
+     * stops early if the query runs into an error.
+     */
+    pub fn solve_all(&mut self, query: &Query) -> Vec<Answer> {
+        self.compact_if_due();
+        let heap_top = self.compiler.heap.len();
+        self.compiler.compile_temporary_query(query.clone());
+        let index = self.compiler.queries.len() - 1;
+        let goals = self.compiler.queries[index].terms.clone();
+        let spine = Spine::new(0, 0, goals, Vec::new(), 0);
+
+        let answers = self.start_query(index, spine).collect();
+
+        // Discard the query so it isn't mistaken for a pending one
+        self.compiler.queries.pop();
+        self.compiler.heap.truncate(heap_top);
+        answers
+    }
+
+    /**
+     * Removes the clauses that were retracted or replaced by reconsulting
+     * from the heap, like Compiler::compact_clauses, and returns the number
+     * of heap cells reclaimed. Does nothing while queries are being stepped
+     * through with step or step_interleaved, or a transaction is open.
+     */
+    pub fn compact_clauses(&mut self) -> usize {
+        if self.stepping.is_some() || !self.turns.is_empty() || self.transaction.is_some() {
+            return 0;
+        }
+        // The compiler relocates the spines of the pending queries it holds
+        self.compiler.spines = std::mem::take(&mut self.pending);
+        let reclaimed = self.compiler.compact_clauses();
+        self.pending = std::mem::take(&mut self.compiler.spines);
+        reclaimed
+    }
+
+    fn compact_if_due(&mut self) {
+        if self.compiler.is_compaction_due() {
+            self.compact_clauses();
+        }
+    }
+
+    fn start_query(&mut self, query: usize, spine: Spine) -> Solutions<'_> {
+        let state = self.start_state(query, spine);
+        Solutions { engine: self, state, fresh: false }
+    }
+
+    /**
+     * Prepares to search for the solutions of a query, abandoning a query
+     * that is being stepped through.
+     */
+    fn start_state(&mut self, query: usize, mut spine: Spine) -> QueryState {
+        if let Some(stepped) = self.stepping.take() {
+            self.compiler.spines.clear();
+            self.backtrack(stepped.base, stepped.trail_top);
+        }
+        self.usage.push(Usage::default());
+        self.error = None;
+        self.deadline = None;
+        self.depth_limit = match self.options.search {
+            SearchStrategy::DepthFirst => None,
+            SearchStrategy::DepthLimited(limit) => Some(limit),
+            SearchStrategy::IterativeDeepening => Some(0),
+        };
+        self.depth_limit_reached = false;
+        self.live_cells = 0;
+
+        // Code may have been compiled since the spine was created
+        spine.base = self.compiler.heap.len();
+        spine.trail_top = self.compiler.trail.len();
+        if spine.has_goals() {
+            spine.unifiable_clauses = self.clauses_for(spine.goals[0]);
+        }
+
+        let restart = match self.options.search {
+            SearchStrategy::IterativeDeepening => Some(Box::new(spine.clone())),
+            _ => None,
+        };
+        QueryState {
+            query,
+            base: spine.base,
+            trail_top: spine.trail_top,
+            restart,
+            initial: Some(Box::new(spine)),
+            undo: None,
+            solutions: 0,
+        }
+    }
+
+    /**
+     * Runs the next query that has not been run yet, or goes on with the one
+     * already being stepped through, for at most the given number of
+     * inferences, so that solving can be interleaved with e.g. an event
+     * loop. The search inside `not` and if-then-else conditions is not
+     * interrupted, and may take the budget over.
+     *
+     * Returns None when there are no more queries. Running a query any other
+     * way abandons the one being stepped through.
+     */
+    pub fn step(&mut self, inferences: u64) -> Option<StepResult> {
+        let mut state = match self.stepping.take() {
+            Some(state) => state,
+            None => {
+                self.compact_if_due();
+                let spine = self.pending.pop()?;
+                let query = self.compiler.queries.len() - self.pending.len() - 1;
+                self.start_state(query, spine)
+            },
+        };
+
+        self.step_budget = Some(inferences);
+        let found = self.advance(&mut state);
+        self.step_budget = None;
+
+        if found {
+            state.solutions += 1;
+            let answer = self.answer(state.query);
+            self.stepping = Some(state);
+            return Some(StepResult::Solution(answer));
+        }
+        if std::mem::take(&mut self.yielded) {
+            self.stepping = Some(state);
+            return Some(StepResult::Yielded);
+        }
+
+        self.compiler.spines.clear();
+        self.backtrack(state.base, state.trail_top);
+        let error = self.error.take();
+        self.refresh_subscriptions();
+        Some(match error {
+            Some(error) => StepResult::Error(error),
+            None if state.solutions > 0 => StepResult::Done,
+            None => StepResult::Failed,
+        })
+    }
+
+    /**
+     * Runs the queries that have not been run yet in turns of at most the
+     * given number of inferences each, round-robin, so that a query that
+     * never terminates doesn't keep the others from running. Each call runs
+     * one turn and returns the index of the query that ran, in source
+     * order, with what its turn ended with, like step. A query leaves the
+     * rotation once it is done, has failed or ran into an error; one that
+     * found a solution goes to the back and searches for the next one on
+     * its following turn.
+     *
+     * Returns None once every query has left the rotation. Queries are
+     * swapped out of the heap between turns, so no code may be compiled
+     * until then. Usage is recorded as each query leaves the rotation.
+     */
+    pub fn step_interleaved(&mut self, inferences: u64) -> Option<(usize, StepResult)> {
+        if self.turns.is_empty() {
+            self.compact_if_due();
+            while let Some(spine) = self.pending.pop() {
+                let query = self.compiler.queries.len() - self.pending.len() - 1;
+                self.turns.push_back(Turn::Waiting(query, spine));
+            }
+        }
+        let state = match self.turns.pop_front()? {
+            Turn::Waiting(query, spine) => self.start_state(query, spine),
+            Turn::Parked(parked) => self.unpark(*parked),
+        };
+        let query = state.query;
+        self.stepping = Some(state);
+
+        let result = self.step(inferences)?;
+        if let Some(parked) = self.park() {
+            self.turns.push_back(Turn::Parked(Box::new(parked)));
+        }
+        Some((query, result))
+    }
+
+    /**
+     * Swaps the query being stepped through out of the heap, undoing its
+     * bindings, so that another query can run from the same base.
+     */
+    fn park(&mut self) -> Option<ParkedQuery> {
+        let state = self.stepping.take()?;
+        let heap = &self.compiler.heap;
+        let cells = (state.base..heap.len()).map(|i| heap.read(i)).collect();
+        let trail = self.compiler.trail[state.trail_top..].to_vec();
+        let bound = trail
+            .iter()
+            .filter(|index| **index < state.base)
+            .map(|index| heap.read(*index))
+            .collect();
+        self.backtrack(state.base, state.trail_top);
+        Some(ParkedQuery {
+            cells,
+            trail,
+            bound,
+            spines: std::mem::take(&mut self.compiler.spines),
+            usage: self.usage.pop().unwrap_or_default(),
+            depth_limit: self.depth_limit,
+            depth_limit_reached: self.depth_limit_reached,
+            live_cells: self.live_cells,
+            state,
+        })
+    }
+
+    /**
+     * Puts a parked query back on the heap, abandoning a query that is being
+     * stepped through, and returns its state.
+     */
+    fn unpark(&mut self, parked: ParkedQuery) -> QueryState {
+        if let Some(stepped) = self.stepping.take() {
+            self.compiler.spines.clear();
+            self.backtrack(stepped.base, stepped.trail_top);
+        }
+        let base = parked.state.base;
+        debug_assert_eq!(base, self.compiler.heap.len(), "code was compiled while queries were interleaved");
+        let start = self.compiler.heap.alloc(parked.cells.len());
+        for (i, cell) in parked.cells.into_iter().enumerate() {
+            self.compiler.heap.write(start + i, cell);
+        }
+        let mut bound = parked.bound.into_iter();
+        for index in &parked.trail {
+            if *index < base {
+                self.compiler.heap.write(*index, bound.next().unwrap());
+            }
+        }
+        self.compiler.trail.extend(parked.trail);
+        self.compiler.spines = parked.spines;
+        self.usage.push(parked.usage);
+        self.error = None;
+        self.deadline = None;
+        self.depth_limit = parked.depth_limit;
+        self.depth_limit_reached = parked.depth_limit_reached;
+        self.live_cells = parked.live_cells;
+        parked.state
+    }
+
+    /**
+     * Saves the query being stepped through with step, so that an engine
+     * for the same program can go on with it after restore_continuation,
+     * e.g. in another process. Returns None if no query is being stepped
+     * through.
+     */
+    pub fn save_continuation(&self) -> Option<Vec<u8>> {
+        let state = self.stepping.as_ref()?;
+        let heap = &self.compiler.heap;
+        let mut out = Encoder::default();
+        out.header();
+
+        // Identifies the compiled program
+        out.usize(state.base);
+        out.usize(self.compiler.symbol_table.len());
+        out.usize(self.compiler.clauses.len());
+        out.usize(self.compiler.queries.len());
+
+        out.usize(state.query);
+        out.usize(state.trail_top);
+        out.usize(state.solutions);
+        out.bool(state.undo.is_some());
+        let (undo_base, undo_trail_top) = state.undo.unwrap_or_default();
+        out.usize(undo_base);
+        out.usize(undo_trail_top);
+        for spine in [&state.initial, &state.restart] {
+            out.bool(spine.is_some());
+            if let Some(spine) = spine {
+                out.spine(spine);
+            }
+        }
+        out.bool(self.depth_limit.is_some());
+        out.usize(self.depth_limit.unwrap_or_default());
+        out.bool(self.depth_limit_reached);
+        let usage = self.usage.last().copied().unwrap_or_default();
+        out.u64(usage.inferences);
+        out.u64(usage.heap_cells);
+
+        out.usize(self.compiler.spines.len());
+        for spine in &self.compiler.spines {
+            out.spine(spine);
+        }
+        let cells: Vec<HeapEntry> = (state.base..heap.len()).map(|i| heap.read(i)).collect();
+        out.entries(&cells);
+        // Bindings of cells below the query's heap base, i.e. of the query's
+        // own variables, along with the rest of the trail
+        let trail = &self.compiler.trail[state.trail_top..];
+        out.usizes(trail);
+        let bound: Vec<HeapEntry> = trail
+            .iter()
+            .filter(|index| **index < state.base)
+            .map(|index| heap.read(*index))
+            .collect();
+        out.entries(&bound);
+        Some(out.bytes)
+    }
+
+    /**
+     * Loads a continuation saved with save_continuation, after which step
+     * goes on with its query where it left off. The engine must have been
+     * compiled from the same program and not have run any query yet, except
+     * ones before the saved one. A query being stepped through is abandoned.
+     */
+    pub fn restore_continuation(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let mut input = Decoder::new(bytes);
+        input.header()?;
+        let fingerprint = [
+            self.compiler.heap.len(),
+            self.compiler.symbol_table.len(),
+            self.compiler.clauses.len(),
+            self.compiler.queries.len(),
+        ];
+        for expected in fingerprint {
+            if input.usize()? != expected {
+                return Err(String::from("continuation was saved for a different program"));
+            }
+        }
+
+        let base = fingerprint[0];
+        let query = input.usize()?;
+        let trail_top = input.usize()?;
+        if query >= self.compiler.queries.len() || self.pending.len() < self.compiler.queries.len() - query {
+            return Err(String::from("the continuation's query has already been run"));
+        }
+        if trail_top != self.compiler.trail.len() {
+            return Err(String::from("continuation was saved for a different program"));
+        }
+        let solutions = input.usize()?;
+        let has_undo = input.bool()?;
+        let undo = (input.usize()?, input.usize()?);
+        let mut spines = [None, None];
+        for spine in &mut spines {
+            if input.bool()? {
+                *spine = Some(Box::new(input.spine()?));
+            }
+        }
+        let [initial, restart] = spines;
+        let has_depth_limit = input.bool()?;
+        let depth_limit = input.usize()?;
+        let depth_limit_reached = input.bool()?;
+        let usage = Usage { inferences: input.u64()?, heap_cells: input.u64()? };
+
+        let count = input.usize()?;
+        let mut stack = Vec::new();
+        for _ in 0..count {
+            stack.push(input.spine()?);
+        }
+        let cells = input.entries()?;
+        let trail = input.usizes()?;
+        let bound = input.entries()?;
+        if !input.is_empty() {
+            return Err(String::from("unexpected data after saved continuation"));
+        }
+
+        // Make sure the saved indeces point into this engine's heap and
+        // clauses, so that running the continuation can't go out of bounds
+        let heap_len = base + cells.len();
+        let valid_entry = |entry: &HeapEntry| match entry.tag {
+            HeapTag::Constant => entry.data < self.compiler.symbol_table.len(),
+            HeapTag::Number => true,
+            _ => entry.data < heap_len,
+        };
+        let valid_spine = |spine: &Spine| {
+            spine.goals.iter().chain(&spine.dereferenced_elements).all(valid_entry)
+                && spine.unifiable_clauses.iter().all(|clause| *clause < self.compiler.clauses.len())
+                && spine.num_unified_clauses <= spine.unifiable_clauses.len()
+        };
+        let below_base = trail.iter().filter(|index| **index < base).count();
+        let valid = cells.iter().chain(&bound).all(valid_entry)
+            && stack.iter().chain(initial.as_deref()).chain(restart.as_deref()).all(valid_spine)
+            && trail.iter().all(|index| *index < heap_len)
+            && below_base == bound.len();
+        if !valid {
+            return Err(String::from("saved continuation is corrupt"));
+        }
+
+        if let Some(stepped) = self.stepping.take() {
+            self.compiler.spines.clear();
+            self.backtrack(stepped.base, stepped.trail_top);
+        }
+        let start = self.compiler.heap.alloc(cells.len());
+        for (i, cell) in cells.into_iter().enumerate() {
+            self.compiler.heap.write(start + i, cell);
+        }
+        let mut bound = bound.into_iter();
+        for index in &trail {
+            if *index < base {
+                self.compiler.heap.write(*index, bound.next().unwrap());
+            }
+        }
+        self.compiler.trail.extend(trail);
+        self.compiler.spines = stack;
+
+        self.pending.truncate(self.compiler.queries.len() - query - 1);
+        self.usage.push(usage);
+        self.error = None;
+        self.deadline = None;
+        self.depth_limit = has_depth_limit.then_some(depth_limit);
+        self.depth_limit_reached = depth_limit_reached;
+        self.stepping = Some(QueryState {
+            query,
+            base,
+            trail_top,
+            initial,
+            restart,
+            undo: has_undo.then_some(undo),
+            solutions,
+        });
+        Ok(())
+    }
+
+    /**
+     * Runs the next query that has not been run yet and reports whether it
+     * has solutions, failed or stopped with an error, without the caller
+     * having to drive the iterator first.
+     */
+    pub fn try_solve(&mut self) -> Option<Outcome<'_>> {
+        self.solve_until(None)
+    }
+
+    /**
+     * Like try_solve, but the query stops with ResourceError::TimeLimit if
+     * it is still running after the timeout, including while searching for
+     * further solutions.
+     */
+    pub fn solve_with_deadline(&mut self, timeout: Duration) -> Option<Outcome<'_>> {
+        self.solve_until(Some(Instant::now() + timeout))
+    }
+
+    fn solve_until(&mut self, deadline: Option<Instant>) -> Option<Outcome<'_>> {
+        let mut solutions = self.next_query()?;
+        solutions.engine.deadline = deadline;
+        let outcome = if solutions.engine.advance(&mut solutions.state) {
+            solutions.fresh = true;
+            Outcome::Solutions(solutions)
+        } else {
+            match solutions.engine.error.take() {
+                Some(error) => Outcome::Error(error),
+                None => Outcome::Failure,
+            }
+        };
+        Some(outcome)
+    }
+
+    /**
+     * Runs every query that has not been run yet, in source order, and
+     * returns whether each of them has a solution.
+     */
+    pub fn solve(&mut self) -> Vec<bool> {
+        let mut results = Vec::new();
+        while let Some(mut solutions) = self.next_query() {
+            results.push(solutions.next().is_some());
+        }
+        results
+    }
+
+    /**
+     * Reads the current bindings of a query's named variables. Variables
+     * starting with `_` are left out.
+     */
+    fn answer(&self, query: usize) -> Answer {
+        let bindings = self.compiler.queries[query].variables
+            .iter()
+            .filter(|(name, _)| !name.starts_with('_'))
+            .map(|(name, index)| {
+                (name.clone(), self.decompile(*index))
+            })
+            .collect();
+        Answer { bindings }
+    }
+
+    /**
+     * Returns the annotations of each clause of a predicate, in the order the
+     * clauses are tried.
+     */
+    pub fn clause_annotations(&self, name: &str, arity: usize) -> Vec<&[Annotation]> {
+        let name = match self.compiler.symbol_table.get_index(name) {
+            Some(index) => HeapEntry::new(HeapTag::Constant, index),
+            None => return Vec::new(),
+        };
+        self.compiler.predicates
+            .predicate(name, arity + 1)
+            .iter()
+            .map(|i| self.compiler.clauses[*i].annotations.as_slice())
+            .collect()
+    }
+
+    /**
+     * Writes the facts of the predicates for which filter returns true, given
+     * their name and arity, in the order they were compiled. Rules are left
+     * out. The result can be loaded into another engine with import_facts.
+     */
+    pub fn export_facts<F>(&self, filter: F) -> String
+    where
+        F: Fn(&str, usize) -> bool
+    {
+        let mut out = String::from(FACT_DUMP_HEADER);
+        out.push('\n');
+        for clause in &self.compiler.clauses {
+            if clause.terms.len() > 1 || clause.removed {
+                continue;
+            }
+            let fact = self.decompile_clause(clause);
+            match predicate_indicator(&fact.head) {
+                Some((name, arity)) if filter(name, arity) => (),
+                _ => continue,
+            }
+            write_clause(&mut out, &fact);
+            out.push('\n');
+        }
+        out
+    }
+
+    /**
+     * Writes the clauses of a predicate, one per line with their annotations
+     * on the lines before. Clauses compiled with source retention enabled
+     * are shown exactly as they were written, others are decompiled.
+     */
+    pub fn listing(&self, name: &str, arity: usize) -> String {
+        let name = match self.compiler.symbol_table.get_index(name) {
+            Some(index) => HeapEntry::new(HeapTag::Constant, index),
+            None => return String::new(),
+        };
+        let mut out = String::new();
+        for i in self.compiler.predicates.predicate(name, arity + 1) {
+            let clause = &self.compiler.clauses[*i];
+            match &clause.source {
+                Some(source) => out.push_str(source),
+                None => {
+                    let decompiled = self.decompile_clause(clause);
+                    for annotation in &decompiled.annotations {
+                        write_annotation(&mut out, annotation);
+                        out.push('\n');
+                    }
+                    write_clause(&mut out, &decompiled);
+                },
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /**
+     * Reports how each goal of a query would be solved, without running it:
+     * whether it is a builtin or resolved with clauses, how many clauses the
+     * predicate table and the argument registers leave to try, and which
+     * other predicates those clauses may call.
+     */
+    pub fn explain(&mut self, query: &Query) -> Vec<GoalPlan> {
+        let heap_top = self.compiler.heap.len();
+        self.compiler.compile_temporary_query(query.clone());
+        let goals = self.compiler.queries.pop().unwrap().terms;
+        let plans = query.sub_queries
+            .iter()
+            .zip(goals)
+            .map(|(term, goal)| GoalPlan { goal: term.to_string(), kind: self.goal_kind(goal) })
+            .collect();
+        self.compiler.heap.truncate(heap_top);
+        plans
+    }
+
+    fn goal_kind(&self, goal: HeapEntry) -> GoalKind {
+        let goal = match self.resolve_goal(goal) {
+            Some(goal) => goal,
+            None => return GoalKind::Variable,
+        };
+        let heap = &self.compiler.heap;
+        let arity = heap.read(goal.data).data;
+        let name = self.compiler.deref(heap.read(goal.data + 1));
+        let predicate = match name.tag {
+            HeapTag::Constant => format!("{}/{}", self.compiler.symbol_table.get(name.data), arity - 1),
+            _ => format!("_/{}", arity - 1),
+        };
+        if [CUT, NOT, IF_THEN_ELSE].iter().any(|control| self.is_named(name, control)) {
+            return GoalKind::Control;
+        }
+        if self.builtin(goal).is_some() {
+            return GoalKind::Builtin(predicate);
+        }
+
+        let registers = self.compiler.get_subterms(goal);
+        let clauses = self.clauses_for(goal);
+        let candidates: Vec<usize> = clauses
+            .iter()
+            .copied()
+            .filter(|clause| registers_match(&self.compiler.clauses[*clause].head_subterms, &registers))
+            .collect();
+        let indexed_arguments = (1..registers.len()).filter(|i| !registers[*i].is_var_or_unify()).collect();
+        let dynamic = self.is_dynamic(name, arity);
+
+        let mut calls = Vec::new();
+        let mut visited = candidates.clone();
+        let mut i = 0;
+        while i < visited.len() {
+            let clause = &self.compiler.clauses[visited[i]];
+            i += 1;
+            // Asserted clauses are facts
+            if clause.asserted.is_some() {
+                continue;
+            }
+            for term in &clause.terms[1..] {
+                self.called_predicates(*term, &mut calls);
+            }
+            for (name, arity) in &calls {
+                for clause in self.compiler.predicates.predicate(*name, *arity) {
+                    if !visited.contains(clause) {
+                        visited.push(*clause);
+                    }
+                }
+            }
+        }
+        let symbols = &self.compiler.symbol_table;
+        GoalKind::Clauses(ClausePlan {
+            predicate,
+            clauses: clauses.len(),
+            candidates: candidates.len(),
+            indexed_arguments,
+            dynamic,
+            defined: !clauses.is_empty() || self.compiler.predicates.is_defined(name, arity),
+            calls: calls.iter().map(|(name, arity)| format!("{}/{}", symbols.get(name.data), arity - 1)).collect(),
+        })
+    }
+
+    /**
+     * Adds the names and Arity cell data of the predicates a body goal
+     * calls to calls, looking inside negations and if-then-else. Builtins
+     * and goals whose name is a variable are left out.
+     */
+    fn called_predicates(&self, goal: HeapEntry, calls: &mut Vec<(HeapEntry, usize)>) {
+        let heap = &self.compiler.heap;
+        let goal = self.compiler.deref(goal);
+        let (name, arity) = match goal.tag {
+            HeapTag::Reference => (self.compiler.deref(heap.read(goal.data + 1)), heap.read(goal.data).data),
+            HeapTag::Constant => (goal, 1),
+            _ => return,
+        };
+        if name.tag != HeapTag::Constant {
+            return;
+        }
+        let symbol = self.compiler.symbol_table.get(name.data);
+        if goal.tag == HeapTag::Reference && [NOT, IF_THEN_ELSE, CONJUNCTION].contains(&symbol.as_ref()) {
+            for i in 2..=arity {
+                self.called_predicates(heap.read(goal.data + i), calls);
+            }
+        } else if symbol != CUT
+            && !self.is_builtin(&symbol, arity - 1)
+            && !calls.contains(&(name, arity))
+        {
+            calls.push((name, arity));
+        }
+    }
+
+    /**
+     * Rebuilds the AST of a compiled clause. Variables are named A, B, ...
+     * since their original names aren't kept.
+     */
+    fn decompile_clause(&self, clause: &ClauseDescriptor) -> Clause {
+        let mut decompiled = match &clause.asserted {
+            Some(asserted) => asserted.clause.clone(),
+            None => Clause {
+                head: self.decompile(clause.base),
+                body: clause.terms[1..].iter().map(|term| self.decompile(term.data)).collect(),
+                annotations: clause.annotations.clone(),
+            },
+        };
+
+        // Unbound variables decompile to names like _G12, which can't be
+        // read back
+        let mut names: HashMap<String, String> = HashMap::new();
+        decompiled.walk_mut(Order::PreOrder, &mut |term| {
+            if let Term::Simple(SimpleTerm::Variable(name)) = term {
+                let count = names.len();
+                *name = names
+                    .entry(name.clone())
+                    .or_insert_with(|| variable_name(count))
+                    .clone();
+            }
+        });
+        decompiled
+    }
+
+    /**
+     * Compiles the facts of a dump written by export_facts and returns how
+     * many there were. Nothing is compiled if the dump contains anything but
+     * facts.
+     */
+    pub fn import_facts(&mut self, dump: &str) -> Result<usize, String> {
+        let program = parse(dump).map_err(|e| e.to_string())?;
+        if let Some(query) = program.queries.first() {
+            return Err(format!("`{}` is not a fact", query));
+        }
+        if let Some(rule) = program.clauses.iter().find(|clause| !clause.body.is_empty()) {
+            return Err(format!("`{}` is not a fact", rule));
+        }
+        let count = program.clauses.len();
+        self.compiler.compile(program);
+        Ok(count)
+    }
+
+    /**
+     * Rebuilds the term stored at a heap index, either a cell holding a value
+     * or the Arity cell at the start of a term. Unbound variables are named
+     * after the index of their cell, e.g. `_G12`.
+     */
+    pub fn decompile(&self, index: HeapIndex) -> Term {
+        let entry = self.compiler.heap.read(index);
+        match entry.tag {
+            HeapTag::Arity => self.decompile_entry(HeapEntry::new(HeapTag::Reference, index)),
+            _ => self.decompile_entry(entry),
+        }
+    }
+
+    fn decompile_entry(&self, entry: HeapEntry) -> Term {
+        let entry = self.compiler.deref(entry);
+        match entry.tag {
+            HeapTag::Variable | HeapTag::Unify => Term::variable(&format!("_G{}", entry.data)),
+            HeapTag::Constant => {
+                let name = self.compiler.symbol_table.get(entry.data).into_owned();
+                Term::Simple(SimpleTerm::Atom(name))
+            },
+            HeapTag::Number => Term::number(entry.number_value()),
+            HeapTag::Reference => {
+                let heap = &self.compiler.heap;
+                let arity = heap.read(entry.data).data;
+                let name = self.decompile_entry(heap.read(entry.data + 1));
+                if arity == 1 {
+                    // Top-level simple term
+                    return name;
+                }
+
+                let parameters = (2..=arity)
+                    .map(|i| self.decompile_entry(heap.read(entry.data + i)));
+                match name {
+                    Term::Simple(name) => Term::Compound(CompoundTerm {
+                        name,
+                        parameters: parameters.collect(),
+                    }),
+                    // A variable functor bound to a compound term adds
+                    // arguments to it, like call/N
+                    Term::Compound(mut compound_term) => {
+                        compound_term.parameters.extend(parameters);
+                        Term::Compound(compound_term)
+                    },
+                }
+            },
+            HeapTag::Arity | HeapTag::Uninitialized => {
+                unreachable!("{:?} entry can't be the value of a term", entry)
+            },
+        }
+    }
+
+    /**
+     * Searches for the next solution of a query and leaves its bindings on
+     * the heap. Returns false if there are no more solutions, or if the
+     * search paused because the step budget ran out.
+     */
+    fn advance(&mut self, state: &mut QueryState) -> bool {
+        // Undo the last step so that the search can go on from the spines
+        // that are left
+        if let Some((base, trail_top)) = state.undo.take() {
+            self.backtrack(base, trail_top);
+        }
+        if let Some(spine) = state.initial.take() {
+            if !spine.has_goals() {
+                // An empty query succeeds once
+                return true;
+            }
+            self.compiler.spines.push(*spine);
+        }
+        if let Some(threshold) = self.options.gc_threshold {
+            // Only collect again once the heap has grown past twice what
+            // survived, so that a large live heap isn't walked every time
+            let used = self.compiler.heap.len() - state.base;
+            if used > threshold.max(2 * self.live_cells) {
+                self.collect_garbage(state.base);
+                self.live_cells = self.compiler.heap.len() - state.base;
+            }
+        }
+
+        loop {
+            match self.yield_answer_spine() {
+                // Iterative deepening found shallower solutions in earlier
+                // iterations
+                Some(spine) if state.restart.is_some()
+                    && Some(spine.depth) != self.depth_limit =>
+                {
+                    self.backtrack(spine.base, spine.trail_top);
+                },
+                Some(spine) => {
+                    state.undo = Some((spine.base, spine.trail_top));
+                    return true;
+                },
+                None if state.restart.is_some()
+                    && self.depth_limit_reached
+                    && self.error.is_none()
+                    && !self.yielded =>
+                {
+                    let spine = state.restart.as_deref().unwrap().clone();
+                    self.depth_limit = self.depth_limit.map(|limit| limit + 1);
+                    self.depth_limit_reached = false;
+                    self.compiler.spines.push(spine);
+                },
+                None => return false,
+            }
+        }
+    }
+
+    /**
+     * Discards the heap cells from base onwards that can't be reached from
+     * the spines or from the bound variables below base, by sliding the
+     * others down. The cells keep their order, so spine bases still separate
+     * the cells each spine discards on backtracking. Trail entries of
+     * discarded cells are dropped as well.
+     */
+    fn collect_garbage(&mut self, base: HeapIndex) {
+        let heap = &self.compiler.heap;
+        let mut marked = vec![false; heap.len()];
+        let mut pending = Vec::new();
+        for spine in &self.compiler.spines {
+            pending.extend(&spine.goals);
+            pending.extend(&spine.dereferenced_elements);
+        }
+        // The only cells below base that are bound are query variables
+        for index in self.compiler.trail.iter().filter(|index| **index < base) {
+            pending.push(HeapEntry::new(HeapTag::Unify, *index));
+        }
+        while let Some(entry) = pending.pop() {
+            match entry.tag {
+                HeapTag::Variable | HeapTag::Unify if !marked[entry.data] => {
+                    marked[entry.data] = true;
+                    pending.push(heap.read(entry.data));
+                },
+                HeapTag::Reference if !marked[entry.data] => {
+                    let arity = heap.read(entry.data).data;
+                    let block = entry.data..=entry.data + arity;
+                    marked[block.clone()].fill(true);
+                    pending.extend(block.map(|index| heap.read(index)));
+                },
+                _ => (),
+            }
+        }
+
+        // New index of each cell from base onwards, and of the heap top
+        let mut forward = Vec::with_capacity(heap.len() - base + 1);
+        let mut top = base;
+        for is_marked in &marked[base..] {
+            forward.push(top);
+            top += *is_marked as usize;
+        }
+        forward.push(top);
+        let relocate = |entry: HeapEntry| match entry.tag {
+            HeapTag::Variable | HeapTag::Unify | HeapTag::Reference if entry.data >= base => {
+                HeapEntry::new(entry.tag, forward[entry.data - base])
+            },
+            _ => entry,
+        };
+
+        let heap = &mut self.compiler.heap;
+        for index in base..heap.len() {
+            if marked[index] {
+                heap.write(forward[index - base], relocate(heap.read(index)));
+            }
+        }
+        heap.truncate(top);
+
+        // New length of the trail at each old length
+        let mut trail_tops = Vec::with_capacity(self.compiler.trail.len() + 1);
+        let mut trail = Vec::new();
+        for index in &self.compiler.trail {
+            trail_tops.push(trail.len());
+            if *index < base {
+                heap.write(*index, relocate(heap.read(*index)));
+                trail.push(*index);
+            } else if marked[*index] {
+                trail.push(forward[*index - base]);
+            }
+        }
+        trail_tops.push(trail.len());
+        self.compiler.trail = trail;
+
+        for spine in &mut self.compiler.spines {
+            spine.base = forward[spine.base - base];
+            spine.trail_top = trail_tops[spine.trail_top];
+            for goal in spine.goals.iter_mut().chain(&mut spine.dereferenced_elements) {
+                *goal = relocate(*goal);
+            }
+        }
+    }
+
+    /**
+     * Returns true if a spine on the stack has a clause left that may match
+     * its first goal.
+     */
+    fn has_choice_points(&self) -> bool {
+        self.compiler.spines.iter().any(|spine| self.has_alternatives(spine))
+    }
+
+    /**
+     * Returns true if a spine has a clause left that may match its first
+     * goal.
+     */
+    fn has_alternatives(&self, spine: &Spine) -> bool {
+        spine.unifiable_clauses[spine.num_unified_clauses..].iter().any(|clause_index| {
+            let clause = &self.compiler.clauses[*clause_index];
+            registers_match(&clause.head_subterms, &spine.dereferenced_elements)
+        })
+    }
+
+    /**
+     * Produce the next spine representing an answer to a query.
+     * This algorithm is taken from iProlog: https://github.com/ptarau/iProlog
+     */
+    fn yield_answer_spine(&mut self) -> Option<Spine> {
+        while let Some(mut next_goal) = self.compiler.spines.pop() {
+            self.check_heap(&next_goal);
+
+            // Goals that are solved by the engine rather than by clauses
+            if let Some(unfolded) = self.solve_control_goal(&next_goal) {
+                match unfolded {
+                    Some(unfolded) if unfolded.has_goals() => {
+                        self.compiler.spines.push(unfolded);
+                        continue;
+                    },
+                    Some(unfolded) => return Some(unfolded),
+                    None if self.error.is_some() => return None,
+                    None => {
+                        self.backtrack(next_goal.base, next_goal.trail_top);
+                        continue;
+                    },
+                }
+            }
+
+            if let Some((BuiltinFlavor::Nondet(builtin), arguments)) = self.builtin(next_goal.goals[0]) {
+                let (base, trail_top) = (next_goal.base, next_goal.trail_top);
+                match self.solve_nondet_builtin(next_goal, builtin, &arguments) {
+                    Some(unfolded) if unfolded.has_goals() => self.compiler.spines.push(unfolded),
+                    Some(unfolded) => return Some(unfolded),
+                    None if self.error.is_some() => return None,
+                    None => self.backtrack(base, trail_top),
+                }
+                continue;
+            }
+
+            let Some(goal) = self.resolve_goal(next_goal.goals[0]) else {
+                self.error = Some(EngineError::Instantiation);
+                self.compiler.spines.clear();
+                return None;
+            };
+            if let Some(mut called) = self.called.take() {
+                called.extend(self.predicate_of(goal));
+                self.called = Some(called);
+            }
+            if let Some(error) = self.unknown_predicate_error(&next_goal) {
+                self.error = Some(error);
+                self.compiler.spines.clear();
+                return None;
+            }
+            if self.step_budget == Some(0) {
+                // Pick up from this goal on the next step
+                self.compiler.spines.push(next_goal);
+                self.yielded = true;
+                return None;
+            }
+            if let Some(error) = self.check_limits() {
+                self.error = Some(error);
+                self.compiler.spines.clear();
+                return None;
+            }
+
+            let depth_limit_reached = self.depth_limit.is_some_and(|limit| next_goal.depth >= limit);
+            if depth_limit_reached && next_goal.has_clauses() {
+                self.depth_limit_reached = true;
+            }
+            let unfolded = if next_goal.has_clauses() && !depth_limit_reached {
+                self.unfold(&mut next_goal)
+            } else {
+                None
+            };
+
+            let mut unfolded = match unfolded {
+                Some(unfolded) => unfolded,
+                None => {
+                    // No clause left for the first goal, so undo the step
+                    // that created this spine
+                    self.backtrack(next_goal.base, next_goal.trail_top);
+                    continue;
+                }
+            };
+
+            let clause_index = next_goal.unifiable_clauses[next_goal.num_unified_clauses - 1];
+            if self.has_alternatives(&next_goal) || self.compiler.clauses[clause_index].has_cut {
+                // Put spine back since it may have more clauses to try
+                self.compiler.spines.push(next_goal);
+            } else {
+                // Last-call optimization: the spine is no longer a choice
+                // point, so it is dropped and the new spine undoes its step
+                // too on backtracking. Deterministic recursion then runs in
+                // constant stack space. Clauses with a cut keep the spine as
+                // their cut barrier.
+                unfolded.base = next_goal.base;
+                unfolded.trail_top = next_goal.trail_top;
+            }
+            if unfolded.has_goals() {
+                self.compiler.spines.push(unfolded);
+                continue;
+            }
+            return Some(unfolded);
+        }
+        None
+    }
+
+    /**
+     * Panics if EngineOptions::validate_heap is enabled in a debug build and
+     * the heap is inconsistent with the clauses, the trail or the spines,
+     * including the one about to be solved.
+     */
+    fn check_heap(&self, current: &Spine) {
+        if !cfg!(debug_assertions) || !self.options.validate_heap {
+            return;
+        }
+        let compiler = &self.compiler;
+        let spines = compiler.spines.iter().chain(std::iter::once(current));
+        if let Err(message) = compiler.heap.validate(&compiler.clauses, spines, &compiler.trail) {
+            panic!("heap is inconsistent: {}", message);
+        }
+    }
+
+    /**
+     * Unifies the first goal in spine's list of goals with the next matching
+     * clause and returns the spine for the remaining goals, or None if no
+     * clause is left.
+     */
+    fn unfold(&mut self, spine: &mut Spine) -> Option<Spine> {
+        let goal = self.resolve_goal(spine.goals[0])?;
+        self.populate_spine_dereferenced_elements(spine, goal);
+
+        let profile = self.profile_of(goal);
+        let started = profile.map(|profile| {
+            if spine.num_unified_clauses == 0 {
+                self.profiler.record_call(profile);
+            }
+            self.profiler.now()
+        });
+
+        let statistics = self.index_statistics_of(goal);
+        if let (Some(key), 0) = (statistics, spine.num_unified_clauses) {
+            let counts = self.index_statistics.get_mut(&key).unwrap();
+            counts.calls += 1;
+            counts.candidates += spine.unifiable_clauses.len() as u64;
+        }
+
+        let base = self.compiler.heap.len();
+        let trail_top = self.compiler.trail.len();
+        // The spine is put back on top of the stack after this
+        let barrier = self.compiler.spines.len() + 1;
+
+        while spine.has_clauses() {
+            let clause_index = spine.unifiable_clauses[spine.num_unified_clauses];
+            spine.num_unified_clauses += 1;
+
+            let clause = &self.compiler.clauses[clause_index];
+            if !registers_match(&clause.head_subterms, &spine.dereferenced_elements) {
+                continue;
+            }
+
+            let terms = self.copy_clause(clause_index);
+            let unified = self.unify(goal, terms[0]);
+            if let Some(key) = statistics {
+                let counts = self.index_statistics.get_mut(&key).unwrap();
+                counts.tried += 1;
+                counts.unified += unified as u64;
+            }
+            if unified {
+                self.current_usage().inferences += 1;
+                if let Some(budget) = &mut self.step_budget {
+                    *budget = budget.saturating_sub(1);
+                }
+                let mut body = terms[1..].to_vec();
+                if let (Some(profile), Some(started)) = (profile, started) {
+                    body.push(self.profile_exit_goal(profile, started));
+                }
+                let goals: Vec<HeapEntry> = body
+                    .iter()
+                    .chain(&spine.goals[1..])
+                    .copied()
+                    .collect();
+                let unifiable_clauses = match goals.first() {
+                    Some(goal) => self.clauses_for(*goal),
+                    None => Vec::new(),
+                };
+                let mut unfolded = Spine::new(base, trail_top, goals, unifiable_clauses, 0);
+                unfolded.depth = spine.depth + 1;
+                unfolded.cut_barriers = std::iter::repeat_n(barrier, body.len())
+                    .chain(spine.cut_barriers[1..].iter().copied())
+                    .collect();
+                return Some(unfolded);
+            }
+            self.backtrack(base, trail_top);
+        }
+        None
+    }
+
+    /**
+     * Solves the cut at the start of spine's goals by discarding the spines
+     * above its barrier, and returns the spine for the remaining goals.
+     */
+    fn cut(&mut self, spine: &Spine) -> Spine {
+        let barrier = spine.cut_barriers[0];
+        self.compiler.spines.truncate(barrier);
+        if barrier > 0 {
+            // Try no other clauses for the parent's goal. It is kept rather
+            // than discarded so that backtracking into it still undoes the
+            // bindings made since it was created.
+            let parent = self.compiler.spines.last_mut().unwrap();
+            parent.num_unified_clauses = parent.unifiable_clauses.len();
+        }
+        self.replace_goal(spine, Vec::new())
+    }
+
+    /**
+     * Solves the first goal of spine if it is a cut, negation or
+     * if-then-else. Returns None if it is another kind of goal, Some(None)
+     * if it failed and otherwise the spine for the goals left to solve.
+     */
+    fn solve_control_goal(&mut self, spine: &Spine) -> Option<Option<Spine>> {
+        let goal = spine.goals[0];
+        if self.is_cut(goal) {
+            return Some(Some(self.cut(spine)));
+        }
+
+        if let Some(goal) = self.negated_goal(goal) {
+            if self.has_solution(goal, spine.depth) || self.error.is_some() {
+                return Some(None);
+            }
+            return Some(Some(self.replace_goal(spine, Vec::new())));
+        }
+
+        if let Some((profile, started)) = self.profile_exit(goal) {
+            self.profiler.record_exit(profile, started);
+            return Some(Some(self.replace_goal(spine, Vec::new())));
+        }
+
+        if let Some((condition, then, otherwise)) = self.if_then_else_goals(goal) {
+            let branch = if self.solve_once(condition, spine.depth) {
+                Some(then)
+            } else if self.error.is_some() {
+                None
+            } else {
+                otherwise
+            };
+            return Some(branch.map(|goals| self.replace_goal(spine, goals)));
+        }
+
+        let solved = match self.builtin(goal) {
+            Some((BuiltinFlavor::Det(builtin), arguments)) => builtin(self, &arguments).map(|()| true),
+            Some((BuiltinFlavor::Semidet(builtin), arguments)) => builtin(self, &arguments),
+            // Solved by yield_answer_spine, which can keep a choice point
+            Some((BuiltinFlavor::Nondet(_), _)) | None => return None,
+        };
+        match solved {
+            Ok(true) => Some(Some(self.replace_goal(spine, Vec::new()))),
+            Ok(false) => Some(None),
+            Err(error) => {
+                self.error = Some(error);
+                Some(None)
+            },
+        }
+    }
+
+    /**
+     * Returns the builtin that solves a goal entry, and the goal's arguments.
+     */
+    fn builtin(&self, goal: HeapEntry) -> Option<(BuiltinFlavor, Vec<HeapEntry>)> {
+        let goal = self.resolve_goal(goal)?;
+        let heap = &self.compiler.heap;
+        let length = heap.read(goal.data).data;
+        let name = self.compiler.deref(heap.read(goal.data + 1));
+        if name.tag != HeapTag::Constant {
+            return None;
+        }
+        let symbol = self.compiler.symbol_table.get(name.data);
+        let builtin = BUILTINS
+            .iter()
+            .find(|(builtin_name, arity, _)| *builtin_name == symbol && *arity == length - 1);
+        let arguments = (2..=length).map(|i| heap.read(goal.data + i));
+        match builtin {
+            Some((_, _, builtin)) => Some((*builtin, arguments.collect())),
+            // solve_foreign is told which function to call by the name cell
+            // put before the arguments
+            None if self.foreign.contains_key(&(name, length)) => {
+                Some((BuiltinFlavor::Semidet(Engine::solve_foreign), std::iter::once(name).chain(arguments).collect()))
+            },
+            None => None,
+        }
+    }
+
+    fn is_builtin(&self, name: &str, arity: usize) -> bool {
+        BUILTINS.iter().any(|(builtin, builtin_arity, _)| *builtin == name && *builtin_arity == arity)
+            || self.compiler.symbol_table.get_index(name).is_some_and(|index| {
+                self.foreign.contains_key(&(HeapEntry::new(HeapTag::Constant, index), arity + 1))
+            })
+    }
+
+    /**
+     * Solves the first goal of spine with the next solution of a
+     * nondeterministic builtin. The spine is put back as a choice point if
+     * the builtin may have more solutions. Returns the spine for the goals
+     * left to solve, or None if there is no solution left.
+     */
+    fn solve_nondet_builtin(
+        &mut self,
+        mut spine: Spine,
+        builtin: NondetBuiltin,
+        arguments: &[HeapEntry],
+    ) -> Option<Spine> {
+        let base = self.compiler.heap.len();
+        let trail_top = self.compiler.trail.len();
+        let (tried, more) = match builtin(self, arguments, spine.builtin_candidates) {
+            Ok(Some(next)) => next,
+            Ok(None) => return None,
+            Err(error) => {
+                self.error = Some(error);
+                return None;
+            },
+        };
+        let mut unfolded = self.replace_goal(&spine, Vec::new());
+        if more {
+            // Backtracking into the spine tries the next candidates
+            unfolded.base = base;
+            unfolded.trail_top = trail_top;
+            spine.builtin_candidates = tried;
+            self.compiler.spines.push(spine);
+        }
+        Some(unfolded)
+    }
+
+    fn solve_unify(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        Ok(self.unify(arguments[0], arguments[1]))
+    }
+
+    /**
+     * `X \\= Y`: tries to unify X and Y, and undoes the bindings made either
+     * way.
+     */
+    fn solve_not_unify(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        Ok(self.unifier(arguments[0], arguments[1]).is_none())
+    }
+
+    /**
+     * `unifiable X Y U`: U is the list of the bindings, as `V = T` terms,
+     * that unifying X and Y would make, in the order it would make them.
+     * Fails if they don't unify. Nothing is bound by X and Y themselves.
+     */
+    fn solve_unifiable(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        let Some(bindings) = self.unifier(arguments[0], arguments[1]) else { return Ok(false) };
+        let unify = self.atom_entry(UNIFY);
+        let equations: Vec<HeapEntry> = bindings
+            .into_iter()
+            .map(|(index, value)| {
+                self.compiler.construct_compound(unify, &[HeapEntry::new(HeapTag::Unify, index), value])
+            })
+            .collect();
+        let list = self.compiler.construct_list(&equations);
+        Ok(self.unify(arguments[2], list))
+    }
+
+    /**
+     * `X ?= Y`: whether X and Y are identical or can't unify, so that
+     * binding their variables can't change whether they are equal.
+     */
+    fn solve_decided(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        Ok(self.unifier(arguments[0], arguments[1]).is_none_or(|bindings| bindings.is_empty()))
+    }
+
+    /**
+     * Unifies two terms and undoes it, leaving the heap as it was. Returns
+     * the cells the unification bound and the values it bound them to, or
+     * None if the terms don't unify.
+     */
+    fn unifier(&mut self, a: HeapEntry, b: HeapEntry) -> Option<Vec<(HeapIndex, HeapEntry)>> {
+        let base = self.compiler.heap.len();
+        let trail_top = self.compiler.trail.len();
+        let unified = self.unify(a, b);
+        let heap = &self.compiler.heap;
+        let bindings = self.compiler.trail[trail_top..].iter().map(|index| (*index, heap.read(*index))).collect();
+        self.backtrack(base, trail_top);
+        unified.then_some(bindings)
+    }
+
+    fn solve_equal(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        Ok(self.compare_terms(arguments[0], arguments[1]) == std::cmp::Ordering::Equal)
+    }
+
+    fn solve_not_equal(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        Ok(self.compare_terms(arguments[0], arguments[1]) != std::cmp::Ordering::Equal)
+    }
+
+    fn solve_compare(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        let order = match self.compare_terms(arguments[1], arguments[2]) {
+            std::cmp::Ordering::Less => "less",
+            std::cmp::Ordering::Equal => "equal",
+            std::cmp::Ordering::Greater => "greater",
+        };
+        let order = self.atom_entry(order);
+        Ok(self.unify(arguments[0], order))
+    }
+
+    /**
+     * Compares two terms in the standard order: unbound variables by the
+     * index of their cell, then numbers by value, atoms by name and compound
+     * terms by arity, then functor, then arguments from left to right.
+     */
+    fn compare_terms(&self, a: HeapEntry, b: HeapEntry) -> std::cmp::Ordering {
+        let rank = |entry: HeapEntry| match entry.tag {
+            HeapTag::Variable | HeapTag::Unify => 0,
+            HeapTag::Number => 1,
+            HeapTag::Constant => 2,
+            _ => 3,
+        };
+        let heap = &self.compiler.heap;
+        let mut pending = vec![(a, b)];
+        while let Some((a, b)) = pending.pop() {
+            let (a, b) = (self.deref_term(a), self.deref_term(b));
+            let order = rank(a).cmp(&rank(b)).then_with(|| match a.tag {
+                HeapTag::Number => a.number_value().cmp(&b.number_value()),
+                HeapTag::Constant => self.compiler.symbol_table.get(a.data).cmp(&self.compiler.symbol_table.get(b.data)),
+                HeapTag::Reference => {
+                    let arity = heap.read(a.data).data;
+                    let order = arity.cmp(&heap.read(b.data).data);
+                    if order.is_eq() {
+                        // Functors first, then the arguments in order
+                        pending.extend((1..=arity).rev().map(|i| (heap.read(a.data + i), heap.read(b.data + i))));
+                    }
+                    order
+                },
+                _ => a.data.cmp(&b.data),
+            });
+            if order.is_ne() {
+                return order;
+            }
+        }
+        std::cmp::Ordering::Equal
+    }
+
+    /**
+     * `functor T N A`: T has name N and arity A. Atoms and numbers are their
+     * own name with arity 0. If T is unbound, it is bound to a term with
+     * fresh variables as arguments.
+     */
+    fn solve_functor(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        let term = self.deref_term(arguments[0]);
+        let (name, arity) = match term.tag {
+            HeapTag::Variable | HeapTag::Unify => {
+                let name = self.compiler.deref(arguments[1]);
+                let arity = self.compiler.deref(arguments[2]);
+                if name.is_var_or_unify() || arity.is_var_or_unify() {
+                    return Err(EngineError::Instantiation);
+                }
+                if arity.tag != HeapTag::Number {
+                    return Ok(false);
+                }
+                let term = match (name.tag, arity.number_value()) {
+                    (HeapTag::Constant | HeapTag::Number, 0) => name,
+                    (HeapTag::Constant, arity) if arity > MAX_ARITY as i64 => {
+                        return Err(EngineError::Representation(RepresentationError::MaxArity));
+                    },
+                    (HeapTag::Constant, arity) if arity > 0 => {
+                        self.compiler.construct_compound_with_variables(name, arity as usize)
+                    },
+                    _ => return Ok(false),
+                };
+                return Ok(self.unify(arguments[0], term));
+            },
+            HeapTag::Reference => {
+                let heap = &self.compiler.heap;
+                (heap.read(term.data + 1), heap.read(term.data).data as i64 - 1)
+            },
+            _ => (term, 0),
+        };
+        Ok(self.unify(arguments[1], name) && self.unify(arguments[2], HeapEntry::number(arity)))
+    }
+
+    /**
+     * `arg N T A`: A is the Nth argument of the compound term T, counting
+     * from 1.
+     */
+    fn solve_arg(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        let position = self.compiler.deref(arguments[0]);
+        let term = self.deref_term(arguments[1]);
+        if position.is_var_or_unify() || term.is_var_or_unify() {
+            return Err(EngineError::Instantiation);
+        }
+        if position.tag != HeapTag::Number || term.tag != HeapTag::Reference {
+            return Ok(false);
+        }
+        let arity = self.compiler.heap.read(term.data).data - 1;
+        match usize::try_from(position.number_value()) {
+            Ok(position) if (1..=arity).contains(&position) => {
+                let argument = self.compiler.heap.read(term.data + 1 + position);
+                Ok(self.unify(arguments[2], argument))
+            },
+            _ => Ok(false),
+        }
+    }
+
+    /**
+     * `T =.. L`: L is the list of T's functor followed by its arguments, or
+     * a list of just T if it is an atom or a number. If T is unbound, it is
+     * built from the list.
+     */
+    fn solve_univ(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        let term = self.deref_term(arguments[0]);
+        let list = match term.tag {
+            HeapTag::Variable | HeapTag::Unify => {
+                let elements = match self.read_list(arguments[1])? {
+                    Some(elements) if !elements.is_empty() => elements,
+                    _ => return Ok(false),
+                };
+                let name = self.deref_term(elements[0]);
+                let term = match name.tag {
+                    HeapTag::Variable | HeapTag::Unify => return Err(EngineError::Instantiation),
+                    HeapTag::Constant | HeapTag::Number if elements.len() == 1 => name,
+                    HeapTag::Constant if elements.len() - 1 > MAX_ARITY => {
+                        return Err(EngineError::Representation(RepresentationError::MaxArity));
+                    },
+                    HeapTag::Constant => self.compiler.construct_compound(name, &elements[1..]),
+                    _ => return Ok(false),
+                };
+                return Ok(self.unify(arguments[0], term));
+            },
+            HeapTag::Reference => {
+                let heap = &self.compiler.heap;
+                let length = heap.read(term.data).data;
+                let cells: Vec<HeapEntry> = (1..=length).map(|i| heap.read(term.data + i)).collect();
+                self.compiler.construct_list(&cells)
+            },
+            _ => self.compiler.construct_list(&[term]),
+        };
+        Ok(self.unify(arguments[1], list))
+    }
+
+    /**
+     * `copyTerm T C`: C is a copy of T with fresh variables, e.g. a copy of
+     * `f X Y X` is `f A B A`.
+     */
+    fn solve_copy_term(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        let copy = self.compiler.copy_term(arguments[0]);
+        Ok(self.unify(arguments[1], copy))
+    }
+
+    /**
+     * `subAtom Atom Before Length After Sub`: Sub is the part of Atom that
+     * starts after Before characters and is Length characters long, with
+     * After characters left after it. Atom must be bound; each way of
+     * picking Sub that fits the other arguments is a solution, by
+     * increasing Before and then Length.
+     */
+    fn solve_sub_atom(&mut self, arguments: &[HeapEntry], tried: usize) -> Result<Option<(usize, bool)>, EngineError> {
+        let atom = self.deref_term(arguments[0]);
+        if atom.is_var_or_unify() {
+            return Err(EngineError::Instantiation);
+        }
+        if atom.tag != HeapTag::Constant {
+            return Ok(None);
+        }
+        let chars: Vec<char> = self.compiler.symbol_table.get(atom.data).chars().collect();
+        let length = chars.len();
+        // Bound arguments rule out candidates before anything is unified
+        let bound = |engine: &Engine, argument: HeapEntry| {
+            let entry = engine.deref_term(argument);
+            match entry.tag {
+                HeapTag::Number => Some(Some(entry.number_value())),
+                HeapTag::Variable | HeapTag::Unify => Some(None),
+                _ => None,
+            }
+        };
+        let (Some(before), Some(sub_length), Some(after)) =
+            (bound(self, arguments[1]), bound(self, arguments[2]), bound(self, arguments[3]))
+        else {
+            return Ok(None);
+        };
+        let sub = self.deref_term(arguments[4]);
+        let sub: Option<Vec<char>> = match sub.tag {
+            HeapTag::Constant => Some(self.compiler.symbol_table.get(sub.data).chars().collect()),
+            HeapTag::Variable | HeapTag::Unify => None,
+            _ => return Ok(None),
+        };
+        let candidates: Vec<(usize, usize)> = (0..=length)
+            .flat_map(|start| (0..=length - start).map(move |count| (start, count)))
+            .filter(|(start, count)| {
+                before.is_none_or(|before| before == *start as i64)
+                    && sub_length.is_none_or(|sub_length| sub_length == *count as i64)
+                    && after.is_none_or(|after| after == (length - start - count) as i64)
+                    && sub.as_ref().is_none_or(|sub| sub[..] == chars[*start..start + count])
+            })
+            .collect();
+
+        Ok(self.try_candidates(&candidates, tried, |engine, (start, count)| {
+            let sub = engine.atom_entry(&chars[*start..start + count].iter().collect::<String>());
+            let values = [*start, *count, length - start - count].map(|value| HeapEntry::number(value as i64));
+            values.iter().zip(&arguments[1..4]).all(|(value, argument)| engine.unify(*argument, *value))
+                && engine.unify(arguments[4], sub)
+        }))
+    }
+
+    /**
+     * Makes the bindings of the first candidate from the tried-th on that
+     * unify succeeds for, undoing those of the candidates it fails for, and
+     * returns what a NondetBuiltin returns.
+     */
+    fn try_candidates<T>(
+        &mut self,
+        candidates: &[T],
+        tried: usize,
+        mut unify: impl FnMut(&mut Engine, &T) -> bool,
+    ) -> Option<(usize, bool)> {
+        for (i, candidate) in candidates.iter().enumerate().skip(tried) {
+            let base = self.compiler.heap.len();
+            let trail_top = self.compiler.trail.len();
+            if unify(self, candidate) {
+                return Some((i + 1, i + 1 < candidates.len()));
+            }
+            self.backtrack(base, trail_top);
+        }
+        None
+    }
+
+    /**
+     * `predicateProperty Head Property`: Property is a property of the
+     * predicate that Head calls, as listed by PredicateProperty, e.g.
+     * `predicateProperty (parent X Y) (numberOfClauses N)`.
+     */
+    fn solve_predicate_property(
+        &mut self,
+        arguments: &[HeapEntry],
+        tried: usize,
+    ) -> Result<Option<(usize, bool)>, EngineError> {
+        let head = self.deref_term(arguments[0]);
+        let (name, arity) = match head.tag {
+            HeapTag::Variable | HeapTag::Unify => return Err(EngineError::Instantiation),
+            HeapTag::Number => return Err(EngineError::NotCallable(head.number_value().to_string())),
+            HeapTag::Constant => (head, 1),
+            _ => {
+                let heap = &self.compiler.heap;
+                (self.compiler.deref(heap.read(head.data + 1)), heap.read(head.data).data)
+            },
+        };
+        if name.tag != HeapTag::Constant {
+            return Ok(None);
+        }
+        let symbol = self.compiler.symbol_table.get(name.data);
+        let properties = match self.is_builtin(&symbol, arity - 1) {
+            true => vec![PredicateProperty::Defined, PredicateProperty::Builtin],
+            false => self.clause_properties(name, arity),
+        };
+        Ok(self.try_candidates(&properties, tried, |engine, property| {
+            let term = engine.property_term(property);
+            engine.unify(arguments[1], term)
+        }))
+    }
+
+    /**
+     * Builds the term a predicate property is written as.
+     */
+    fn property_term(&mut self, property: &PredicateProperty) -> HeapEntry {
+        match property {
+            PredicateProperty::Defined => self.atom_entry("defined"),
+            PredicateProperty::Dynamic => self.atom_entry("dynamic"),
+            PredicateProperty::Builtin => self.atom_entry("builtin"),
+            PredicateProperty::NumberOfClauses(count) => {
+                let name = self.atom_entry("numberOfClauses");
+                self.compiler.construct_compound(name, &[HeapEntry::number(*count as i64)])
+            },
+        }
+    }
+
+    /**
+     * `splitString Text Separators Pad Parts`: Parts is the list of atoms
+     * that Text is split into at each of the characters of Separators, with
+     * the characters of Pad stripped from both ends of each part. With no
+     * separators, Text is only stripped. Text, Separators and Pad are atoms
+     * or lists of character codes, e.g. `cons 0', nil` for a comma, since
+     * atoms can't be written with spaces or punctuation.
+     */
+    fn solve_split_string(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        let mut texts = Vec::new();
+        for argument in &arguments[..3] {
+            match self.read_text(*argument)? {
+                Some(text) => texts.push(text),
+                None => return Ok(false),
+            }
+        }
+        let [text, separators, pad] = &texts[..] else { unreachable!() };
+        let fields: Vec<&str> = match separators.is_empty() {
+            true => vec![text],
+            false => text.split(|c| separators.contains(c)).collect(),
+        };
+        let parts: Vec<HeapEntry> = fields
+            .into_iter()
+            .map(|field| self.atom_entry(field.trim_matches(|c| pad.contains(c))))
+            .collect();
+        let list = self.compiler.construct_list(&parts);
+        Ok(self.unify(arguments[3], list))
+    }
+
+    /**
+     * Returns the text of an atom or of a list of character codes, or None
+     * if the term is neither.
+     */
+    fn read_text(&self, term: HeapEntry) -> Result<Option<String>, EngineError> {
+        let entry = self.deref_term(term);
+        match entry.tag {
+            HeapTag::Variable | HeapTag::Unify => return Err(EngineError::Instantiation),
+            HeapTag::Constant if self.compiler.symbol_table.get(entry.data) != LIST_NIL => {
+                return Ok(Some(self.compiler.symbol_table.get(entry.data).into_owned()));
+            },
+            _ => (),
+        }
+        let Some(codes) = self.read_list(term)? else { return Ok(None) };
+        let mut text = String::new();
+        for code in codes {
+            let code = self.deref_term(code);
+            match code.tag {
+                HeapTag::Variable | HeapTag::Unify => return Err(EngineError::Instantiation),
+                HeapTag::Number => match u32::try_from(code.number_value()).ok().and_then(char::from_u32) {
+                    Some(c) => text.push(c),
+                    None => return Ok(None),
+                },
+                _ => return Ok(None),
+            }
+        }
+        Ok(Some(text))
+    }
+
+    /**
+     * `assertz F`: adds the fact F after the clauses of its predicate. Only
+     * facts can be asserted, as a rule can't be written as a term. Asserted
+     * facts are kept when the goal is backtracked over, and goals that were
+     * already being resolved don't see them (the logical update view).
+     * Asserting a number is a type error.
+     */
+    fn solve_assertz(&mut self, arguments: &[HeapEntry]) -> Result<(), EngineError> {
+        self.assert_entry(arguments[0], false)
+    }
+
+    /**
+     * `asserta F`: like assertz, but adds F before the clauses of its
+     * predicate.
+     */
+    fn solve_asserta(&mut self, arguments: &[HeapEntry]) -> Result<(), EngineError> {
+        self.assert_entry(arguments[0], true)
+    }
+
+    fn assert_entry(&mut self, fact: HeapEntry, at_front: bool) -> Result<(), EngineError> {
+        let fact = self.deref_term(fact);
+        if fact.is_var_or_unify() {
+            return Err(EngineError::Instantiation);
+        }
+        let head = self.decompile_entry(fact);
+        self.add_fact(head, at_front)
+    }
+
+    fn add_fact(&mut self, fact: Term, at_front: bool) -> Result<(), EngineError> {
+        match &fact {
+            Term::Simple(SimpleTerm::Variable(_)) => return Err(EngineError::Instantiation),
+            Term::Simple(SimpleTerm::Number(number)) => return Err(EngineError::NotCallable(number.to_string())),
+            _ => (),
+        }
+        let clause = Clause { head: fact, body: Vec::new(), annotations: Vec::new() };
+        self.update(Update::Assert { clause, at_front });
+        Ok(())
+    }
+
+    /**
+     * Changes the clauses, or holds the change back until the open
+     * transaction is committed.
+     */
+    fn update(&mut self, update: Update) {
+        match &mut self.transaction {
+            Some(updates) => updates.push(update),
+            None => self.apply(update),
+        }
+    }
+
+    fn apply(&mut self, update: Update) {
+        let predicate = match update {
+            Update::Assert { clause, at_front } => {
+                let (name, arity) = predicate_indicator(&clause.head).unwrap();
+                let predicate = (self.atom_entry(name), arity + 1);
+                self.compiler.assert_clause(clause, at_front);
+                predicate
+            },
+            Update::Retract { clause, predicate } => {
+                self.compiler.retract_clause(clause);
+                predicate
+            },
+        };
+        if !self.subscriptions.is_empty() {
+            self.changed.insert(predicate);
+        }
+    }
+
+    /**
+     * Applies the updates of the open transaction, see
+     * Engine::commit_transaction.
+     */
+    fn commit(&mut self) -> bool {
+        let Some(updates) = self.transaction.take() else { return false };
+        for update in updates {
+            self.apply(update);
+        }
+        true
+    }
+
+    /**
+     * `retract F`: removes the first fact that unifies with F, whether it
+     * was asserted or loaded, and keeps the bindings. Rules are never
+     * removed. Like assertz, this doesn't change the clauses of goals that
+     * were already being resolved, and it isn't undone on backtracking.
+     */
+    fn solve_retract(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        let fact = self.deref_term(arguments[0]);
+        let (name, length, fact) = match fact.tag {
+            HeapTag::Variable | HeapTag::Unify => return Err(EngineError::Instantiation),
+            HeapTag::Number => return Ok(false),
+            // Heads are stored with an Arity cell even if they are atoms
+            HeapTag::Constant => (fact, 1, self.compiler.construct_compound(fact, &[])),
+            _ => {
+                let heap = &self.compiler.heap;
+                (self.compiler.deref(heap.read(fact.data + 1)), heap.read(fact.data).data, fact)
+            },
+        };
+
+        let base = self.compiler.heap.len();
+        let trail_top = self.compiler.trail.len();
+        for clause in self.compiler.predicates.clauses_for(name, length) {
+            let retracted = |update: &Update| matches!(update, Update::Retract { clause: retracted, .. } if *retracted == clause);
+            if self.compiler.clauses[clause].terms.len() > 1 || self.transaction.iter().flatten().any(retracted) {
+                continue;
+            }
+            let terms = self.copy_clause(clause);
+            if self.unify(fact, terms[0]) {
+                self.update(Update::Retract { clause, predicate: (name, length) });
+                return Ok(true);
+            }
+            self.backtrack(base, trail_top);
+        }
+        Ok(false)
+    }
+
+    /**
+     * `beginTransaction`, `commitTransaction` and `rollbackTransaction`: see
+     * Engine::begin_transaction. They fail if a transaction is already open,
+     * or isn't open, respectively.
+     */
+    fn solve_begin_transaction(&mut self, _: &[HeapEntry]) -> Result<bool, EngineError> {
+        Ok(self.begin_transaction())
+    }
+
+    fn solve_commit_transaction(&mut self, _: &[HeapEntry]) -> Result<bool, EngineError> {
+        Ok(self.commit())
+    }
+
+    fn solve_rollback_transaction(&mut self, _: &[HeapEntry]) -> Result<bool, EngineError> {
+        Ok(self.rollback_transaction())
+    }
+
+    /**
+     * `is X E`: unifies X with the value of the arithmetic expression E.
+     */
+    fn solve_is(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        let value = evaluate(&self.compiler, arguments[1])?;
+        Ok(self.unify(arguments[0], HeapEntry::number(value)))
+    }
+
+    /**
+     * `currentPrologFlag F V`: unifies V with the value of the flag F, one
+     * of FLAGS. The version is a number, 10000 * major + 100 * minor + patch
+     * as in other Prologs. Unknown flags fail, or are an error with
+     * Unknown::Error.
+     */
+    fn solve_current_prolog_flag(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        let flag = self.compiler.deref(arguments[0]);
+        match flag.tag {
+            HeapTag::Variable | HeapTag::Unify => return Err(EngineError::Instantiation),
+            HeapTag::Constant => (),
+            _ => return Ok(false),
+        }
+        let flag = self.compiler.symbol_table.get(flag.data).into_owned();
+        let value = match self.flag_value(&flag) {
+            Some(value) => value,
+            None if self.options.unknown == Unknown::Error => {
+                let suggestion = closest(&flag, FLAGS.iter().copied()).map(str::to_string);
+                return Err(EngineError::UnknownFlag { flag, suggestion });
+            },
+            None => return Ok(false),
+        };
+        Ok(self.unify(arguments[1], value))
+    }
+
+    /**
+     * Builds the value of a flag, or returns None if there is no such flag.
+     */
+    fn flag_value(&mut self, flag: &str) -> Option<HeapEntry> {
+        let capabilities = self.capabilities();
+        let value = match flag {
+            "version" => {
+                let version: Vec<i64> = capabilities.version
+                    .split('.')
+                    .map(|part| part.parse().unwrap_or(0))
+                    .collect();
+                HeapEntry::number(version.iter().take(3).fold(0, |number, part| number * 100 + part))
+            },
+            "features" => {
+                let features: Vec<HeapEntry> = capabilities.features.iter().map(|feature| self.atom_entry(feature)).collect();
+                self.compiler.construct_list(&features)
+            },
+            "bounded" => self.atom_entry("true"),
+            "minInteger" => HeapEntry::number(capabilities.min_integer),
+            "maxInteger" => HeapEntry::number(capabilities.max_integer),
+            "maxArity" => HeapEntry::number(capabilities.max_arity as i64),
+            "maxInferences" => match capabilities.max_inferences {
+                Some(max) => HeapEntry::number(i64::try_from(max).unwrap_or(i64::MAX)),
+                None => self.atom_entry("infinite"),
+            },
+            "occursCheck" => self.atom_entry(if self.options.occurs_check { "true" } else { "false" }),
+            "unknown" => self.atom_entry(if self.options.unknown == Unknown::Error { "error" } else { "fail" }),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /**
+     * `rewrite Rules Term Out`: Out is Term rewritten with the rule set
+     * Rules until no rule applies, as RuleSet::rewrite does. The rule set is
+     * the one added with Engine::add_rule_set under that name, or else the
+     * facts of the predicate `Rules/2`, e.g. `simplify (plus X zero) X`;
+     * its rules with a body are left out. Variables of Term are matched as
+     * if they were atoms and stay shared with Out.
+     */
+    fn solve_rewrite(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        let rules = self.compiler.deref(arguments[0]);
+        match rules.tag {
+            HeapTag::Variable | HeapTag::Unify => return Err(EngineError::Instantiation),
+            HeapTag::Constant => (),
+            _ => return Ok(false),
+        }
+        let term = self.decompile_entry(arguments[1]);
+        let name = self.compiler.symbol_table.get(rules.data).into_owned();
+        let rewritten = match self.rule_sets.get(&name) {
+            Some(rule_set) => rule_set.rewrite(&term),
+            None => {
+                let mut rule_set = RuleSet::new();
+                for clause in self.compiler.predicates.predicate(rules, 3) {
+                    let clause = self.decompile_clause(&self.compiler.clauses[*clause]);
+                    if let (Term::Compound(head), true) = (clause.head, clause.body.is_empty()) {
+                        let mut parameters = head.parameters.into_iter();
+                        rule_set.add_rule(parameters.next().unwrap(), parameters.next().unwrap());
+                    }
+                }
+                rule_set.rewrite(&term)
+            },
+        };
+        let rewritten = rewritten.map_err(|error| EngineError::Resource(ResourceError::RewriteSteps(error.steps)))?;
+        let out = self.construct_term(&rewritten, &mut HashMap::new());
+        Ok(self.unify(arguments[2], out))
+    }
+
+    /**
+     * Builds a term on top of the heap. Variables named after a cell by
+     * decompile, e.g. `_G12`, are that cell's variable; other variables are
+     * fresh, one for each name in variables.
+     */
+    fn construct_term(&mut self, term: &Term, variables: &mut HashMap<String, HeapEntry>) -> HeapEntry {
+        match term {
+            Term::Simple(term) => self.construct_simple_term(term, variables),
+            Term::Compound(CompoundTerm { name, parameters }) => {
+                let name = self.construct_simple_term(name, variables);
+                let arguments: Vec<HeapEntry> =
+                    parameters.iter().map(|parameter| self.construct_term(parameter, variables)).collect();
+                self.compiler.construct_compound(name, &arguments)
+            },
+        }
+    }
+
+    fn construct_simple_term(&mut self, term: &SimpleTerm, variables: &mut HashMap<String, HeapEntry>) -> HeapEntry {
+        match term {
+            SimpleTerm::Atom(name) => self.atom_entry(name),
+            SimpleTerm::Number(value) => HeapEntry::number(*value),
+            SimpleTerm::Variable(name) => {
+                if let Some(variable) = variables.get(name) {
+                    return *variable;
+                }
+                if let Some(index) = name.strip_prefix("_G").and_then(|index| index.parse().ok()) {
+                    return HeapEntry::new(HeapTag::Unify, index);
+                }
+                let variable = self.new_variable();
+                variables.insert(name.clone(), variable);
+                variable
+            },
+        }
+    }
+
+    /**
+     * Builds a term given from outside the engine on top of the heap, with
+     * a fresh variable for each of its variable names, even one such as
+     * `_G12`.
+     */
+    fn construct_new_term(&mut self, term: &Term) -> HeapEntry {
+        let mut variables = HashMap::new();
+        for subterm in term.iter() {
+            if let Term::Simple(SimpleTerm::Variable(name)) = subterm {
+                if let Entry::Vacant(entry) = variables.entry(name.clone()) {
+                    entry.insert(self.new_variable());
+                }
+            }
+        }
+        self.construct_term(term, &mut variables)
+    }
+
+    fn new_variable(&mut self) -> HeapEntry {
+        let index = self.compiler.heap.alloc(1);
+        self.compiler.heap.write(index, HeapEntry::new(HeapTag::Variable, index));
+        HeapEntry::new(HeapTag::Unify, index)
+    }
+
+    /**
+     * `currentFlag F V`: each flag F of FLAGS in turn, with its value V as
+     * read by currentPrologFlag.
+     */
+    fn solve_current_flag(
+        &mut self,
+        arguments: &[HeapEntry],
+        tried: usize,
+    ) -> Result<Option<(usize, bool)>, EngineError> {
+        Ok(self.try_candidates(FLAGS, tried, |engine, flag| {
+            let name = engine.atom_entry(flag);
+            let value = engine.flag_value(flag).unwrap();
+            engine.unify(arguments[0], name) && engine.unify(arguments[1], value)
+        }))
+    }
+
+    /**
+     * `currentPredicate Name Arity`: each predicate that has clauses, by
+     * name and then arity. Builtins are left out.
+     */
+    fn solve_current_predicate(
+        &mut self,
+        arguments: &[HeapEntry],
+        tried: usize,
+    ) -> Result<Option<(usize, bool)>, EngineError> {
+        let predicates = self.current_predicates();
+        Ok(self.try_candidates(&predicates, tried, |engine, (name, arity)| {
+            let name = engine.atom_entry(name);
+            engine.unify(arguments[0], name) && engine.unify(arguments[1], HeapEntry::number(*arity as i64))
+        }))
+    }
+
+    /**
+     * `currentOp Priority Type Name`: each operator of OPERATORS.
+     */
+    fn solve_current_op(
+        &mut self,
+        arguments: &[HeapEntry],
+        tried: usize,
+    ) -> Result<Option<(usize, bool)>, EngineError> {
+        Ok(self.try_candidates(OPERATORS, tried, |engine, (priority, kind, name)| {
+            let kind = engine.atom_entry(kind);
+            let name = engine.atom_entry(name);
+            engine.unify(arguments[0], HeapEntry::number(*priority as i64))
+                && engine.unify(arguments[1], kind)
+                && engine.unify(arguments[2], name)
+        }))
+    }
+
+    fn solve_var(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        Ok(self.deref_term(arguments[0]).is_var_or_unify())
+    }
+
+    fn solve_nonvar(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        Ok(!self.deref_term(arguments[0]).is_var_or_unify())
+    }
+
+    fn solve_atom(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        Ok(self.deref_term(arguments[0]).tag == HeapTag::Constant)
+    }
+
+    fn solve_number(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        Ok(self.deref_term(arguments[0]).tag == HeapTag::Number)
+    }
+
+    fn solve_compound(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        Ok(self.deref_term(arguments[0]).tag == HeapTag::Reference)
+    }
+
+    /**
+     * `ground T`: succeeds if no unbound variable can be reached from T.
+     */
+    fn solve_ground(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        Ok(self.is_ground(arguments[0]))
+    }
+
+    fn is_ground(&self, term: HeapEntry) -> bool {
+        let mut pending = vec![term];
+        while let Some(entry) = pending.pop() {
+            let entry = self.deref_term(entry);
+            match entry.tag {
+                HeapTag::Variable | HeapTag::Unify => return false,
+                HeapTag::Reference => {
+                    let arity = self.compiler.heap.read(entry.data).data;
+                    pending.extend((1..=arity).map(|i| self.compiler.heap.read(entry.data + i)));
+                },
+                _ => (),
+            }
+        }
+        true
+    }
+
+    /**
+     * Calls the foreign function named by the first argument, see
+     * Engine::register_foreign.
+     */
+    fn solve_foreign(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        let (name, inputs, output) = (arguments[0], &arguments[1..arguments.len() - 1], arguments[arguments.len() - 1]);
+        if !inputs.iter().all(|input| self.is_ground(*input)) {
+            return Err(EngineError::Instantiation);
+        }
+        let inputs: Vec<Term> = inputs.iter().map(|input| self.decompile_entry(self.deref_term(*input))).collect();
+        let function = &self.foreign[&(name, arguments.len())];
+        let result = function(&inputs).map_err(|error| EngineError::Foreign {
+            predicate: format!("{}/{}", self.compiler.symbol_table.get(name.data), arguments.len() - 1),
+            error,
+        })?;
+        let result = self.construct_new_term(&result);
+        Ok(self.unify(output, result))
+    }
+
+    /**
+     * Dereferences an entry, and a Reference to a simple term stored with
+     * its own Arity cell to that term, so that e.g. `(a)` is an atom.
+     */
+    fn deref_term(&self, entry: HeapEntry) -> HeapEntry {
+        let mut entry = self.compiler.deref(entry);
+        while entry.tag == HeapTag::Reference && self.compiler.heap.read(entry.data).data == 1 {
+            entry = self.compiler.deref(self.compiler.heap.read(entry.data + 1));
+        }
+        entry
+    }
+
+    /**
+     * `profilePred Name Arity`: profiles the predicate like
+     * Engine::profile_predicate.
+     */
+    fn solve_profile_pred(&mut self, arguments: &[HeapEntry]) -> Result<bool, EngineError> {
+        let name = self.compiler.deref(arguments[0]);
+        let arity = self.compiler.deref(arguments[1]);
+        if name.is_var_or_unify() || arity.is_var_or_unify() {
+            return Err(EngineError::Instantiation);
+        }
+        if name.tag != HeapTag::Constant || arity.tag != HeapTag::Number || arity.number_value() < 0 {
+            return Ok(false);
+        }
+        let name = self.compiler.symbol_table.get(name.data).into_owned();
+        self.profile_predicate(&name, arity.number_value() as usize);
+        Ok(true)
+    }
+
+    fn atom_entry(&mut self, name: &str) -> HeapEntry {
+        HeapEntry::new(HeapTag::Constant, self.compiler.symbol_table.intern(name))
+    }
+
+    /**
+     * Returns the elements of a `cons`/`nil` list, or None if the term is
+     * not a list. Lists whose tail is unbound are an instantiation error.
+     */
+    fn read_list(&self, list: HeapEntry) -> Result<Option<Vec<HeapEntry>>, EngineError> {
+        let heap = &self.compiler.heap;
+        let symbols = &self.compiler.symbol_table;
+        let is_atom = |entry: HeapEntry, name: &str| entry.tag == HeapTag::Constant && symbols.get(entry.data) == name;
+        let mut elements = Vec::new();
+        let mut rest = self.deref_term(list);
+        loop {
+            match rest.tag {
+                HeapTag::Variable | HeapTag::Unify => return Err(EngineError::Instantiation),
+                HeapTag::Constant if is_atom(rest, LIST_NIL) => return Ok(Some(elements)),
+                HeapTag::Reference
+                    if heap.read(rest.data).data == 3 && is_atom(self.compiler.deref(heap.read(rest.data + 1)), LIST_CONS) =>
+                {
+                    elements.push(heap.read(rest.data + 2));
+                    rest = self.deref_term(heap.read(rest.data + 3));
+                },
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    /**
+     * Returns the key of the index statistics of a goal's predicate, adding
+     * them if needed, or None if they aren't being counted or the goal's
+     * name is not an atom.
+     */
+    fn index_statistics_of(&mut self, goal: HeapEntry) -> Option<(HeapEntry, usize)> {
+        if !self.options.index_statistics {
+            return None;
+        }
+        let (name, length) = self.predicate_of(goal)?;
+        if !self.index_statistics.contains_key(&(name, length)) {
+            let statistics = IndexStatistics {
+                name: self.compiler.symbol_table.get(name.data).into_owned(),
+                arity: length - 1,
+                ..Default::default()
+            };
+            self.index_statistics.insert((name, length), statistics);
+        }
+        Some((name, length))
+    }
+
+    /**
+     * Returns the name cell and length of a resolved goal, or None if its
+     * name is not an atom.
+     */
+    fn predicate_of(&self, goal: HeapEntry) -> Option<(HeapEntry, usize)> {
+        let length = self.compiler.heap.read(goal.data).data;
+        let name = self.compiler.deref(self.compiler.heap.read(goal.data + 1));
+        (name.tag == HeapTag::Constant).then_some((name, length))
+    }
+
+    /**
+     * Returns the profile of the predicate of a resolved goal, if it is
+     * profiled.
+     */
+    fn profile_of(&self, goal: HeapEntry) -> Option<usize> {
+        let length = self.compiler.heap.read(goal.data).data;
+        let name = self.compiler.deref(self.compiler.heap.read(goal.data + 1));
+        self.profiler.profile_of(name, length)
+    }
+
+    /**
+     * Allocates the goal that records the exit of a call to a profiled
+     * predicate, which started at the given time.
+     */
+    fn profile_exit_goal(&mut self, profile: usize, started: i64) -> HeapEntry {
+        let exit = HeapEntry::new(HeapTag::Constant, self.profiler.exit_symbol.unwrap());
+        let index = self.compiler.heap.alloc(4);
+        self.compiler.heap.write(index, HeapEntry::new(HeapTag::Arity, 3));
+        self.compiler.heap.write(index + 1, exit);
+        self.compiler.heap.write(index + 2, HeapEntry::number(profile as i64));
+        self.compiler.heap.write(index + 3, HeapEntry::number(started));
+        HeapEntry::new(HeapTag::Reference, index)
+    }
+
+    /**
+     * Returns the profile and start time of a goal allocated by
+     * profile_exit_goal.
+     */
+    fn profile_exit(&self, goal: HeapEntry) -> Option<(usize, i64)> {
+        let exit = self.profiler.exit_symbol?;
+        let goal = self.resolve_goal(goal)?;
+        let heap = &self.compiler.heap;
+        if heap.read(goal.data).data != 3 || heap.read(goal.data + 1) != HeapEntry::new(HeapTag::Constant, exit) {
+            return None;
+        }
+        Some((heap.read(goal.data + 2).number_value() as usize, heap.read(goal.data + 3).number_value()))
+    }
+
+    /**
+     * Returns the spine for spine's goals with the first one replaced by the
+     * given goals, which inherit its cut barrier.
+     */
+    fn replace_goal(&mut self, spine: &Spine, replacement: Vec<HeapEntry>) -> Spine {
+        let barriers: Vec<usize> = std::iter::repeat_n(spine.cut_barriers[0], replacement.len())
+            .chain(spine.cut_barriers[1..].iter().copied())
+            .collect();
+        let goals: Vec<HeapEntry> = replacement
+            .into_iter()
+            .chain(spine.goals[1..].iter().copied())
+            .collect();
+        let unifiable_clauses = match goals.first() {
+            Some(goal) => self.clauses_for(*goal),
+            None => Vec::new(),
+        };
+        // Backtracking from the new spine undoes the step that created the
+        // old one, which is not tried again
+        let mut unfolded = Spine::new(spine.base, spine.trail_top, goals, unifiable_clauses, 0);
+        unfolded.cut_barriers = barriers;
+        unfolded.depth = spine.depth;
+        unfolded
+    }
+
+    /**
+     * Returns the goal G of a goal entry of the form `not G`.
+     */
+    fn negated_goal(&self, goal: HeapEntry) -> Option<HeapEntry> {
+        let heap = &self.compiler.heap;
+        if heap.read(goal.data) != HeapEntry::new(HeapTag::Arity, 2) {
+            return None;
+        }
+        if !self.is_named(heap.read(goal.data + 1), NOT) {
+            return None;
+        }
+        Some(heap.read(goal.data + 2))
+    }
+
+    /**
+     * Returns the goals of the condition, then and else branches of a goal
+     * entry of the form `if (and C...) (and T...) (and E...)`.
+     */
+    fn if_then_else_goals(&mut self, goal: HeapEntry) -> Option<Branches> {
+        let heap = &self.compiler.heap;
+        let arity = heap.read(goal.data);
+        if arity != HeapEntry::new(HeapTag::Arity, 3) && arity != HeapEntry::new(HeapTag::Arity, 4) {
+            return None;
+        }
+        if !self.is_named(heap.read(goal.data + 1), IF_THEN_ELSE) {
+            return None;
+        }
+
+        let mut branches = Vec::new();
+        for i in 2..=arity.data {
+            let conjunction = self.compiler.deref(self.compiler.heap.read(goal.data + i));
+            let heap = &self.compiler.heap;
+            if conjunction.tag != HeapTag::Reference
+                || !self.is_named(heap.read(conjunction.data + 1), CONJUNCTION)
+            {
+                return None;
+            }
+            let length = heap.read(conjunction.data).data;
+            let goals: Vec<HeapEntry> = (2..=length)
+                .map(|j| self.compiler.heap.read(conjunction.data + j))
+                .collect();
+            branches.push(goals.into_iter().map(|goal| self.goal_entry(goal)).collect());
+        }
+        let mut branches = branches.into_iter();
+        Some((branches.next()?, branches.next()?, branches.next()))
+    }
+
+    /**
+     * Returns true if an entry dereferences to the given atom.
+     */
+    fn is_named(&self, entry: HeapEntry, name: &str) -> bool {
+        let entry = self.compiler.deref(entry);
+        entry.tag == HeapTag::Constant && self.compiler.symbol_table.get(entry.data) == name
+    }
+
+    /**
+     * Returns an entry that can be used as a spine goal for a term: a
+     * Reference to an Arity entry. Terms that are atoms or variables get one
+     * allocated on top of the heap.
+     */
+    fn goal_entry(&mut self, term: HeapEntry) -> HeapEntry {
+        let term = self.compiler.deref(term);
+        if term.tag == HeapTag::Reference {
+            return term;
+        }
+        let index = self.compiler.heap.alloc(2);
+        self.compiler.heap.write(index, HeapEntry::new(HeapTag::Arity, 1));
+        self.compiler.heap.write(index + 1, term);
+        HeapEntry::new(HeapTag::Reference, index)
+    }
+
+    /**
+     * Searches for a solution of a goal in a separate computation, leaving
+     * the spines and bindings as they were. An error is left in self.error.
+     */
+    fn has_solution(&mut self, goal: HeapEntry, depth: usize) -> bool {
+        let base = self.compiler.heap.len();
+        let trail_top = self.compiler.trail.len();
+        let goal = self.goal_entry(goal);
+        let found = self.solve_once(vec![goal], depth);
+        self.backtrack(base, trail_top);
+        found
+    }
+
+    /**
+     * Searches for the first solution of goals in a separate computation
+     * with its own choice points, and keeps the bindings it makes. Its other
+     * solutions are discarded and the spines are left as they were. An error
+     * is left in self.error. Depth is the depth the goals are at, for depth
+     * limits.
+     */
+    fn solve_once(&mut self, goals: Vec<HeapEntry>, depth: usize) -> bool {
+        let spines = std::mem::take(&mut self.compiler.spines);
+        let unifiable_clauses = self.clauses_for(goals[0]);
+        let base = self.compiler.heap.len();
+        let trail_top = self.compiler.trail.len();
+        let mut spine = Spine::new(base, trail_top, goals, unifiable_clauses, 0);
+        spine.depth = depth;
+        self.compiler.spines.push(spine);
+
+        // The nested search can't pause, so it runs to the end and is
+        // charged to the step budget afterwards
+        let budget = self.step_budget.take();
+        let inferences = self.current_usage().inferences;
+        let found = self.yield_answer_spine().is_some();
+        let used = self.current_usage().inferences - inferences;
+        self.step_budget = budget.map(|budget| budget.saturating_sub(used));
+        if self.error.is_none() {
+            self.compiler.spines = spines;
+        }
+        found
+    }
+
+    /**
+     * Returns true if a goal entry is the atom `!`.
+     */
+    fn is_cut(&self, goal: HeapEntry) -> bool {
+        let heap = &self.compiler.heap;
+        if heap.read(goal.data) != HeapEntry::new(HeapTag::Arity, 1) {
+            return false;
+        }
+        self.is_named(heap.read(goal.data + 1), CUT)
+    }
+
+    /**
+     * Returns the error to stop the current query with if it has run out of
+     * inferences or time or has been cancelled.
+     */
+    fn check_limits(&mut self) -> Option<EngineError> {
+        if self.options.max_inferences.is_some_and(|max| self.current_usage().inferences >= max) {
+            return Some(EngineError::Resource(ResourceError::InferenceLimit));
+        }
+
+        self.steps_until_poll -= 1;
+        if self.steps_until_poll > 0 {
+            return None;
+        }
+        self.steps_until_poll = POLL_INTERVAL;
+        if self.cancellation.cancelled.swap(false, Ordering::Relaxed) {
+            return Some(EngineError::Cancelled);
+        }
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Some(EngineError::Resource(ResourceError::TimeLimit));
+        }
+        None
+    }
+
+    fn current_usage(&mut self) -> &mut Usage {
+        self.usage.last_mut().unwrap()
+    }
+
+    /**
+     * Populates dereferenced_elements array of spine based on the the given goal.
+     */
+    fn populate_spine_dereferenced_elements(&self, spine: &mut Spine, goal: HeapEntry) {
+        if !spine.dereferenced_elements.is_empty() {
+            // Already populated
+            return;
+        }
+        spine.dereferenced_elements = self.compiler.get_subterms(goal);
+    }
+
+    /**
+     * Returns the goal to call for a goal entry. A goal that is just a
+     * variable calls the term the variable is bound to; None is returned if
+     * it is unbound.
+     */
+    fn resolve_goal(&self, goal: HeapEntry) -> Option<HeapEntry> {
+        let heap = &self.compiler.heap;
+        if heap.read(goal.data) != HeapEntry::new(HeapTag::Arity, 1) {
+            return Some(goal);
+        }
+        let entry = self.compiler.deref(heap.read(goal.data + 1));
+        match entry.tag {
+            HeapTag::Reference => Some(entry),
+            HeapTag::Variable | HeapTag::Unify => None,
+            _ => Some(goal),
+        }
+    }
+
+    /**
+     * Returns the error for the first goal of a spine if it calls a
+     * predicate that was never defined and Unknown::Error is set.
+     */
+    fn unknown_predicate_error(&self, spine: &Spine) -> Option<EngineError> {
+        if self.options.unknown == Unknown::Fail || !spine.unifiable_clauses.is_empty() {
+            return None;
+        }
+        let goal = self.resolve_goal(spine.goals[0])?;
+        let heap = &self.compiler.heap;
+        let length = heap.read(goal.data).data;
+        let name = self.compiler.deref(heap.read(goal.data + 1));
+        if name.tag != HeapTag::Constant || self.compiler.predicates.is_defined(name, length) {
+            return None;
+        }
+
+        let symbols = &self.compiler.symbol_table;
+        let predicate = format!("{}/{}", symbols.get(name.data), length - 1);
+        let candidates: Vec<String> = self.compiler.predicates
+            .names()
+            .filter(|(name, _)| name.tag == HeapTag::Constant)
+            .map(|(name, length)| format!("{}/{}", symbols.get(name.data), length - 1))
+            .chain(BUILTINS.iter().map(|(name, arity, _)| format!("{}/{}", name, arity)))
+            .collect();
+        let suggestion = closest(&predicate, candidates.iter().map(String::as_str)).map(str::to_string);
+        Some(EngineError::UnknownPredicate { predicate, suggestion })
+    }
+
+    /**
+     * Returns the clauses whose head has the same name and arity as the goal.
+     */
+    fn clauses_for(&self, goal: HeapEntry) -> Vec<usize> {
+        let goal = match self.resolve_goal(goal) {
+            Some(goal) => goal,
+            None => return Vec::new(),
+        };
+        let heap = &self.compiler.heap;
+        let arity = heap.read(goal.data).data;
+        let name = self.compiler.deref(heap.read(goal.data + 1));
+
+        let mut clauses = self.compiler.predicates.clauses_for(name, arity);
+        if self.options.clause_order == ClauseOrder::Priority {
+            // Stable, so ties stay in source order
+            clauses.sort_by_key(|i| std::cmp::Reverse(self.compiler.clauses[*i].priority));
+        }
+        clauses
+    }
+
+    /**
+     * Copies a clause to the top of the heap, relocating its variables and
+     * references, and returns References to the copies of its head and body
+     * goals.
+     */
+    fn copy_clause(&mut self, clause_index: usize) -> Vec<HeapEntry> {
+        let length = self.compiler.clauses[clause_index].length;
+        self.current_usage().heap_cells += length as u64;
+        let clause = &self.compiler.clauses[clause_index];
+        let base = clause.base;
+        let heap = &mut self.compiler.heap;
+
+        // Asserted clauses can be copied below where they were compiled
+        let new_base = heap.alloc(length);
+        let relocate = |index: HeapIndex| index - base + new_base;
+        for i in 0..length {
+            let mut entry = match &clause.asserted {
+                Some(asserted) => asserted.cells[i],
+                None => heap.read(base + i),
+            };
+            match entry.tag {
+                HeapTag::Variable | HeapTag::Unify => entry.data = relocate(entry.data),
+                // Shared ground subterms live outside the clause and are
+                // never modified, so they are not copied
+                HeapTag::Reference if entry.data >= base && entry.data < base + length => {
+                    entry.data = relocate(entry.data);
+                },
+                _ => (),
+            }
+            heap.write(new_base + i, entry);
+        }
+
+        clause.terms
+            .iter()
+            .map(|term| HeapEntry::new(HeapTag::Reference, relocate(term.data)))
+            .collect()
+    }
+
+    /**
+     * Unifies two heap entries, binding variables as needed and recording
+     * them on the trail. The entries can be any cells of a term: Variable and
+     * Unify cells are dereferenced, Constant and Number cells must be equal,
+     * and References to compound terms are unified cell by cell starting
+     * from their Arity entries. On failure, some variables may already have
+     * been bound; the caller undoes them by unwinding the trail.
+     */
+    fn unify(&mut self, a: HeapEntry, b: HeapEntry) -> bool {
+        let mut pairs = vec![(a, b)];
+        while let Some((a, b)) = pairs.pop() {
+            let a = self.compiler.deref(a);
+            let b = self.compiler.deref(b);
+            if a == b {
+                continue;
+            }
+
+            if a.is_var_or_unify() && b.is_var_or_unify() {
+                // Bind the newer variable to the older one, so that no
+                // binding points to cells that are discarded before it is
+                // undone
+                self.bind_variable(a.data.max(b.data), a.data.min(b.data));
+            } else if a.is_var_or_unify() {
+                if self.options.occurs_check && self.occurs(a.data, b) {
+                    return false;
+                }
+                self.bind(a.data, b);
+            } else if b.is_var_or_unify() {
+                if self.options.occurs_check && self.occurs(b.data, a) {
+                    return false;
+                }
+                self.bind(b.data, a);
+            } else if a.tag == HeapTag::Reference && b.tag == HeapTag::Reference {
+                let heap = &self.compiler.heap;
+                let arity = heap.read(a.data);
+                if arity != heap.read(b.data) {
+                    return false;
+                }
+                for i in 1..=arity.data {
+                    pairs.push((heap.read(a.data + i), heap.read(b.data + i)));
+                }
+            } else {
+                return false;
+            }
+        }
+        true
+    }
+
+    /**
+     * Returns true if the unbound variable at index appears in the term a
+     * dereferenced entry refers to.
+     */
+    fn occurs(&self, index: HeapIndex, value: HeapEntry) -> bool {
+        let heap = &self.compiler.heap;
+        let mut entries = vec![value];
+        while let Some(entry) = entries.pop() {
+            let entry = self.compiler.deref(entry);
+            match entry.tag {
+                HeapTag::Variable | HeapTag::Unify if entry.data == index => return true,
+                HeapTag::Reference => {
+                    let arity = heap.read(entry.data).data;
+                    entries.extend((1..=arity).map(|i| heap.read(entry.data + i)));
+                },
+                _ => (),
+            }
+        }
+        false
+    }
+
+    /**
+     * Binds the unbound variable at index to a dereferenced non-variable
+     * entry.
+     */
+    fn bind(&mut self, index: HeapIndex, value: HeapEntry) {
+        self.compiler.heap.write(index, value);
+        self.compiler.trail.push(index);
+    }
+
+    /**
+     * Binds the unbound variable at index to the unbound variable at target.
+     */
+    fn bind_variable(&mut self, index: HeapIndex, target: HeapIndex) {
+        self.bind(index, HeapEntry::new(HeapTag::Unify, target));
+    }
+
+    /**
+     * Undoes the bindings recorded since trail_top and discards the heap from
+     * base onwards.
+     */
+    fn backtrack(&mut self, base: HeapIndex, trail_top: HeapIndex) {
+        while self.compiler.trail.len() > trail_top {
+            let index = self.compiler.trail.pop().unwrap();
+            self.compiler.heap.write(index, HeapEntry::new(HeapTag::Variable, index));
+        }
+        self.compiler.heap.truncate(base);
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineError::Instantiation => {
+                f.write_str("instantiation error: arguments are not sufficiently instantiated")
+            },
+            EngineError::Resource(ResourceError::InferenceLimit) => {
+                f.write_str("resource error: inference limit exceeded")
+            },
+            EngineError::Resource(ResourceError::TimeLimit) => f.write_str("resource error: time limit exceeded"),
+            EngineError::Resource(ResourceError::RewriteSteps(steps)) => {
+                write!(f, "resource error: no normal form found after {} rewrite steps", steps)
+            },
+            EngineError::Cancelled => f.write_str("query cancelled"),
+            EngineError::NotEvaluable(function) => {
+                write!(f, "type error: {} is not an arithmetic function", function)
+            },
+            EngineError::NotCallable(term) => write!(f, "type error: {} is not callable", term),
+            EngineError::Evaluation(EvaluationError::ZeroDivisor) => f.write_str("evaluation error: division by zero"),
+            EngineError::Evaluation(EvaluationError::Overflow) => f.write_str("evaluation error: integer overflow"),
+            EngineError::Evaluation(EvaluationError::Undefined) => f.write_str("evaluation error: undefined"),
+            EngineError::UnknownPredicate { predicate, suggestion } => {
+                write!(f, "existence error: unknown predicate {}", predicate)?;
+                write_suggestion(f, suggestion)
+            },
+            EngineError::UnknownFlag { flag, suggestion } => {
+                write!(f, "domain error: unknown flag {}", flag)?;
+                write_suggestion(f, suggestion)
+            },
+            EngineError::Representation(RepresentationError::MaxArity) => {
+                write!(f, "representation error: arity is more than the maximum of {}", MAX_ARITY)
+            },
+            EngineError::Foreign { predicate, error: ForeignError::Argument { position, error } } => {
+                write!(f, "type error: argument {} of {}: {}", position + 1, predicate, error)
+            },
+            EngineError::Foreign { predicate, error: ForeignError::Failed(message) } => {
+                write!(f, "foreign error: {} failed: {}", predicate, message)
+            },
+        }
+    }
+}
+
+fn write_suggestion(f: &mut fmt::Formatter, suggestion: &Option<String>) -> fmt::Result {
+    match suggestion {
+        Some(suggestion) => write!(f, ", did you mean {}?", suggestion),
+        None => Ok(()),
+    }
+}
+
+impl Answer {
+    /**
+     * Returns the term a variable of the query is bound to.
+     */
+    pub fn get(&self, variable: &str) -> Option<&Term> {
+        self.bindings
+            .iter()
+            .find(|(name, _)| name == variable)
+            .map(|(_, term)| term)
+    }
+
+    /**
+     * Returns the named variables of the query and the terms they are bound
+     * to, in the order they appear in the query.
+     */
+    pub fn bindings(&self) -> &[(String, Term)] {
+        &self.bindings
+    }
+
+    /**
+     * Converts the term a variable of the query is bound to, e.g. a list of
+     * numbers to a Vec<i64>.
+     */
+    pub fn get_as<T: FromTerm>(&self, variable: &str) -> Result<T, ExtractError> {
+        T::from_term(self.binding(variable)?)
+    }
+
+    pub fn get_i64(&self, variable: &str) -> Result<i64, ExtractError> {
+        self.get_as(variable)
+    }
+
+    /**
+     * Returns the name of the atom a variable of the query is bound to.
+     */
+    pub fn get_atom(&self, variable: &str) -> Result<&str, ExtractError> {
+        match self.binding(variable)? {
+            Term::Simple(SimpleTerm::Atom(name)) => Ok(name),
+            term => Err(ExtractError::Mismatch { expected: "atom", found: term.clone() }),
+        }
+    }
+
+    /**
+     * Converts each element of the `cons`/`nil` list a variable of the
+     * query is bound to.
+     */
+    pub fn get_list_of<T: FromTerm>(&self, variable: &str) -> Result<Vec<T>, ExtractError> {
+        self.get_as(variable)
+    }
+
+    fn binding(&self, variable: &str) -> Result<&Term, ExtractError> {
+        self.get(variable).ok_or_else(|| ExtractError::UnknownVariable(variable.to_string()))
+    }
+}
+
+impl From<Answer> for HashMap<String, Term> {
+    fn from(answer: Answer) -> Self {
+        answer.bindings.into_iter().collect()
+    }
+}
+
+impl Iterator for Solutions<'_> {
+    type Item = Answer;
+
+    fn next(&mut self) -> Option<Answer> {
+        if !std::mem::take(&mut self.fresh) && !self.engine.advance(&mut self.state) {
+            return None;
+        }
+        Some(self.engine.answer(self.state.query))
+    }
+}
+
+impl Solutions<'_> {
+    /**
+     * Returns the error that ended the search early, if any.
+     */
+    pub fn error(&self) -> Option<&EngineError> {
+        self.engine.error.as_ref()
+    }
+
+    /**
+     * Returns true once the search has no choice points left, i.e. the last
+     * solution found is the only remaining one and asking for another would
+     * fail. A REPL can use this to not prompt for more. It may return false
+     * even if no further solution exists.
+     */
+    pub fn is_last(&self) -> bool {
+        let state = &self.state;
+        if state.initial.is_some() {
+            return false;
+        }
+        // Iterative deepening searches again with a higher limit
+        if state.restart.is_some() && self.engine.depth_limit_reached {
+            return false;
+        }
+        !self.engine.has_choice_points()
+    }
+
+    /**
+     * Like next, but returns a view of the solution's bindings on the heap
+     * instead of copying them. The view has to be dropped before the search
+     * can go on.
+     */
+    pub fn next_view(&mut self) -> Option<SolutionView<'_>> {
+        if !std::mem::take(&mut self.fresh) && !self.engine.advance(&mut self.state) {
+            return None;
+        }
+        Some(SolutionView { engine: self.engine, query: self.state.query })
+    }
+}
+
+impl<'a> SolutionView<'a> {
+    /**
+     * Returns the term a variable of the query is bound to.
+     */
+    pub fn get(&self, variable: &str) -> Option<TermRef<'a>> {
+        self.engine.compiler.queries[self.query].variables
+            .iter()
+            .find(|(name, _)| name == variable)
+            .map(|(_, index)| TermRef::new(self.engine, *index))
+    }
+
+    /**
+     * Returns the named variables of the query and the terms they are bound
+     * to, in the order they appear in the query. Variables starting with `_`
+     * are left out.
+     */
+    pub fn bindings(&self) -> impl Iterator<Item = (&'a str, TermRef<'a>)> + 'a {
+        let engine = self.engine;
+        engine.compiler.queries[self.query].variables
+            .iter()
+            .filter(|(name, _)| !name.starts_with('_'))
+            .map(move |(name, index)| (name.as_str(), TermRef::new(engine, *index)))
+    }
+
+    /**
+     * Copies the bindings into an Answer that outlives the search.
+     */
+    pub fn to_answer(&self) -> Answer {
+        self.engine.answer(self.query)
+    }
+}
+
+impl<'a> TermRef<'a> {
+    fn new(engine: &'a Engine, index: HeapIndex) -> Self {
+        TermRef::from_entry(engine, engine.compiler.heap.read(index))
+    }
+
+    fn from_entry(engine: &'a Engine, entry: HeapEntry) -> Self {
+        let heap = &engine.compiler.heap;
+        let entry = engine.compiler.deref(entry);
+        if entry.tag == HeapTag::Reference && heap.read(entry.data).data == 1 {
+            // Top-level simple term
+            return TermRef::from_entry(engine, heap.read(entry.data + 1));
+        }
+        TermRef { engine, entry }
+    }
+
+    /**
+     * Returns the index of the cell of an unbound variable.
+     */
+    pub fn variable(&self) -> Option<HeapIndex> {
+        match self.entry.tag {
+            HeapTag::Variable | HeapTag::Unify => Some(self.entry.data),
+            _ => None,
+        }
+    }
+
+    pub fn atom(&self) -> Option<Cow<'a, str>> {
+        match self.entry.tag {
+            HeapTag::Constant => Some(self.engine.compiler.symbol_table.get(self.entry.data)),
+            _ => None,
+        }
+    }
+
+    pub fn number(&self) -> Option<i64> {
+        match self.entry.tag {
+            HeapTag::Number => Some(self.entry.number_value()),
+            _ => None,
+        }
+    }
+
+    /**
+     * Returns the functor of a compound term. It is a variable functor's
+     * binding, which may itself be a compound term.
+     */
+    pub fn functor(&self) -> Option<TermRef<'a>> {
+        match self.entry.tag {
+            HeapTag::Reference => Some(TermRef::new(self.engine, self.entry.data + 1)),
+            _ => None,
+        }
+    }
+
+    /**
+     * Returns the number of arguments of a compound term, 0 for other terms.
+     */
+    pub fn arity(&self) -> usize {
+        match self.entry.tag {
+            HeapTag::Reference => self.engine.compiler.heap.read(self.entry.data).data - 1,
+            _ => 0,
+        }
+    }
+
+    /**
+     * Returns the argument at a 0-based position.
+     */
+    pub fn arg(&self, position: usize) -> Option<TermRef<'a>> {
+        if position >= self.arity() {
+            return None;
+        }
+        Some(TermRef::new(self.engine, self.entry.data + 2 + position))
+    }
+
+    pub fn args(&self) -> impl Iterator<Item = TermRef<'a>> + 'a {
+        let term = *self;
+        (0..self.arity()).map(move |i| term.arg(i).unwrap())
+    }
+
+    /**
+     * Copies the term into an ast::Term.
+     */
+    pub fn to_term(&self) -> Term {
+        self.engine.decompile_entry(self.entry)
+    }
+
+    /**
+     * Writes the term with the given options instead of the engine's, e.g.
+     * WriteOptions::default() to write it in full whatever the engine's
+     * limits are.
+     */
+    pub fn write_with(&self, options: WriteOptions) -> String {
+        let mut out = String::new();
+        write_term_with_options(&mut out, &self.to_term(), options);
+        out
+    }
+}
+
+impl fmt::Display for TermRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let options = &self.engine.options;
+        f.write_str(&self.write_with(WriteOptions {
+            number_format: options.number_format,
+            max_depth: options.max_depth,
+            max_list_length: options.max_list_length,
+        }))
+    }
+}
+
+impl fmt::Debug for TermRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TermRef({})", self)
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Solutions<'_> {
+    fn drop(&mut self) {
+        self.engine.compiler.spines.clear();
+        self.engine.backtrack(self.state.base, self.state.trail_top);
+        self.engine.refresh_subscriptions();
+    }
+}
+
+/**
+ * Goals of the condition, then and else branches of an if-then-else goal.
+ */
+type Branches = (Vec<HeapEntry>, Vec<HeapEntry>, Option<Vec<HeapEntry>>);
+
+/**
+ * Returns a variable name made of letters for the nth distinct variable:
+ * A, B, ..., Z, AA, AB, ...
+ */
+fn variable_name(mut n: usize) -> String {
+    let mut name = Vec::new();
+    loop {
+        name.push(b'A' + (n % 26) as u8);
+        if n < 26 {
+            break;
+        }
+        n = n / 26 - 1;
+    }
+    name.reverse();
+    String::from_utf8(name).unwrap()
+}
+
+/**
+ * Returns the answers in new but not in old, and those in old but not in
+ * new. Answers found more than once are counted.
+ */
+fn answer_delta(old: &[Answer], new: &[Answer]) -> (Vec<Answer>, Vec<Answer>) {
+    let mut added = new.to_vec();
+    let mut removed = Vec::new();
+    for answer in old {
+        match added.iter().position(|candidate| candidate == answer) {
+            Some(position) => {
+                added.remove(position);
+            },
+            None => removed.push(answer.clone()),
+        }
+    }
+    (added, removed)
+}
+
+/**
+ * Returns false if two sets of registers can't unify because some position
+ * holds different non-variable entries.
+ */
+fn registers_match(a: &[HeapEntry], b: &[HeapEntry]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|(a, b)| a.is_var_or_unify() || b.is_var_or_unify() || a == b)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::machine::*;
+    use crate::parser::parse;
+    use crate::writer::Radix;
+
+    fn solve(code: &str) -> Vec<bool> {
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        Engine::new(compiler).solve()
+    }
+
+    #[test]
+    fn test_solve_facts() {
+        let code = "parent tom bob \n\
+                    parent bob ann \n\
+                    ? parent tom bob \n\
+                    ? parent tom ann \n\
+                    ? parent X ann \n\
+                    ? missing";
+        assert_eq!(vec![true, false, true, false], solve(code));
+    }
+
+    #[test]
+    fn test_solve_rules() {
+        let code = "parent tom bob \n\
+                    parent bob ann \n\
+                    grandparent X Z if parent X Y and parent Y Z \n\
+                    ? grandparent tom ann \n\
+                    ? grandparent bob ann \n\
+                    ? grandparent X Y";
+        assert_eq!(vec![true, false, true], solve(code));
+    }
+
+    #[test]
+    fn test_solve_backtracks_over_clauses() {
+        // The first edge out of a leads to a dead end
+        let code = "edge a b \n\
+                    edge a c \n\
+                    edge c d \n\
+                    path X Y if edge X Y \n\
+                    path X Y if edge X Z and path Z Y \n\
+                    ? path a d \n\
+                    ? path d a";
+        assert_eq!(vec![true, false], solve(code));
+    }
+
+    #[test]
+    fn test_solve_conjunctive_query() {
+        // Bindings made by the first goal constrain the second
+        let code = "p a \n p b \n q b \n\
+                    ? (p X) (q X) \n\
+                    ? (q X) (p X) \n\
+                    ? (p a) (q a)";
+        assert_eq!(vec![true, true, false], solve(code));
+    }
+
+    #[test]
+    fn test_solve_nested_terms() {
+        let code = "len nil zero \n\
+                    len (cons H T) (s N) if len T N \n\
+                    ? len (cons a (cons b nil)) (s (s zero)) \n\
+                    ? len (cons a nil) (s (s zero)) \n\
+                    ? len L (s (s zero))";
+        assert_eq!(vec![true, false, true], solve(code));
+    }
+
+    #[test]
+    fn test_solve_repeated_variable() {
+        let code = "same X X \n ? same a a \n ? same a b \n ? same (f Y) (f b)";
+        assert_eq!(vec![true, false, true], solve(code));
+    }
+
+    #[test]
+    fn test_solve_variable_goal() {
+        let code = "q a \n call X if X \n ? call (q a) \n ? call (q b) \n ? call Y";
+        assert_eq!(vec![true, false, false], solve(code));
+    }
+
+    #[test]
+    fn test_solve_with_structure_sharing() {
+        let code = "p (f a b) \n q (f a b) \n r X if p X and q X \n ? r (f a b) \n ? r (f a c)";
+        let mut compiler = Compiler::new();
+        compiler.set_structure_sharing(true);
+        compiler.compile(parse(code).unwrap());
+        assert_eq!(vec![true, false], Engine::new(compiler).solve());
+    }
+
+    #[test]
+    fn test_solve_restores_heap() {
+        let code = "p a \n p b \n r X if p X \n ? r b \n ? r c";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let heap_len = compiler.heap.len();
+
+        let mut engine = Engine::new(compiler);
+        assert_eq!(vec![true, false], engine.solve());
+        assert_eq!(heap_len, engine.compiler.heap.len());
+        assert!(engine.compiler.trail.is_empty());
+        assert!(engine.compiler.spines.is_empty());
+
+        // Queries only run once
+        assert!(engine.solve().is_empty());
+    }
+
+    #[test]
+    fn test_failed_branch_bindings_are_undone() {
+        // X = a is tried first, then undone when check fails
+        let code = "pick X Y if choose X and check X Y \n\
+                    choose a \n\
+                    choose b \n\
+                    check b yes \n\
+                    ? pick X Y";
+        assert_eq!(vec!["X = b, Y = yes"], solutions(code));
+
+        // The first clause binds Y = b before its second argument fails to
+        // unify, so the second clause must see Y unbound
+        let code = "p b (f c) \n p Z (f a) \n ? p Y (f a)";
+        let answers = solutions(code);
+        assert_eq!(1, answers.len());
+        assert!(answers[0].starts_with("Y = _G"), "{}", answers[0]);
+
+        // Every answer starts over from the same heap
+        let code = "q a \n q b \n q c \n r X Y if q X and q Y \n ? r X X";
+        assert_eq!(vec!["X = a", "X = b", "X = c"], solutions(code));
+    }
+
+    #[test]
+    fn test_usage() {
+        let code = "p a \n p b \n r X if p X \n ? r b \n ? p c";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+        engine.solve();
+
+        // ? r b copies `r X if p X` (6 cells) and `p b` (3 cells); `p a` is
+        // ruled out without being copied, as are both clauses for ? p c
+        assert_eq!(
+            &[
+                Usage { inferences: 2, heap_cells: 9 },
+                Usage { inferences: 0, heap_cells: 0 },
+            ],
+            engine.usage()
+        );
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_eq!(
+            vec![true, false, true],
+            solve("age tom 42 \n age ann -3 \n ? age tom 0x2A \n ? age tom 41 \n ? age ann -3")
+        );
+
+        let code = "age tom 42 \n ? age tom X";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+        let mut solutions = engine.next_query().unwrap();
+        let view = solutions.next_view().unwrap();
+        assert_eq!(Some(42), view.get("X").unwrap().number());
+        assert_eq!(Term::number(42), view.get("X").unwrap().to_term());
+        drop(solutions);
+
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let number_format = NumberFormat { radix: Radix::Hexadecimal, digit_group: None };
+        let options = EngineOptions { number_format, ..Default::default() };
+        let mut engine = Engine::with_options(compiler, options);
+        let mut solutions = engine.next_query().unwrap();
+        assert_eq!("0x2A", solutions.next_view().unwrap().get("X").unwrap().to_string());
+    }
+
+    #[test]
+    fn test_term_ref_display_limits() {
+        let mut compiler = Compiler::new();
+        compiler.compile(parse("? (L = cons 1 (cons 2 (cons 3 (cons 4 nil)))) (T = f (g (h a)))").unwrap());
+        let options = EngineOptions { max_depth: Some(2), max_list_length: Some(3), ..Default::default() };
+        let mut engine = Engine::with_options(compiler, options);
+        let mut solutions = engine.next_query().unwrap();
+        let view = solutions.next_view().unwrap();
+        assert_eq!("cons 1 (cons 2 (cons 3 ...))", view.get("L").unwrap().to_string());
+        assert_eq!("f (g ...)", view.get("T").unwrap().to_string());
+        assert_eq!("f (g (h a))", view.get("T").unwrap().write_with(WriteOptions::default()));
+    }
+
+    #[test]
+    fn test_solve_all() {
+        let code = "parent tom bob \n parent tom liz \n parent bob ann \n ? parent X ann";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+        let heap_len = engine.compiler.heap.len();
+
+        let query = Query::new(vec![Term::compound("parent", vec![
+            Term::atom("tom"), Term::compound("f", vec![Term::variable("X")])
+        ])]);
+        assert!(engine.solve_all(&query).is_empty());
+
+        let query = &parse("? (parent tom X) (parent X Y)").unwrap().queries[0];
+        let answers: Vec<String> = engine.solve_all(query)
+            .iter()
+            .map(|answer| format!("{} {}", answer.get("X").unwrap(), answer.get("Y").unwrap()))
+            .collect();
+        assert_eq!(vec!["bob ann"], answers);
+        assert_eq!(heap_len, engine.compiler.heap.len());
+
+        // The compiled query is still pending
+        let answer = engine.next_query().unwrap().next().unwrap();
+        assert_eq!(Some(&Term::atom("bob")), answer.get("X"));
+    }
+
+    #[test]
+    fn test_solve_all_with_structure_sharing() {
+        let mut compiler = Compiler::new();
+        compiler.set_structure_sharing(true);
+        compiler.consult_str("s X X Y Y").unwrap();
+        let mut engine = Engine::new(compiler);
+        assert_eq!(1, engine.solve_all(&parse("? s A (g b) B C").unwrap().queries[0]).len());
+
+        // The first query's subterms were discarded, so they can't be shared
+        let answers = engine.solve_all(&parse("? s Z (h c d e) Y (k (g b))").unwrap().queries[0]);
+        let answer: Vec<String> = answers[0].bindings().iter().map(|(name, term)| format!("{} = {}", name, term)).collect();
+        assert_eq!(vec!["Z = h c d e", "Y = k (g b)"], answer);
+    }
+
+    #[test]
+    fn test_step() {
+        let code = "nat z \n nat (s X) if nat X \n p a \n p b \n\
+                    ? nat (s (s (s z))) \n ? p X \n ? nat a";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+
+        // Proving nat (s (s (s z))) takes 4 inferences
+        assert_eq!(Some(StepResult::Yielded), engine.step(3));
+        assert!(matches!(engine.step(3), Some(StepResult::Solution(_))));
+        assert_eq!(Some(StepResult::Done), engine.step(3));
+
+        let mut answers = Vec::new();
+        while let Some(StepResult::Solution(answer)) = engine.step(1) {
+            answers.push(answer.get("X").unwrap().to_string());
+        }
+        assert_eq!(vec!["a", "b"], answers);
+
+        assert_eq!(Some(StepResult::Failed), engine.step(10));
+        assert_eq!(None, engine.step(10));
+    }
+
+    #[test]
+    fn test_step_abandoned() {
+        let code = "loop X if loop X \n p a \n ? loop a \n ? p X";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+        let heap_len = engine.compiler.heap.len();
+
+        assert_eq!(Some(StepResult::Yielded), engine.step(100));
+        // Running the next query another way drops the stepped one
+        let answer = engine.next_query().unwrap().next().unwrap();
+        assert_eq!(Some(&Term::atom("a")), answer.get("X"));
+        assert_eq!(heap_len, engine.compiler.heap.len());
+        assert!(engine.compiler.trail.is_empty());
+    }
+
+    #[test]
+    fn test_step_interleaved() {
+        let code = "loop X if loop X \n p a \n p b \n q (f Y) Y \n ? loop a \n ? p X \n ? (q Z W) (p W) \n ? p c";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+        let heap_len = engine.compiler.heap.len();
+
+        // The query that loops forever doesn't keep the others from running
+        let mut answers: Vec<Vec<String>> = vec![Vec::new(); 4];
+        let mut ended: Vec<Option<StepResult>> = (0..4).map(|_| None).collect();
+        for _ in 0..100 {
+            let (query, result) = engine.step_interleaved(1).unwrap();
+            assert_eq!(None, ended[query]);
+            match result {
+                StepResult::Solution(answer) => answers[query].push(
+                    answer.bindings()
+                        .iter()
+                        .map(|(name, term)| format!("{} = {}", name, term))
+                        .collect::<Vec<String>>()
+                        .join(", "),
+                ),
+                StepResult::Yielded => (),
+                result => ended[query] = Some(result),
+            }
+        }
+        assert_eq!(vec!["X = a", "X = b"], answers[1]);
+        assert_eq!(vec!["Z = f a, W = a", "Z = f b, W = b"], answers[2]);
+        assert_eq!(
+            vec![None, Some(StepResult::Done), Some(StepResult::Done), Some(StepResult::Failed)],
+            ended
+        );
+
+        // Only the looping query is left
+        assert_eq!(Some((0, StepResult::Yielded)), engine.step_interleaved(10));
+        assert_eq!(0, engine.compact_clauses());
+        assert_eq!(None, engine.next_query().map(|_| ()));
+        // Running a query another way leaves the parked ones alone
+        assert_eq!(1, engine.solve_all(&parse("? p a").unwrap().queries[0]).len());
+        assert_eq!(Some((0, StepResult::Yielded)), engine.step_interleaved(10));
+        assert_eq!(heap_len, engine.compiler.heap.len());
+        assert!(engine.compiler.trail.is_empty());
+    }
+
+    #[test]
+    fn test_index_statistics() {
+        let code = "edge a b \n edge a c \n edge b c \n edge c d \n path X Y if edge X Y \n twin X X \n\
+                    ? edge X d \n ? path a Y \n ? edge (f X) Y \n ? twin a b";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let options = EngineOptions { index_statistics: true, ..Default::default() };
+        let mut engine = Engine::with_options(compiler, options);
+        while let Some(solutions) = engine.next_query() {
+            solutions.for_each(drop);
+        }
+
+        // The argument registers rule out most edges before unifying, but
+        // not the twin whose head only fails to unify
+        let statistics = |name: &str, calls, candidates, tried, unified| {
+            IndexStatistics { name: name.to_string(), arity: 2, calls, candidates, tried, unified }
+        };
+        assert_eq!(
+            vec![statistics("edge", 3, 12, 3, 3), statistics("path", 1, 1, 1, 1), statistics("twin", 1, 1, 1, 0)],
+            engine.index_statistics()
+        );
+        assert_eq!(Some(1.0), engine.index_statistics()[0].hit_rate());
+        assert_eq!(Some(0.0), engine.index_statistics()[2].hit_rate());
+
+        engine.reset_index_statistics();
+        assert!(engine.index_statistics().is_empty());
+        // Nothing is counted unless enabled
+        assert_eq!(1, engine.solve_all(&parse("? edge a b").unwrap().queries[0]).len());
+        assert!(Engine::new(Compiler::new()).index_statistics().is_empty());
+    }
+
+    #[test]
+    fn test_is_last() {
+        let code = "p a \n p b \n q a \n r X if p X and q X \n\
+                    ? p X \n ? p b \n ? r X \n ?";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+
+        let mut solutions = engine.next_query().unwrap();
+        assert!(!solutions.is_last());
+        solutions.next().unwrap();
+        assert!(!solutions.is_last());
+        solutions.next().unwrap();
+        assert!(solutions.is_last());
+        drop(solutions);
+
+        // `p a` can't match `p b`, so it isn't a choice point
+        let mut solutions = engine.next_query().unwrap();
+        solutions.next().unwrap();
+        assert!(solutions.is_last());
+        drop(solutions);
+
+        // p b is still to be tried, though q b then fails
+        let mut solutions = engine.next_query().unwrap();
+        solutions.next().unwrap();
+        assert!(!solutions.is_last());
+        assert_eq!(None, solutions.next());
+        drop(solutions);
+
+        let mut solutions = engine.next_query().unwrap();
+        solutions.next().unwrap();
+        assert!(solutions.is_last());
+    }
+
+    #[test]
+    fn test_save_and_restore_continuation() {
+        let code = "nat z \n nat (s X) if nat X \n p a \n ? p X \n ? nat X \n ? p Y";
+        let engine = || {
+            let mut compiler = Compiler::new();
+            compiler.compile(parse(code).unwrap());
+            Engine::new(compiler)
+        };
+        let solution = |result: Option<StepResult>| match result {
+            Some(StepResult::Solution(answer)) => answer.get("X").unwrap().to_string(),
+            result => panic!("expected a solution, got {:?}", result),
+        };
+
+        let mut original = engine();
+        assert!(original.save_continuation().is_none());
+        assert_eq!("a", solution(original.step(10)));
+        assert_eq!(Some(StepResult::Done), original.step(10));
+        assert_eq!("z", solution(original.step(10)));
+        assert_eq!("s z", solution(original.step(10)));
+        assert_eq!(Some(StepResult::Yielded), original.step(1));
+        let saved = original.save_continuation().unwrap();
+
+        let mut restored = engine();
+        restored.restore_continuation(&saved).unwrap();
+        for _ in 0..3 {
+            let expected = solution(original.step(10));
+            assert_eq!(expected, solution(restored.step(10)));
+        }
+        // The next query comes after the restored one
+        restored.next_query().unwrap();
+        assert!(restored.next_query().is_none());
+
+        let mut other = Engine::new(Compiler::new());
+        assert!(other.restore_continuation(&saved).is_err());
+        let mut corrupt = engine();
+        assert!(corrupt.restore_continuation(&saved[..saved.len() - 1]).is_err());
+        assert!(corrupt.restore_continuation(b"nonsense").is_err());
+
+        // Numbers are saved with all their bits
+        let engine = || {
+            let mut compiler = Compiler::new();
+            compiler.compile(parse("n -5000000000 \n n 7 \n p X Y if n X and n Y \n ? p X Y").unwrap());
+            Engine::new(compiler)
+        };
+        let mut original = engine();
+        assert_eq!("-5000000000", solution(original.step(10)));
+        let saved = original.save_continuation().unwrap();
+        let mut restored = engine();
+        restored.restore_continuation(&saved).unwrap();
+        assert_eq!("-5000000000", solution(restored.step(10)));
+    }
+
+    #[test]
+    fn test_listing() {
+        let code = "@priority 2\n\
+                    ancestor X Y if (parent X Y)\n\
+                    ancestor X Y if  parent X Z  and  ancestor Z Y\n\
+                    parent tom bob\n";
+        let engine = |retain_source| {
+            let mut compiler = Compiler::new();
+            compiler.set_retain_source(retain_source);
+            compiler.consult_str(code).unwrap();
+            Engine::new(compiler)
+        };
+
+        assert_eq!(
+            "@priority 2\n\
+             ancestor X Y if (parent X Y)\n\
+             ancestor X Y if  parent X Z  and  ancestor Z Y\n",
+            engine(true).listing("ancestor", 2)
+        );
+        assert_eq!(
+            "@priority 2\n\
+             ancestor A B if parent A B\n\
+             ancestor A B if parent A C and ancestor C B\n",
+            engine(false).listing("ancestor", 2)
+        );
+        assert_eq!("", engine(true).listing("ancestor", 3));
+    }
+
+    #[test]
+    fn test_last_call_optimization() {
+        // A chain of 1000 steps from a to the last node, with atoms made of
+        // letters only
+        let node = |i| format!("n{}", variable_name(i).to_lowercase());
+        let mut code: String = (0..1000).map(|i| format!("next {} {}\n", node(i), node(i + 1))).collect();
+        code.push_str(&format!("reach X if next X Y and reach Y \n reach {} \n ? reach {}", node(1000), node(0)));
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(&code).unwrap());
+        let mut engine = Engine::new(compiler);
+        let mut solutions = engine.next_query().unwrap();
+
+        assert!(solutions.next().is_some());
+        // Every step was deterministic, so no spines were kept
+        assert!(solutions.engine.compiler.spines.is_empty());
+        assert!(solutions.is_last());
+        assert_eq!(None, solutions.next());
+    }
+
+    #[test]
+    fn test_garbage_collection() {
+        // Every solution leaves a junk term behind below the choice point
+        let code = "count z \n count (s N) if junk N J and count N \n junk N (f N N N N) \n ? (count X) (count Y)";
+        let run = |gc_threshold| {
+            let mut compiler = Compiler::new();
+            compiler.compile(parse(code).unwrap());
+            // Collections must leave the heap consistent for the next steps
+            let options = EngineOptions { gc_threshold, validate_heap: true, ..Default::default() };
+            let mut engine = Engine::with_options(compiler, options);
+            let mut solutions = engine.next_query().unwrap();
+            let answers: Vec<String> = solutions
+                .by_ref()
+                .take(30)
+                .map(|answer| format!("{} {}", answer.get("X").unwrap(), answer.get("Y").unwrap()))
+                .collect();
+            (answers, solutions.engine.compiler.heap.len())
+        };
+
+        let (expected, heap_cells) = run(None);
+        let (answers, collected_heap_cells) = run(Some(16));
+        assert_eq!(expected, answers);
+        assert!(collected_heap_cells < heap_cells, "{} < {}", collected_heap_cells, heap_cells);
+    }
+
+    #[test]
+    fn test_max_inferences() {
+        let code = "loop X if loop X \n p a \n ? loop a \n ? p X";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let options = EngineOptions { max_inferences: Some(100), ..Default::default() };
+        let mut engine = Engine::with_options(compiler, options);
+
+        assert!(matches!(
+            engine.try_solve(),
+            Some(Outcome::Error(EngineError::Resource(ResourceError::InferenceLimit)))
+        ));
+        assert_eq!(100, engine.usage()[0].inferences);
+        // The budget is per query, and the engine is still usable
+        assert!(matches!(engine.try_solve(), Some(Outcome::Solutions(_))));
+    }
+
+    #[test]
+    fn test_deadline_and_cancellation() {
+        let code = "loop X if loop X \n p a \n ? loop a \n ? p X \n ? loop a \n ? p X";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+
+        assert!(matches!(
+            engine.solve_with_deadline(Duration::from_millis(10)),
+            Some(Outcome::Error(EngineError::Resource(ResourceError::TimeLimit)))
+        ));
+        assert!(matches!(engine.try_solve(), Some(Outcome::Solutions(_))));
+
+        let token = engine.cancellation_token();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            token.cancel();
+        });
+        assert!(matches!(engine.try_solve(), Some(Outcome::Error(EngineError::Cancelled))));
+        canceller.join().unwrap();
+        assert!(!engine.cancellation_token().is_cancelled());
+        assert!(matches!(engine.try_solve(), Some(Outcome::Solutions(_))));
+    }
+
+    /**
+     * Returns an engine for the given program along with References to the
+     * heads of its clauses.
+     */
+    fn compile_heads(code: &str) -> (Engine, Vec<HeapEntry>) {
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let heads = compiler.clauses.iter().map(|clause| clause.terms[0]).collect();
+        (Engine::new(compiler), heads)
+    }
+
+    #[test]
+    fn test_unify_binds_and_trails() {
+        // 0: p X _5 a    5: f Y    8: p b _13 Z    13: f c
+        let (mut engine, heads) = compile_heads("p X (f Y) a \n p b (f c) Z");
+        assert!(engine.unify(heads[0], heads[1]));
+
+        let heap = &engine.compiler.heap;
+        // X = b, Y = c, Z = a
+        assert_eq!(heap.read(10), heap.read(2));
+        assert_eq!(heap.read(15), heap.read(7));
+        assert_eq!(heap.read(4), heap.read(12));
+        let mut trail = engine.compiler.trail.clone();
+        trail.sort();
+        assert_eq!(vec![2, 7, 12], trail);
+
+        engine.backtrack(engine.compiler.heap.len(), 0);
+        assert_eq!(HeapEntry::new(HeapTag::Variable, 2), engine.compiler.heap.read(2));
+        assert_eq!(HeapEntry::new(HeapTag::Variable, 12), engine.compiler.heap.read(12));
+    }
+
+    #[test]
+    fn test_decompile() {
+        // 0: p X _5 a    5: f Y    8: p b _13 Z    13: f c
+        let (mut engine, heads) = compile_heads("p X (f Y) a \n p b (f c) Z");
+        assert_eq!("p _G2 (f _G7) a", engine.decompile(0).to_string());
+        assert_eq!("f c", engine.decompile(13).to_string());
+        // A cell inside a term
+        assert_eq!("f _G7", engine.decompile(3).to_string());
+        assert_eq!("a", engine.decompile(4).to_string());
+
+        assert!(engine.unify(heads[0], heads[1]));
+        assert_eq!("p b (f c) a", engine.decompile(0).to_string());
+        assert_eq!("p b (f c) a", engine.decompile(8).to_string());
+        assert_eq!("b", engine.decompile(2).to_string());
+    }
+
+    #[test]
+    fn test_unify_variables() {
+        // 0: p X X    4: p Y Z
+        let (mut engine, heads) = compile_heads("p X X \n p Y Z");
+        assert!(engine.unify(heads[0], heads[1]));
+
+        // Y and Z were bound to the older X
+        let heap = &engine.compiler.heap;
+        assert_eq!(HeapEntry::new(HeapTag::Variable, 2), heap.read(2));
+        assert_eq!(HeapEntry::new(HeapTag::Unify, 2), heap.read(6));
+        assert_eq!(HeapEntry::new(HeapTag::Variable, 2), engine.compiler.deref(heap.read(7)));
+    }
+
+    #[test]
+    fn test_cut() {
+        let code = "max X Y X if ge X Y and ! \n\
+                    max X Y Y \n\
+                    ge two one \n\
+                    ge two two \n\
+                    ? max two one M \n\
+                    ? max one two M";
+        assert_eq!(vec!["M = two"], solutions(code));
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+        engine.next_query();
+        assert_eq!(vec!["two"], engine.next_query().unwrap()
+            .map(|answer| answer.get("M").unwrap().to_string())
+            .collect::<Vec<_>>());
+
+        // Goals before the cut get no other solutions, goals after it do
+        let code = "p a \n p b \n q X Y if p X and ! and p Y \n q c c \n ? q X Y";
+        assert_eq!(vec!["X = a, Y = a", "X = a, Y = b"], solutions(code));
+
+        // The cut only commits the clause it appears in
+        let code = "p a \n p b \n first X if p X and ! \n r X Y if p X and first Y \n ? r X Y";
+        assert_eq!(vec!["X = a, Y = a", "X = b, Y = a"], solutions(code));
+
+        // A cut in a query discards the other solutions of the goals before
+        // it. On its own it has to be parenthesized, since `! (p Y)` would
+        // be a compound term.
+        let code = "p a \n p b \n ? (p X) (!) (p Y)";
+        assert_eq!(vec!["X = a, Y = a", "X = a, Y = b"], solutions(code));
+    }
+
+    #[test]
+    fn test_negation() {
+        let code = "man tom \n man bob \n married bob \n\
+                    bachelor X if man X and not married X \n\
+                    ? bachelor X";
+        assert_eq!(vec!["X = tom"], solutions(code));
+
+        // Nothing the negated goal binds is kept
+        let code = "p a \n q X if not (not p X) \n ? q Y";
+        let answers = solutions(code);
+        assert_eq!(1, answers.len());
+        assert!(answers[0].starts_with("Y = _G"), "{}", answers[0]);
+
+        let code = "p a \n\
+                    ? not p b \n\
+                    ? not p a \n\
+                    ? not p X \n\
+                    ? not q \n\
+                    ? (p X) (not p X)";
+        assert_eq!(vec![true, false, false, true, false], solve(code));
+
+        // A cut inside the negated goal only cuts that goal
+        let code = "p a \n p b \n r if p X and ! and eq X b \n eq X X \n\
+                    s X if p X and not r \n ? s X";
+        assert_eq!(vec!["X = a", "X = b"], solutions(code));
+    }
+
+    #[test]
+    fn test_if_then_else() {
+        let code = "lt one two \n lt two three \n eq X X \n\
+                    max X Y Z if (if lt X Y then eq Z Y else eq Z X) \n\
+                    ? max one two Z \n\
+                    ? max two one Z";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+        for expected in ["two", "two"] {
+            let answers: Vec<String> = engine.next_query().unwrap()
+                .map(|answer| answer.get("Z").unwrap().to_string())
+                .collect();
+            assert_eq!(vec![expected], answers);
+        }
+
+        // Only the first solution of the condition is used, the then branch
+        // and the goals after it can still backtrack
+        let code = "p a \n p b \n q c \n q d \n\
+                    r X Y if (if p X then q Y) \n\
+                    ? r X Y";
+        assert_eq!(vec!["X = a, Y = c", "X = a, Y = d"], solutions(code));
+
+        // Without an else branch a false condition fails
+        let code = "p a \n ? (if p b then p a) \n ? (if p b then p a else p a and p a)";
+        assert_eq!(vec![false, true], solve(code));
+
+        // A cut in a branch cuts the clause, one in the condition doesn't
+        let code = "p a \n p b \n\
+                    s X if (if p Y and ! then p X) \n\
+                    t X if (if p a then p X and !) \n\
+                    t c \n\
+                    ? s X \n\
+                    ? t X";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+        assert_eq!(2, engine.next_query().unwrap().count());
+        let answers: Vec<String> = engine.next_query().unwrap()
+            .map(|answer| answer.get("X").unwrap().to_string())
+            .collect();
+        assert_eq!(vec!["a"], answers);
+    }
+
+    #[test]
+    fn test_negation_error() {
+        let code = "p a \n ? (p X) (not Y)";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+        assert!(matches!(engine.try_solve(), Some(Outcome::Error(EngineError::Instantiation))));
+    }
+
+    #[test]
+    fn test_is() {
+        let code = "len nil 0 \n\
+                    len (cons H T) N if len T M and N is plus M 1 \n\
+                    ? len (cons a (cons b (cons c nil))) N";
+        assert_eq!(vec!["N = 3"], solutions(code));
+
+        let code = "? X is times (minus 2 9) (max 3 (abs -4)) \n\
+                    ? (X is div -7 2) (Y is mod -7 2) (Z is quot -7 2) (W is rem -7 2) (V is mod 7 -2)";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+        let answer = engine.next_query().unwrap().next().unwrap();
+        assert_eq!(Ok(-28), answer.get_i64("X"));
+        let answer = engine.next_query().unwrap().next().unwrap();
+        let values: Vec<i64> = ["X", "Y", "Z", "W", "V"].iter().map(|v| answer.get_i64(v).unwrap()).collect();
+        assert_eq!(vec![-4, 1, -3, -1, -1], values);
+
+        // Comparing with a bound result, and backtracking over bindings made
+        // by is
+        assert_eq!(vec!["X = 2"], solutions("n 1 \n n 2 \n n 3 \n ? (n X) (4 is times X 2)"));
+        assert_eq!(vec!["X = 1, Y = 2", "X = 2, Y = 3"], solutions("n 1 \n n 2 \n ? (n X) (Y is plus X 1)"));
+
+        let error = |code| {
+            let mut compiler = Compiler::new();
+            compiler.compile(parse(code).unwrap());
+            let mut engine = Engine::new(compiler);
+            let message = match engine.try_solve() {
+                Some(Outcome::Error(error)) => error.to_string(),
+                _ => panic!("expected an error: {}", code),
+            };
+            message
+        };
+        assert_eq!("instantiation error: arguments are not sufficiently instantiated", error("? X is plus Y 1"));
+        assert_eq!("type error: foo/0 is not an arithmetic function", error("? X is plus foo 1"));
+        assert_eq!("type error: plus/3 is not an arithmetic function", error("? X is plus 1 2 3"));
+        assert_eq!("evaluation error: division by zero", error("? X is mod 1 0"));
+        assert_eq!("evaluation error: integer overflow", error("? X is times 0x7FFFFFFFFFFFFFFF 2"));
+        assert_eq!("evaluation error: integer overflow", error("? X is shiftLeft 0x4000000000000000 1"));
+        assert_eq!("evaluation error: integer overflow", error("? X is gcd -0x8000000000000000 0"));
+        assert_eq!("evaluation error: undefined", error("? X is msb 0"));
+    }
+
+    #[test]
+    fn test_let() {
+        let code = "price apple 150 \n \
+                    total Item Qty Total if price Item Price and let Total = Price * Qty * (100 + 20) / 100 \n \
+                    ? total apple 3 T";
+        assert_eq!(vec!["T = 540"], solutions(code));
+        assert_eq!(vec!["X = 7"], solutions("? let X = 1 - 2 * -3"));
+        // A bound result is compared with the value
+        assert_eq!(vec!["X = 7"], solutions("? (X = 7) let X = 1 + 6"));
+        assert!(solutions("? (X = 8) let X = 1 + 6").is_empty());
+    }
+
+    #[test]
+    fn test_bitwise_arithmetic() {
+        let code = "? (A is shiftLeft 3 4) (B is shiftRight -16 2) (C is shiftLeft 16 -2) (D is shiftRight 1 70) \
+                      (E is bitAnd 12 10) (F is bitOr 12 10) (G is xor 12 10) (H is msb 1000) (I is gcd -12 18) \
+                      (J is gcd 0 0) (K is shiftLeft -1 63) (L is shiftRight -5 100)";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+        let answer = engine.next_query().unwrap().next().unwrap();
+        let values: Vec<i64> = ["A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L"]
+            .iter()
+            .map(|v| answer.get_i64(v).unwrap())
+            .collect();
+        assert_eq!(vec![48, -4, 4, 0, 8, 14, 6, 9, 6, 0, i64::MIN, -1], values);
+    }
+
+    #[test]
+    fn test_unification_goals() {
+        assert_eq!(vec!["X = f a, Y = a"], solutions("? (X = f Y) (Y = a)"));
+        assert!(solutions("? (X = a) (X = b)").is_empty());
+        assert_eq!(vec!["X = b"], solutions("p a \n p b \n ? (p X) (X \\= a)"));
+        // Trial unification doesn't bind anything
+        assert_eq!(vec!["X = z"], solutions("? (f X b \\= f a c) (X = z)"));
+        assert!(solutions("? (f X \\= f a)").is_empty());
+    }
+
+    #[test]
+    fn test_type_tests() {
+        assert_eq!(vec!["X = a"], solutions("? (var X) (nonvar f) (X = a) (nonvar X)"));
+        assert!(solutions("? (X = Y) (Y = a) (var X)").is_empty());
+        assert_eq!(1, solutions("? (atom a) (number -3) (compound (f a)) (compound (X a))").len());
+        assert!(solutions("? atom 3").is_empty());
+        assert!(solutions("? number a").is_empty());
+        assert!(solutions("? compound a").is_empty());
+        assert!(solutions("? atom (f a)").is_empty());
+
+        assert_eq!(vec!["X = f a (g b)"], solutions("? (X = f a (g b)) (ground X)"));
+        assert!(solutions("? ground (f a (g Y))").is_empty());
+        // The functor of a term can be a variable too
+        assert!(solutions("? ground (F a)").is_empty());
+        assert_eq!(vec!["X = f (g b), Y = b"], solutions("? (X = f (g Y)) (Y = b) (ground X)"));
+    }
+
+    #[test]
+    fn test_profile_predicate() {
+        let code = "nat z \n\
+                    nat (s X) if nat X \n\
+                    color red \n\
+                    color green \n\
+                    ? profilePred nat 1 \n\
+                    ? nat (s (s z)) \n\
+                    ? color X";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+        assert_eq!(1, engine.next_query().unwrap().count());
+        engine.profile_predicate("color", 1);
+        assert_eq!(1, engine.next_query().unwrap().count());
+        assert_eq!(2, engine.next_query().unwrap().count());
+
+        let profiles = engine.profile_statistics();
+        assert_eq!(2, profiles.len());
+        // Every recursive call is timed, and the failed retries of the
+        // second clause don't count as calls
+        assert_eq!(("nat", 1, 3, 3), (profiles[0].name.as_str(), profiles[0].arity, profiles[0].calls, profiles[0].exits));
+        assert_eq!(3, profiles[0].latencies.counts().iter().sum::<u64>());
+        assert!(profiles[0].max_time <= profiles[0].total_time);
+        assert_eq!((1, 2), (profiles[1].calls, profiles[1].exits));
+
+        // The exit goals don't change the solutions
+        assert_eq!(vec!["X = s z"], solutions("nat z \n nat (s X) if nat X \n ? (profilePred nat 1) (nat (s (s z))) (X = s z)"));
+        assert!(solutions("? profilePred P 2").is_empty());
+    }
+
+    #[test]
+    fn test_structural_equality() {
+        assert_eq!(1, solutions("? (X = Y) (X == Y)").len());
+        assert!(solutions("? X == Y").is_empty());
+        assert_eq!(1, solutions("? X \\== Y").len());
+        assert_eq!(vec!["X = f a"], solutions("? (X = f a) (X == f a) (X \\== f b)"));
+        // Equality doesn't bind anything
+        assert!(solutions("? f X == f a").is_empty());
+    }
+
+    #[test]
+    fn test_compare() {
+        let order = |x: &str, y: &str| solutions(&format!("? compare O ({}) ({})", x, y))[0].clone();
+        assert_eq!(vec!["O = less, X = 1"], solutions("? (compare O X 3) (X = 1)"));
+        assert_eq!("O = less", order("3", "a"));
+        assert_eq!("O = less", order("-5", "3"));
+        assert_eq!("O = greater", order("b", "a"));
+        assert_eq!("O = less", order("zebra", "f a"));
+        // Compound terms by arity, then functor, then arguments
+        assert_eq!("O = greater", order("f a b", "g a"));
+        assert_eq!("O = less", order("f a b", "g a a"));
+        assert_eq!("O = less", order("f a (g 1)", "f a (g 2)"));
+        assert_eq!("O = equal", order("f a (g b)", "f a (g b)"));
+        assert_eq!(vec!["X = a"], solutions("? (X = a) (compare equal X a)"));
+        assert!(solutions("? compare less a a").is_empty());
+    }
+
+    #[test]
+    fn test_term_construction() {
+        assert_eq!(vec!["N = f, A = 2"], solutions("? functor (f a (g b)) N A"));
+        assert_eq!(vec!["N = a, A = 0"], solutions("? functor a N A"));
+        assert_eq!(vec!["N = 7, A = 0"], solutions("? functor 7 N A"));
+        assert_eq!(vec!["T = f a b"], solutions("? (functor T f 2) (arg 1 T a) (arg 2 T b)"));
+        assert_eq!(vec!["T = 7"], solutions("? functor T 7 0"));
+        assert!(solutions("? functor T 3 2").is_empty());
+        assert!(solutions("? functor T f -1").is_empty());
+
+        assert_eq!(vec!["A = g b"], solutions("? arg 2 (f a (g b)) A"));
+        assert!(solutions("? arg 3 (f a (g b)) A").is_empty());
+        assert!(solutions("? arg 0 (f a) A").is_empty());
+
+        assert_eq!(vec!["L = cons f (cons a (cons (g b) nil))"], solutions("? f a (g b) =.. L"));
+        assert_eq!(vec!["L = cons a nil"], solutions("? a =.. L"));
+        assert_eq!(vec!["T = f a 3"], solutions("? T =.. (cons f (cons a (cons 3 nil)))"));
+        assert_eq!(vec!["T = a"], solutions("? T =.. (cons a nil)"));
+        // Arguments that are unbound variables are shared with the list
+        assert_eq!(vec!["T = g b, X = b"], solutions("? (T =.. (cons g (cons X nil))) (X = b)"));
+        assert!(solutions("? T =.. nil").is_empty());
+    }
+
+    #[test]
+    fn test_max_arity() {
+        let error = |code: &str| {
+            let mut compiler = Compiler::new();
+            compiler.compile(parse(code).unwrap());
+            let mut engine = Engine::new(compiler);
+            let error = match engine.try_solve() {
+                Some(Outcome::Error(error)) => error,
+                _ => panic!("expected an error: {}", code),
+            };
+            error
+        };
+        let max_arity = Engine::new(Compiler::new()).capabilities().max_arity;
+        assert_eq!(MAX_ARITY, max_arity);
+        assert_eq!(vec![format!("A = {}", MAX_ARITY)], solutions("? currentPrologFlag maxArity A"));
+
+        let representation_error = EngineError::Representation(RepresentationError::MaxArity);
+        assert_eq!(representation_error, error("? functor T f 0x7FFFFFFFFFFFFFFF"));
+        assert_eq!(representation_error, error(&format!("? functor T f {}", MAX_ARITY + 1)));
+        assert_eq!(
+            "representation error: arity is more than the maximum of 65535",
+            representation_error.to_string()
+        );
+        let answers = solutions(&format!("? (functor T f {}) (functor T N A)", MAX_ARITY));
+        assert!(answers[0].ends_with(", N = f, A = 65535"));
+
+        // L is f followed by MAX_ARITY arguments
+        let code = format!("? (functor T f {}) (T =.. L) (U =.. (cons g L))", MAX_ARITY);
+        assert_eq!(representation_error, error(&code));
+    }
+
+    #[test]
+    fn test_copy_term() {
+        assert_eq!(
+            vec!["X = f a b a, A = a, B = b, Y = f 1 2 1, Z = 1"],
+            solutions("? (X = f A B A) (copyTerm X Y) (Y = f 1 2 Z) (A = a) (B = b)")
+        );
+        // Bound variables are copied as their value
+        assert_eq!(vec!["X = a, Y = g a (h a)"], solutions("? (X = a) (copyTerm (g X (h X)) Y)"));
+        // The copy's variables are not the original's
+        assert_eq!(vec!["Y = 3, X = 4, V = 5"], solutions("? (copyTerm 3 Y) (copyTerm X V) (V = 5) (X = 4)"));
+    }
+
+    #[test]
+    fn test_sub_atom() {
+        assert_eq!(
+            vec!["B = 0, A = 2, S = ab", "B = 1, A = 1, S = bc", "B = 2, A = 0, S = cd"],
+            solutions("? subAtom abcd B 2 A S")
+        );
+        assert_eq!(vec!["B = 1, L = 2, A = 3", "B = 3, L = 2, A = 1"], solutions("? subAtom abcbcd B L A bc"));
+        // Every sub atom, by start and then length
+        assert_eq!(6, solutions("? subAtom ab B L A S").len());
+        assert_eq!(vec!["L = 1, S = b"], solutions("? subAtom abc 1 L 1 S"));
+        assert!(solutions("? subAtom abc B 4 A S").is_empty());
+        assert!(solutions("? subAtom (f a) B L A S").is_empty());
+        // Solutions are undone on backtracking, and the rest of the query
+        // runs for each
+        assert_eq!(vec!["B = 2, A = 0, S = c"], solutions("? (subAtom abc B 1 A S) (A = 0)"));
+
+        // The choice point is dropped with the last candidate
+        let mut compiler = Compiler::new();
+        compiler.compile(parse("? subAtom abc B 2 A S").unwrap());
+        let mut engine = Engine::new(compiler);
+        let mut solutions = engine.next_query().unwrap();
+        assert!(solutions.next().is_some());
+        assert_eq!(1, solutions.engine.compiler.spines.len());
+        assert!(solutions.next().is_some());
+        assert!(solutions.engine.compiler.spines.is_empty());
+        assert!(solutions.is_last());
+
+        let mut compiler = Compiler::new();
+        compiler.compile(parse("? subAtom X B L A S").unwrap());
+        let mut engine = Engine::new(compiler);
+        let mut solutions = engine.next_query().unwrap();
+        assert!(solutions.next().is_none());
+        assert_eq!(Some(&EngineError::Instantiation), solutions.error());
+    }
+
+    #[test]
+    fn test_predicate_property() {
+        let code = "parent a b \n parent b c \n ? predicateProperty (parent a b) P";
+        assert_eq!(vec!["P = defined", "P = numberOfClauses 2"], solutions(code));
+        let code = "parent a b \n parent b c \n ? (retract (parent a b)) (predicateProperty (parent a b) (numberOfClauses N))";
+        assert_eq!(vec!["N = 1"], solutions(code));
+        assert_eq!(vec![""], solutions("? (assertz done) (predicateProperty done dynamic)"));
+        assert_eq!(vec!["P = defined", "P = builtin"], solutions("? predicateProperty (atom a) P"));
+        assert!(solutions("parent a b \n ? predicateProperty (parent a) P").is_empty());
+
+        let mut compiler = Compiler::new();
+        compiler.compile(parse("? predicateProperty 1 P").unwrap());
+        let mut engine = Engine::new(compiler);
+        let mut solutions = engine.next_query().unwrap();
+        assert!(solutions.next().is_none());
+        assert_eq!(Some(&EngineError::NotCallable("1".to_string())), solutions.error());
+    }
+
+    #[test]
+    fn test_predicate_properties() {
+        let mut compiler = Compiler::new();
+        compiler.compile(parse("parent a b \n parent b c \n ? assertz (parent c d)").unwrap());
+        let mut engine = Engine::new(compiler);
+        assert_eq!(1, engine.next_query().unwrap().count());
+        assert_eq!(
+            vec![PredicateProperty::Defined, PredicateProperty::Dynamic, PredicateProperty::NumberOfClauses(3)],
+            engine.predicate_properties("parent", 2)
+        );
+        let builtin = vec![PredicateProperty::Defined, PredicateProperty::Builtin];
+        assert_eq!(builtin, engine.predicate_properties("assertz", 1));
+        assert!(engine.predicate_properties("parent", 1).is_empty());
+        assert!(engine.predicate_properties("missing", 0).is_empty());
+    }
+
+    #[test]
+    fn test_current_predicate() {
+        let code = "q a \n p a b \n p b c \n p a \n ? currentPredicate N A";
+        assert_eq!(vec!["N = p, A = 1", "N = p, A = 2", "N = q, A = 1"], solutions(code));
+        assert_eq!(vec!["A = 2"], solutions("p a b \n q a \n ? currentPredicate p A"));
+        // Predicates whose clauses were all retracted are left out
+        assert!(solutions("p a \n ? (retract (p a)) (currentPredicate p A)").is_empty());
+        assert!(solutions("? currentPredicate atom A").is_empty());
+    }
+
+    #[test]
+    fn test_current_flag() {
+        let flags: Vec<String> = solutions("? currentFlag F V")
+            .into_iter()
+            .map(|answer| answer.split(',').next().unwrap().to_string())
+            .collect();
+        let expected: Vec<String> = FLAGS.iter().map(|flag| format!("F = {}", flag)).collect();
+        assert_eq!(expected, flags);
+        assert_eq!(vec!["F = occursCheck"], solutions("? currentFlag F false"));
+        assert_eq!(vec!["V = fail"], solutions("? currentFlag unknown V"));
+        assert!(solutions("? currentFlag missing V").is_empty());
+    }
+
+    #[test]
+    fn test_current_op() {
+        assert_eq!(
+            vec!["N = =", "N = \\=", "N = ==", "N = \\==", "N = =..", "N = ?=", "N = is"],
+            solutions("? currentOp 700 xfx N")
+        );
+        assert_eq!(vec!["P = 700, T = xfx"], solutions("? currentOp P T is"));
+    }
+
+    #[test]
+    fn test_introspection_api() {
+        let mut compiler = Compiler::new();
+        compiler.compile(parse("q a \n p a b \n p a").unwrap());
+        let mut engine = Engine::new(compiler);
+        assert_eq!(
+            vec![("p".to_string(), 1), ("p".to_string(), 2), ("q".to_string(), 1)],
+            engine.current_predicates()
+        );
+        let flags = engine.current_flags();
+        assert_eq!(FLAGS.len(), flags.len());
+        assert!(flags.contains(&("maxArity", Term::number(MAX_ARITY as i64))));
+        assert!(engine.current_operators().contains(&(700, "xfx", "=")));
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let rules = "simplify (plus X zero) X \n simplify (times X one) X \n simplify (times X zero) zero \n";
+        assert_eq!(
+            vec!["Out = a"],
+            solutions(&format!("{}? rewrite simplify (times (plus a zero) one) Out", rules))
+        );
+        // Variables of the term stay shared with the result
+        assert_eq!(
+            vec!["Out = b, Y = b"],
+            solutions(&format!("{}? (rewrite simplify (plus Y zero) Out) (Y = b)", rules))
+        );
+        assert_eq!(vec!["Out = f a"], solutions(&format!("{}? rewrite simplify (f a) Out", rules)));
+        assert!(solutions(&format!("{}? rewrite simplify (plus a zero) b", rules)).is_empty());
+
+        // Rule sets added from Rust take precedence over facts
+        let mut compiler = Compiler::new();
+        compiler.compile(parse("swap a b \n ? rewrite swap (pair a b) Out").unwrap());
+        let mut engine = Engine::new(compiler);
+        let mut rules = RuleSet::new();
+        rules.add_function(|term| match term {
+            Term::Compound(CompoundTerm { name: SimpleTerm::Atom(name), parameters }) if name == "pair" => {
+                Some(Term::compound("swapped", vec![parameters[1].clone(), parameters[0].clone()]))
+            },
+            _ => None,
+        });
+        engine.add_rule_set("swap", rules);
+        let answers: Vec<String> =
+            engine.next_query().unwrap().map(|answer| answer.get("Out").unwrap().to_string()).collect();
+        assert_eq!(vec!["swapped b a"], answers);
+
+        let mut compiler = Compiler::new();
+        compiler.compile(parse("? rewrite commute (plus a b) Out").unwrap());
+        let mut engine = Engine::new(compiler);
+        let mut rules = RuleSet::new();
+        let plus = |x, y| Term::compound("plus", vec![Term::variable(x), Term::variable(y)]);
+        rules.add_rule(plus("X", "Y"), plus("Y", "X"));
+        rules.set_max_steps(10);
+        engine.add_rule_set("commute", rules);
+        let mut solutions = engine.next_query().unwrap();
+        assert!(solutions.next().is_none());
+        let error = solutions.error().unwrap();
+        assert_eq!(&EngineError::Resource(ResourceError::RewriteSteps(10)), error);
+        assert_eq!("resource error: no normal form found after 10 rewrite steps", error.to_string());
+    }
+
+    #[test]
+    fn test_unifiable() {
+        // The equations share the variables, which unifiable leaves unbound
+        assert_eq!(
+            vec!["U = cons (d = b) (cons (c = a) nil), X = c, Y = d"],
+            solutions("? (unifiable (f X b) (f a Y) U) (var X) (var Y) (X = c) (Y = d)")
+        );
+        assert_eq!(vec!["X = c, Y = d, U = cons (d = c) nil"], solutions("? (unifiable X Y U) (X = c) (Y = d)"));
+        assert_eq!(vec!["U = nil"], solutions("? unifiable (f a) (f a) U"));
+        assert!(solutions("? unifiable (f a) (f b) U").is_empty());
+    }
+
+    #[test]
+    fn test_decided() {
+        assert_eq!(
+            vec!["X = c, Y = d"],
+            solutions("? (f a ?= f a) (f a ?= g a) (f X ?= f X) (f X ?= g Y) (X = c) (Y = d)")
+        );
+        assert!(solutions("? f X ?= f a").is_empty());
+        // Nothing is bound either way
+        assert_eq!(vec!["X = b"], solutions("? (not (X ?= a)) (X = b)"));
+    }
+
+    #[test]
+    fn test_split_string() {
+        assert_eq!(
+            vec!["P = cons a (cons b (cons c nil))"],
+            solutions("? splitString axbxc x nil P")
+        );
+        // Several separators, and padding stripped from each part
+        assert_eq!(
+            vec!["P = cons ab (cons cd (cons ef nil))"],
+            solutions("? splitString ppabpxcdpyefp xy p P")
+        );
+        // No separators only strips the padding
+        assert_eq!(vec!["P = cons ab nil"], solutions("? splitString ppabpp nil p P"));
+        // Character code lists for characters atoms can't hold
+        assert_eq!(
+            vec!["P = cons a (cons b nil)"],
+            solutions("? splitString (cons 0'a (cons 0', (cons 0'  (cons 0'b nil)))) (cons 0', nil) (cons 0'  nil) P")
+        );
+        assert!(solutions("? splitString (f a) nil nil P").is_empty());
+
+        let mut compiler = Compiler::new();
+        compiler.compile(parse("? splitString abc S nil P").unwrap());
+        let mut engine = Engine::new(compiler);
+        let mut solutions = engine.next_query().unwrap();
+        assert!(solutions.next().is_none());
+        assert_eq!(Some(&EngineError::Instantiation), solutions.error());
+    }
+
+    #[test]
+    fn test_assert() {
+        assert_eq!(
+            vec!["X = a", "X = b", "X = c"],
+            solutions("? (assertz (p b)) (asserta (p a)) (assertz (p c)) (p X)")
+        );
+        assert_eq!(vec![""], solutions("? (assertz done) done"));
+        // Variables of the fact are fresh in each use of it
+        assert_eq!(vec!["A = 0, X = 1, Y = 2"], solutions("? (assertz (same A A)) (same X 1) (same Y 2) (A = 0)"));
+        // The goal being resolved keeps the clauses it started with
+        assert_eq!(vec!["X = a"], solutions("p a \n ? (p X) (assertz (p b))"));
+
+        // Asserted facts survive backtracking and later queries
+        let code = "p a \n p b \n ? (p X) (assertz (q X)) (X = b) \n ? q Y";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+        assert_eq!(1, engine.next_query().unwrap().count());
+        let answers: Vec<String> = engine.next_query().unwrap().map(|answer| answer.get_as("Y").unwrap()).collect();
+        assert_eq!(vec!["a", "b"], answers);
+        assert_eq!("q a\nq b\n", engine.listing("q", 1));
+
+        let mut compiler = Compiler::new();
+        compiler.compile(parse("? assertz X").unwrap());
+        let mut engine = Engine::new(compiler);
+        let mut solutions = engine.next_query().unwrap();
+        assert!(solutions.next().is_none());
+        assert_eq!(Some(&EngineError::Instantiation), solutions.error());
+
+        let mut compiler = Compiler::new();
+        compiler.compile(parse("? asserta 1").unwrap());
+        let mut engine = Engine::new(compiler);
+        let mut solutions = engine.next_query().unwrap();
+        assert!(solutions.next().is_none());
+        let error = solutions.error().unwrap();
+        assert_eq!(&EngineError::NotCallable("1".to_string()), error);
+        assert_eq!("type error: 1 is not callable", error.to_string());
+    }
+
+    #[test]
+    fn test_retract() {
+        let code = "p a \n p b \n p c \n ? (retract (p b)) (retract (p X)) (p Y)";
+        assert_eq!(vec!["X = a, Y = c"], solutions(code));
+        // Rules are not removed
+        assert!(solutions("p X if q X \n ? retract (p X)").is_empty());
+        // Retracted facts can still be used by goals that started before
+        let code = "p a \n p b \n ? (p X) (retract (p Y))";
+        assert_eq!(vec!["X = a, Y = a", "X = b, Y = b"], solutions(code));
+        assert_eq!(vec!["X = b"], solutions("? (assertz (p a)) (assertz (p b)) (retract (p a)) (p X)"));
+    }
+
+    #[test]
+    fn test_transaction() {
+        // The updates are only seen once committed
+        let code = "p a \n ? (beginTransaction) (assertz (p b)) (retract (p a)) (p a) (not (p b)) (commitTransaction) \
+                    (p b) (not (p a))";
+        assert_eq!(vec![""], solutions(code));
+        // A fact pending retraction isn't retracted twice
+        let code = "p a \n p b \n ? (beginTransaction) (retract (p X)) (retract (p Y)) (commitTransaction) (not (p a)) (not (p b))";
+        assert_eq!(vec!["X = a, Y = b"], solutions(code));
+        let code = "p a \n ? (beginTransaction) (asserta (p b)) (retract (p a)) (rollbackTransaction) (p X)";
+        assert_eq!(vec!["X = a"], solutions(code));
+        // Transactions don't nest
+        assert!(solutions("? (beginTransaction) (beginTransaction)").is_empty());
+        assert!(solutions("? (commitTransaction)").is_empty());
+        assert!(solutions("? (rollbackTransaction)").is_empty());
+
+        // A transaction stays open across queries
+        let code = "p a \n ? (beginTransaction) (assertz (p b)) \n ? p X \n ? commitTransaction \n ? p X";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+        let answers = |engine: &mut Engine| -> Vec<String> {
+            engine.next_query().unwrap().map(|answer| answer.get_as("X").unwrap_or_default()).collect()
+        };
+        assert_eq!(vec![""], answers(&mut engine));
+        assert_eq!(vec!["a"], answers(&mut engine));
+        assert_eq!(vec![""], answers(&mut engine));
+        assert_eq!(vec!["a", "b"], answers(&mut engine));
+
+        // The same from Rust
+        assert!(engine.begin_transaction());
+        assert!(!engine.begin_transaction());
+        engine.assert_fact(Term::compound("p", vec![Term::atom("c")]), true).unwrap();
+        assert_eq!(Ok(true), engine.retract_fact(&Term::compound("p", vec![Term::atom("a")])));
+        assert_eq!(Ok(false), engine.retract_fact(&Term::compound("p", vec![Term::atom("a")])));
+        assert_eq!("p a\np b\n", engine.listing("p", 1));
+        assert_eq!(0, engine.compact_clauses());
+        assert!(engine.commit_transaction());
+        assert!(!engine.commit_transaction());
+        assert_eq!("p c\np b\n", engine.listing("p", 1));
+        // Variables are fresh even if named like those of decompiled terms
+        assert_eq!(Ok(true), engine.retract_fact(&Term::compound("p", vec![Term::variable("_G100000")])));
+        assert_eq!("p b\n", engine.listing("p", 1));
+        assert_eq!(Err(EngineError::Instantiation), engine.assert_fact(Term::variable("X"), false));
+        assert_eq!(Err(EngineError::NotCallable("1".to_string())), engine.assert_fact(Term::number(1), false));
+    }
+
+    #[test]
+    fn test_foreign() {
+        fn with_tax(price: i64, rate: i64) -> Result<i64, String> {
+            if rate < 0 {
+                return Err(format!("negative rate {}", rate));
+            }
+            Ok(price * (100 + rate) / 100)
+        }
+        fn greetings(name: &str, times: u8) -> Result<Vec<String>, String> {
+            Ok(vec![format!("hi{}", name); times as usize])
+        }
+
+        let code = "? withTax 200 10 P \n ? withTax 200 10 220 \n ? withTax 200 10 1 \n \
+                    ? greetings tom 2 L \n ? withTax P 10 Q \n ? withTax a 10 P \n ? greetings tom 300 L \n \
+                    ? withTax 200 (-1) P";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+        foreign!(engine, "withTax", with_tax: fn(i64, i64));
+        foreign!(engine, "greetings", greetings: fn(&str, u8));
+
+        let answers: Vec<i64> = engine.next_query().unwrap().map(|answer| answer.get_i64("P").unwrap()).collect();
+        assert_eq!(vec![220], answers);
+        assert_eq!(1, engine.next_query().unwrap().count());
+        assert_eq!(0, engine.next_query().unwrap().count());
+        let answers: Vec<Vec<String>> =
+            engine.next_query().unwrap().map(|answer| answer.get_list_of("L").unwrap()).collect();
+        assert_eq!(vec![vec!["hitom", "hitom"]], answers);
+
+        let error = |engine: &mut Engine| {
+            let mut solutions = engine.next_query().unwrap();
+            assert!(solutions.next().is_none());
+            solutions.error().unwrap().to_string()
+        };
+        assert_eq!("instantiation error: arguments are not sufficiently instantiated", error(&mut engine));
+        assert_eq!("type error: argument 1 of withTax/3: expected number, found a", error(&mut engine));
+        assert_eq!("type error: argument 2 of greetings/3: 300 is out of range for u8", error(&mut engine));
+        assert_eq!("foreign error: withTax/3 failed: negative rate -1", error(&mut engine));
+
+        let properties = vec![PredicateProperty::Defined, PredicateProperty::Builtin];
+        assert_eq!(properties, engine.predicate_properties("withTax", 3));
+        assert!(engine.predicate_properties("withTax", 2).is_empty());
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let code = "stock apple 3 \n stock pear 0 \n available X if stock X N and not (N = 0) \n \
+                    ? (assertz (stock plum 1)) (retract (stock apple 3)) \n \
+                    ? assertz (price plum 2) \n \
+                    ? (beginTransaction) (assertz (stock fig 1)) \n \
+                    ? commitTransaction";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+
+        let deltas = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let subscriber = {
+            let deltas = Arc::clone(&deltas);
+            move |delta: &AnswerDelta| {
+                let items = |answers: &[Answer]| -> Vec<String> {
+                    answers.iter().map(|answer| answer.get_as("X").unwrap()).collect()
+                };
+                deltas.lock().unwrap().push((delta.subscription, items(&delta.added), items(&delta.removed)));
+            }
+        };
+        let query = &parse("? available X").unwrap().queries[0];
+        assert_eq!(0, engine.subscribe(query, subscriber));
+        let delta = |added: &[&str], removed: &[&str]| {
+            (0, added.iter().map(|s| s.to_string()).collect(), removed.iter().map(|s| s.to_string()).collect())
+        };
+        assert_eq!(vec![delta(&["apple"], &[])], *deltas.lock().unwrap());
+
+        // Notified once the query that changed the facts is done
+        assert_eq!(1, engine.next_query().unwrap().count());
+        assert_eq!(delta(&["plum"], &["apple"]), deltas.lock().unwrap()[1]);
+        // Not run again for predicates it doesn't call
+        let queries_run = engine.usage().len();
+        assert_eq!(1, engine.next_query().unwrap().count());
+        assert_eq!(queries_run + 1, engine.usage().len());
+        // Nor for updates held back by a transaction
+        assert_eq!(1, engine.next_query().unwrap().count());
+        assert_eq!(2, deltas.lock().unwrap().len());
+        assert_eq!(1, engine.next_query().unwrap().count());
+        assert_eq!(delta(&["fig"], &[]), deltas.lock().unwrap()[2]);
+
+        engine.assert_fact(parse("stock kiwi 0").unwrap().clauses[0].head.clone(), false).unwrap();
+        assert_eq!(3, deltas.lock().unwrap().len());
+        assert_eq!(Ok(true), engine.retract_fact(&parse("stock plum N").unwrap().clauses[0].head));
+        assert_eq!(delta(&[], &["plum"]), deltas.lock().unwrap()[3]);
+
+        assert!(engine.unsubscribe(0));
+        assert!(!engine.unsubscribe(0));
+        engine.assert_fact(parse("stock lime 1").unwrap().clauses[0].head.clone(), false).unwrap();
+        assert_eq!(4, deltas.lock().unwrap().len());
+        assert_eq!(0, engine.refresh_subscriptions());
+    }
+
+    #[test]
+    fn test_compact_clauses() {
+        let code = "p (f a) \n p b \n q (f a) \n \
+                    ? retract (p X) \n \
+                    ? (assertz (s 1)) (assertz (s 2)) (retract (s 1)) (p X) (q Y) \n \
+                    ? (p X) (s Z)";
+        let answers = |engine: &mut Engine| -> Vec<String> {
+            engine.next_query().unwrap().map(|answer| {
+                answer.bindings().iter().map(|(name, term)| format!("{} = {}", name, term)).collect::<Vec<_>>().join(", ")
+            }).collect()
+        };
+        let engine = |threshold| {
+            let mut compiler = Compiler::new();
+            // q's argument is shared with the clause that gets retracted
+            compiler.set_structure_sharing(true);
+            compiler.compile(parse(code).unwrap());
+            compiler.set_compaction_threshold(threshold);
+            let options = EngineOptions { validate_heap: true, ..Default::default() };
+            Engine::with_options(compiler, options)
+        };
+
+        // Compacted before the second query starts
+        let mut compacted = engine(Some(0));
+        assert_eq!(vec!["X = f a"], answers(&mut compacted));
+        let heap_cells = compacted.compiler.heap.len();
+        assert_eq!(vec!["X = b, Y = f a"], answers(&mut compacted));
+        assert_eq!(heap_cells - 3, compacted.compiler.heap.len());
+        assert_eq!("q (f a)\n", compacted.listing("q", 1));
+        // Retracted asserted clauses are dropped too, without moving the heap
+        assert_eq!(0, compacted.compact_clauses());
+        assert_eq!(3, compacted.compiler.clauses.len());
+        assert_eq!(vec!["X = b, Z = 2"], answers(&mut compacted));
+
+        // Only compacted when asked to
+        let mut engine = engine(None);
+        answers(&mut engine);
+        assert_eq!(6, engine.compiler.dead_clause_cells());
+        assert_eq!(3, engine.compact_clauses());
+        assert_eq!(vec!["X = b, Y = f a"], answers(&mut engine));
+        assert_eq!(vec!["X = b, Z = 2"], answers(&mut engine));
+    }
+
+    #[test]
+    fn test_explain() {
+        let code = "parent tom bob \n parent tom liz \n parent bob ann \n\
+                    ancestor X Y if parent X Y \n\
+                    ancestor X Y if parent X Z and ancestor Z Y \n\
+                    childless X if person X and not (parent X Y) \n\
+                    ? retract (parent tom liz)";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+        let query = "? (parent tom X) (ancestor X (f Y)) (childless Z) (X = bob) (not (parent X bob)) (missing) (Z)";
+        let explain = |engine: &mut Engine| -> Vec<String> {
+            let query = parse(query).unwrap().queries.remove(0);
+            engine.explain(&query).iter().map(GoalPlan::to_string).collect()
+        };
+        let heap_cells = engine.compiler.heap.len();
+        assert_eq!(
+            vec![
+                "parent tom X: parent/2, 2 of 3 clauses to try, argument 1 indexed",
+                "ancestor X (f Y): ancestor/2, 2 of 2 clauses to try, argument 2 indexed, calls parent/2 ancestor/2",
+                "childless Z: childless/1, 1 of 1 clauses to try, no argument indexed, calls person/1 parent/2",
+                "X = bob: builtin =/2",
+                "not (parent X bob): control construct",
+                "missing: missing/0 is not defined",
+                "Z: calls the term its variable is bound to",
+            ],
+            explain(&mut engine)
+        );
+        // The query was not run
+        assert_eq!(heap_cells, engine.compiler.heap.len());
+        assert!(engine.usage().is_empty());
+
+        assert_eq!(1, engine.next_query().unwrap().count());
+        let plans = explain(&mut engine);
+        assert_eq!("parent tom X: parent/2, 1 of 2 clauses to try, argument 1 indexed, dynamic", plans[0]);
+    }
+
+    #[test]
+    fn test_unknown_error() {
+        let error = |code: &str| {
+            let mut compiler = Compiler::new();
+            compiler.compile(parse(code).unwrap());
+            let options = EngineOptions { unknown: Unknown::Error, ..Default::default() };
+            let mut engine = Engine::with_options(compiler, options);
+            let mut solutions = engine.next_query().unwrap();
+            assert!(solutions.next().is_none());
+            solutions.error().map(EngineError::to_string)
+        };
+        let code = "parent tom bob \n grandparent X Z if parnet X Y and parent Y Z \n ? grandparent tom X";
+        assert_eq!(Some("existence error: unknown predicate parnet/2, did you mean parent/2?"), error(code).as_deref());
+        assert_eq!(
+            Some("existence error: unknown predicate copyterm/2, did you mean copyTerm/2?"),
+            error("? copyterm a X").as_deref()
+        );
+        assert_eq!(Some("existence error: unknown predicate q/0"), error("p a \n ? q").as_deref());
+        assert_eq!(
+            Some("domain error: unknown flag maxInference, did you mean maxInferences?"),
+            error("? currentPrologFlag maxInference X").as_deref()
+        );
+        // Predicates whose clauses were all retracted are still defined, and
+        // goals that match no clause just fail
+        assert_eq!(None, error("p a \n ? (retract (p a)) (p X)"));
+        assert_eq!(None, error("p a \n ? p b"));
+        // Undefined predicates fail by default
+        assert!(solutions("? parnet tom X").is_empty());
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let options = EngineOptions { max_inferences: Some(500), ..Default::default() };
+        let engine = Engine::with_options(Compiler::new(), options);
+        let capabilities = engine.capabilities();
+        assert_eq!(env!("CARGO_PKG_VERSION"), capabilities.version);
+        assert!(capabilities.builtins.contains(&String::from("is/2")));
+        assert_eq!(Some(500), capabilities.max_inferences);
+
+        let code = "? (currentPrologFlag version V) (currentPrologFlag maxInferences M) \
+                    (currentPrologFlag features F) (currentPrologFlag bounded B)";
+        let version = env!("CARGO_PKG_VERSION_MAJOR").parse::<i64>().unwrap() * 10000
+            + env!("CARGO_PKG_VERSION_MINOR").parse::<i64>().unwrap() * 100
+            + env!("CARGO_PKG_VERSION_PATCH").parse::<i64>().unwrap();
+        assert_eq!(vec![format!("V = {}, M = infinite, F = nil, B = true", version)], solutions(code));
+        assert!(solutions("? currentPrologFlag nonsense X").is_empty());
+    }
+
+    #[test]
+    fn test_cut_undoes_bindings_on_backtracking() {
+        // The cut makes t fail without trying its second clause. Y = a must
+        // be undone before s tries its second clause.
+        let code = "t X if eq X a and ! and fails \n\
+                    t X \n\
+                    s X if t X \n\
+                    s b \n\
+                    eq X X \n\
+                    ? s Y";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let heap_len = compiler.heap.len();
+        let mut engine = Engine::new(compiler);
+
+        let answers: Vec<String> = engine.next_query().unwrap()
+            .map(|answer| answer.get("Y").unwrap().to_string())
+            .collect();
+        assert_eq!(vec!["b"], answers);
+        assert_eq!(heap_len, engine.compiler.heap.len());
+        assert!(engine.compiler.trail.is_empty());
+    }
+
+    #[test]
+    fn test_export_and_import_facts() {
+        let code = "parent tom bob \n\
+                    parent bob (person ann) \n\
+                    likes X X \n\
+                    pair X Y Z Y \n\
+                    grandparent X Z if parent X Y and parent Y Z \n\
+                    edge rdf:type a \n\
+                    ? parent tom X";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let engine = Engine::new(compiler);
+
+        let dump = engine.export_facts(|_, _| true);
+        assert_eq!(
+            "# butterfly-prolog facts v1\n\
+             parent tom bob\n\
+             parent bob (person ann)\n\
+             likes A A\n\
+             pair A B C B\n\
+             edge rdf:type a\n",
+            dump
+        );
+        assert_eq!(
+            "# butterfly-prolog facts v1\nlikes A A\n",
+            engine.export_facts(|name, arity| name == "likes" && arity == 2)
+        );
+
+        let mut compiler = Compiler::new();
+        compiler.compile(parse("grandparent X Z if parent X Y and parent Y Z \n ? grandparent tom W").unwrap());
+        let mut engine = Engine::new(compiler);
+        assert_eq!(Ok(5), engine.import_facts(&dump));
+        let answer = engine.next_query().unwrap().next().unwrap();
+        assert_eq!(Some(&Term::compound("person", vec![Term::atom("ann")])), answer.get("W"));
+
+        assert!(engine.import_facts("p X if q X").is_err());
+        assert!(engine.import_facts("p a \n ? p a").is_err());
+    }
+
+    #[test]
+    fn test_reconsult_file() {
+        let path = std::env::temp_dir().join(format!("butterfly-prolog-reconsult-{}.pl", std::process::id()));
+        let mut compiler = Compiler::new();
+        std::fs::write(&path, "color red \n color green").unwrap();
+        compiler.consult_file(&path).unwrap();
+        compiler.consult_str("color blue").unwrap();
+        std::fs::write(&path, "color red \n color yellow").unwrap();
+        compiler.consult_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut engine = Engine::new(compiler);
+        let query = parse("? color X").unwrap().queries.remove(0);
+        let colors: Vec<String> = engine.solve_all(&query).iter().map(|answer| answer.get_as("X").unwrap()).collect();
+        assert_eq!(vec!["blue", "red", "yellow"], colors);
+        assert_eq!(
+            "# butterfly-prolog facts v1\ncolor blue\ncolor red\ncolor yellow\n",
+            engine.export_facts(|_, _| true)
+        );
+    }
+
+    #[test]
+    fn test_clause_annotations() {
+        let code = "@priority 5\n\
+                    @deprecated\n\
+                    fly X if bird X\n\
+                    fly plane\n\
+                    @source birds.csv\n\
+                    bird tweety\n";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let engine = Engine::new(compiler);
+
+        let annotations = engine.clause_annotations("fly", 1);
+        assert_eq!(2, annotations.len());
+        assert_eq!(vec!["priority", "deprecated"], annotations[0]
+            .iter()
+            .map(|annotation| annotation.name.as_str())
+            .collect::<Vec<_>>());
+        assert_eq!(vec!["5"], annotations[0][0].arguments);
+        assert!(annotations[1].is_empty());
+        assert_eq!("birds.csv", engine.clause_annotations("bird", 1)[0][0].arguments[0]);
+        assert!(engine.clause_annotations("bird", 2).is_empty());
+    }
+
+    #[test]
+    fn test_variable_name() {
+        assert_eq!("A", variable_name(0));
+        assert_eq!("Z", variable_name(25));
+        assert_eq!("AA", variable_name(26));
+        assert_eq!("BA", variable_name(52));
+    }
+
+    #[test]
+    fn test_priority_clause_order() {
+        let code = "flies X yes if bird X \n\
+                    @priority 10\n\
+                    flies X no if penguin X and ! \n\
+                    @priority 5\n\
+                    flies X maybe if bird X \n\
+                    bird tweety \n\
+                    bird pingu \n\
+                    penguin pingu \n\
+                    ? flies tweety R \n\
+                    ? flies pingu R";
+        let program = parse(code).unwrap();
+        let answers = |options| {
+            let mut compiler = Compiler::new();
+            compiler.compile(program.clone());
+            let mut engine = Engine::with_options(compiler, options);
+            let mut answers = Vec::new();
+            while let Some(solutions) = engine.next_query() {
+                answers.push(solutions
+                    .map(|answer| answer.get("R").unwrap().to_string())
+                    .collect::<Vec<_>>());
+            }
+            answers
+        };
+
+        assert_eq!(
+            vec![vec!["yes", "maybe"], vec!["yes", "no"]],
+            answers(EngineOptions::default())
+        );
+        // The exception is tried first and cuts away the other rules
+        let options = EngineOptions { clause_order: ClauseOrder::Priority, ..Default::default() };
+        assert_eq!(vec![vec!["maybe", "yes"], vec!["no"]], answers(options));
+    }
+
+    #[test]
+    fn test_search_strategies() {
+        let code = "parent tom bob \n parent bob ann \n\
+                    ancestor X Y if ancestor X Z and parent Z Y \n\
+                    ancestor X Y if parent X Y \n\
+                    ? ancestor tom Y";
+        let program = parse(code).unwrap();
+        let answers = |search, count| {
+            let mut compiler = Compiler::new();
+            compiler.compile(program.clone());
+            let options = EngineOptions { search, ..Default::default() };
+            let mut engine = Engine::with_options(compiler, options);
+            engine.next_query().unwrap()
+                .take(count)
+                .map(|answer| answer.get("Y").unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // Depth-first search would recurse forever before finding anything.
+        // The limited search still tries the recursive clause first.
+        assert_eq!(vec!["ann", "bob"], answers(SearchStrategy::DepthLimited(5), 10));
+        assert!(answers(SearchStrategy::DepthLimited(1), 10).is_empty());
+        // The shallowest solutions come first
+        assert_eq!(vec!["bob", "ann"], answers(SearchStrategy::IterativeDeepening, 2));
+
+        // Iterative deepening stops once the whole tree has been searched
+        let code = "p a \n p b \n q X if p X \n q c \n ? q X";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let options = EngineOptions { search: SearchStrategy::IterativeDeepening, ..Default::default() };
+        let mut engine = Engine::with_options(compiler, options);
+        let answers: Vec<String> = engine.next_query().unwrap()
+            .map(|answer| answer.get("X").unwrap().to_string())
+            .collect();
+        assert_eq!(vec!["c", "a", "b"], answers);
+
+        assert_eq!(Some(SearchStrategy::DepthLimited(3)), SearchStrategy::from_name("depth-limited:3"));
+        assert_eq!(None, SearchStrategy::from_name("depth-limited:"));
+    }
+
+    #[test]
+    fn test_occurs_check() {
+        // Variables starting with _ so that the cyclic terms aren't printed
+        let code = "eq X X \n ? eq _Y (f _Y) \n ? eq (g _Z a) (g (h b (k _Z)) a) \n ? eq W (f V)";
+        let program = parse(code).unwrap();
+
+        // Without the check the cyclic bindings are made
+        let mut compiler = Compiler::new();
+        compiler.compile(program.clone());
+        assert_eq!(vec![true, true, true], Engine::new(compiler).solve());
+
+        let mut compiler = Compiler::new();
+        compiler.compile(program);
+        let options = EngineOptions { occurs_check: true, ..Default::default() };
+        assert_eq!(vec![false, false, true], Engine::with_options(compiler, options).solve());
+    }
+
+    #[test]
+    fn test_unify_failure() {
+        let (mut engine, heads) = compile_heads("p a \n p b \n p a a \n q a \n p (f a) \n p (f a b)");
+        assert!(!engine.unify(heads[0], heads[1]));
+        // Different arity
+        assert!(!engine.unify(heads[0], heads[2]));
+        // Different name
+        assert!(!engine.unify(heads[0], heads[3]));
+        // Constant against compound
+        assert!(!engine.unify(heads[0], heads[4]));
+        assert!(!engine.unify(heads[4], heads[5]));
+        assert!(engine.compiler.trail.is_empty());
+
+        // Bindings made before a mismatch is found stay on the trail
+        let (mut engine, heads) = compile_heads("p b X \n p c a");
+        assert!(!engine.unify(heads[0], heads[1]));
+        assert_eq!(1, engine.compiler.trail.len());
+    }
+
+    /**
+     * Returns the bindings of every solution of the first query, written out
+     * like `X = a, Y = f b`.
+     */
+    fn solutions(code: &str) -> Vec<String> {
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+        let solutions = engine.next_query().unwrap();
+        solutions
+            .map(|answer| {
+                answer.bindings()
+                    .iter()
+                    .map(|(name, term)| format!("{} = {}", name, term))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_solutions() {
+        let code = "parent tom bob \n\
+                    parent tom liz \n\
+                    parent bob ann \n\
+                    ? parent tom X";
+        assert_eq!(vec!["X = bob", "X = liz"], solutions(code));
+
+        let code = "parent tom bob \n\
+                    parent bob ann \n\
+                    parent bob pat \n\
+                    grandparent X Z if parent X Y and parent Y Z \n\
+                    ? grandparent G C";
+        assert_eq!(vec!["G = tom, C = ann", "G = tom, C = pat"], solutions(code));
+    }
+
+    #[test]
+    fn test_solutions_compound_bindings() {
+        let code = "append nil L L \n\
+                    append (cons H T) L (cons H R) if append T L R \n\
+                    ? append X Y (cons a (cons b nil))";
+        assert_eq!(
+            vec![
+                "X = nil, Y = cons a (cons b nil)",
+                "X = cons a nil, Y = cons b nil",
+                "X = cons a (cons b nil), Y = nil",
+            ],
+            solutions(code)
+        );
+    }
+
+    #[test]
+    fn test_solutions_unbound_and_anonymous_variables() {
+        let code = "p X Y \n ? p A _B";
+        let answers = solutions(code);
+        assert_eq!(1, answers.len());
+        assert!(answers[0].starts_with("A = _G"));
+
+        assert_eq!(vec![""], solutions("? "));
+        assert!(solutions("p a \n ? p b").is_empty());
+    }
+
+    #[test]
+    fn test_solutions_are_lazy() {
+        // nat has infinitely many solutions
+        let code = "nat zero \n nat (s N) if nat N \n ? nat X \n ? nat (s zero)";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+
+        let answers: Vec<Answer> = engine.next_query().unwrap().take(3).collect();
+        assert_eq!(
+            Some(&Term::compound("s", vec![Term::compound("s", vec![Term::atom("zero")])])),
+            answers[2].get("X")
+        );
+        assert_eq!(None, answers[2].get("Y"));
+
+        let bindings: HashMap<String, Term> = answers[0].clone().into();
+        assert_eq!(Some(&Term::atom("zero")), bindings.get("X"));
+
+        // Dropping the iterator left the engine ready for the next query
+        assert!(engine.next_query().unwrap().next().is_some());
+        assert!(engine.next_query().is_none());
+    }
+
+    #[test]
+    fn test_try_solve() {
+        let code = "p a \n p b \n call X if X \n ? p X \n ? p c \n ? call Y \n ? (p a) (call Y)";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+
+        match engine.try_solve() {
+            Some(Outcome::Solutions(solutions)) => {
+                let answers: Vec<Answer> = solutions.collect();
+                assert_eq!(2, answers.len());
+                assert_eq!(Some(&Term::atom("a")), answers[0].get("X"));
+            },
+            _ => panic!("expected solutions"),
+        }
+        assert!(matches!(engine.try_solve(), Some(Outcome::Failure)));
+        assert!(matches!(engine.try_solve(), Some(Outcome::Error(EngineError::Instantiation))));
+
+        // The error is also reported after iterating
+        let mut solutions = engine.next_query().unwrap();
+        assert!(solutions.next().is_none());
+        assert_eq!(Some(&EngineError::Instantiation), solutions.error());
+        drop(solutions);
+
+        assert!(engine.try_solve().is_none());
+    }
+
+    #[test]
+    fn test_solution_views() {
+        let code = "parent tom bob \n\
+                    parent tom (person liz) \n\
+                    age bob Y \n\
+                    ? parent tom X \n\
+                    ? age bob Y \n\
+                    ? parent P bob";
+        let mut compiler = Compiler::new();
+        compiler.compile(parse(code).unwrap());
+        let mut engine = Engine::new(compiler);
+
+        let mut solutions = engine.next_query().unwrap();
+        let view = solutions.next_view().unwrap();
+        let x = view.get("X").unwrap();
+        assert_eq!(Some("bob"), x.atom().as_deref());
+        assert_eq!(0, x.arity());
+        assert_eq!(None, x.variable());
+        assert_eq!(None, view.get("Y").map(|term| term.to_string()));
+
+        let view = solutions.next_view().unwrap();
+        let x = view.get("X").unwrap();
+        assert_eq!(None, x.atom());
+        assert_eq!(1, x.arity());
+        assert_eq!(Some("person"), x.functor().unwrap().atom().as_deref());
+        assert_eq!(Some("liz"), x.arg(0).unwrap().atom().as_deref());
+        assert!(x.arg(1).is_none());
+        assert_eq!(vec!["liz"], x.args().map(|arg| arg.to_string()).collect::<Vec<_>>());
+        assert_eq!(Term::compound("person", vec![Term::atom("liz")]), x.to_term());
+        assert_eq!(Some(&x.to_term()), view.to_answer().get("X"));
+
+        assert!(solutions.next_view().is_none());
+        drop(solutions);
+
+        // Unbound variables
+        let mut solutions = engine.next_query().unwrap();
+        let view = solutions.next_view().unwrap();
+        let (name, y) = view.bindings().next().unwrap();
+        assert_eq!("Y", name);
+        assert!(y.variable().is_some());
+        drop(solutions);
+
+        // Views and answers can be mixed, also after try_solve
+        match engine.try_solve() {
+            Some(Outcome::Solutions(mut solutions)) => {
+                let view = solutions.next_view().unwrap();
+                assert_eq!("tom", view.get("P").unwrap().to_string());
+                assert!(solutions.next().is_none());
+            },
+            _ => panic!("expected solutions"),
+        };
+    }
+}
@@ -0,0 +1,173 @@
+/**
+ * Builds an `ast::Term` from Rust tokens.
+ *
+ * Identifiers follow the parser's naming rule (uppercase or `_` means
+ * variable), string literals are always atoms and `name(args, ...)` is a
+ * compound term:
+ *
+ * ```
+ * use butterfly_prolog::term;
+ * let term = term!(parent("Tom", X));
+ * ```
+ */
+#[macro_export]
+macro_rules! term {
+    ($name:ident ( $($args:tt)* )) => {
+        $crate::ast::Term::compound(stringify!($name), $crate::__terms!(@acc [] $($args)*))
+    };
+    ($name:literal ( $($args:tt)* )) => {
+        $crate::ast::Term::Compound($crate::ast::CompoundTerm {
+            name: $crate::ast::SimpleTerm::Atom(String::from($name)),
+            parameters: $crate::__terms!(@acc [] $($args)*),
+        })
+    };
+    ($name:ident) => {
+        $crate::ast::Term::from_name(stringify!($name))
+    };
+    ($name:literal) => {
+        $crate::ast::Term::atom($name)
+    };
+    (( $($term:tt)+ )) => {
+        $crate::term!($($term)+)
+    };
+}
+
+/**
+ * Builds an `ast::Clause`: `clause!(head)` for a fact or
+ * `clause!(head :- goal, goal)` for a rule.
+ *
+ * ```
+ * use butterfly_prolog::clause;
+ * let clause = clause!(grandparent(X, Z) :- parent(X, Y), parent(Y, Z));
+ * ```
+ */
+#[macro_export]
+macro_rules! clause {
+    (@head [$($head:tt)+] : - $($body:tt)+) => {
+        $crate::ast::Clause::rule(
+            $crate::term!($($head)+),
+            $crate::__terms!(@acc [] $($body)+)
+        )
+    };
+    (@head [$($head:tt)+]) => {
+        $crate::ast::Clause::fact($crate::term!($($head)+))
+    };
+    (@head [$($head:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::clause!(@head [$($head)* $next] $($rest)*)
+    };
+    ($($tokens:tt)+) => {
+        $crate::clause!(@head [] $($tokens)+)
+    };
+}
+
+/**
+ * Builds an `ast::Query` from comma separated goals.
+ */
+#[macro_export]
+macro_rules! query {
+    ($($goals:tt)*) => {
+        $crate::ast::Query::new($crate::__terms!(@acc [] $($goals)*))
+    };
+}
+
+/**
+ * Registers a Rust function as a foreign predicate of an Engine, e.g.
+ * `foreign!(engine, "withTax", with_tax: fn(i64, i64))` for
+ * `fn with_tax(price: i64, rate: i64) -> Result<i64, String>` makes
+ * `withTax 100 20 P` bind P to its result. Each argument is converted from
+ * its term with `extract::ForeignArgument`, and the value of an `Ok` result
+ * back to a term with `extract::IntoTerm`. An `Err` result stops the query
+ * with its message. See `Engine::register_foreign`.
+ *
+ * ```
+ * use butterfly_prolog::compiler::Compiler;
+ * use butterfly_prolog::foreign;
+ * use butterfly_prolog::machine::Engine;
+ *
+ * fn repeat(text: &str, times: i64) -> Result<String, String> {
+ *     let times = usize::try_from(times).map_err(|_| "negative count")?;
+ *     Ok(text.repeat(times))
+ * }
+ *
+ * let mut engine = Engine::new(Compiler::new());
+ * foreign!(engine, "repeat", repeat: fn(&str, i64));
+ * ```
+ */
+#[macro_export]
+macro_rules! foreign {
+    ($engine:expr, $name:expr, $function:path : fn ( $($argument:ty),* $(,)? )) => {
+        $engine.register_foreign($name, <[&str]>::len(&[$(stringify!($argument)),*]), |arguments| {
+            let mut arguments = arguments.iter().enumerate();
+            let result = $function($({
+                let (position, term) = arguments.next().unwrap();
+                <$argument as $crate::extract::ForeignArgument>::from_argument(term)
+                    .map_err(|error| $crate::machine::ForeignError::Argument { position, error })?
+            }),*);
+            result
+                .map($crate::extract::IntoTerm::into_term)
+                .map_err(|error| $crate::machine::ForeignError::Failed(error.to_string()))
+        })
+    };
+}
+
+/**
+ * Turns a comma separated list of term tokens into a `Vec<Term>`.
+ */
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __terms {
+    (@acc [$($done:expr),*]) => {
+        vec![$($done),*]
+    };
+    (@acc [$($done:expr),*] $name:tt ( $($args:tt)* ) $(, $($rest:tt)*)?) => {
+        $crate::__terms!(@acc [$($done,)* $crate::term!($name($($args)*))] $($($rest)*)?)
+    };
+    (@acc [$($done:expr),*] $term:tt $(, $($rest:tt)*)?) => {
+        $crate::__terms!(@acc [$($done,)* $crate::term!($term)] $($($rest)*)?)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_term_macro() {
+        assert_eq!(Term::atom("tom"), term!(tom));
+        assert_eq!(Term::variable("X"), term!(X));
+        assert_eq!(Term::variable("_x"), term!(_x));
+        assert_eq!(Term::atom("Tom"), term!("Tom"));
+
+        let program = parse("a (a (b e f)) c").unwrap();
+        assert_eq!(program.clauses[0].head, term!(a(a(b(e, f)), c)));
+    }
+
+    #[test]
+    fn test_term_macro_variable_functor() {
+        let expected = Term::Compound(CompoundTerm {
+            name: SimpleTerm::Variable(String::from("F")),
+            parameters: vec![Term::atom("a")],
+        });
+        assert_eq!(expected, term!(F(a)));
+    }
+
+    #[test]
+    fn test_clause_macro() {
+        let program = parse("grandparent X Z if parent X Y and parent Y Z \n parent tom bob").unwrap();
+
+        assert_eq!(
+            program.clauses[0],
+            clause!(grandparent(X, Z) :- parent(X, Y), parent(Y, Z))
+        );
+        assert_eq!(program.clauses[1], clause!(parent(tom, bob)));
+    }
+
+    #[test]
+    fn test_query_macro() {
+        let program = parse("? ancestor tom X").unwrap();
+        assert_eq!(program.queries[0], query!(ancestor(tom, X)));
+        assert_eq!(Query::new(vec![Term::atom("a"), Term::atom("b")]), query!(a, b));
+        assert_eq!(Query::new(Vec::new()), query!());
+    }
+}
@@ -1,11 +1,390 @@
-extern crate pest;
-#[macro_use]
-extern crate pest_derive;
+use std::env;
+use std::fs;
+use std::panic;
+use std::process;
 
-mod ast;
-mod parser;
-mod heap;
-mod compiler;
+use butterfly_prolog::analysis::{check_coverage, check_program, diagnostics_to_json, error_to_json, Severity};
+use butterfly_prolog::codegen::generate_rust;
+use butterfly_prolog::compiler::Compiler;
+use butterfly_prolog::expect::check_expectations;
+use butterfly_prolog::export::{export, Dialect};
+use butterfly_prolog::import::import;
+use butterfly_prolog::machine::{ClauseOrder, Engine, EngineOptions, Outcome, SearchStrategy, Unknown};
+use butterfly_prolog::minimize::{minimize, shows_symptom, Symptom};
+use butterfly_prolog::parser::{parse, parse_with_spans};
+use butterfly_prolog::writer::{write_program, write_query, Radix};
+
+/**
+ * Limits on how much of an answer `run` prints, unless told otherwise.
+ */
+const DEFAULT_MAX_DEPTH: usize = 10;
+const DEFAULT_MAX_LIST_LENGTH: usize = 10;
+
+const USAGE: &str = "\
+usage: butterfly-prolog run [--occurs-check] [--clause-order priority] [--search <strategy>]
+                           [--max-inferences <n>] [--radix <radix>] [--digit-group <n>]
+                           [--unknown error] [--max-depth <n>] [--max-list-length <n>] <file>
+       butterfly-prolog check [--deny-warnings] [--coverage] [--format json] <file>
+       butterfly-prolog test [--max-inferences <n>] <file>
+       butterfly-prolog explain <file>
+       butterfly-prolog export [--dialect swi] <file>
+       butterfly-prolog import [--dialect swi] <file>
+       butterfly-prolog minimize <file> --expect <error text>
+       butterfly-prolog minimize <file> --expect-answer <solution> --actual-answer <solution>
+       butterfly-prolog codegen [--format json] <file> [-o <out.rs>]";
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("run") => run_queries(&args[1..]),
+        Some("check") => run_check(&args[1..]),
+        Some("test") => run_tests(&args[1..]),
+        Some("explain") => run_explain(&args[1..]),
+        Some("export") => run_export(&args[1..]),
+        Some("import") => run_import(&args[1..]),
+        Some("minimize") => run_minimize(&args[1..]),
+        Some("codegen") => run_codegen(&args[1..]),
+        _ => Err(USAGE.to_string()),
+    };
+
+    match result {
+        Ok(output) => print!("{}", output),
+        Err(message) => {
+            // Failures reported as JSON are printed to stdout already
+            if !message.is_empty() {
+                eprintln!("{}", message);
+            }
+            process::exit(1);
+        }
+    }
+}
+
+fn run_queries(args: &[String]) -> Result<String, String> {
+    let mut options = EngineOptions {
+        max_depth: Some(DEFAULT_MAX_DEPTH),
+        max_list_length: Some(DEFAULT_MAX_LIST_LENGTH),
+        ..Default::default()
+    };
+    let mut path = None;
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--occurs-check" => options.occurs_check = true,
+            "--clause-order" => {
+                let name = it.next().ok_or(USAGE)?;
+                options.clause_order = ClauseOrder::from_name(name)
+                    .ok_or(format!("unknown clause order: {}", name))?;
+            },
+            "--search" => {
+                let name = it.next().ok_or(USAGE)?;
+                options.search = SearchStrategy::from_name(name)
+                    .ok_or(format!("unknown search strategy: {}", name))?;
+            },
+            "--radix" => {
+                let name = it.next().ok_or(USAGE)?;
+                options.number_format.radix = Radix::from_name(name)
+                    .ok_or(format!("unknown radix: {}", name))?;
+            },
+            "--digit-group" => {
+                let size = it.next().ok_or(USAGE)?;
+                options.number_format.digit_group = Some(size.parse().map_err(|_| format!("not a number: {}", size))?);
+            },
+            "--max-inferences" => {
+                let max = it.next().ok_or(USAGE)?;
+                options.max_inferences = Some(max.parse().map_err(|_| format!("not a number: {}", max))?);
+            },
+            "--max-depth" => options.max_depth = read_limit(it.next())?,
+            "--max-list-length" => options.max_list_length = read_limit(it.next())?,
+            "--unknown" => {
+                let name = it.next().ok_or(USAGE)?;
+                options.unknown = Unknown::from_name(name).ok_or(format!("unknown value of --unknown: {}", name))?;
+            },
+            _ => path = Some(arg),
+        }
+    }
+
+    let path = path.ok_or(USAGE)?;
+    let code = read_source(path)?;
+    let program = parse(&code).map_err(|e| format!("{}: {}", path, e))?;
+
+    let mut compiler = Compiler::new();
+    compiler.compile(program.clone());
+    let mut engine = Engine::with_options(compiler, options);
+
+    let mut report = String::new();
+    for query in &program.queries {
+        write_query(&mut report, query);
+        report.push('\n');
+        match engine.try_solve() {
+            Some(Outcome::Solutions(mut solutions)) => {
+                let answer = solutions.next_view().unwrap();
+                for (variable, term) in answer.bindings() {
+                    report.push_str(&format!("{} = {}\n", variable, term));
+                }
+                report.push_str("yes\n");
+            },
+            Some(Outcome::Error(error)) => report.push_str(&format!("error: {}\n", error)),
+            _ => report.push_str("no\n"),
+        }
+    }
+    Ok(report)
+}
+
+fn run_check(args: &[String]) -> Result<String, String> {
+    let mut deny_warnings = false;
+    let mut coverage = false;
+    let mut json = false;
+    let mut path = None;
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--deny-warnings" => deny_warnings = true,
+            "--coverage" => coverage = true,
+            "--format" => json = read_format(it.next())?,
+            _ => path = Some(arg),
+        }
+    }
+
+    let path = path.ok_or(USAGE)?;
+    let code = read_source(path)?;
+    let (program, spans) = match parse_with_spans(&code) {
+        Ok(parsed) => parsed,
+        Err(error) if json => return json_failure(error_to_json("syntax-error", Some(error.span), &error.message)),
+        Err(error) => return Err(format!("{}: {}", path, error)),
+    };
+
+    let mut diagnostics = check_program(&program);
+    if coverage {
+        diagnostics.extend(check_coverage(&program));
+    }
+    if deny_warnings {
+        for diagnostic in &mut diagnostics {
+            diagnostic.severity = Severity::Error;
+        }
+    }
+
+    let failed = diagnostics.iter().any(|d| d.severity == Severity::Error);
+    if json {
+        let report = diagnostics_to_json(&diagnostics, &spans);
+        return if failed { json_failure(report) } else { Ok(report) };
+    }
+    let report: String = diagnostics
+        .iter()
+        .map(|diagnostic| format!("{}: {}\n", path, diagnostic))
+        .collect();
+    if failed {
+        return Err(report.trim_end().to_string());
+    }
+    Ok(report)
+}
+
+/**
+ * Reads the value of a limit option, where 0 means no limit.
+ */
+fn read_limit(limit: Option<&String>) -> Result<Option<usize>, String> {
+    let limit = limit.ok_or(USAGE)?;
+    match limit.parse() {
+        Ok(0) => Ok(None),
+        Ok(limit) => Ok(Some(limit)),
+        Err(_) => Err(format!("not a number: {}", limit)),
+    }
+}
+
+/**
+ * Reads the value of `--format`, returning whether it is `json`.
+ */
+fn read_format(format: Option<&String>) -> Result<bool, String> {
+    match format.map(String::as_str) {
+        Some("json") => Ok(true),
+        Some("text") => Ok(false),
+        Some(format) => Err(format!("unknown format: {}", format)),
+        None => Err(USAGE.to_string()),
+    }
+}
+
+/**
+ * Prints a JSON report of a failure to stdout, where tools read it from,
+ * and fails without another message.
+ */
+fn json_failure(report: String) -> Result<String, String> {
+    print!("{}", report);
+    Err(String::new())
+}
+
+fn run_tests(args: &[String]) -> Result<String, String> {
+    let mut options = EngineOptions::default();
+    let mut path = None;
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--max-inferences" => {
+                let max = it.next().ok_or(USAGE)?;
+                options.max_inferences = Some(max.parse().map_err(|_| format!("not a number: {}", max))?);
+            },
+            _ => path = Some(arg),
+        }
+    }
+
+    let path = path.ok_or(USAGE)?;
+    let code = read_source(path)?;
+    let program = parse(&code).map_err(|e| format!("{}: {}", path, e))?;
+
+    let results = check_expectations(&program, options);
+    let mut report: String = results
+        .iter()
+        .map(|result| format!("{}: {}\n", path, result))
+        .collect();
+    let failed = results.iter().filter(|result| !result.passed()).count();
+    report.push_str(&format!("{} passed, {} failed\n", results.len() - failed, failed));
+    if failed > 0 {
+        return Err(report.trim_end().to_string());
+    }
+    Ok(report)
+}
+
+fn run_explain(args: &[String]) -> Result<String, String> {
+    let path = match args {
+        [path] => path,
+        _ => return Err(USAGE.to_string()),
+    };
+    let code = read_source(path)?;
+    let program = parse(&code).map_err(|e| format!("{}: {}", path, e))?;
+
+    let mut compiler = Compiler::new();
+    compiler.compile(program.clone());
+    let mut engine = Engine::new(compiler);
+
+    let mut report = String::new();
+    for query in &program.queries {
+        write_query(&mut report, query);
+        report.push('\n');
+        for plan in engine.explain(query) {
+            report.push_str(&format!("  {}\n", plan));
+        }
+    }
+    Ok(report)
+}
+
+fn run_export(args: &[String]) -> Result<String, String> {
+    let mut dialect = Dialect::Swi;
+    let mut path = None;
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--dialect" => {
+                let name = it.next().ok_or(USAGE)?;
+                dialect = Dialect::from_name(name)
+                    .ok_or(format!("unknown dialect: {}", name))?;
+            },
+            _ => path = Some(arg),
+        }
+    }
+
+    let path = path.ok_or(USAGE)?;
+    let code = read_source(path)?;
+    let program = parse(&code).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(export(&program, dialect))
+}
+
+fn run_import(args: &[String]) -> Result<String, String> {
+    let mut dialect = Dialect::Swi;
+    let mut path = None;
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--dialect" => {
+                let name = it.next().ok_or(USAGE)?;
+                dialect = Dialect::from_name(name)
+                    .ok_or(format!("unknown dialect: {}", name))?;
+            },
+            _ => path = Some(arg),
+        }
+    }
+
+    let path = path.ok_or(USAGE)?;
+    let code = read_source(path)?;
+    let program = import(&code, dialect).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(write_program(&program))
+}
+
+fn run_minimize(args: &[String]) -> Result<String, String> {
+    let mut error = None;
+    let mut expected_answer = None;
+    let mut actual_answer = None;
+    let mut path = None;
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--expect" => error = Some(it.next().ok_or(USAGE)?),
+            "--expect-answer" => expected_answer = Some(it.next().ok_or(USAGE)?),
+            "--actual-answer" => actual_answer = Some(it.next().ok_or(USAGE)?),
+            _ => path = Some(arg),
+        }
+    }
+
+    let path = path.ok_or(USAGE)?;
+    let symptom = match (error, expected_answer, actual_answer) {
+        (Some(error), None, None) => Symptom::Error(error.clone()),
+        (None, Some(expected), Some(actual)) => {
+            Symptom::WrongAnswer { expected: expected.clone(), actual: actual.clone() }
+        },
+        _ => return Err(USAGE.to_string()),
+    };
+    let code = read_source(path)?;
+    let program = parse(&code).map_err(|e| format!("{}: {}", path, e))?;
+
+    // Every candidate that reproduces a crash panics, don't print them all
+    panic::set_hook(Box::new(|_| {}));
+
+    if !shows_symptom(&program, &symptom) {
+        return Err(format!("{} does not {}", path, symptom));
+    }
+    let minimized = minimize(program, |program| shows_symptom(program, &symptom));
+    Ok(write_program(&minimized))
+}
+
+fn run_codegen(args: &[String]) -> Result<String, String> {
+    let mut output = None;
+    let mut json = false;
+    let mut path = None;
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "-o" => output = Some(it.next().ok_or(USAGE)?),
+            "--format" => json = read_format(it.next())?,
+            _ => path = Some(arg),
+        }
+    }
+
+    let path = path.ok_or(USAGE)?;
+    let code = read_source(path)?;
+    let program = match parse_with_spans(&code) {
+        Ok((program, _)) => program,
+        Err(error) if json => return json_failure(error_to_json("syntax-error", Some(error.span), &error.message)),
+        Err(error) => return Err(format!("{}: {}", path, error)),
+    };
+    let generated = match generate_rust(&program) {
+        Ok(generated) => generated,
+        Err(error) if json => return json_failure(error_to_json("unsupported-by-codegen", None, &error)),
+        Err(error) => return Err(format!("{}: {}", path, error)),
+    };
+
+    match output {
+        Some(output) => {
+            fs::write(output, generated).map_err(|e| format!("{}: {}", output, e))?;
+            Ok(String::new())
+        },
+        None => Ok(generated),
+    }
+}
+
+fn read_source(path: &str) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))
 }
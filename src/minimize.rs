@@ -0,0 +1,310 @@
+use std::fmt;
+use std::panic;
+
+use crate::ast::*;
+use crate::compiler::Compiler;
+use crate::machine::{Answer, Engine, EngineOptions};
+
+/**
+ * Shrinks a program while `reproduces` keeps returning true for it, using
+ * delta debugging (ddmin) first over whole clauses, queries and type
+ * declarations and then over the goals inside each of them.
+ *
+ * `reproduces` must return true for the original program.
+ */
+pub fn minimize<F>(program: Program, mut reproduces: F) -> Program
+where
+    F: FnMut(&Program) -> bool
+{
+    let Program { clauses, queries, types } = program;
+
+    let clauses = ddmin(clauses, |clauses| {
+        reproduces(&Program {
+            clauses: clauses.to_vec(),
+            queries: queries.clone(),
+            types: types.clone(),
+        })
+    });
+    let queries = ddmin(queries, |queries| {
+        reproduces(&Program {
+            clauses: clauses.clone(),
+            queries: queries.to_vec(),
+            types: types.clone(),
+        })
+    });
+    let types = ddmin(types, |types| {
+        reproduces(&Program {
+            clauses: clauses.clone(),
+            queries: queries.clone(),
+            types: types.to_vec(),
+        })
+    });
+    let mut program = Program { clauses, queries, types };
+
+    for i in 0..program.clauses.len() {
+        let body = std::mem::take(&mut program.clauses[i].body);
+        let body = ddmin(body, |body| {
+            let mut candidate = program.clone();
+            candidate.clauses[i].body = body.to_vec();
+            reproduces(&candidate)
+        });
+        program.clauses[i].body = body;
+    }
+
+    for i in 0..program.queries.len() {
+        let goals = std::mem::take(&mut program.queries[i].sub_queries);
+        let goals = ddmin(goals, |goals| {
+            let mut candidate = program.clone();
+            candidate.queries[i].sub_queries = goals.to_vec();
+            reproduces(&candidate)
+        });
+        program.queries[i].sub_queries = goals;
+    }
+
+    program
+}
+
+/**
+ * Limits on running a candidate program, so that a candidate that loops
+ * forever or has infinitely many solutions doesn't hang the search.
+ */
+const MAX_INFERENCES: u64 = 1_000_000;
+const MAX_SOLUTIONS: usize = 100;
+
+/**
+ * How a program misbehaves, which a smaller program has to keep doing to
+ * reproduce the bug.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Symptom {
+    // Compiling or running the program panics, or one of its queries stops
+    // with an error, with a message containing this text
+    Error(String),
+    // One of the queries has a solution containing `actual`, e.g. `X = c`,
+    // but none containing `expected`. Solutions are written with their
+    // bindings separated by commas, e.g. `X = a, Y = b`, or as `yes` if the
+    // query has no named variables.
+    WrongAnswer { expected: String, actual: String },
+}
+
+impl fmt::Display for Symptom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Symptom::Error(expected) => write!(f, "fail with \"{}\"", expected),
+            Symptom::WrongAnswer { expected, actual } => {
+                write!(f, "answer \"{}\" instead of \"{}\"", actual, expected)
+            },
+        }
+    }
+}
+
+/**
+ * What running one query of a candidate program did.
+ */
+struct QueryRun {
+    // Text of each solution found, up to MAX_SOLUTIONS
+    solutions: Vec<String>,
+    // Message of the error that stopped the query, if any
+    error: Option<String>,
+}
+
+/**
+ * Returns true if compiling the program and running its queries shows the
+ * symptom.
+ */
+pub fn shows_symptom(program: &Program, symptom: &Symptom) -> bool {
+    let runs = match run_queries(program) {
+        Ok(runs) => runs,
+        Err(panic) => {
+            return matches!(symptom, Symptom::Error(expected) if panic.contains(expected.as_str()));
+        },
+    };
+
+    match symptom {
+        Symptom::Error(expected) => runs
+            .iter()
+            .filter_map(|run| run.error.as_ref())
+            .any(|error| error.contains(expected.as_str())),
+        Symptom::WrongAnswer { expected, actual } => runs.iter().any(|run| {
+            run.solutions.iter().any(|solution| solution.contains(actual.as_str()))
+                && !run.solutions.iter().any(|solution| solution.contains(expected.as_str()))
+        }),
+    }
+}
+
+/**
+ * Compiles the program and runs each of its queries, or returns the message
+ * of the panic if either panics.
+ */
+fn run_queries(program: &Program) -> Result<Vec<QueryRun>, String> {
+    let program = program.clone();
+    let result = panic::catch_unwind(move || {
+        let mut compiler = Compiler::new();
+        compiler.compile(program);
+        let options = EngineOptions { max_inferences: Some(MAX_INFERENCES), ..Default::default() };
+        let mut engine = Engine::with_options(compiler, options);
+
+        let mut runs = Vec::new();
+        while let Some(mut solutions) = engine.next_query() {
+            let found = solutions
+                .by_ref()
+                .take(MAX_SOLUTIONS)
+                .map(|answer| solution_text(&answer))
+                .collect();
+            let error = solutions.error().map(ToString::to_string);
+            runs.push(QueryRun { solutions: found, error });
+        }
+        runs
+    });
+
+    result.map_err(|payload| {
+        payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default()
+    })
+}
+
+fn solution_text(answer: &Answer) -> String {
+    if answer.bindings().is_empty() {
+        return "yes".to_string();
+    }
+    answer
+        .bindings()
+        .iter()
+        .map(|(variable, term)| format!("{} = {}", variable, term))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/**
+ * Finds a 1-minimal subsequence of items for which test returns true, i.e.
+ * removing any single remaining item makes the test pass.
+ * See Zeller and Hildebrandt, "Simplifying and Isolating Failure-Inducing
+ * Input".
+ */
+fn ddmin<T, F>(mut items: Vec<T>, mut test: F) -> Vec<T>
+where
+    T: Clone,
+    F: FnMut(&[T]) -> bool
+{
+    if items.is_empty() {
+        return items;
+    }
+    if test(&[]) {
+        return Vec::new();
+    }
+
+    let mut granularity = 2;
+    while items.len() >= 2 {
+        let chunk_size = items.len().div_ceil(granularity);
+        let chunks: Vec<(usize, usize)> = (0..items.len())
+            .step_by(chunk_size)
+            .map(|start| (start, (start + chunk_size).min(items.len())))
+            .collect();
+
+        let mut reduced = false;
+
+        // Try to reduce to a single chunk
+        for &(start, end) in &chunks {
+            if test(&items[start..end]) {
+                items = items[start..end].to_vec();
+                granularity = 2;
+                reduced = true;
+                break;
+            }
+        }
+
+        // Try to remove a single chunk
+        if !reduced {
+            for &(start, end) in &chunks {
+                let complement: Vec<T> = items[..start]
+                    .iter()
+                    .chain(&items[end..])
+                    .cloned()
+                    .collect();
+                if test(&complement) {
+                    items = complement;
+                    granularity = (granularity - 1).max(2);
+                    reduced = true;
+                    break;
+                }
+            }
+        }
+
+        if !reduced {
+            if granularity >= items.len() {
+                break;
+            }
+            granularity = (granularity * 2).min(items.len());
+        }
+    }
+
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::minimize::*;
+    use crate::parser::parse;
+    use crate::writer::write_program;
+
+    #[test]
+    fn test_ddmin() {
+        let items: Vec<usize> = (0..20).collect();
+        let result = ddmin(items, |items| items.contains(&3) && items.contains(&17));
+        assert_eq!(vec![3, 17], result);
+    }
+
+    #[test]
+    fn test_ddmin_empty_reproduces() {
+        let result = ddmin(vec![1, 2, 3], |_| true);
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    fn test_minimize_program() {
+        let program = parse(
+            "a\n\
+             b if c and bad X and d\n\
+             c if d\n\
+             d\n\
+             ? a\n\
+             ? b"
+        ).unwrap();
+
+        // Pretend the bug is triggered by any goal calling bad together with
+        // a fact d being defined.
+        let minimized = minimize(program, |program| {
+            let written = write_program(program);
+            written.contains("bad X") && written.lines().any(|line| line == "d")
+        });
+
+        assert_eq!("b if bad X\nd\n", write_program(&minimized));
+    }
+
+    #[test]
+    fn test_shows_engine_error() {
+        let program = parse("p X if X \n q \n ? q \n ? p Z").unwrap();
+        assert!(shows_symptom(&program, &Symptom::Error("instantiation error".to_string())));
+        assert!(!shows_symptom(&program, &Symptom::Error("type error".to_string())));
+
+        let minimized = minimize(program, |program| {
+            shows_symptom(program, &Symptom::Error("instantiation error".to_string()))
+        });
+        assert_eq!("p X if X\n? p Z\n", write_program(&minimized));
+    }
+
+    #[test]
+    fn test_shows_wrong_answer() {
+        let symptom = Symptom::WrongAnswer { expected: "X = b".to_string(), actual: "X = c".to_string() };
+        assert!(shows_symptom(&parse("p c \n q \n ? p X").unwrap(), &symptom));
+        assert!(!shows_symptom(&parse("p c \n p b \n ? p X").unwrap(), &symptom));
+        assert!(!shows_symptom(&parse("p a \n ? p X").unwrap(), &symptom));
+
+        let program = parse("p c \n q \n r X if p X \n ? q \n ? r X").unwrap();
+        let minimized = minimize(program, |program| shows_symptom(program, &symptom));
+        assert_eq!("p c\nr X if p X\n? r X\n", write_program(&minimized));
+    }
+}
@@ -1,5 +1,9 @@
+use std::fmt;
+
 use pest::Parser;
+use pest::error::{Error, ErrorVariant, LineColLocation};
 use pest::iterators::Pair;
+use pest::iterators::Pairs;
 
 use crate::ast::*;
 
@@ -8,59 +12,422 @@ use crate::ast::*;
 struct ButterflyPLParser;
 
 /**
- * Top-level function for parsing a program.
+ * Top-level function for parsing a program. Returns the syntax error, if
+ * there is one.
+ */
+pub fn parse(code: &str) -> Result<Program, String> {
+    let statements = parse_statements(code)?;
+
+    let mut program = Program::new();
+    for statement in statements {
+        match statement {
+            Statement::Clause(clause) => program.push_clause(clause),
+            Statement::Query(query) => program.push_query(query),
+            Statement::TypeDeclaration(declaration) => program.push_type(declaration),
+        }
+    }
+
+    Ok(program)
+}
+
+/**
+ * Returns true if a name can be written as an atom, i.e. the parser reads it
+ * back as that atom and not as a keyword, variable or anything else.
+ */
+pub fn is_atom(name: &str) -> bool {
+    let Ok(mut pairs) = ButterflyPLParser::parse(Rule::clause, name) else {
+        return false;
+    };
+    let mut inner = pairs.next().unwrap().into_inner();
+    match (inner.next(), inner.next()) {
+        (Some(pair), None) => pair.as_rule() == Rule::atom && pair.as_str() == name,
+        _ => false,
+    }
+}
+
+/**
+ * Returns the value of an integer written the way the parser reads number
+ * literals, e.g. `-7`, `1_000` or `0x1F`, or None if the text is anything
+ * else or doesn't fit in 64 bits.
+ */
+pub fn parse_integer(text: &str) -> Option<i64> {
+    let mut pairs = ButterflyPLParser::parse(Rule::number, text).ok()?;
+    let pair = pairs.next().unwrap();
+    if pair.as_str() != text {
+        return None;
+    }
+    parse_number(text)
+}
+
+/**
+ * A region of source code. Lines and columns are 1-based and the end is
+ * exclusive.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+/**
+ * A syntax error and where it was found.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyntaxError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.span.line, self.span.column, self.message)
+    }
+}
+
+/**
+ * Parses a program like parse, but reports where a syntax error is, and
+ * also returns the span of each clause in the order of Program.clauses,
+ * for tools that point at the clauses they report on. A clause's span
+ * leaves out its annotations.
  */
-pub fn parse(code: &str) -> Result<Program, &str> {
-    let parsed_program = ButterflyPLParser::parse(Rule::program, code)
-        .expect("Parsing error.")
+pub fn parse_with_spans(code: &str) -> Result<(Program, Vec<Span>), SyntaxError> {
+    let parsed_program = parse_rule(Rule::program, code)
+        .map_err(|error| {
+            let span = match error.line_col {
+                LineColLocation::Pos((line, column)) => Span { line, column, end_line: line, end_column: column },
+                LineColLocation::Span((line, column), (end_line, end_column)) => {
+                    Span { line, column, end_line, end_column }
+                },
+            };
+            SyntaxError { span, message: syntax_error_message(&error) }
+        })?
         .next()
         .unwrap();
 
     let mut program = Program::new();
+    let mut spans = Vec::new();
     for pair in parsed_program.into_inner() {
-        match pair.as_rule() {
-            Rule::clause => {
-                let clause = construct_clause(pair);
-                program.push_clause(clause);
-            },
-            Rule::query => {
-                let query = construct_query(pair);
-                program.push_query(query);
-            },
-            _ => unreachable!()
+        if pair.as_rule() == Rule::clause {
+            let start = pair
+                .clone()
+                .into_inner()
+                .find(|pair| pair.as_rule() != Rule::annotation)
+                .unwrap()
+                .as_span()
+                .start_pos();
+            let source = pair.as_str().trim_end();
+            let end = pair.as_span().start_pos().pos() + source.len();
+            let (line, column) = start.line_col();
+            let (end_line, end_column) = pest::Position::new(code, end).unwrap().line_col();
+            spans.push(Span { line, column, end_line, end_column });
+        }
+        match construct_statement(pair) {
+            Statement::Clause(clause) => program.push_clause(clause),
+            Statement::Query(query) => program.push_query(query),
+            Statement::TypeDeclaration(declaration) => program.push_type(declaration),
         }
     }
+    Ok((program, spans))
+}
 
-    Ok(program)
+/**
+ * Parses event-condition-action rules, one per line, e.g.
+ * `on temperature R L if critical L do alert R`.
+ */
+pub fn parse_event_rules(code: &str) -> Result<Vec<EventRule>, String> {
+    let rules = parse_rule(Rule::event_rules, code)
+        .map_err(|e| e.to_string())?
+        .next()
+        .unwrap();
+    Ok(rules.into_inner().map(construct_event_rule).collect())
 }
 
-fn construct_clause(pair: Pair<Rule>) -> Clause {
-    let mut it = pair.into_inner();
-    let head = construct_term(it.next().unwrap());
+/**
+ * A single top-level item of a program.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Statement {
+    Clause(Clause),
+    Query(Query),
+    TypeDeclaration(TypeDeclaration),
+}
+
+/**
+ * Parses a program and returns an iterator that builds the AST of one clause
+ * or query at a time, so callers that process statements one by one never
+ * need to hold the AST of the whole program.
+ */
+pub fn parse_statements(code: &str) -> Result<Statements<'_>, String> {
+    let parsed_program = parse_rule(Rule::program, code)
+        .map_err(|e| e.to_string())?
+        .next()
+        .unwrap();
+
+    Ok(Statements { pairs: parsed_program.into_inner() })
+}
 
-    let mut body = Vec::new();
-    match it.next() {
-        Some(pair) => {
-            for pair in pair.into_inner() {
-                body.push(construct_term(pair));
+pub struct Statements<'a> {
+    pairs: Pairs<'a, Rule>,
+}
+
+impl<'a> Statements<'a> {
+    /**
+     * Like next, but also returns the source text of the statement, with
+     * its annotations and comments but without the line break that ends it.
+     */
+    pub fn next_with_source(&mut self) -> Option<(Statement, &'a str)> {
+        let pair = self.pairs.next()?;
+        let source = pair.as_str().trim_end();
+        Some((construct_statement(pair), source))
+    }
+}
+
+impl Iterator for Statements<'_> {
+    type Item = Statement;
+
+    fn next(&mut self) -> Option<Statement> {
+        self.next_with_source().map(|(statement, _)| statement)
+    }
+}
+
+/**
+ * A statement parsed by parse_statements_recovering.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceStatement<'a> {
+    pub statement: Statement,
+    // Text of the statement, with its annotations and comments
+    pub source: &'a str,
+    // 1-based line number the statement starts on
+    pub line: usize,
+}
+
+/**
+ * A statement that was skipped because it could not be loaded.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadError {
+    // 1-based line number of the error
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/**
+ * Like parse_statements, but a syntax error only loses the statement it is
+ * in instead of the whole program. Each line is parsed on its own, together
+ * with the annotation lines before it. Returns the statements that could be
+ * parsed and an error for each one that couldn't, both in source order.
+ */
+pub fn parse_statements_recovering(code: &str) -> (Vec<SourceStatement<'_>>, Vec<LoadError>) {
+    let mut statements = Vec::new();
+    let mut errors = Vec::new();
+    let mut chunk_start = 0;
+    let mut chunk_line = 1;
+    let mut offset = 0;
+    for (i, line) in code.split_inclusive('\n').enumerate() {
+        offset += line.len();
+        // Annotations belong to the clause on the next line
+        if line.trim_start().starts_with('@') {
+            continue;
+        }
+        parse_chunk(&code[chunk_start..offset], chunk_line, &mut statements, &mut errors);
+        chunk_start = offset;
+        chunk_line = i + 2;
+    }
+    if chunk_start < code.len() {
+        // Annotations at the end of the code, without a clause
+        parse_chunk(&code[chunk_start..], chunk_line, &mut statements, &mut errors);
+    }
+    (statements, errors)
+}
+
+/**
+ * Parses statements that start on line first_line of a program.
+ */
+fn parse_chunk<'a>(
+    chunk: &'a str,
+    first_line: usize,
+    statements: &mut Vec<SourceStatement<'a>>,
+    errors: &mut Vec<LoadError>,
+) {
+    match parse_rule(Rule::program, chunk) {
+        Ok(mut pairs) => {
+            for pair in pairs.next().unwrap().into_inner() {
+                let line = first_line + pair.as_span().start_pos().line_col().0 - 1;
+                let source = pair.as_str().trim_end();
+                statements.push(SourceStatement { statement: construct_statement(pair), source, line });
             }
         },
-        None => ()
+        Err(error) => {
+            let (LineColLocation::Pos((line, _)) | LineColLocation::Span((line, _), _)) = error.line_col;
+            errors.push(LoadError { line: first_line + line - 1, message: syntax_error_message(&error) });
+        },
     }
+}
 
-    Clause { head, body }
+/**
+ * Returns the part of a pest error that says what was expected, without the
+ * excerpt of the source.
+ */
+fn syntax_error_message(error: &Error<Rule>) -> String {
+    let message = error.to_string();
+    let expected = message.lines().last().and_then(|line| line.trim_start().strip_prefix("= "));
+    format!("syntax error: {}", expected.unwrap_or("invalid statement"))
+}
+
+fn construct_statement(pair: Pair<Rule>) -> Statement {
+    match pair.as_rule() {
+        Rule::clause => Statement::Clause(construct_clause(pair)),
+        Rule::query => Statement::Query(construct_query(pair)),
+        Rule::type_declaration => Statement::TypeDeclaration(construct_type_declaration(pair)),
+        _ => unreachable!()
+    }
+}
+
+fn construct_clause(pair: Pair<Rule>) -> Clause {
+    let mut it = pair.into_inner().peekable();
+    let mut annotations = Vec::new();
+    while let Some(pair) = it.next_if(|pair| pair.as_rule() == Rule::annotation) {
+        annotations.push(construct_annotation(pair));
+    }
+    let head = construct_term(it.next().unwrap());
+
+    let body = it.next().map_or_else(Vec::new, |pair| construct_goals(pair.into_inner()));
+
+    Clause { head, body, annotations }
+}
+
+fn construct_event_rule(pair: Pair<Rule>) -> EventRule {
+    // Skip the on keyword
+    let mut it = pair.into_inner().skip(1);
+    let event = construct_term(it.next().unwrap());
+
+    let mut condition = Vec::new();
+    let mut pair = it.next().unwrap();
+    if pair.as_rule() == Rule::conjunction {
+        condition = construct_goals(pair.into_inner());
+        pair = it.next().unwrap();
+    }
+    // pair is the do keyword
+    debug_assert_eq!(Rule::do_keyword, pair.as_rule());
+    let actions = construct_goals(it.next().unwrap().into_inner());
+
+    EventRule { event, condition, actions }
+}
+
+fn construct_annotation(pair: Pair<Rule>) -> Annotation {
+    let mut it = pair.into_inner();
+    let name = it.next().unwrap().as_str().to_string();
+    let arguments = it.map(|pair| pair.as_str().to_string()).collect();
+    Annotation { name, arguments }
+}
+
+/**
+ * Matches code against a rule of the grammar, then checks what the grammar
+ * can't: that every number literal fits in 64 bits. A literal that doesn't
+ * is reported like a syntax error at the literal.
+ */
+fn parse_rule(rule: Rule, code: &str) -> Result<Pairs<'_, Rule>, Box<Error<Rule>>> {
+    let pairs = ButterflyPLParser::parse(rule, code)?;
+    let out_of_range = pairs
+        .clone()
+        .flatten()
+        .find(|pair| pair.as_rule() == Rule::number && parse_number(pair.as_str()).is_none());
+    if let Some(pair) = out_of_range {
+        let message = format!("number {} doesn't fit in 64 bits", pair.as_str());
+        return Err(Box::new(Error::new_from_span(ErrorVariant::CustomError { message }, pair.as_span())));
+    }
+    Ok(pairs)
 }
 
 fn construct_term(pair: Pair<Rule>) -> Term {
     match pair.as_rule() {
         Rule::atom => Term::Simple(SimpleTerm::Atom(pair.as_str().to_string())),
         Rule::variable => Term::Simple(SimpleTerm::Variable(pair.as_str().to_string())),
+        Rule::cut => Term::cut(),
+        // Out of range literals are rejected by parse_rule
+        Rule::number => Term::number(parse_number(pair.as_str()).unwrap()),
+        Rule::if_then_else => {
+            // Branches are separated by the then and else keywords
+            let mut branches = pair
+                .into_inner()
+                .filter(|pair| pair.as_rule() == Rule::conjunction)
+                .map(|pair| construct_goals(pair.into_inner()));
+            let condition = branches.next().unwrap();
+            let then = branches.next().unwrap();
+            Term::if_then_else(condition, then, branches.next())
+        },
+        Rule::negation => {
+            // Skip the keyword
+            let goal = pair.into_inner().nth(1).unwrap();
+            Term::negation(construct_term(goal))
+        },
+        Rule::evaluation => {
+            let mut it = pair.into_inner();
+            let result = construct_term(it.next().unwrap());
+            // Skip the keyword
+            let expression = construct_term(it.nth(1).unwrap());
+            Term::evaluation(result, expression)
+        },
+        Rule::unification => {
+            let mut it = pair.into_inner();
+            let left = construct_term(it.next().unwrap());
+            let operator = it.next().unwrap().as_str();
+            let right = construct_term(it.next().unwrap());
+            Term::compound(operator, vec![left, right])
+        },
         Rule::compound_term => construct_compound_term(pair),
         Rule::term => construct_term(pair),
         _ => unreachable!()
     }
 }
 
+/**
+ * Reads a number literal matched by the grammar, or returns None if it is
+ * out of range.
+ */
+fn parse_number(text: &str) -> Option<i64> {
+    let (sign, digits) = match text.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", text),
+    };
+    if let Some(code) = digits.strip_prefix("0'") {
+        let code = code.chars().next().unwrap() as i64;
+        return Some(if sign.is_empty() { code } else { -code });
+    }
+
+    let digits = digits.replace('_', "");
+    let (radix, digits) = match digits.get(..2) {
+        Some("0x") => (16, &digits[2..]),
+        Some("0o") => (8, &digits[2..]),
+        Some("0b") => (2, &digits[2..]),
+        _ => (10, digits.as_str()),
+    };
+    // Parsed with the sign so that i64::MIN is in range
+    i64::from_str_radix(&format!("{}{}", sign, digits), radix).ok()
+}
+
+/**
+ * Spells out n > 0 in letters, bijectively: A, B, ..., Z, AA, AB, ...
+ */
+pub(crate) fn letters(mut n: usize) -> String {
+    let mut out = Vec::new();
+    while n > 0 {
+        n -= 1;
+        out.push((b'A' + (n % 26) as u8) as char);
+        n /= 26;
+    }
+    out.iter().rev().collect()
+}
+
 fn construct_compound_term(pair: Pair<Rule>) -> Term {
     let mut it = pair.into_inner();
     let name = match construct_term(it.next().unwrap()) {
@@ -78,19 +445,88 @@ fn construct_compound_term(pair: Pair<Rule>) -> Term {
 }
 
 fn construct_query(pair: Pair<Rule>) -> Query {
-    let mut sub_queries = Vec::new();
-    for term in pair.into_inner() {
-        sub_queries.push(construct_term(term));
+    Query { sub_queries: construct_goals(pair.into_inner()) }
+}
+
+/**
+ * Constructs the goals of a body or query, where a `let` goal stands for
+ * several.
+ */
+fn construct_goals(pairs: Pairs<Rule>) -> Vec<Term> {
+    let mut goals = Vec::new();
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::let_expression => construct_let(pair, &mut goals),
+            _ => goals.push(construct_term(pair)),
+        }
+    }
+    goals
+}
+
+/**
+ * Adds the `is` goals that a `let` goal is compiled into: one for each
+ * operation, innermost first, whose result is a temporary variable except
+ * for the last one, e.g. `let Y = (X + 1) * 2` becomes `T is plus X 1`
+ * and `Y is times T 2` with a temporary variable T. `/` is `div`.
+ */
+fn construct_let(pair: Pair<Rule>, goals: &mut Vec<Term>) {
+    // Skip the keyword
+    let mut it = pair.into_inner().skip(1);
+    let result = it.next().unwrap().as_str();
+    let first = goals.len();
+    let value = construct_arithmetic(it.next().unwrap(), result, goals);
+    if goals.len() == first {
+        goals.push(Term::evaluation(Term::variable(result), value));
+    } else if let Some(Term::Compound(evaluation)) = goals.last_mut() {
+        evaluation.parameters[0] = Term::variable(result);
+    }
+}
+
+/**
+ * Adds the `is` goals for the operations of an expression in a `let` goal,
+ * and returns the number or variable holding its value. Temporary variables
+ * are named after the result and the position of their operator, so that
+ * they are distinct within a clause.
+ */
+fn construct_arithmetic(pair: Pair<Rule>, result: &str, goals: &mut Vec<Term>) -> Term {
+    match pair.as_rule() {
+        Rule::sum | Rule::product => {
+            let mut it = pair.into_inner();
+            let mut value = construct_arithmetic(it.next().unwrap(), result, goals);
+            while let Some(operator) = it.next() {
+                let right = construct_arithmetic(it.next().unwrap(), result, goals);
+                let function = match operator.as_str() {
+                    "+" => "plus",
+                    "-" => "minus",
+                    "*" => "times",
+                    "/" => "div",
+                    _ => unreachable!(),
+                };
+                let temporary = Term::variable(&format!("_{}{}", result, letters(operator.as_span().start() + 1)));
+                goals.push(Term::evaluation(temporary.clone(), Term::compound(function, vec![value, right])));
+                value = temporary;
+            }
+            value
+        },
+        _ => construct_term(pair),
     }
+}
+
+fn construct_type_declaration(pair: Pair<Rule>) -> TypeDeclaration {
+    // Skip the type keyword
+    let mut it = pair.into_inner().skip(1);
+    let name = it.next().unwrap().as_str().to_string();
+    let arguments = it.map(|pair| pair.as_str().to_string()).collect();
 
-    Query { sub_queries }
+    TypeDeclaration { name, arguments }
 }
 
 #[cfg(test)]
+#[allow(clippy::vec_init_then_push)]
 mod tests {
     use crate::parser::*;
 
-    fn parse_and_unwrap(rule: Rule, code: &str) -> Pair<Rule> {
+    fn parse_and_unwrap(rule: Rule, code: &str) -> Pair<'_, Rule> {
         ButterflyPLParser::parse(rule, code)
             .unwrap()
             .next()
@@ -118,6 +554,235 @@ mod tests {
         assert_eq!(term, Term::Simple(SimpleTerm::Variable(String::from("_abc"))));
     }
 
+    #[test]
+    fn test_construct_term_namespaced_atom() {
+        let pair = parse_and_unwrap(Rule::term, "rdf:type");
+        let term = construct_term(pair);
+        assert_eq!(term, Term::Simple(SimpleTerm::Atom(String::from("rdf:type"))));
+    }
+
+    #[test]
+    fn test_construct_term_numbers() {
+        let pair = parse_and_unwrap(Rule::term, "p 42 -7 1_000_000 0x1F -0xff 0o17 0b101 0'a 0'_ X");
+        let expected = Term::compound("p", vec![
+            Term::number(42),
+            Term::number(-7),
+            Term::number(1_000_000),
+            Term::number(31),
+            Term::number(-255),
+            Term::number(15),
+            Term::number(5),
+            Term::number(97),
+            Term::number(95),
+            Term::variable("X"),
+        ]);
+        assert_eq!(expected, construct_term(pair));
+
+        // Numbers can't be functors
+        assert!(ButterflyPLParser::parse(Rule::clause, "3 a").is_err());
+    }
+
+    #[test]
+    fn test_construct_clause_with_cut() {
+        let pair = parse_and_unwrap(Rule::clause, "max X Y X if ge X Y and !");
+        let clause = construct_clause(pair);
+        assert_eq!(2, clause.body.len());
+        assert!(clause.body[1].is_cut());
+        assert_eq!(Term::atom("!"), clause.body[1]);
+    }
+
+    #[test]
+    fn test_construct_clause_with_annotations() {
+        let pair = parse_and_unwrap(Rule::clause, "@priority 5\n@deprecated\n  fly X if bird X");
+        let clause = construct_clause(pair);
+        assert_eq!(
+            vec![
+                Annotation { name: String::from("priority"), arguments: vec![String::from("5")] },
+                Annotation { name: String::from("deprecated"), arguments: Vec::new() },
+            ],
+            clause.annotations
+        );
+        assert_eq!(Clause::rule(
+            Term::compound("fly", vec![Term::variable("X")]),
+            vec![Term::compound("bird", vec![Term::variable("X")])]
+        ), Clause { annotations: Vec::new(), ..clause });
+    }
+
+    #[test]
+    fn test_construct_clause_with_negation() {
+        let pair = parse_and_unwrap(Rule::clause, "bachelor X if man X and not married X");
+        let clause = construct_clause(pair);
+        assert_eq!(
+            Term::negation(Term::compound("married", vec![Term::variable("X")])),
+            clause.body[1]
+        );
+
+        // Atoms starting with not are not negations
+        let pair = parse_and_unwrap(Rule::clause, "p if nothing and not q");
+        let clause = construct_clause(pair);
+        assert_eq!(vec![Term::atom("nothing"), Term::negation(Term::atom("q"))], clause.body);
+
+        let program = parse("? not p X").unwrap();
+        assert_eq!(
+            vec![Term::negation(Term::compound("p", vec![Term::variable("X")]))],
+            program.queries[0].sub_queries
+        );
+    }
+
+    #[test]
+    fn test_construct_clause_with_evaluation() {
+        let pair = parse_and_unwrap(Rule::clause, "succ X Y if Y is plus X 1");
+        let clause = construct_clause(pair);
+        let expression = Term::compound("plus", vec![Term::variable("X"), Term::number(1)]);
+        assert_eq!(vec![Term::evaluation(Term::variable("Y"), expression)], clause.body);
+
+        // The prefix form is the same goal
+        let program = parse("? 3 is N \n ? is 3 N \n ? island X \n ? (3 is N) (p)").unwrap();
+        let goal = Term::evaluation(Term::number(3), Term::variable("N"));
+        assert_eq!(vec![goal.clone()], program.queries[0].sub_queries);
+        assert_eq!(vec![goal.clone()], program.queries[1].sub_queries);
+        assert_eq!(vec![Term::compound("island", vec![Term::variable("X")])], program.queries[2].sub_queries);
+        assert_eq!(goal, program.queries[3].sub_queries[0]);
+    }
+
+    #[test]
+    fn test_construct_let() {
+        let evaluation = |result: &str, function: &str, a: Term, b: Term| {
+            Term::evaluation(Term::variable(result), Term::compound(function, vec![a, b]))
+        };
+        let program = parse("? let X = 2 * 3 + 4 \n ? (p) let X = 1 - 2 - 3 (q) \n ? let X = (Y) \n let x").unwrap();
+        // Temporaries are named after the position of their operator in the code
+        assert_eq!(
+            vec![
+                evaluation("_XM", "times", Term::number(2), Term::number(3)),
+                evaluation("X", "plus", Term::variable("_XM"), Term::number(4)),
+            ],
+            program.queries[0].sub_queries
+        );
+        assert_eq!(
+            vec![
+                Term::atom("p"),
+                evaluation("_XAM", "minus", Term::number(1), Term::number(2)),
+                evaluation("X", "minus", Term::variable("_XAM"), Term::number(3)),
+                Term::atom("q"),
+            ],
+            program.queries[1].sub_queries
+        );
+        assert_eq!(vec![Term::evaluation(Term::variable("X"), Term::variable("Y"))], program.queries[2].sub_queries);
+        // let is still an atom elsewhere
+        assert_eq!(Term::compound("let", vec![Term::atom("x")]), program.clauses[0].head);
+
+        let clause = parse("total T if let T = P * (Q + 1) / 2").unwrap().clauses.remove(0);
+        let goals: Vec<String> = clause.body.iter().map(Term::to_string).collect();
+        assert_eq!(vec!["is _TAA (plus Q 1)", "is _TV (times P _TAA)", "is T (div _TV 2)"], goals);
+    }
+
+    #[test]
+    fn test_parse_with_spans() {
+        let (program, spans) = parse_with_spans("? p X\n@priority 2\n  p a if q a  \np b").unwrap();
+        assert_eq!(parse("? p X\n@priority 2\n  p a if q a  \np b").unwrap(), program);
+        assert_eq!(
+            vec![
+                Span { line: 3, column: 3, end_line: 3, end_column: 13 },
+                Span { line: 4, column: 1, end_line: 4, end_column: 4 },
+            ],
+            spans
+        );
+
+        let error = parse_with_spans("p a\nq if\n").unwrap_err();
+        assert_eq!("line 2, column 5: syntax error: expected conjunction", error.to_string());
+
+        let error = parse_with_spans("p a \n? q -0x8000000000000001").unwrap_err();
+        assert_eq!(Span { line: 2, column: 5, end_line: 2, end_column: 24 }, error.span);
+        assert_eq!("syntax error: number -0x8000000000000001 doesn't fit in 64 bits", error.message);
+    }
+
+    #[test]
+    fn test_construct_clause_with_unification() {
+        let pair = parse_and_unwrap(Rule::clause, "p X Y if X = f a and not Y \\= X");
+        let clause = construct_clause(pair);
+        let unify = Term::compound(UNIFY, vec![Term::variable("X"), Term::compound("f", vec![Term::atom("a")])]);
+        let not_unify = Term::compound(NOT_UNIFY, vec![Term::variable("Y"), Term::variable("X")]);
+        assert_eq!(vec![unify, Term::negation(not_unify)], clause.body);
+
+        let program = parse("? (f X = f a) (p (b = c)) (not X = a)").unwrap();
+        let unify = Term::compound(
+            UNIFY,
+            vec![Term::compound("f", vec![Term::variable("X")]), Term::compound("f", vec![Term::atom("a")])],
+        );
+        let argument = Term::compound(UNIFY, vec![Term::atom("b"), Term::atom("c")]);
+        let negation = Term::negation(Term::compound(UNIFY, vec![Term::variable("X"), Term::atom("a")]));
+        assert_eq!(vec![unify, Term::compound("p", vec![argument]), negation], program.queries[0].sub_queries);
+    }
+
+    #[test]
+    fn test_parse_event_rules() {
+        let rules = parse_event_rules(
+            "on temperature R L if critical L and not ignored R do alert R and log L\n\
+             on door R open do alert R"
+        ).unwrap();
+        assert_eq!(
+            vec![
+                EventRule {
+                    event: Term::compound("temperature", vec![Term::variable("R"), Term::variable("L")]),
+                    condition: vec![
+                        Term::compound("critical", vec![Term::variable("L")]),
+                        Term::negation(Term::compound("ignored", vec![Term::variable("R")])),
+                    ],
+                    actions: vec![
+                        Term::compound("alert", vec![Term::variable("R")]),
+                        Term::compound("log", vec![Term::variable("L")]),
+                    ],
+                },
+                EventRule {
+                    event: Term::compound("door", vec![Term::variable("R"), Term::atom("open")]),
+                    condition: Vec::new(),
+                    actions: vec![Term::compound("alert", vec![Term::variable("R")])],
+                },
+            ],
+            rules
+        );
+
+        // Atoms starting with do or on are still atoms
+        assert_eq!(
+            Term::compound("on", vec![Term::atom("dog"), Term::atom("done")]),
+            parse("on dog done").unwrap().clauses[0].head
+        );
+        assert!(parse_event_rules("on door R open").is_err());
+    }
+
+    #[test]
+    fn test_construct_clause_with_if_then_else() {
+        let pair = parse_and_unwrap(
+            Rule::clause,
+            "max X Y Z if (if lt X Y and nonzero Y then eq Z Y else eq Z X) and done"
+        );
+        let clause = construct_clause(pair);
+        let lt = Term::compound("lt", vec![Term::variable("X"), Term::variable("Y")]);
+        let nonzero = Term::compound("nonzero", vec![Term::variable("Y")]);
+        let then = Term::compound("eq", vec![Term::variable("Z"), Term::variable("Y")]);
+        let otherwise = Term::compound("eq", vec![Term::variable("Z"), Term::variable("X")]);
+        assert_eq!(
+            vec![
+                Term::if_then_else(vec![lt, nonzero], vec![then], Some(vec![otherwise])),
+                Term::atom("done"),
+            ],
+            clause.body
+        );
+
+        let program = parse("? (if p then q)").unwrap();
+        let (condition, then, otherwise) = program.queries[0].sub_queries[0]
+            .as_if_then_else()
+            .unwrap();
+        assert_eq!((&[Term::atom("p")][..], &[Term::atom("q")][..], None), (condition, then, otherwise));
+
+        // Atoms starting with then or else are still atoms
+        assert_eq!(
+            Term::compound("thence", vec![Term::atom("elsewhere")]),
+            parse("thence elsewhere").unwrap().clauses[0].head
+        );
+    }
+
     #[test]
     #[should_panic]
     fn test_construct_term_panic() {
@@ -194,7 +859,8 @@ mod tests {
 
         let expected_clause = Clause {
             head: expected_head,
-            body: expected_body
+            body: expected_body,
+            annotations: Vec::new()
         };
 
         assert_eq!(expected_clause, clause);
@@ -237,7 +903,8 @@ mod tests {
 
         let expected_clause = Clause {
             head: expected_head,
-            body: expected_body
+            body: expected_body,
+            annotations: Vec::new()
         };
 
         assert_eq!(expected_clause, clause);
@@ -257,6 +924,66 @@ mod tests {
         assert_eq!(expected_program, program);
     }
 
+    #[test]
+    fn test_parse_statements() {
+        let mut statements = parse_statements("a if b \n ? a \n b").unwrap();
+
+        assert_eq!(
+            Some(Statement::Clause(Clause {
+                head: Term::Simple(SimpleTerm::Atom(String::from("a"))),
+                body: vec![Term::Simple(SimpleTerm::Atom(String::from("b")))],
+                annotations: Vec::new()
+            })),
+            statements.next()
+        );
+        assert_eq!(
+            Some(Statement::Query(Query {
+                sub_queries: vec![Term::Simple(SimpleTerm::Atom(String::from("a")))]
+            })),
+            statements.next()
+        );
+        assert_eq!(
+            Some(Statement::Clause(Clause {
+                head: Term::Simple(SimpleTerm::Atom(String::from("b"))),
+                body: Vec::new(),
+                annotations: Vec::new()
+            })),
+            statements.next()
+        );
+        assert_eq!(None, statements.next());
+    }
+
+    #[test]
+    fn test_parse_statements_error() {
+        assert!(parse_statements("a if").is_err());
+    }
+
+    #[test]
+    fn test_parse_statements_recovering() {
+        let code = "a if b \n\
+                    a if \n\
+                    @priority 5 \n\
+                    b \n\
+                    ? a ( \n\
+                    # comment \n\
+                    c # trailing comment \n\
+                    @priority";
+        let (statements, errors) = parse_statements_recovering(code);
+        let lines: Vec<(usize, &str)> = statements.iter().map(|s| (s.line, s.source)).collect();
+        assert_eq!(vec![(1, "a if b"), (3, "@priority 5 \nb"), (7, "c # trailing comment")], lines);
+        assert_eq!(Statement::Clause(Clause::fact(Term::atom("c"))), statements[2].statement);
+        let error_lines: Vec<usize> = errors.iter().map(|error| error.line).collect();
+        assert_eq!(vec![2, 5, 8], error_lines);
+        assert!(errors[0].to_string().starts_with("line 2: syntax error: expected"), "{}", errors[0]);
+
+        let (statements, errors) = parse_statements_recovering("p a \np 99999999999999999999\np b");
+        assert_eq!(2, statements.len());
+        assert_eq!(
+            vec!["line 2: syntax error: number 99999999999999999999 doesn't fit in 64 bits"],
+            errors.iter().map(|error| error.to_string()).collect::<Vec<String>>()
+        );
+    }
+
     #[test]
     fn test_parse() {
         let program = parse("a \n a (a (b e f)) c if a and b \n b").unwrap();
@@ -265,7 +992,8 @@ mod tests {
 
         expected_program.push_clause(Clause {
             head: Term::Simple(SimpleTerm::Atom(String::from("a"))),
-            body: Vec::new()
+            body: Vec::new(),
+            annotations: Vec::new()
         });
 
         let mut parameters = Vec::new();
@@ -300,16 +1028,58 @@ mod tests {
 
         let expected_clause = Clause {
             head: expected_head,
-            body: expected_body
+            body: expected_body,
+            annotations: Vec::new()
         };
 
         expected_program.push_clause(expected_clause);
 
         expected_program.push_clause(Clause {
             head: Term::Simple(SimpleTerm::Atom(String::from("b"))),
-            body: Vec::new()
+            body: Vec::new(),
+            annotations: Vec::new()
+        });
+
+        assert_eq!(expected_program, program);
+    }
+
+    #[test]
+    fn test_parse_number_out_of_range() {
+        let program = parse("p -9223372036854775808 0x7FFFFFFFFFFFFFFF").unwrap();
+        let expected = Term::compound("p", vec![Term::number(i64::MIN), Term::number(i64::MAX)]);
+        assert_eq!(expected, program.clauses[0].head);
+
+        let error = parse("p a \np 9223372036854775808").unwrap_err();
+        assert!(error.contains("2:3"), "{}", error);
+        assert!(error.contains("number 9223372036854775808 doesn't fit in 64 bits"), "{}", error);
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let error = parse("parent tom bob.").unwrap_err();
+        assert!(error.contains("1:15"), "{}", error);
+    }
+
+    #[test]
+    fn test_parse_type_declaration() {
+        let program = parse("@type parent person person \n typed parent \n type tom person").unwrap();
+
+        let mut expected_program = Program::new();
+        expected_program.push_type(TypeDeclaration {
+            name: String::from("parent"),
+            arguments: vec![String::from("person"), String::from("person")],
         });
+        // Atoms that merely start with the keyword are ordinary clauses
+        expected_program.push_clause(Clause::fact(Term::compound(
+            "typed",
+            vec![Term::atom("parent")]
+        )));
+        // Without the `@`, `type` is an ordinary atom
+        expected_program.push_clause(Clause::fact(Term::compound(
+            "type",
+            vec![Term::atom("tom"), Term::atom("person")]
+        )));
 
         assert_eq!(expected_program, program);
     }
-}
\ No newline at end of file
+}
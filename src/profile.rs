@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::heap::*;
+
+/**
+ * Number of buckets of a LatencyHistogram.
+ */
+pub const LATENCY_BUCKETS: usize = 32;
+
+/**
+ * Name of the goal that the engine adds after the body of a clause of a
+ * profiled predicate, to record when the call exits. Atoms can't start
+ * with `$`, so programs can't call it themselves.
+ */
+pub(crate) const PROFILE_EXIT: &str = "$profileExit";
+
+/**
+ * Latencies counted in buckets whose bounds are powers of two microseconds:
+ * bucket 0 counts latencies under 1µs and bucket i those of at least
+ * 2^(i-1)µs and under 2^iµs. The last bucket also counts anything longer.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    counts: [u64; LATENCY_BUCKETS],
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros();
+        let bucket = (u128::BITS - micros.leading_zeros()) as usize;
+        self.counts[bucket.min(LATENCY_BUCKETS - 1)] += 1;
+    }
+
+    /**
+     * Returns the number of latencies in each bucket.
+     */
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /**
+     * Returns the exclusive upper bound of a bucket's latencies.
+     */
+    pub fn bucket_limit(bucket: usize) -> Duration {
+        Duration::from_micros(1 << bucket)
+    }
+}
+
+/**
+ * Timings of a predicate profiled with Engine::profile_predicate or
+ * `profilePred Name Arity`. Only resolution with the predicate's clauses is
+ * timed, so profiling a builtin records nothing.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PredicateProfile {
+    pub name: String,
+    pub arity: usize,
+    // Goals for the predicate that were resolved, not counting retries on
+    // backtracking
+    pub calls: u64,
+    // Solutions of those goals, including the ones found on backtracking
+    pub exits: u64,
+    // Time from each call, or retry after backtracking, to the exit that
+    // followed it
+    pub total_time: Duration,
+    pub max_time: Duration,
+    pub latencies: LatencyHistogram,
+}
+
+/**
+ * How well the clause index fits the goals of a predicate, counted while
+ * EngineOptions::index_statistics is on. Candidates that are tried but
+ * don't unify are work the index could have saved, e.g. by putting the
+ * argument that is bound in most calls first.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IndexStatistics {
+    pub name: String,
+    pub arity: usize,
+    // Goals for the predicate that were resolved, not counting retries on
+    // backtracking
+    pub calls: u64,
+    // Clauses that the predicate table returned for those goals
+    pub candidates: u64,
+    // Candidates that the argument registers didn't rule out, so their
+    // heads were unified with the goal
+    pub tried: u64,
+    // Head unifications that succeeded
+    pub unified: u64,
+}
+
+impl IndexStatistics {
+    /**
+     * Returns the share of the tried candidates that unified, or None if
+     * none were tried.
+     */
+    pub fn hit_rate(&self) -> Option<f64> {
+        (self.tried > 0).then(|| self.unified as f64 / self.tried as f64)
+    }
+}
+
+/**
+ * Predicates being profiled by an engine and their timings so far.
+ */
+pub(crate) struct Profiler {
+    // Times are stored on the heap as nanoseconds since this instant
+    epoch: Instant,
+    // Index in profiles of each profiled predicate, by the name cell and the
+    // Arity cell data of its goals
+    predicates: HashMap<(HeapEntry, usize), usize>,
+    pub(crate) profiles: Vec<PredicateProfile>,
+    // Symbol of PROFILE_EXIT, once a predicate is profiled
+    pub(crate) exit_symbol: Option<usize>,
+}
+
+impl Profiler {
+    pub(crate) fn new() -> Self {
+        Profiler {
+            epoch: Instant::now(),
+            predicates: HashMap::new(),
+            profiles: Vec::new(),
+            exit_symbol: None,
+        }
+    }
+
+    /**
+     * Starts profiling a predicate, unless it already is, and returns the
+     * index of its profile.
+     */
+    pub(crate) fn add(&mut self, name_entry: HeapEntry, name: &str, arity: usize) -> usize {
+        let profiles = &mut self.profiles;
+        *self.predicates.entry((name_entry, arity + 1)).or_insert_with(|| {
+            profiles.push(PredicateProfile {
+                name: name.to_string(),
+                arity,
+                calls: 0,
+                exits: 0,
+                total_time: Duration::ZERO,
+                max_time: Duration::ZERO,
+                latencies: LatencyHistogram::default(),
+            });
+            profiles.len() - 1
+        })
+    }
+
+    /**
+     * Returns the profile of the predicate with the given dereferenced name
+     * cell and Arity cell data, if it is profiled.
+     */
+    pub(crate) fn profile_of(&self, name: HeapEntry, length: usize) -> Option<usize> {
+        if self.predicates.is_empty() {
+            return None;
+        }
+        self.predicates.get(&(name, length)).copied()
+    }
+
+    pub(crate) fn now(&self) -> i64 {
+        i64::try_from(self.epoch.elapsed().as_nanos()).unwrap_or(i64::MAX)
+    }
+
+    pub(crate) fn record_call(&mut self, profile: usize) {
+        self.profiles[profile].calls += 1;
+    }
+
+    pub(crate) fn record_exit(&mut self, profile: usize, started: i64) {
+        let latency = Duration::from_nanos(u64::try_from(self.now() - started).unwrap_or(0));
+        // Continuations resumed by another engine can refer to profiles
+        // this one doesn't have
+        if let Some(profile) = self.profiles.get_mut(profile) {
+            profile.exits += 1;
+            profile.total_time += latency;
+            profile.max_time = profile.max_time.max(latency);
+            profile.latencies.record(latency);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::profile::*;
+
+    #[test]
+    fn test_latency_histogram() {
+        let mut histogram = LatencyHistogram::default();
+        histogram.record(Duration::from_nanos(500));
+        histogram.record(Duration::from_micros(1));
+        histogram.record(Duration::from_micros(3));
+        histogram.record(Duration::from_micros(4));
+        histogram.record(Duration::from_secs(1 << 40));
+
+        assert_eq!(&[1, 1, 1, 1, 0], &histogram.counts()[..5]);
+        assert_eq!(1, histogram.counts()[LATENCY_BUCKETS - 1]);
+        assert_eq!(Duration::from_micros(4), LatencyHistogram::bucket_limit(2));
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::ast::*;
+
+/**
+ * Number of rule applications after which rewriting gives up, since rule
+ * sets such as `plus X Y` -> `plus Y X` never reach a normal form.
+ */
+pub const DEFAULT_MAX_STEPS: usize = 100_000;
+
+#[derive(Debug, PartialEq)]
+pub struct RewriteError {
+    // Number of rules applied before giving up
+    pub steps: usize,
+}
+
+impl fmt::Display for RewriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "no normal form found after {} rewrite steps", self.steps)
+    }
+}
+
+type RewriteFunction = Box<dyn Fn(&Term) -> Option<Term> + Send + Sync>;
+
+enum RewriteRule {
+    // Rewrites terms matching lhs into rhs, with the variables of lhs
+    // replaced by the subterms they matched
+    Pattern { lhs: Term, rhs: Term },
+    Function(RewriteFunction),
+}
+
+/**
+ * An ordered set of rewrite rules. Terms are rewritten bottom-up: arguments
+ * are normalized before their parent, and at each node the first rule that
+ * applies is used until none does.
+ */
+pub struct RuleSet {
+    rules: Vec<RewriteRule>,
+    max_steps: usize,
+}
+
+impl RuleSet {
+    pub fn new() -> Self {
+        RuleSet { rules: Vec::new(), max_steps: DEFAULT_MAX_STEPS }
+    }
+
+    /**
+     * Builds a rule set from the facts of a binary predicate in a program,
+     * e.g. `simplify (plus X zero) X` when `predicate` is `simplify`.
+     */
+    pub fn from_program(program: &Program, predicate: &str) -> Result<Self, String> {
+        let mut rules = RuleSet::new();
+        for clause in &program.clauses {
+            match &clause.head {
+                Term::Compound(CompoundTerm { name: SimpleTerm::Atom(name), parameters })
+                    if name == predicate && parameters.len() == 2 =>
+                {
+                    if !clause.body.is_empty() {
+                        return Err(format!("rewrite rule `{}` must be a fact", clause));
+                    }
+                    rules.add_rule(parameters[0].clone(), parameters[1].clone());
+                },
+                _ => (),
+            }
+        }
+        Ok(rules)
+    }
+
+    pub fn set_max_steps(&mut self, max_steps: usize) {
+        self.max_steps = max_steps;
+    }
+
+    pub fn add_rule(&mut self, lhs: Term, rhs: Term) {
+        self.rules.push(RewriteRule::Pattern { lhs, rhs });
+    }
+
+    /**
+     * Adds a rule implemented in Rust. The function returns the rewritten
+     * term, or None if the rule does not apply.
+     */
+    pub fn add_function<F>(&mut self, function: F)
+    where
+        F: Fn(&Term) -> Option<Term> + Send + Sync + 'static
+    {
+        self.rules.push(RewriteRule::Function(Box::new(function)));
+    }
+
+    /**
+     * Rewrites a term until no rule applies to it or any of its subterms.
+     */
+    pub fn rewrite(&self, term: &Term) -> Result<Term, RewriteError> {
+        let mut steps = 0;
+        self.normalize(term.clone(), &mut steps)
+    }
+
+    fn normalize(&self, term: Term, steps: &mut usize) -> Result<Term, RewriteError> {
+        let mut term = match term {
+            Term::Compound(CompoundTerm { name, parameters }) => {
+                let parameters = parameters
+                    .into_iter()
+                    .map(|param| self.normalize(param, steps))
+                    .collect::<Result<_, _>>()?;
+                Term::Compound(CompoundTerm { name, parameters })
+            },
+            simple_term => simple_term,
+        };
+
+        while let Some(rewritten) = self.apply(&term) {
+            if *steps == self.max_steps {
+                return Err(RewriteError { steps: *steps });
+            }
+            *steps += 1;
+            // The rewritten term may contain new redexes anywhere
+            term = self.normalize(rewritten, steps)?;
+        }
+        Ok(term)
+    }
+
+    /**
+     * Applies the first applicable rule to the root of a term.
+     */
+    fn apply(&self, term: &Term) -> Option<Term> {
+        self.rules.iter().find_map(|rule| match rule {
+            RewriteRule::Pattern { lhs, rhs } => {
+                let mut bindings = HashMap::new();
+                if matches(lhs, term, &mut bindings) {
+                    Some(substitute(rhs, &bindings))
+                } else {
+                    None
+                }
+            },
+            RewriteRule::Function(function) => function(term),
+        })
+    }
+}
+
+impl Default for RuleSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/**
+ * One-way matching: binds the variables of the pattern so that it becomes
+ * equal to the term. Variables in the term are treated like atoms.
+ */
+pub(crate) fn matches(pattern: &Term, term: &Term, bindings: &mut HashMap<String, Term>) -> bool {
+    match (pattern, term) {
+        (Term::Simple(SimpleTerm::Variable(name)), _) => bind(name, term, bindings),
+        (Term::Simple(pattern), Term::Simple(term)) => pattern == term,
+        (Term::Compound(pattern), Term::Compound(term)) => {
+            let name_matches = match &pattern.name {
+                SimpleTerm::Variable(name) => bind(name, &Term::Simple(term.name.clone()), bindings),
+                name => *name == term.name,
+            };
+            name_matches
+                && pattern.parameters.len() == term.parameters.len()
+                && pattern.parameters
+                    .iter()
+                    .zip(&term.parameters)
+                    .all(|(pattern, term)| matches(pattern, term, bindings))
+        },
+        _ => false
+    }
+}
+
+fn bind(name: &str, term: &Term, bindings: &mut HashMap<String, Term>) -> bool {
+    match bindings.get(name) {
+        Some(bound) => bound == term,
+        None => {
+            bindings.insert(name.to_string(), term.clone());
+            true
+        }
+    }
+}
+
+pub(crate) fn substitute(term: &Term, bindings: &HashMap<String, Term>) -> Term {
+    match term {
+        Term::Simple(SimpleTerm::Variable(name)) => match bindings.get(name) {
+            Some(bound) => bound.clone(),
+            None => term.clone(),
+        },
+        Term::Simple(_) => term.clone(),
+        Term::Compound(compound_term) => {
+            let parameters: Vec<Term> = compound_term.parameters
+                .iter()
+                .map(|param| substitute(param, bindings))
+                .collect();
+            match substitute(&Term::Simple(compound_term.name.clone()), bindings) {
+                Term::Simple(name) => Term::Compound(CompoundTerm { name, parameters }),
+                // A functor variable bound to a compound term adds arguments
+                // to it, like call/N
+                Term::Compound(mut bound) => {
+                    bound.parameters.extend(parameters);
+                    Term::Compound(bound)
+                },
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rewrite::*;
+    use crate::parser::parse;
+
+    fn term(code: &str) -> Term {
+        parse(code).unwrap().clauses.remove(0).head
+    }
+
+    fn simplifier() -> RuleSet {
+        let rules = parse(
+            "simplify (plus X zero) X \n\
+             simplify (plus zero X) X \n\
+             simplify (times X one) X \n\
+             simplify (times X zero) zero \n\
+             simplify (minus X X) zero"
+        ).unwrap();
+        RuleSet::from_program(&rules, "simplify").unwrap()
+    }
+
+    #[test]
+    fn test_rewrite_bottom_up() {
+        let rules = simplifier();
+        assert_eq!(
+            term("x"),
+            rules.rewrite(&term("plus (times x (plus one zero)) (minus y y)")).unwrap()
+        );
+        // Terms that no rule applies to are left alone
+        assert_eq!(term("plus x y"), rules.rewrite(&term("plus x y")).unwrap());
+    }
+
+    #[test]
+    fn test_rewrite_repeated_variable() {
+        let rules = simplifier();
+        assert_eq!(term("minus x y"), rules.rewrite(&term("minus x y")).unwrap());
+        assert_eq!(term("zero"), rules.rewrite(&term("minus (f X) (f X)")).unwrap());
+    }
+
+    #[test]
+    fn test_rewrite_result_is_normalized() {
+        let mut rules = RuleSet::new();
+        rules.add_rule(term("double X"), term("plus X X"));
+        rules.add_rule(term("plus zero zero"), term("zero"));
+        assert_eq!(term("zero"), rules.rewrite(&term("double zero")).unwrap());
+    }
+
+    #[test]
+    fn test_rewrite_function() {
+        // Peano addition, one step at a time
+        let mut rules = RuleSet::new();
+        rules.add_function(|term| match term {
+            Term::Compound(CompoundTerm { name: SimpleTerm::Atom(name), parameters })
+                if name == "add" && parameters.len() == 2 =>
+            {
+                match &parameters[0] {
+                    Term::Simple(SimpleTerm::Atom(zero)) if zero == "zero" => {
+                        Some(parameters[1].clone())
+                    },
+                    Term::Compound(CompoundTerm { name: SimpleTerm::Atom(s), parameters: n })
+                        if s == "s" && n.len() == 1 =>
+                    {
+                        Some(Term::compound("s", vec![
+                            Term::compound("add", vec![n[0].clone(), parameters[1].clone()])
+                        ]))
+                    },
+                    _ => None
+                }
+            },
+            _ => None
+        });
+        assert_eq!(
+            term("s (s (s zero))"),
+            rules.rewrite(&term("add (s (s zero)) (s zero)")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_rewrite_variable_functor() {
+        let mut rules = RuleSet::new();
+        rules.add_rule(term("twice F X"), term("F (F X)"));
+        assert_eq!(term("f (f a)"), rules.rewrite(&term("twice f a")).unwrap());
+    }
+
+    #[test]
+    fn test_rewrite_step_limit() {
+        let mut rules = RuleSet::new();
+        rules.add_rule(term("plus X Y"), term("plus Y X"));
+        rules.set_max_steps(10);
+        let error = rules.rewrite(&term("plus a b")).unwrap_err();
+        assert_eq!("no normal form found after 10 rewrite steps", error.to_string());
+    }
+
+    #[test]
+    fn test_rule_set_from_program_requires_facts() {
+        let program = parse("simplify X Y if other X Y").unwrap();
+        assert!(RuleSet::from_program(&program, "simplify").is_err());
+    }
+}
@@ -0,0 +1,71 @@
+/**
+ * Returns the candidate closest to name, if it is close enough to be what a
+ * typo of it was meant to be: at most one edit away for every four
+ * characters of name, so that very short names get no suggestion. Ties go
+ * to the candidate that sorts first, so that the suggestion doesn't depend
+ * on the order of the candidates.
+ */
+pub(crate) fn closest<'a, I>(name: &str, candidates: I) -> Option<&'a str>
+where
+    I: IntoIterator<Item = &'a str>
+{
+    let max_distance = name.chars().count() / 4;
+    candidates
+        .into_iter()
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/**
+ * Number of insertions, deletions, substitutions and swaps of adjacent
+ * characters that turn a into b (the optimal string alignment distance).
+ */
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // Distances between prefixes of a and b, by prefix lengths, keeping only
+    // the last three rows
+    let mut before_previous: Vec<usize> = Vec::new();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut current = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let substitution = previous[j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            current[j] = substitution.min(previous[j] + 1).min(current[j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before_previous[j - 2] + 1);
+            }
+        }
+        before_previous = std::mem::replace(&mut previous, current);
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::suggest::*;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(0, edit_distance("parent", "parent"));
+        assert_eq!(1, edit_distance("parnet", "parent"));
+        assert_eq!(1, edit_distance("paren", "parent"));
+        assert_eq!(2, edit_distance("prent/3", "parent/2"));
+        assert_eq!(3, edit_distance("", "abc"));
+    }
+
+    #[test]
+    fn test_closest() {
+        let candidates = ["parent/2", "parent/3", "ancestor/2", "age/2"];
+        assert_eq!(Some("parent/2"), closest("parnet/2", candidates));
+        assert_eq!(Some("parent/3"), closest("parent/1", ["parent/3", "ancestor/2"]));
+        // Ties go to the first candidate in sorted order
+        assert_eq!(Some("parent/2"), closest("parent/1", candidates));
+        assert_eq!(None, closest("sibling/2", candidates));
+        assert_eq!(None, closest("parent/2", ["parent/2"]));
+        assert_eq!(None, closest("q/1", ["p/1"]));
+    }
+}
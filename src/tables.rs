@@ -0,0 +1,310 @@
+use std::collections::HashSet;
+
+use crate::ast::*;
+use crate::parser::{is_atom, parse_integer};
+use crate::triples::ImportError;
+
+/**
+ * Cell value meaning that a condition does not matter for a row.
+ */
+pub const ANY_VALUE: &str = "-";
+
+/**
+ * Loads a decision table into facts of the given predicate, one per row.
+ *
+ * The table is either CSV or a Markdown table (detected by a leading `|`).
+ * The first row names the columns: names ending in `?` are conditions and
+ * the rest are actions. If no column is marked, the last column is the action
+ * and the others are conditions. Each fact lists the conditions followed by
+ * the actions, e.g.
+ *
+ * ```text
+ * level?,season?,discount
+ * gold,winter,high
+ * silver,-,low
+ * ```
+ *
+ * becomes `discount gold winter high` and `discount silver Season low` when
+ * loaded as `discount`. A condition cell that is empty or `-` matches
+ * anything, and is written as a variable named after its column. Any other
+ * cell must be an integer, which is loaded as a number, or a valid atom, so
+ * values such as `Gold` or `two words` are rejected rather than loaded as
+ * atoms that can't be written back.
+ */
+pub fn load_decision_table(predicate: &str, source: &str) -> Result<Program, ImportError> {
+    let rows = if source.trim_start().starts_with('|') {
+        read_markdown(source)?
+    } else {
+        read_csv(source)?
+    };
+
+    let mut rows = rows.into_iter();
+    let (header_line, header) = rows.next().ok_or(ImportError {
+        line: 1,
+        message: String::from("missing header row"),
+    })?;
+    let columns = read_header(&header).map_err(|message| ImportError { line: header_line, message })?;
+
+    let mut program = Program::new();
+    for (line, cells) in rows {
+        let error = |message: String| ImportError { line, message };
+        if cells.len() != columns.len() {
+            return Err(error(format!(
+                "expected {} columns, found {}",
+                columns.len(), cells.len()
+            )));
+        }
+
+        let mut parameters = Vec::new();
+        for column in &columns {
+            let cell = cells[column.index].as_str();
+            if cell.is_empty() || cell == ANY_VALUE {
+                if !column.condition {
+                    return Err(error(format!("missing value for action '{}'", column.name)));
+                }
+                parameters.push(Term::variable(&column.variable));
+            } else if is_atom(cell) {
+                parameters.push(Term::atom(cell));
+            } else if let Some(value) = parse_integer(cell) {
+                parameters.push(Term::number(value));
+            } else {
+                return Err(error(format!("'{}' in column '{}' is not an atom or an integer", cell, column.name)));
+            }
+        }
+
+        program.push_clause(Clause::fact(Term::Compound(CompoundTerm {
+            name: SimpleTerm::Atom(predicate.to_string()),
+            parameters,
+        })));
+    }
+    Ok(program)
+}
+
+struct Column {
+    // Position of the column in the table
+    index: usize,
+    name: String,
+    condition: bool,
+    // Variable used for cells that match anything
+    variable: String,
+}
+
+/**
+ * Returns the columns of a table in argument order: conditions first, then
+ * actions.
+ */
+fn read_header(header: &[String]) -> Result<Vec<Column>, String> {
+    let marked = header.iter().any(|name| name.ends_with('?'));
+    let mut variables = HashSet::new();
+    let mut columns = Vec::new();
+    for (index, name) in header.iter().enumerate() {
+        let condition = if marked { name.ends_with('?') } else { index + 1 < header.len() };
+        let name = name.trim_end_matches('?').trim().to_string();
+        let variable = column_variable(&name);
+        if variable.is_empty() {
+            return Err(format!("column {} needs a name with at least one letter", index + 1));
+        }
+        if !variables.insert(variable.clone()) {
+            return Err(format!("duplicate column '{}'", name));
+        }
+        columns.push(Column { index, name, condition, variable });
+    }
+    if !columns.iter().any(|column| !column.condition) {
+        return Err(String::from("table has no action column"));
+    }
+
+    columns.sort_by_key(|column| !column.condition);
+    Ok(columns)
+}
+
+/**
+ * Turns a column name such as `customer level` into a variable name such as
+ * `CustomerLevel`.
+ */
+fn column_variable(name: &str) -> String {
+    name.split(|c: char| !c.is_ascii_alphabetic())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            let first = chars.next().unwrap().to_ascii_uppercase();
+            std::iter::once(first).chain(chars).collect::<String>()
+        })
+        .collect()
+}
+
+/**
+ * Splits CSV into rows of trimmed cells, each with its 1-based line number.
+ * Cells may be quoted with `"`, with `""` standing for a quote inside them.
+ */
+fn read_csv(source: &str) -> Result<Vec<(usize, Vec<String>)>, ImportError> {
+    let mut rows = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut cells = Vec::new();
+        let mut chars = line.chars().peekable();
+        loop {
+            let mut cell = String::new();
+            while chars.peek().is_some_and(|c| *c == ' ' || *c == '\t') {
+                chars.next();
+            }
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            cell.push('"');
+                        },
+                        Some('"') => break,
+                        Some(c) => cell.push(c),
+                        None => return Err(ImportError {
+                            line: i + 1,
+                            message: String::from("unterminated quoted cell"),
+                        }),
+                    }
+                }
+            }
+            while let Some(c) = chars.next_if(|c| *c != ',') {
+                cell.push(c);
+            }
+            cells.push(cell.trim().to_string());
+            if chars.next().is_none() {
+                break;
+            }
+        }
+        rows.push((i + 1, cells));
+    }
+    Ok(rows)
+}
+
+/**
+ * Splits a Markdown table into rows of trimmed cells, each with its 1-based
+ * line number, skipping the row that separates the header from the body.
+ */
+fn read_markdown(source: &str) -> Result<Vec<(usize, Vec<String>)>, ImportError> {
+    let mut rows = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let inner = line
+            .strip_prefix('|')
+            .ok_or(ImportError { line: i + 1, message: String::from("expected '|' at start of row") })?;
+        let inner = inner.strip_suffix('|').unwrap_or(inner);
+        let cells: Vec<String> = inner.split('|').map(|cell| cell.trim().to_string()).collect();
+
+        let separator = cells.iter().all(|cell| {
+            let dashes = cell.trim_start_matches(':').trim_end_matches(':');
+            !dashes.is_empty() && dashes.chars().all(|c| c == '-')
+        });
+        if rows.len() == 1 && separator {
+            continue;
+        }
+        rows.push((i + 1, cells));
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tables::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_load_csv() {
+        let source = "\
+# customer discounts
+level?, season?, discount
+gold, winter, high
+silver, -, low
+bronze,,none
+";
+        let program = load_decision_table("discount", source).unwrap();
+        let expected = parse(
+            "discount gold winter high \n\
+             discount silver Season low \n\
+             discount bronze Season none"
+        ).unwrap();
+        assert_eq!(expected, program);
+    }
+
+    #[test]
+    fn test_load_markdown() {
+        let source = "\
+| ship to | customer level | carrier |
+|---------|:--------------:|--------:|
+| home    | gold           | express |
+| -       | silver         | post    |
+";
+        let program = load_decision_table("carrier", source).unwrap();
+        let expected = parse(
+            "carrier home gold express \n\
+             carrier ShipTo silver post"
+        ).unwrap();
+        assert_eq!(expected, program);
+    }
+
+    #[test]
+    fn test_actions_follow_conditions() {
+        let source = "discount,level?\nhigh,gold\n";
+        let program = load_decision_table("discount", source).unwrap();
+        assert_eq!(parse("discount gold high").unwrap(), program);
+    }
+
+    #[test]
+    fn test_quoted_cells() {
+        let source = "item?,label\n\"book\",\" rdf:type \"\n";
+        let program = load_decision_table("label", source).unwrap();
+        assert_eq!(parse("label book rdf:type").unwrap(), program);
+
+        let source = "item?,label\nbook,\"Say \"\"hi\"\", please\"\n";
+        let error = load_decision_table("label", source).unwrap_err();
+        assert_eq!("line 2: 'Say \"hi\", please' in column 'label' is not an atom or an integer", error.to_string());
+    }
+
+    #[test]
+    fn test_numeric_cells() {
+        let source = "quantity?,discount\n10,5\n-,0\n0x10,-3\n1_000,20\n";
+        let program = load_decision_table("discount", source).unwrap();
+        let expected = parse(
+            "discount 10 5 \n\
+             discount Quantity 0 \n\
+             discount 16 -3 \n\
+             discount 1000 20"
+        ).unwrap();
+        assert_eq!(expected, program);
+    }
+
+    #[test]
+    fn test_cells_must_be_atoms() {
+        let error = load_decision_table("discount", "level?,discount\nGold,high\n").unwrap_err();
+        assert_eq!(ImportError { line: 2, message: String::from("'Gold' in column 'level' is not an atom or an integer") }, error);
+
+        for cell in ["two words", "and", "f(x)", "x_y", "1.5", "99999999999999999999"] {
+            let source = format!("level?,discount\ngold,{}\n", cell);
+            assert!(load_decision_table("discount", &source).is_err(), "{}", cell);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = load_decision_table("p", "a?,b\nx,y,z\n").unwrap_err();
+        assert_eq!(ImportError { line: 2, message: String::from("expected 2 columns, found 3") }, error);
+
+        let error = load_decision_table("p", "a?,b\nx,-\n").unwrap_err();
+        assert_eq!(ImportError { line: 2, message: String::from("missing value for action 'b'") }, error);
+
+        let error = load_decision_table("p", "a?,b?\n").unwrap_err();
+        assert_eq!(1, error.line);
+
+        let error = load_decision_table("p", "a?,A?,b\n").unwrap_err();
+        assert_eq!("line 1: duplicate column 'A'", error.to_string());
+
+        assert_eq!(1, load_decision_table("p", "").unwrap_err().line);
+    }
+}
@@ -0,0 +1,325 @@
+use std::fmt;
+
+use crate::ast::*;
+use crate::parser::parse;
+
+/**
+ * Name of the predicate that imported triples are stored under:
+ * `triple Subject Predicate Object`.
+ */
+pub const TRIPLE_PREDICATE: &str = "triple";
+
+/**
+ * Predicate used for rows of an edge list that only have two columns.
+ */
+pub const EDGE_LIST_PREDICATE: &str = "edge";
+
+/**
+ * Helper predicates for traversing imported graphs.
+ */
+pub const GRAPH_PRELUDE: &str = "\
+# S is directly connected to O by any predicate
+edge S O if triple S P O
+# O can be reached from S by following one or more edges
+reachable S O if edge S O
+reachable S O if edge S X and reachable X O
+";
+
+#[derive(Debug, PartialEq)]
+pub struct ImportError {
+    // 1-based line number of the offending line
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/**
+ * Returns the graph traversal helpers as a program that can be compiled
+ * alongside imported facts.
+ */
+pub fn graph_prelude() -> Program {
+    parse(GRAPH_PRELUDE).unwrap()
+}
+
+/**
+ * Loads an N-Triples document into `triple S P O` facts.
+ *
+ * IRIs become atoms of the IRI text without the angle brackets, blank nodes
+ * keep their `_:label` form and literals become atoms of their lexical form
+ * (language tags and datatypes are dropped). Since atoms are interned by the
+ * compiler, each distinct IRI is only stored once no matter how many triples
+ * mention it.
+ */
+pub fn load_ntriples(source: &str) -> Result<Program, ImportError> {
+    load_ntriples_with_prefixes(source, &[])
+}
+
+/**
+ * Like load_ntriples, but IRIs that start with one of the given namespaces are
+ * compacted into namespaced atoms, e.g. `rdf:type`. Register the same prefixes
+ * with the compiler to expand them back into IRIs.
+ */
+pub fn load_ntriples_with_prefixes(
+    source: &str,
+    prefixes: &[(&str, &str)]
+) -> Result<Program, ImportError> {
+    let mut program = Program::new();
+    for (i, line) in source.lines().enumerate() {
+        let error = |message: &str| ImportError { line: i + 1, message: message.to_string() };
+
+        let mut rest = line.trim_start();
+        if rest.is_empty() || rest.starts_with('#') {
+            continue;
+        }
+
+        let mut nodes = Vec::new();
+        for _ in 0..3 {
+            let (node, remaining) = read_node(rest).map_err(error)?;
+            nodes.push(compact_iri(node, prefixes));
+            rest = remaining.trim_start();
+        }
+
+        if !rest.starts_with('.') {
+            return Err(error("expected '.' at end of triple"));
+        }
+        let rest = rest[1..].trim_start();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            return Err(error("unexpected text after triple"));
+        }
+
+        program.push_clause(triple_fact(nodes));
+    }
+    Ok(program)
+}
+
+/**
+ * Loads a tab-separated edge list into `triple S P O` facts. Rows have either
+ * three columns (subject, predicate, object) or two (subject, object), in which
+ * case the predicate is `edge`.
+ */
+pub fn load_edge_list(source: &str) -> Result<Program, ImportError> {
+    let mut program = Program::new();
+    for (i, line) in source.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let columns: Vec<String> = line.split('\t').map(|c| c.trim().to_string()).collect();
+        let nodes = match columns.len() {
+            2 => {
+                let mut columns = columns.into_iter();
+                let subject = columns.next().unwrap();
+                let object = columns.next().unwrap();
+                vec![subject, EDGE_LIST_PREDICATE.to_string(), object]
+            },
+            3 => columns,
+            n => return Err(ImportError {
+                line: i + 1,
+                message: format!("expected 2 or 3 columns, found {}", n),
+            }),
+        };
+
+        program.push_clause(triple_fact(nodes));
+    }
+    Ok(program)
+}
+
+/**
+ * Replaces the longest matching namespace at the start of an IRI with its
+ * prefix.
+ */
+fn compact_iri(iri: String, prefixes: &[(&str, &str)]) -> String {
+    let best = prefixes
+        .iter()
+        .filter(|(_, namespace)| iri.len() > namespace.len() && iri.starts_with(namespace))
+        .max_by_key(|(_, namespace)| namespace.len());
+    match best {
+        Some((prefix, namespace)) => format!("{}:{}", prefix, &iri[namespace.len()..]),
+        None => iri,
+    }
+}
+
+fn triple_fact(nodes: Vec<String>) -> Clause {
+    let parameters = nodes
+        .into_iter()
+        .map(|node| Term::Simple(SimpleTerm::Atom(node)))
+        .collect();
+
+    Clause {
+        head: Term::Compound(CompoundTerm {
+            name: SimpleTerm::Atom(TRIPLE_PREDICATE.to_string()),
+            parameters,
+        }),
+        body: Vec::new(),
+        annotations: Vec::new(),
+    }
+}
+
+/**
+ * Reads one IRI, blank node or literal from the start of `input` and returns it
+ * along with the remaining input.
+ */
+fn read_node(input: &str) -> Result<(String, &str), &'static str> {
+    if let Some(rest) = input.strip_prefix('<') {
+        let end = rest.find('>').ok_or("unterminated IRI")?;
+        Ok((rest[..end].to_string(), &rest[end + 1..]))
+    } else if input.starts_with("_:") {
+        // Labels may contain '.' but not end with it, so a trailing '.' is
+        // the end of the triple.
+        let end = input.find(char::is_whitespace).unwrap_or(input.len());
+        let end = input[..end].trim_end_matches('.').len();
+        Ok((input[..end].to_string(), &input[end..]))
+    } else if let Some(rest) = input.strip_prefix('"') {
+        let (literal, rest) = read_literal(rest)?;
+        Ok((literal, skip_literal_suffix(rest)?))
+    } else {
+        Err("expected IRI, blank node or literal")
+    }
+}
+
+/**
+ * Reads the body of a string literal (after the opening quote), processing
+ * escape sequences.
+ */
+fn read_literal(input: &str) -> Result<(String, &str), &'static str> {
+    let mut literal = String::new();
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((literal, &input[i + 1..])),
+            '\\' => {
+                let escaped = match chars.next().map(|(_, c)| c) {
+                    Some('t') => '\t',
+                    Some('b') => '\u{8}',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('f') => '\u{c}',
+                    Some('"') => '"',
+                    Some('\'') => '\'',
+                    Some('\\') => '\\',
+                    Some('u') => read_unicode_escape(&mut chars, 4)?,
+                    Some('U') => read_unicode_escape(&mut chars, 8)?,
+                    _ => return Err("invalid escape sequence in literal"),
+                };
+                literal.push(escaped);
+            },
+            _ => literal.push(c),
+        }
+    }
+    Err("unterminated literal")
+}
+
+fn read_unicode_escape(
+    chars: &mut std::str::CharIndices,
+    digits: usize
+) -> Result<char, &'static str> {
+    let hex: String = chars.take(digits).map(|(_, c)| c).collect();
+    if hex.len() != digits {
+        return Err("invalid unicode escape in literal");
+    }
+    u32::from_str_radix(&hex, 16)
+        .ok()
+        .and_then(char::from_u32)
+        .ok_or("invalid unicode escape in literal")
+}
+
+/**
+ * Skips the optional language tag or datatype that follows a literal.
+ */
+fn skip_literal_suffix(input: &str) -> Result<&str, &'static str> {
+    if let Some(rest) = input.strip_prefix('@') {
+        let end = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+            .unwrap_or(rest.len());
+        Ok(&rest[end..])
+    } else if let Some(rest) = input.strip_prefix("^^<") {
+        let end = rest.find('>').ok_or("unterminated datatype IRI")?;
+        Ok(&rest[end + 1..])
+    } else {
+        Ok(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::triples::*;
+
+    fn triple(s: &str, p: &str, o: &str) -> Clause {
+        triple_fact(vec![s.to_string(), p.to_string(), o.to_string()])
+    }
+
+    #[test]
+    fn test_load_ntriples() {
+        let source = "\
+# people
+<http://ex.org/alice> <http://xmlns.com/foaf/0.1/knows> <http://ex.org/bob> .
+_:b0 <http://xmlns.com/foaf/0.1/name> \"Bob \\\"B\\\" \\u00e9\"@en .
+
+<http://ex.org/bob> <http://ex.org/age> \"42\"^^<http://www.w3.org/2001/XMLSchema#integer> . # comment
+";
+        let program = load_ntriples(source).unwrap();
+
+        let mut expected = Program::new();
+        expected.push_clause(triple(
+            "http://ex.org/alice",
+            "http://xmlns.com/foaf/0.1/knows",
+            "http://ex.org/bob"
+        ));
+        expected.push_clause(triple("_:b0", "http://xmlns.com/foaf/0.1/name", "Bob \"B\" é"));
+        expected.push_clause(triple("http://ex.org/bob", "http://ex.org/age", "42"));
+
+        assert_eq!(expected, program);
+    }
+
+    #[test]
+    fn test_load_ntriples_with_prefixes() {
+        let source = "<http://ex.org/a> <http://www.w3.org/1999/02/22-rdf-syntax-ns#type> <http://ex.org/people/Person> .\n";
+        let prefixes = [
+            ("rdf", "http://www.w3.org/1999/02/22-rdf-syntax-ns#"),
+            ("ex", "http://ex.org/"),
+            ("people", "http://ex.org/people/"),
+        ];
+        let program = load_ntriples_with_prefixes(source, &prefixes).unwrap();
+        assert_eq!(vec![triple("ex:a", "rdf:type", "people:Person")], program.clauses);
+    }
+
+    #[test]
+    fn test_load_ntriples_blank_node_object() {
+        let program = load_ntriples("<a> <b> _:x.\n").unwrap();
+        assert_eq!(vec![triple("a", "b", "_:x")], program.clauses);
+    }
+
+    #[test]
+    fn test_load_ntriples_errors() {
+        let error = load_ntriples("<a> <b> <c>\n").unwrap_err();
+        assert_eq!(1, error.line);
+
+        let error = load_ntriples("<a> <b> <c> .\n<a> <b> \"c .\n").unwrap_err();
+        assert_eq!(ImportError { line: 2, message: String::from("unterminated literal") }, error);
+    }
+
+    #[test]
+    fn test_load_edge_list() {
+        let program = load_edge_list("a\tknows\tb\nb\tc\n").unwrap();
+
+        let mut expected = Program::new();
+        expected.push_clause(triple("a", "knows", "b"));
+        expected.push_clause(triple("b", "edge", "c"));
+
+        assert_eq!(expected, program);
+
+        let error = load_edge_list("a\tb\tc\td\n").unwrap_err();
+        assert_eq!(1, error.line);
+    }
+
+    #[test]
+    fn test_graph_prelude_parses() {
+        let program = graph_prelude();
+        assert_eq!(3, program.clauses.len());
+    }
+}
@@ -0,0 +1,365 @@
+use crate::ast::*;
+use crate::extract::LIST_CONS;
+
+/**
+ * What write_term_with_options writes in place of the subterms and list
+ * elements it leaves out.
+ */
+pub const ELISION: &str = "...";
+
+/**
+ * How write_term_with writes integers. Every format can be read back.
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct NumberFormat {
+    pub radix: Radix,
+    // Separate groups of this many digits with underscores, counting from
+    // the right, e.g. 1_000_000
+    pub digit_group: Option<usize>,
+}
+
+/**
+ * How write_term_with_options writes terms. By default a term is written in
+ * full and can be read back; limits make it write `...` instead of the parts
+ * beyond them, so that a huge term stays readable.
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct WriteOptions {
+    pub number_format: NumberFormat,
+    // Subterms nested deeper than this are left out. The term is at depth 1
+    // and the elements of a `cons` list are all one level deeper than the
+    // list.
+    pub max_depth: Option<usize>,
+    // Elements of a `cons` list after this many are left out, along with
+    // the rest of the list
+    pub max_list_length: Option<usize>,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Radix {
+    Binary,
+    Octal,
+    #[default]
+    Decimal,
+    Hexadecimal,
+}
+
+impl Radix {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "binary" | "2" => Some(Radix::Binary),
+            "octal" | "8" => Some(Radix::Octal),
+            "decimal" | "10" => Some(Radix::Decimal),
+            "hex" | "16" => Some(Radix::Hexadecimal),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Writes a program back out in butterfly syntax, one clause or query per line.
+ */
+pub fn write_program(program: &Program) -> String {
+    let mut out = String::new();
+    for declaration in &program.types {
+        write_type_declaration(&mut out, declaration);
+        out.push('\n');
+    }
+    for clause in &program.clauses {
+        for annotation in &clause.annotations {
+            write_annotation(&mut out, annotation);
+            out.push('\n');
+        }
+        write_clause(&mut out, clause);
+        out.push('\n');
+    }
+    for query in &program.queries {
+        write_query(&mut out, query);
+        out.push('\n');
+    }
+    out
+}
+
+pub fn write_type_declaration(out: &mut String, declaration: &TypeDeclaration) {
+    out.push_str("@type ");
+    out.push_str(&declaration.name);
+    for argument in &declaration.arguments {
+        out.push(' ');
+        out.push_str(argument);
+    }
+}
+
+pub fn write_annotation(out: &mut String, annotation: &Annotation) {
+    out.push('@');
+    out.push_str(&annotation.name);
+    for argument in &annotation.arguments {
+        out.push(' ');
+        out.push_str(argument);
+    }
+}
+
+/**
+ * Writes a clause on one line. Its annotations are left out, write_program
+ * puts them on the lines before.
+ */
+pub fn write_clause(out: &mut String, clause: &Clause) {
+    write_term(out, &clause.head);
+    for (i, goal) in clause.body.iter().enumerate() {
+        out.push_str(if i == 0 { " if " } else { " and " });
+        write_term(out, goal);
+    }
+}
+
+pub fn write_query(out: &mut String, query: &Query) {
+    out.push('?');
+    // Consecutive goals would be read back as a single compound term, so
+    // each goal gets its own parentheses when there is more than one.
+    let parenthesize = query.sub_queries.len() > 1;
+    for goal in &query.sub_queries {
+        out.push(' ');
+        // If-then-else goals have their own parentheses
+        if parenthesize && goal.as_if_then_else().is_none() {
+            out.push('(');
+            write_term(out, goal);
+            out.push(')');
+        } else {
+            write_term(out, goal);
+        }
+    }
+}
+
+pub fn write_term(out: &mut String, term: &Term) {
+    write_term_with(out, term, NumberFormat::default());
+}
+
+/**
+ * Writes a term with its numbers in the given format.
+ */
+pub fn write_term_with(out: &mut String, term: &Term, format: NumberFormat) {
+    write_term_with_options(out, term, WriteOptions { number_format: format, ..Default::default() });
+}
+
+pub fn write_term_with_options(out: &mut String, term: &Term, options: WriteOptions) {
+    write_term_at(out, term, options, 1);
+}
+
+fn write_term_at(out: &mut String, term: &Term, options: WriteOptions, depth: usize) {
+    if options.max_depth.is_some_and(|max_depth| depth > max_depth) {
+        out.push_str(ELISION);
+        return;
+    }
+
+    if let Some((condition, then, otherwise)) = term.as_if_then_else() {
+        out.push_str("(if ");
+        write_conjunction(out, condition, options, depth + 1);
+        out.push_str(" then ");
+        write_conjunction(out, then, options, depth + 1);
+        if let Some(otherwise) = otherwise {
+            out.push_str(" else ");
+            write_conjunction(out, otherwise, options, depth + 1);
+        }
+        out.push(')');
+        return;
+    }
+
+    match term {
+        Term::Simple(simple_term) => write_simple_term(out, simple_term, options.number_format),
+        // Unification and equality have no prefix form that can be read back
+        Term::Compound(CompoundTerm { name: SimpleTerm::Atom(name), parameters })
+            if [UNIFY, NOT_UNIFY, EQUAL, NOT_EQUAL, UNIV, DECIDED].contains(&name.as_str()) && parameters.len() == 2 =>
+        {
+            write_argument(out, &parameters[0], options, depth + 1);
+            out.push(' ');
+            out.push_str(name);
+            out.push(' ');
+            write_argument(out, &parameters[1], options, depth + 1);
+        },
+        Term::Compound(compound_term) if as_cons(term).is_some() => {
+            write_list(out, compound_term, options, depth);
+        },
+        Term::Compound(compound_term) => {
+            write_simple_term(out, &compound_term.name, options.number_format);
+            for param in &compound_term.parameters {
+                out.push(' ');
+                write_argument(out, param, options, depth + 1);
+            }
+        },
+    }
+}
+
+/**
+ * Writes a `cons` list the same way as any other term, but without counting
+ * each tail as one level deeper than the list before it.
+ */
+fn write_list(out: &mut String, list: &CompoundTerm, options: WriteOptions, depth: usize) {
+    let mut parentheses = 0;
+    let mut length = 0;
+    let mut list = &list.parameters;
+    loop {
+        out.push_str(LIST_CONS);
+        out.push(' ');
+        write_argument(out, &list[0], options, depth + 1);
+        out.push(' ');
+        length += 1;
+        match as_cons(&list[1]) {
+            Some(_) if options.max_list_length.is_some_and(|max_length| length >= max_length) => {
+                out.push_str(ELISION);
+                break;
+            },
+            Some(tail) => {
+                out.push('(');
+                parentheses += 1;
+                list = &tail.parameters;
+            },
+            None => {
+                write_argument(out, &list[1], options, depth + 1);
+                break;
+            },
+        }
+    }
+    out.push_str(&")".repeat(parentheses));
+}
+
+fn as_cons(term: &Term) -> Option<&CompoundTerm> {
+    match term {
+        Term::Compound(compound_term @ CompoundTerm { name: SimpleTerm::Atom(name), parameters })
+            if name == LIST_CONS && parameters.len() == 2 =>
+        {
+            Some(compound_term)
+        },
+        _ => None,
+    }
+}
+
+/**
+ * Writes an integer with the prefix of its radix, e.g. `-0x1F` or `0b101`.
+ */
+pub fn write_number(out: &mut String, value: i64, format: NumberFormat) {
+    let magnitude = value.unsigned_abs();
+    let (prefix, digits) = match format.radix {
+        Radix::Binary => ("0b", format!("{:b}", magnitude)),
+        Radix::Octal => ("0o", format!("{:o}", magnitude)),
+        Radix::Decimal => ("", magnitude.to_string()),
+        Radix::Hexadecimal => ("0x", format!("{:X}", magnitude)),
+    };
+    if value < 0 {
+        out.push('-');
+    }
+    out.push_str(prefix);
+    match format.digit_group {
+        Some(size) if size > 0 => {
+            for (i, digit) in digits.chars().enumerate() {
+                if i > 0 && (digits.len() - i) % size == 0 {
+                    out.push('_');
+                }
+                out.push(digit);
+            }
+        },
+        _ => out.push_str(&digits),
+    }
+}
+
+fn write_conjunction(out: &mut String, goals: &[Term], options: WriteOptions, depth: usize) {
+    for (i, goal) in goals.iter().enumerate() {
+        if i > 0 {
+            out.push_str(" and ");
+        }
+        write_term_at(out, goal, options, depth);
+    }
+}
+
+/**
+ * Arguments that are compound terms need parentheses so their own arguments
+ * are not read as arguments of the enclosing term.
+ */
+fn write_argument(out: &mut String, term: &Term, options: WriteOptions, depth: usize) {
+    match term {
+        Term::Simple(_) => write_term_at(out, term, options, depth),
+        Term::Compound(_) if options.max_depth.is_some_and(|max_depth| depth > max_depth) => {
+            out.push_str(ELISION);
+        },
+        Term::Compound(_) => {
+            out.push('(');
+            write_term_at(out, term, options, depth);
+            out.push(')');
+        },
+    }
+}
+
+fn write_simple_term(out: &mut String, term: &SimpleTerm, format: NumberFormat) {
+    match term {
+        SimpleTerm::Atom(name) | SimpleTerm::Variable(name) => out.push_str(name),
+        SimpleTerm::Number(value) => write_number(out, *value, format),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::writer::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_write_program_round_trip() {
+        let code = "@type p person\n\
+                    a\n\
+                    @priority 5\n\
+                    @source rules.csv\n\
+                    a (a (b e f)) c if a and X\n\
+                    p X if X a b\n\
+                    age tom 42 (f -7)\n\
+                    ? parent tom X\n\
+                    ? (a X) (b) (c (d e))\n\
+                    ? (if a X and b then c else not d) (e X)\n\
+                    ? (X = (f (g Y))) (not (X \\= (a = b)))\n\
+                    ? (X == Y) ((f X) \\== (a == b)) (X =.. L) (X ?= Y)\n";
+        let program = parse(code).unwrap();
+        let written = write_program(&program);
+
+        assert_eq!(code, written);
+        assert_eq!(program, parse(&written).unwrap());
+    }
+
+    #[test]
+    fn test_write_with_limits() {
+        let write = |code: &str, max_depth, max_list_length| {
+            let term = parse(&format!("? {}", code)).unwrap().queries.remove(0).sub_queries.remove(0);
+            let mut out = String::new();
+            write_term_with_options(&mut out, &term, WriteOptions { max_depth, max_list_length, ..Default::default() });
+            out
+        };
+        let list = "cons 1 (cons (f (g a)) (cons 3 nil))";
+        assert_eq!(list, write(list, None, None));
+        assert_eq!(list, write(list, Some(4), Some(3)));
+        assert_eq!("cons 1 (cons (f ...) (cons 3 nil))", write(list, Some(2), None));
+        assert_eq!("cons 1 (cons (f (g a)) ...)", write(list, None, Some(2)));
+        assert_eq!("cons 1 ...", write(list, Some(2), Some(1)));
+        assert_eq!("f (g ...) b", write("f (g (h c)) b", Some(2), None));
+        assert_eq!("f ... ...", write("f (g (h c)) b", Some(1), None));
+        assert_eq!("X = (f ...)", write("X = (f (g a))", Some(2), None));
+        // A partial list's tail is written like an element
+        assert_eq!("cons a (cons b T)", write("cons a (cons b T)", Some(2), Some(2)));
+    }
+
+    #[test]
+    fn test_write_number_formats() {
+        let write = |value, radix, digit_group| {
+            let mut out = String::new();
+            write_number(&mut out, value, NumberFormat { radix, digit_group });
+            out
+        };
+        assert_eq!("1234567", write(1234567, Radix::Decimal, None));
+        assert_eq!("1_234_567", write(1234567, Radix::Decimal, Some(3)));
+        assert_eq!("-0x1F", write(-31, Radix::Hexadecimal, None));
+        assert_eq!("0b1010_0101", write(0xA5, Radix::Binary, Some(4)));
+        assert_eq!("0o17", write(15, Radix::Octal, Some(3)));
+        assert_eq!("-0x8000000000000000", write(i64::MIN, Radix::Hexadecimal, None));
+
+        // Every format is read back as the same number
+        let term = Term::compound("p", vec![Term::number(-0x5EED), Term::number(1 << 40)]);
+        for radix in [Radix::Binary, Radix::Octal, Radix::Decimal, Radix::Hexadecimal] {
+            let mut out = String::from("? ");
+            write_term_with(&mut out, &term, NumberFormat { radix, digit_group: Some(3) });
+            assert_eq!(vec![term.clone()], parse(&out).unwrap().queries[0].sub_queries, "{}", out);
+        }
+    }
+}
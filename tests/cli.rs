@@ -0,0 +1,88 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/**
+ * Writes code to a file named after the test and runs the butterfly-prolog
+ * binary on it with the given subcommand and options.
+ */
+fn run(name: &str, code: &str, args: &[&str]) -> Output {
+    let path: PathBuf = env::temp_dir().join(format!("butterfly-prolog-cli-{}-{}", std::process::id(), name));
+    fs::write(&path, code).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_butterfly-prolog"))
+        .args(args)
+        .arg(&path)
+        .output()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+    output
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn test_syntax_errors_are_reported() {
+    for command in ["run", "test", "explain", "export", "import", "minimize"] {
+        let args: &[&str] = match command {
+            "minimize" => &["minimize", "--expect", "anything"],
+            _ => &[command],
+        };
+        let output = run(&format!("syntax-error-{}", command), "parent tom (bob\n", args);
+        assert_eq!(Some(1), output.status.code(), "{}", command);
+        assert!(!stderr(&output).contains("panicked"), "{}: {}", command, stderr(&output));
+        assert!(stderr(&output).contains("1:"), "{}: {}", command, stderr(&output));
+    }
+}
+
+#[test]
+fn test_explain() {
+    let code = "edge a b\n\
+                edge b c\n\
+                path X Y if edge X Y\n\
+                path X Y if edge X Z and path Z Y\n\
+                ? path a Y\n";
+    let output = run("explain", code, &["explain"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        "? path a Y\n  path a Y: path/2, 2 of 2 clauses to try, argument 1 indexed, calls edge/2 path/2\n",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn test_export_and_import() {
+    let output = run("export", "grandparent X Z if parent X Y and parent Y Z\n", &["export"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!("grandparent(X, Z) :-\n    parent(X, Y),\n    parent(Y, Z).\n", stdout(&output));
+
+    let output = run("import", &stdout(&output), &["import", "--dialect", "swi"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!("grandparent X Z if parent X Y and parent Y Z\n", stdout(&output));
+
+    let output = run("import-error", "p :- a ; b.\n", &["import"]);
+    assert_eq!(Some(1), output.status.code());
+    assert!(stderr(&output).contains("line 1: disjunction `;` without `->` has no butterfly equivalent"), "{}", stderr(&output));
+}
+
+#[test]
+fn test_minimize_engine_error() {
+    let code = "q\n\
+                p X if q and X\n\
+                r if q\n\
+                ? r\n\
+                ? p Z\n";
+    let output = run("minimize", code, &["minimize", "--expect", "instantiation error"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!("q\np X if X\n? p Z\n", stdout(&output));
+
+    let output = run("minimize-no-error", "p\n? p\n", &["minimize", "--expect", "instantiation error"]);
+    assert_eq!(Some(1), output.status.code());
+    assert!(stderr(&output).ends_with("does not fail with \"instantiation error\"\n"), "{}", stderr(&output));
+}